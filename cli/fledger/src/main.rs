@@ -1,12 +1,25 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
 use flarch::{
     data_storage::{DataStorage, DataStorageFile},
-    tasks::wait_ms,
-    web_rtc::connection::{ConnectionConfig, HostLogin, Login},
+    tasks::{now, spawn_local, time::Duration, wait_ms},
+    web_rtc::{
+        connection::{ConnectionConfig, HostLogin, Login},
+        probe::probe,
+    },
 };
-use flmodules::network::{network_broker_start, signal::SIGNAL_VERSION};
-use flnode::{node::Node, version::VERSION_STRING};
+use flmodules::{
+    gossip_events::core::{Category, EventsArchive},
+    network::{network_broker_start, signal::SIGNAL_VERSION},
+    nodeconfig::{NodeConfig, NodeRole},
+    Capabilities,
+};
+use flnode::{
+    crash::{install_panic_hook, RecentLogs},
+    node::Node,
+    version::VERSION_STRING,
+};
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
 
 /// Fledger node CLI binary
 #[derive(Parser, Debug)]
@@ -28,9 +41,295 @@ struct Args {
     #[clap(short, long, default_value = "wss://signal.fledg.re")]
     signal_url: String,
 
+    /// More signalling servers to connect to at the same time
+    #[clap(long)]
+    extra_signal_url: Vec<String>,
+
+    /// Runs a read-only node, which syncs and serves data but never publishes
+    /// anything, and uses a new identity every time
+    #[clap(long)]
+    read_only: bool,
+
+    /// Encrypts the messages to other nodes end-to-end, if they also enable it
+    #[clap(long)]
+    encrypt: bool,
+
+    /// Stores crash reports with the last log lines, and sends them encrypted
+    /// to the collector set in the crash report configuration
+    #[clap(long)]
+    crash_reports: bool,
+
     /// Verbosity of the logger
     #[clap(flatten)]
     verbosity: clap_verbosity_flag::Verbosity,
+
+    /// Serves the metrics in the Prometheus format on this address, e.g., 0.0.0.0:9090,
+    /// and the health of the brokers under /health
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    metrics_addr: Option<String>,
+
+    /// Runs a command instead of the node
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Handles the stored gossip events
+    #[clap(subcommand)]
+    Gossip(GossipCommand),
+    /// Runs a node dedicated to one role, with the matching modules and quotas,
+    /// and prints a report about this role every minute
+    Serve {
+        #[clap(long, value_enum)]
+        profile: Profile,
+    },
+    /// Runs the node and reads commands from the terminal to inspect it
+    Shell,
+    /// Removes old node infos and audit log entries from the storage, and
+    /// reports the reclaimed space
+    Gc {
+        /// Remove data older than this number of days
+        #[clap(long, default_value_t = GC_MAX_AGE_DAYS)]
+        max_age_days: i64,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Profile {
+    /// Keeps the chat messages and node infos for other nodes
+    Storage,
+    /// Keeps connections to other nodes open
+    Relay,
+    /// Answers web proxy requests of other nodes
+    Proxy,
+}
+
+impl From<Profile> for NodeRole {
+    fn from(profile: Profile) -> Self {
+        match profile {
+            Profile::Storage => NodeRole::Storage,
+            Profile::Relay => NodeRole::Relay,
+            Profile::Proxy => NodeRole::Proxy,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum GossipCommand {
+    /// Writes a signed archive of the stored chat messages
+    Export {
+        /// File to write the archive to
+        file: String,
+        /// Only export messages created after this time in ms since the epoch
+        #[clap(long, default_value_t = 0)]
+        from: i64,
+        /// Only export messages created before this time in ms since the epoch
+        #[clap(long, default_value_t = i64::MAX)]
+        to: i64,
+    },
+    /// Verifies an archive and adds its messages to the stored chat messages
+    Import {
+        /// File to read the archive from
+        file: String,
+    },
+}
+
+fn gossip_command(
+    storage: &mut DataStorageFile,
+    cmd: GossipCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match cmd {
+        GossipCommand::Export { file, from, to } => {
+            let archive = Node::gossip_export(storage, Category::TextMessage, from, to)?;
+            std::fs::write(&file, archive.encode()?)?;
+            log::info!("Exported {} messages to {file}", archive.events.len());
+        }
+        GossipCommand::Import { file } => {
+            let archive = EventsArchive::decode(&std::fs::read_to_string(&file)?)?;
+            let added = Node::gossip_import(storage, &archive)?;
+            log::info!(
+                "Imported {added} new messages signed by {}",
+                archive.signer.name
+            );
+        }
+    }
+    Ok(())
+}
+
+const PROBE_WAIT: Duration = Duration::from_secs(5);
+
+/// Data older than this is removed by `fledger gc` and by the running node.
+const GC_MAX_AGE_DAYS: i64 = 30;
+/// How often the running node removes old data, in seconds.
+const GC_INTERVAL_SEC: i32 = 3600;
+/// How many log lines are added to a crash report.
+const CRASH_LOG_LINES: usize = 100;
+/// How often the running node tries to send the crash reports, in seconds.
+const CRASH_INTERVAL_SEC: i32 = 60;
+
+fn gc_before(max_age_days: i64) -> i64 {
+    now() - max_age_days * 24 * 3600 * 1000
+}
+
+const SHELL_HELP: &str = "Commands:
+  nodes           lists the nodes online
+  chat <message>  sends a chat message
+  messages        lists the chat messages
+  stats           shows the connection and ping statistics
+  brokers         shows the health of the brokers
+  probe           checks the network of this node
+  help            shows this help
+  quit            stops the node";
+
+// Returns once the process receives a SIGTERM or a Ctrl-C.
+async fn terminate() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => log::warn!("Couldn't listen for SIGTERM: {e}"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!("Couldn't listen for Ctrl-C: {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn shutdown(node: Node) -> Result<(), Box<dyn std::error::Error>> {
+    log::info!("Shutting down the node");
+    node.shutdown().await?;
+    Ok(())
+}
+
+// Reads commands from stdin while keeping the node running.
+async fn shell(mut node: Node, config: ConnectionConfig) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{SHELL_HELP}");
+    let mut lines = BufReader::new(stdin()).lines();
+    let term = terminate();
+    tokio::pin!(term);
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return shutdown(node).await;
+                };
+                match shell_command(&mut node, &config, line.trim()).await {
+                    Ok(true) => {}
+                    Ok(false) => return shutdown(node).await,
+                    Err(e) => println!("Error: {e}"),
+                }
+            }
+            _ = &mut term => return shutdown(node).await,
+            _ = wait_ms(1000) => {
+                if let Err(e) = node.process().await {
+                    log::warn!("Couldn't process node: {e:?}");
+                }
+            }
+        }
+    }
+}
+
+// Returns false if the shell should stop.
+async fn shell_command(
+    node: &mut Node,
+    config: &ConnectionConfig,
+    line: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+    match cmd {
+        "" => {}
+        "nodes" => {
+            for ni in node.nodes_online()? {
+                println!("{} {} {:?}", ni.get_id(), ni.name, ni.client);
+            }
+        }
+        "chat" if !arg.trim().is_empty() => node.add_chat_message(arg.trim().into()).await?,
+        "messages" => {
+            let names = node.nodes_info_all()?;
+            for event in node.gossip.as_ref().map_or(vec![], |g| g.chat_events()) {
+                let name = names.get(&event.src).map_or("unknown", |ni| &ni.name);
+                println!("{} {name}: {}", event.created, event.msg);
+            }
+        }
+        "stats" => {
+            println!("Connected nodes: {}", node.nodes_connected()?.len());
+            if let Some(stat) = node.stat.as_ref() {
+                println!("Connection setups: {:?}", stat.setups);
+                println!("Traffic: {:?}", stat.traffic_total());
+                for (id, traffic) in stat.traffic.iter().filter(|(_, t)| t.turn > 0) {
+                    println!("TURN bytes with {id}: {}", traffic.turn);
+                }
+                for (id, t) in stat.transport.iter() {
+                    println!(
+                        "Transport with {id}: {} bps, rtt {:?}ms, lost {:?}, buffered {}B, turn: {}",
+                        t.bitrate_bps,
+                        t.rtt_ms,
+                        t.packets_lost,
+                        t.buffered_bytes,
+                        t.turn()
+                    );
+                }
+            }
+            if let Some(ping) = node.ping.as_ref().map(|p| &p.storage) {
+                println!("Pings: {:?}", ping.stats);
+                println!("Estimated clock offset: {:?}ms", ping.clock_offset_ms());
+            }
+        }
+        "brokers" => print!("{}", node.health().await),
+        "probe" => {
+            let mut report = probe(config, PROBE_WAIT).await;
+            report.add_clock_offset(node.ping.as_ref().and_then(|p| p.storage.clock_offset_ms()));
+            for result in report.results {
+                println!("{result}");
+            }
+        }
+        "quit" | "exit" => return Ok(false),
+        _ => println!("{SHELL_HELP}"),
+    }
+    Ok(true)
+}
+
+fn role_report(node: &mut Node, role: NodeRole) {
+    let connected = node
+        .random
+        .as_ref()
+        .map_or(0, |r| r.storage.connected.get_nodes().0.len());
+    match role {
+        NodeRole::Storage => log::info!(
+            "Storage report: {} chat messages and {} node infos stored, {connected} nodes connected",
+            node.gossip.as_ref().map_or(0, |g| g.chat_events().len()),
+            node.gossip
+                .as_ref()
+                .map_or(0, |g| g.events(Category::NodeInfo).len()),
+        ),
+        NodeRole::Relay => log::info!(
+            "Relay report: {connected} nodes connected, connection setups: {:?}",
+            node.stat.as_ref().map(|s| s.setups)
+        ),
+        NodeRole::Proxy => {
+            if let Some(wp) = node.webproxy.as_mut() {
+                let rejected = wp
+                    .get_audit_log()
+                    .iter()
+                    .filter(|e| e.rejected.is_some())
+                    .count();
+                log::info!(
+                    "Proxy report: {} requests served, {rejected} of the last logged requests rejected, {connected} nodes connected",
+                    wp.get_counters().rx_requests
+                );
+            }
+        }
+    }
 }
 
 #[tokio::main]
@@ -40,11 +339,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut logger = env_logger::Builder::new();
     logger.filter_module("fl", args.verbosity.log_level_filter());
     logger.parse_env("RUST_LOG");
-    logger.try_init().expect("Failed to initialize logger");
+    let logs = RecentLogs::new(CRASH_LOG_LINES);
+    if args.crash_reports {
+        let logger = logger.build();
+        log::set_max_level(logger.filter());
+        log::set_boxed_logger(Box::new(logs.wrap(Box::new(logger))))
+            .expect("Failed to initialize logger");
+    } else {
+        logger.try_init().expect("Failed to initialize logger");
+    }
 
-    let storage = DataStorageFile::new(args.config, "fledger".into());
+    let mut storage = DataStorageFile::new(args.config, "fledger".into());
+    let crash_reports = args.crash_reports;
+    if crash_reports {
+        install_panic_hook(storage.clone(), logs);
+    }
+    let role: Option<NodeRole> = match args.command {
+        Some(Command::Gossip(cmd)) => return gossip_command(&mut storage, cmd),
+        Some(Command::Serve { profile }) => Some(profile.into()),
+        Some(Command::Gc { max_age_days }) => {
+            let report = Node::gc(&mut storage, gc_before(max_age_days))?;
+            log::info!(
+                "Removed {} node infos and {} audit log entries, reclaimed {} bytes",
+                report.node_infos,
+                report.audit_entries,
+                report.reclaimed()
+            );
+            return Ok(());
+        }
+        Some(Command::Shell) | None => None,
+    };
     let mut node_config = Node::get_config(storage.clone())?;
+    // The stored keypair is kept for when the node runs normally again.
+    if args.read_only && !node_config.read_only {
+        node_config = NodeConfig::new_read_only();
+    }
     args.name.map(|name| node_config.info.name = name);
+    node_config
+        .info
+        .capabilities
+        .set(Capabilities::ENCRYPT, args.encrypt);
+    if let Some(role) = role {
+        log::info!("Serving as a {role:?} node");
+        node_config.info.role = Some(role);
+        node_config.info.modules = role.modules();
+    }
 
     log::info!(
         "Starting app with version {}/{}",
@@ -53,26 +392,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     log::debug!("Connecting to websocket at {}", args.signal_url);
-    let network = network_broker_start(
-        node_config.clone(),
-        ConnectionConfig::new(
-            Some(args.signal_url),
-            None,
-            Some(HostLogin {
-                url: "turn:web.fledg.re:3478".into(),
-                login: Some(Login {
-                    user: "something".into(),
-                    pass: "something".into(),
-                }),
+    let config = ConnectionConfig::new(
+        Some(args.signal_url),
+        None,
+        Some(HostLogin {
+            url: "turn:web.fledg.re:3478".into(),
+            login: Some(Login {
+                user: "something".into(),
+                pass: "something".into(),
             }),
-        ),
+        }),
     )
-    .await?;
+    .with_extra_signals(args.extra_signal_url);
+    let probe_config = config.clone();
+    spawn_local(async move { probe(&probe_config, PROBE_WAIT).await.log() });
+    let network = network_broker_start(node_config.clone(), config.clone()).await?;
     let mut node = Node::start(Box::new(storage), node_config, network).await?;
     let nc = &node.node_config.info;
     log::info!("Starting node {}: {}", nc.get_id(), nc.name);
 
     log::info!("Started successfully");
+    if matches!(args.command, Some(Command::Shell)) {
+        return shell(node, config).await;
+    }
+    let (metrics, _) = tokio::sync::watch::channel(String::new());
+    let (health, _) = tokio::sync::watch::channel(String::new());
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics_addr.as_ref() {
+        flarch::metrics::serve_with_health(addr, metrics.subscribe(), health.subscribe()).await?;
+    }
+    let term = terminate();
+    tokio::pin!(term);
     let mut i: i32 = 0;
     loop {
         i += 1;
@@ -80,16 +430,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .await
             .err()
             .map(|e| log::warn!("Couldn't process node: {e:?}"));
+        if metrics.receiver_count() > 0 {
+            metrics.send_replace(node.metrics().await.to_string());
+        }
+        if health.receiver_count() > 0 {
+            health.send_replace(node.health().await);
+        }
 
         if i % 3 == 2 {
-            log::info!("Nodes are: {:?}", node.nodes_online()?);
-            let ping = &node.ping.as_ref().unwrap().storage;
-            log::info!("Nodes countdowns are: {:?}", ping.stats);
-            log::debug!(
-                "Chat messages are: {:?}",
-                node.gossip.as_ref().unwrap().chat_events()
-            );
+            if let Some(gossip) = node.gossip.as_ref() {
+                log::info!("Nodes are: {:?}", node.nodes_online()?);
+                log::debug!("Chat messages are: {:?}", gossip.chat_events());
+            }
+            if let Some(ping) = node.ping.as_ref().map(|p| &p.storage) {
+                log::info!("Nodes countdowns are: {:?}", ping.stats);
+                log::info!("Estimated clock offset is: {:?}ms", ping.clock_offset_ms());
+            }
+            log::debug!("Brokers are: {:?}", node.registry.list().await);
+        }
+        if i % GC_INTERVAL_SEC == 0 {
+            if let Err(e) = node.gc_events(gc_before(GC_MAX_AGE_DAYS)) {
+                log::warn!("Couldn't remove old events: {e:?}");
+            }
+        }
+        if crash_reports && i % CRASH_INTERVAL_SEC == 0 {
+            match node.submit_crash_reports() {
+                Ok(0) => {}
+                Ok(sent) => log::info!("Sent {sent} crash reports"),
+                Err(e) => log::warn!("Couldn't send crash reports: {e:?}"),
+            }
+        }
+        if let Some(role) = role.filter(|_| i % 60 == 0) {
+            role_report(&mut node, role);
+        }
+        tokio::select! {
+            _ = wait_ms(1000) => {}
+            _ = &mut term => return shutdown(node).await,
         }
-        wait_ms(1000).await;
     }
 }
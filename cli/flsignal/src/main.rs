@@ -1,6 +1,15 @@
+use std::{path::PathBuf, time::Duration};
+
 use clap::Parser;
-use flmodules::network::signal::{SignalServer, SignalMessage, SignalOutput};
-use flarch::web_rtc::web_socket_server::WebSocketServer;
+use flarch::metrics::{MetricType, Metrics, Sample};
+use flarch::web_rtc::{
+    tls::TlsFiles,
+    web_socket_server::{WebSocketServer, WebSocketServerConfig},
+    websocket::WSServerInput,
+};
+use flmodules::network::signal::{
+    RateLimited, SignalInput, SignalMessage, SignalOutput, SignalServer,
+};
 
 /// Fledger signalling server
 #[derive(Parser, Debug)]
@@ -9,6 +18,78 @@ struct Args {
     /// Verbosity
     #[clap(flatten)]
     verbosity: clap_verbosity_flag::Verbosity,
+
+    /// Serves the metrics in the Prometheus format on this address, e.g., 0.0.0.0:9090
+    #[cfg(feature = "metrics")]
+    #[clap(long)]
+    metrics_addr: Option<String>,
+
+    /// Signalling server the nodes switch to when this one stops, e.g., wss://signal2.fledg.re
+    #[clap(long)]
+    alternate_url: Option<String>,
+
+    /// Seconds to wait for the nodes to leave before stopping
+    #[clap(long, default_value = "60")]
+    drain_timeout: u64,
+
+    /// PEM file with the certificate chain, to serve wss:// instead of ws://
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+/// Counts what happens on the signalling server.
+#[derive(Default)]
+struct SignalStats {
+    nodes: usize,
+    announced: u64,
+    node_stats: u64,
+    rate_limited: RateLimited,
+}
+
+impl SignalStats {
+    fn update(&mut self, msg: &SignalOutput) {
+        match msg {
+            SignalOutput::NewNode(_) => self.announced += 1,
+            SignalOutput::NodeCount(nodes) => self.nodes = *nodes,
+            SignalOutput::NodeStats(_) => self.node_stats += 1,
+            SignalOutput::RateLimited(rl) => self.rate_limited = rl.clone(),
+            SignalOutput::Stopped | SignalOutput::Drained => {}
+        }
+    }
+
+    fn metrics(&self) -> Metrics {
+        let mut m = Metrics::default();
+        m.gauge(
+            "fledger_signal_nodes",
+            "Nodes currently announced",
+            self.nodes as f64,
+        )
+        .counter(
+            "fledger_signal_announcements_total",
+            "Announcements of nodes",
+            self.announced as f64,
+        )
+        .counter(
+            "fledger_signal_node_stats_total",
+            "Statistics reports sent by nodes",
+            self.node_stats as f64,
+        )
+        .family(
+            MetricType::Counter,
+            "fledger_signal_rate_limited_total",
+            "Messages dropped by the rate limits",
+            &[
+                Sample::label("kind", "announce", self.rate_limited.announce as f64),
+                Sample::label("kind", "list_ids", self.rate_limited.list_ids as f64),
+                Sample::label("kind", "peer_setup", self.rate_limited.peer_setup as f64),
+            ],
+        );
+        m
+    }
 }
 
 #[tokio::main]
@@ -20,17 +101,100 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     logger.parse_env("RUST_LOG");
     logger.try_init().expect("Failed to initialize logger");
 
-    let wss = WebSocketServer::new(8765).await?;
+    let (metrics, _) = tokio::sync::watch::channel(SignalStats::default().metrics().to_string());
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics_addr.as_ref() {
+        flarch::metrics::serve(addr, metrics.subscribe()).await?;
+    }
+
+    let tls = args
+        .tls_cert
+        .zip(args.tls_key)
+        .map(|(cert, key)| TlsFiles { cert, key });
+    let wss = WebSocketServer::new_with_config(WebSocketServerConfig { port: 8765, tls }).await?;
     let mut signal_server = SignalServer::new(wss, 2).await?;
-    let (msgs, _) = signal_server.get_tap_sync().await?;
+    let (mut msgs, _) = signal_server.get_tap().await?;
+    let mut stats = SignalStats::default();
 
-    log::info!("Started listening on port 8765");
-    for msg in msgs {
+    let mut handle = |msg: SignalMessage| -> Option<SignalOutput> {
         log::debug!("{:?}", msg);
-        if matches!(msg, SignalMessage::Output(SignalOutput::Stopped)) {
-            log::error!("Server stopped working - exiting");
-            return Ok(());
+        if let SignalMessage::Output(out) = msg {
+            stats.update(&out);
+            if metrics.receiver_count() > 0 {
+                metrics.send_replace(stats.metrics().to_string());
+            }
+            return Some(out);
+        }
+        None
+    };
+
+    log::info!("Started listening on port 8765");
+    let term = terminate();
+    tokio::pin!(term);
+    loop {
+        tokio::select! {
+            msg = msgs.recv() => {
+                let Some(msg) = msg else {
+                    return Ok(());
+                };
+                if let Some(SignalOutput::Stopped) = handle(msg) {
+                    log::error!("Server stopped working - exiting");
+                    return Ok(());
+                }
+            }
+            _ = &mut term => break,
+        }
+    }
+
+    log::info!(
+        "Waiting up to {}s for the nodes to leave, send the signal again to stop now",
+        args.drain_timeout
+    );
+    signal_server.emit_msg(SignalInput::Maintenance(args.alternate_url).into())?;
+    let timeout = tokio::time::sleep(Duration::from_secs(args.drain_timeout));
+    tokio::pin!(timeout);
+    let term = terminate();
+    tokio::pin!(term);
+    loop {
+        tokio::select! {
+            msg = msgs.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                if let Some(SignalOutput::Drained | SignalOutput::Stopped) = handle(msg) {
+                    break;
+                }
+            }
+            _ = &mut timeout => {
+                log::warn!("Not all nodes left in time");
+                break;
+            }
+            _ = &mut term => break,
         }
     }
+    log::info!("Stopping the signalling server");
+    signal_server.emit_msg(WSServerInput::Stop.into())?;
     Ok(())
 }
+
+// Returns once the process receives a SIGTERM or a Ctrl-C.
+async fn terminate() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => log::warn!("Couldn't listen for SIGTERM: {e}"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!("Couldn't listen for Ctrl-C: {e}");
+        std::future::pending::<()>().await;
+    }
+}
@@ -33,8 +33,9 @@
 
 use core::fmt;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::Formatter,
+    panic::AssertUnwindSafe,
     sync::{
         mpsc::{channel, Receiver, Sender},
         Arc,
@@ -42,11 +43,16 @@ use std::{
 };
 
 use flarch_macro::platform_async_trait;
-use futures::{future::BoxFuture, lock::Mutex};
+use futures::{future::BoxFuture, lock::Mutex, FutureExt};
 use thiserror::Error;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{
+    unbounded_channel, UnboundedReceiver, UnboundedSender, WeakUnboundedSender,
+};
 
-use crate::{nodeids::U256, tasks::spawn_local};
+use crate::{
+    nodeids::U256,
+    tasks::{now, spawn_local},
+};
 
 #[derive(Debug, Error)]
 /// The only error that can happen is that sending to another broker fails.
@@ -60,6 +66,159 @@ pub enum BrokerError {
 /// Identifies a broker for loop detection.
 pub type BrokerID = U256;
 
+/// Sent to all crash taps whenever a handler of a broker panics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubsystemCrashed {
+    /// The broker the subsystem belongs to
+    pub broker: BrokerID,
+    /// The index of the subsystem, as returned by [`Broker::add_subsystem`]
+    pub subsystem: usize,
+    /// The panic message, if it was a string
+    pub reason: String,
+    /// Whether the subsystem will be restarted, which is only the case for
+    /// [`Subsystem::Supervised`] handlers
+    pub restart: bool,
+}
+
+/// The health report of a broker.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BrokerHealth {
+    /// How many times each subsystem crashed
+    pub crashes: HashMap<usize, u32>,
+    /// The number of subsystems currently running
+    pub subsystems: usize,
+    /// The number of messages waiting to be processed
+    pub pending: usize,
+}
+
+/// Describes a broker registered in a [`BrokerRegistry`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerInfo {
+    /// The name given when registering the broker
+    pub name: String,
+    /// The type of the messages of this broker
+    pub type_name: &'static str,
+    /// The current health of the broker
+    pub health: BrokerHealth,
+}
+
+impl fmt::Display for BrokerInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} subsystems, {} pending, {} crashes ({})",
+            self.name,
+            self.health.subsystems,
+            self.health.pending,
+            self.health.crashes.values().sum::<u32>(),
+            self.type_name
+        )
+    }
+}
+
+#[platform_async_trait()]
+trait RegisteredBroker: Async {
+    fn type_name(&self) -> &'static str;
+    async fn health(&mut self) -> Result<BrokerHealth, BrokerError>;
+    fn stop(&mut self);
+}
+
+// Only keeps a weak reference, so that the registry doesn't keep the broker alive.
+struct WeakBroker<T: Async + Clone + fmt::Debug> {
+    intern_tx: WeakUnboundedSender<InternMessage<T>>,
+}
+
+#[platform_async_trait()]
+impl<T: 'static + Async + Clone + fmt::Debug> RegisteredBroker for WeakBroker<T> {
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    async fn health(&mut self) -> Result<BrokerHealth, BrokerError> {
+        match self.intern_tx.upgrade() {
+            Some(intern_tx) => get_health(&intern_tx).await,
+            None => Err(BrokerError::SendQueue("health".into())),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(intern_tx) = self.intern_tx.upgrade() {
+            // If the broker is already stopped, there is nothing to do.
+            let _ = intern_tx.send(InternMessage::Stop);
+        }
+    }
+}
+
+async fn get_health<T: Async + Clone + fmt::Debug>(
+    intern_tx: &UnboundedSender<InternMessage<T>>,
+) -> Result<BrokerHealth, BrokerError> {
+    let (tx, mut rx) = unbounded_channel();
+    intern_tx
+        .send(InternMessage::Health(tx))
+        .map_err(|_| BrokerError::SendQueue("health".into()))?;
+    rx.recv()
+        .await
+        .ok_or(BrokerError::SendQueue("health reply".into()))
+}
+
+/// A list of named brokers, to be able to see at runtime which brokers are
+/// running, and how busy they are.
+/// The registry can be cloned and shared by all parts of a node.
+#[derive(Clone, Default)]
+pub struct BrokerRegistry {
+    brokers: Arc<Mutex<Vec<NamedBroker>>>,
+}
+
+type NamedBroker = (String, Box<dyn RegisteredBroker>);
+
+impl BrokerRegistry {
+    /// Creates a new, empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a broker under the given name.
+    pub async fn add<T: 'static + Async + Clone + fmt::Debug>(
+        &self,
+        name: &str,
+        broker: &Broker<T>,
+    ) {
+        self.brokers.lock().await.push((
+            name.to_string(),
+            Box::new(WeakBroker {
+                intern_tx: broker.intern_tx.downgrade(),
+            }),
+        ));
+    }
+
+    /// Returns the information of all registered brokers which are still running.
+    /// Brokers which don't answer anymore are removed from the registry.
+    pub async fn list(&self) -> Vec<BrokerInfo> {
+        let mut brokers = self.brokers.lock().await;
+        let mut infos = vec![];
+        let mut running = vec![];
+        for (name, mut broker) in brokers.drain(..) {
+            if let Ok(health) = broker.health().await {
+                infos.push(BrokerInfo {
+                    name: name.clone(),
+                    type_name: broker.type_name(),
+                    health,
+                });
+                running.push((name, broker));
+            }
+        }
+        *brokers = running;
+        infos
+    }
+
+    /// Stops all registered brokers, see [`Broker::stop`], and empties the registry.
+    pub async fn stop_all(&self) {
+        for (_, mut broker) in self.brokers.lock().await.drain(..) {
+            broker.stop();
+        }
+    }
+}
+
 /// The Destination of the message, and also handles forwarded messages
 /// to make sure no loop is created.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -74,6 +233,36 @@ pub enum Destination {
     Handled(usize),
 }
 
+/// The QoS class of a message.
+/// The broker always processes the waiting control messages first.
+/// Interactive messages are preferred to bulk messages, but after
+/// [`Priority::INTERACTIVE_WEIGHT`] interactive messages, a waiting bulk
+/// message is processed, so that bulk transfers still make progress.
+/// Messages of the same class are processed in the order they arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Priority {
+    /// Keepalives, pings, and connection setup
+    Control,
+    /// Messages a user is waiting for - used by all `emit_msg*` methods
+    #[default]
+    Interactive,
+    /// Big transfers like DHT synchronization
+    Bulk,
+}
+
+impl Priority {
+    /// How many interactive messages are processed before a waiting bulk message.
+    pub const INTERACTIVE_WEIGHT: usize = 8;
+
+    fn index(&self) -> usize {
+        match self {
+            Priority::Control => 0,
+            Priority::Interactive => 1,
+            Priority::Bulk => 2,
+        }
+    }
+}
+
 enum SubsystemAction<T> {
     Add(usize, Subsystem<T>),
     Remove(usize),
@@ -166,6 +355,16 @@ impl<T: 'static + Async + Clone + fmt::Debug> Broker<T> {
         self.settle(vec![]).await
     }
 
+    /// Removes a subsystem without waiting for the broker to settle.
+    /// Messages enqueued after this call are not passed to the subsystem anymore.
+    /// This can be used from within a handler, where waiting for the other broker
+    /// might block.
+    pub fn unlink(&mut self, ss: usize) -> Result<(), BrokerError> {
+        self.intern_tx
+            .send(InternMessage::Subsystem(SubsystemAction::Remove(ss)))
+            .map_err(|_| BrokerError::SendQueue("unlink".into()))
+    }
+
     /// Adds an async tap to the subsystems that can be used to listen to messages.
     /// The async tap is returned.
     pub async fn get_tap(&mut self) -> Result<(UnboundedReceiver<T>, usize), BrokerError> {
@@ -174,6 +373,21 @@ impl<T: 'static + Async + Clone + fmt::Debug> Broker<T> {
         Ok((rx, pos))
     }
 
+    /// Adds an async tap which only receives the messages for which `filter`
+    /// returns true.
+    /// The filter is evaluated by the broker, so the other messages are
+    /// neither cloned nor sent to the tap.
+    pub async fn get_tap_filtered<F: Fn(&T) -> bool + Async + 'static>(
+        &mut self,
+        filter: F,
+    ) -> Result<(UnboundedReceiver<T>, usize), BrokerError> {
+        let (tx, rx) = unbounded_channel();
+        let pos = self
+            .add_subsystem(Subsystem::TapFiltered(tx, Box::new(filter)))
+            .await?;
+        Ok((rx, pos))
+    }
+
     /// Adds a synchronous tap that can be used to listen to messages.
     /// Care must be taken that the async handling will still continue while
     /// waiting for a message!
@@ -185,14 +399,68 @@ impl<T: 'static + Async + Clone + fmt::Debug> Broker<T> {
         Ok((rx, pos))
     }
 
+    /// Adds a handler which will be re-created using the factory if it panics.
+    /// The handler is restarted after an exponential backoff, and messages
+    /// arriving in the meantime are dropped.
+    ///
+    /// This only works on unix: on wasm a panic aborts the whole wasm instance,
+    /// so the handler cannot be restarted.
+    pub async fn add_handler_supervised(
+        &mut self,
+        factory: HandlerFactory<T>,
+    ) -> Result<usize, BrokerError> {
+        self.add_subsystem(Subsystem::Supervised(Supervisor::new(factory)))
+            .await
+    }
+
+    /// Returns a tap which receives a [`SubsystemCrashed`] every time a handler
+    /// of this broker panics.
+    /// On wasm panics cannot be caught, so this tap never receives anything.
+    pub async fn get_crash_tap(
+        &mut self,
+    ) -> Result<UnboundedReceiver<SubsystemCrashed>, BrokerError> {
+        let (tx, rx) = unbounded_channel();
+        self.intern_tx
+            .send(InternMessage::CrashTap(tx))
+            .map_err(|_| BrokerError::SendQueue("get_crash_tap".into()))?;
+        Ok(rx)
+    }
+
+    /// Returns the crash counts, the number of subsystems, and the number of
+    /// pending messages of this broker.
+    pub async fn health(&mut self) -> Result<BrokerHealth, BrokerError> {
+        get_health(&self.intern_tx).await
+    }
+
     /// Emit a message to a given destination of other listeners.
     /// The message will be processed asynchronously.
     pub fn emit_msg_dest(&mut self, dst: Destination, msg: T) -> Result<(), BrokerError> {
         self.intern_tx
-            .send(InternMessage::Message(dst, msg))
+            .send(InternMessage::Message(Priority::default(), dst, msg))
             .map_err(|_| BrokerError::SendQueue("emit_msg_dest".into()))
     }
 
+    /// Enqueue a message with the given QoS class to a given destination of other listeners.
+    /// The message will be processed asynchronously, after all waiting messages
+    /// with a higher priority.
+    pub fn enqueue_msg_dest_priority(
+        &mut self,
+        priority: Priority,
+        dst: Destination,
+        msg: T,
+    ) -> Result<(), BrokerError> {
+        self.intern_tx
+            .send(InternMessage::Message(priority, dst, msg))
+            .map_err(|_| BrokerError::SendQueue("enqueue_msg_dest_priority".into()))
+    }
+
+    /// Enqueue a message with the given QoS class to other listeners.
+    /// The message will be processed asynchronously, after all waiting messages
+    /// with a higher priority.
+    pub fn enqueue_msg_priority(&mut self, priority: Priority, msg: T) -> Result<(), BrokerError> {
+        self.enqueue_msg_dest_priority(priority, Destination::All, msg)
+    }
+
     /// Emit a message to other listeners.
     /// The message will be processed asynchronously.
     pub fn emit_msg(&mut self, msg: T) -> Result<(), BrokerError> {
@@ -203,7 +471,7 @@ impl<T: 'static + Async + Clone + fmt::Debug> Broker<T> {
     /// brokers to settle.
     pub async fn settle_msg_dest(&mut self, dst: Destination, msg: T) -> Result<(), BrokerError> {
         self.intern_tx
-            .send(InternMessage::Message(dst, msg))
+            .send(InternMessage::Message(Priority::default(), dst, msg))
             .map_err(|_| BrokerError::SendQueue("settle_msg_dest".into()))?;
         self.settle(vec![]).await
     }
@@ -238,13 +506,36 @@ impl<T: 'static + Async + Clone + fmt::Debug> Broker<T> {
         broker: Broker<R>,
         link_tr: Translate<T, R>,
     ) {
+        self.forward_priority(broker, Priority::default(), link_tr)
+            .await;
+    }
+
+    /// Forwards all messages from this broker to another broker, where they are
+    /// queued with the given [`Priority`].
+    /// The returned subsystem can be removed with [`Broker::unlink`].
+    pub async fn forward_priority<R: 'static + Async + Clone + fmt::Debug>(
+        &mut self,
+        broker: Broker<R>,
+        priority: Priority,
+        link_tr: Translate<T, R>,
+    ) -> usize {
         let translator_tr = Translator {
             broker,
+            priority,
             translate: link_tr,
         };
         self.add_subsystem(Subsystem::Translator(Box::new(translator_tr)))
             .await
-            .unwrap();
+            .unwrap()
+    }
+
+    /// Stops the broker once the messages already sent have been processed.
+    /// All subsystems are dropped, so the taps get closed, and sending new
+    /// messages to the broker returns an error.
+    pub fn stop(&mut self) -> Result<(), BrokerError> {
+        self.intern_tx
+            .send(InternMessage::Stop)
+            .map_err(|_| BrokerError::SendQueue("stop".into()))
     }
 
     /// Waits for all messages in the queue to be forwarded / handled, before returning.
@@ -272,6 +563,7 @@ pub type Translate<R, T> = Box<dyn Fn(R) -> Option<T> + 'static>;
 
 struct Translator<R: Clone, S: Async + Clone + fmt::Debug> {
     broker: Broker<S>,
+    priority: Priority,
     translate: Translate<R, S>,
 }
 
@@ -289,7 +581,7 @@ impl<R: Async + Clone + fmt::Debug, S: 'static + Async + Clone + fmt::Debug> Sub
                 self.broker.id,
             );
             self.broker
-                .emit_msg_dest(Destination::Forwarded(trail), msg_tr)
+                .enqueue_msg_dest_priority(self.priority, Destination::Forwarded(trail), msg_tr)
                 .err()
                 .map(|e| {
                     log::error!(
@@ -314,15 +606,28 @@ impl<R: Async + Clone + fmt::Debug, S: 'static + Async + Clone + fmt::Debug> Sub
 
 enum InternMessage<T: Async + Clone + fmt::Debug> {
     Subsystem(SubsystemAction<T>),
-    Message(Destination, T),
+    Message(Priority, Destination, T),
     Settle(Vec<BrokerID>, UnboundedSender<bool>),
+    CrashTap(UnboundedSender<SubsystemCrashed>),
+    Health(UnboundedSender<BrokerHealth>),
+    Stop,
 }
 
 struct Intern<T: Async + Clone + fmt::Debug> {
     main_rx: UnboundedReceiver<InternMessage<T>>,
     subsystems: HashMap<usize, Subsystem<T>>,
     msg_queue: Vec<(Destination, T)>,
+    // The messages waiting to be processed, one queue per [`Priority`].
+    waiting: [VecDeque<(Destination, T)>; 3],
+    // The next non-message taken out of main_rx while collecting the waiting messages.
+    held: Option<InternMessage<T>>,
+    // How many interactive messages have been processed since the last bulk message.
+    interactive_count: usize,
     id: BrokerID,
+    crash_taps: Vec<UnboundedSender<SubsystemCrashed>>,
+    crashes: HashMap<usize, u32>,
+    // Set by [`Broker::stop`].
+    stopped: bool,
 }
 
 impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
@@ -334,25 +639,38 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
                 main_rx,
                 subsystems: HashMap::new(),
                 msg_queue: vec![],
+                waiting: Default::default(),
+                held: None,
+                interactive_count: 0,
                 id,
+                crash_taps: vec![],
+                crashes: HashMap::new(),
+                stopped: false,
             };
             loop {
                 if !intern.get_msg().await {
-                    log::warn!(
-                        "{}: Closed Intern.main_rx for {}",
-                        intern.id,
-                        intern.type_id()
-                    );
+                    if !intern.stopped {
+                        log::warn!(
+                            "{}: Closed Intern.main_rx for {}",
+                            intern.id,
+                            intern.type_id()
+                        );
+                    }
                     return;
                 }
 
-                if intern.msg_queue.len() > 0 {
+                // Process the waiting messages one by one, and look for new messages
+                // after each one, so that a control message doesn't have to wait
+                // for all bulk messages to be processed.
+                intern.collect_msgs();
+                while intern.schedule() {
                     match intern.process().await {
                         Ok(nbr) => log::trace!("{}: Processed {nbr} messages", intern.type_id()),
                         Err(e) => {
                             log::error!("{}: Couldn't process: {e:?}", intern.type_id());
                         }
                     }
+                    intern.collect_msgs();
                 }
             }
         });
@@ -380,11 +698,14 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
     // This call blocks on purpose until a new message is available.
     // If the return value is false, then the channel has been closed.
     async fn get_msg(&mut self) -> bool {
-        let msg_queue = match self.main_rx.recv().await {
+        let msg_queue = match self.held.take() {
             Some(msg) => msg,
-            None => {
-                return false;
-            }
+            None => match self.main_rx.recv().await {
+                Some(msg) => msg,
+                None => {
+                    return false;
+                }
+            },
         };
         let msg = match msg_queue {
             InternMessage::Subsystem(ss) => {
@@ -392,7 +713,7 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
                 self.subsystem_action(ss);
                 return true;
             }
-            InternMessage::Message(dst, msg) => (dst, msg),
+            InternMessage::Message(priority, dst, msg) => (priority, dst, msg),
             InternMessage::Settle(list, reply) => {
                 let type_id = self.type_id();
                 if !list.contains(&self.id) {
@@ -411,12 +732,79 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
                     .map(|e| log::error!("{}: Couldn't send: {e:?}", type_id));
                 return true;
             }
+            InternMessage::CrashTap(tap) => {
+                self.crash_taps.push(tap);
+                return true;
+            }
+            InternMessage::Health(reply) => {
+                let health = BrokerHealth {
+                    crashes: self.crashes.clone(),
+                    subsystems: self.subsystems.len(),
+                    pending: self.main_rx.len()
+                        + self.waiting.iter().map(|w| w.len()).sum::<usize>(),
+                };
+                if let Err(e) = reply.send(health) {
+                    log::error!("{}: Couldn't send: {e:?}", self.type_id());
+                }
+                return true;
+            }
+            InternMessage::Stop => {
+                log::debug!("{}: Stopping broker for {}", self.id, self.type_id());
+                self.subsystems.clear();
+                self.stopped = true;
+                return false;
+            }
         };
-        self.msg_queue.push(msg);
+        self.waiting[msg.0.index()].push_back((msg.1, msg.2));
 
         true
     }
 
+    // Moves all messages available in main_rx to the waiting queues.
+    // It stops at the first non-message, which must only be handled once all
+    // messages before it have been processed.
+    fn collect_msgs(&mut self) {
+        while self.held.is_none() {
+            match self.main_rx.try_recv() {
+                Ok(InternMessage::Message(priority, dst, msg)) => {
+                    self.waiting[priority.index()].push_back((dst, msg))
+                }
+                Ok(other) => self.held = Some(other),
+                Err(_) => break,
+            }
+        }
+    }
+
+    // Puts the next waiting message in the msg_queue, following the fairness
+    // rules described in [`Priority`].
+    // Returns false if no message is waiting.
+    fn schedule(&mut self) -> bool {
+        let [control, interactive, bulk] = &mut self.waiting;
+        let next = match control.pop_front() {
+            Some(msg) => Some(msg),
+            None => {
+                if !interactive.is_empty()
+                    && (bulk.is_empty() || self.interactive_count < Priority::INTERACTIVE_WEIGHT)
+                {
+                    if !bulk.is_empty() {
+                        self.interactive_count += 1;
+                    }
+                    interactive.pop_front()
+                } else {
+                    self.interactive_count = 0;
+                    bulk.pop_front()
+                }
+            }
+        };
+        match next {
+            Some(msg) => {
+                self.msg_queue.push(msg);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Adds a SubsystemInit
     fn subsystem_action(&mut self, ssa: SubsystemAction<T>) {
         match ssa {
@@ -498,18 +886,19 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
     }
 
     // Then send the messages to the taps, except Destination::NoTap.
+    // Messages are only cloned for the taps which receive them.
     async fn send_tap(&mut self) -> Vec<usize> {
         let mut faulty = vec![];
-        let msgs: Vec<T> = self
+        let msgs: Vec<&T> = self
             .msg_queue
             .iter()
             .filter(|(dst, _)| dst != &Destination::NoTap)
-            .map(|(_, msg)| msg.clone())
+            .map(|(_, msg)| msg)
             .collect();
 
-        let type_id = self.type_id();
-        for (i, ss) in self.subsystems.iter_mut().filter(|(_, ss)| ss.is_tap()) {
-            if let Err(e) = ss.put_messages(*i, msgs.clone()).await {
+        let type_id = std::any::type_name::<T>();
+        for (i, ss) in self.subsystems.iter().filter(|(_, ss)| ss.is_tap()) {
+            if let Err(e) = ss.put_tap(&msgs) {
                 log::warn!(
                     "{}: Couldn't send to tap: {e:?} - perhaps you should use 'remove_subsystem'?",
                     type_id
@@ -523,11 +912,15 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
 
     // Finally send messages to all other subsystems, and collect
     // new messages for next call to 'process'.
+    // A handler which panics is removed, unless it is supervised.
+    // On wasm32-unknown-unknown panics abort the wasm instance, so catch_unwind
+    // never returns an error there.
     async fn process_handle_messages(&mut self) -> Vec<usize> {
         if self.msg_queue.len() == 0 {
             return vec![];
         }
         let mut ss_remove = vec![];
+        let mut ss_crashed = vec![];
         let mut new_msg_queue = vec![];
         let type_id = self.type_id();
         for (index_ss, ss) in self.subsystems.iter_mut().filter(|(_, ss)| ss.is_handler()) {
@@ -541,21 +934,64 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
                 .map(|nm| &nm.1)
                 .cloned()
                 .collect();
-            match ss.put_messages(*index_ss, msgs.clone()).await {
-                Ok(mut new_msgs) => {
+            match AssertUnwindSafe(ss.put_messages(*index_ss, msgs.clone()))
+                .catch_unwind()
+                .await
+            {
+                Ok(Ok(mut new_msgs)) => {
                     new_msg_queue.append(&mut new_msgs);
                 }
-                Err(e) => {
+                Ok(Err(e)) => {
                     ss_remove.push(*index_ss);
                     log::error!("{}: While sending messages: {e}", type_id);
                 }
+                Err(panic) => {
+                    ss_crashed.push((*index_ss, Self::panic_reason(panic)));
+                }
             }
         }
         self.msg_queue = new_msg_queue;
 
+        for (index, reason) in ss_crashed {
+            let count = self.crashes.entry(index).or_default();
+            *count += 1;
+            let restart = match self.subsystems.get_mut(&index) {
+                Some(Subsystem::Supervised(sup)) => {
+                    sup.crashed(now());
+                    true
+                }
+                _ => {
+                    ss_remove.push(index);
+                    false
+                }
+            };
+            log::error!(
+                "{}: Subsystem {index} panicked with '{reason}', restart: {restart}",
+                type_id
+            );
+            let crash = SubsystemCrashed {
+                broker: self.id,
+                subsystem: index,
+                reason,
+                restart,
+            };
+            self.crash_taps
+                .retain(|tap| tap.send(crash.clone()).is_ok());
+        }
+
         ss_remove
     }
 
+    fn panic_reason(panic: Box<dyn std::any::Any + Send>) -> String {
+        if let Some(s) = panic.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = panic.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown".into()
+        }
+    }
+
     fn type_id(&self) -> String {
         std::any::type_name::<T>().into()
     }
@@ -566,22 +1002,26 @@ impl<T: Async + Clone + fmt::Debug + 'static> Intern<T> {
 /// Every subsystem can be added zero, one, or more times.
 pub enum Subsystem<T> {
     Tap(UnboundedSender<T>),
+    TapFiltered(UnboundedSender<T>, TapFilter<T>),
     TapSync(Sender<T>),
     Handler(Box<dyn SubsystemHandler<T>>),
     Translator(Box<dyn SubsystemTranslator<T>>),
     Callback(SubsystemCallback<T>),
     TranslatorCallback(SubsystemTranslatorCallback<T>),
+    Supervised(Supervisor<T>),
 }
 #[cfg(target_family = "unix")]
 /// Subsystems available in a broker.
 /// Every subsystem can be added zero, one, or more times.
 pub enum Subsystem<T> {
     Tap(UnboundedSender<T>),
+    TapFiltered(UnboundedSender<T>, TapFilter<T>),
     TapSync(Sender<T>),
     Handler(Box<dyn SubsystemHandler<T> + Send>),
     Translator(Box<dyn SubsystemTranslator<T> + Send>),
     Callback(SubsystemCallback<T>),
     TranslatorCallback(SubsystemTranslatorCallback<T>),
+    Supervised(Supervisor<T>),
 }
 
 impl<T: Async + Clone + fmt::Debug> Subsystem<T> {
@@ -591,33 +1031,55 @@ impl<T: Async + Clone + fmt::Debug> Subsystem<T> {
         msgs: Vec<T>,
     ) -> Result<Vec<(Destination, T)>, BrokerError> {
         Ok(match self {
+            Self::Handler(h) => {
+                let ret = h.messages(msgs).await;
+                ret.into_iter()
+                    .map(|m| (Destination::Handled(index), m))
+                    .collect()
+            }
+            Self::Callback(h) => h(msgs).await,
+            Self::Supervised(sup) => match sup.handler() {
+                Some(h) => h
+                    .messages(msgs)
+                    .await
+                    .into_iter()
+                    .map(|m| (Destination::Handled(index), m))
+                    .collect(),
+                None => vec![],
+            },
+            _ => vec![],
+        })
+    }
+
+    fn put_tap(&self, msgs: &[&T]) -> Result<(), BrokerError> {
+        match self {
             Self::TapSync(s) => {
                 for msg in msgs {
-                    s.send(msg.clone())
+                    s.send((*msg).clone())
                         .map_err(|_| BrokerError::SendQueue("send_tap".into()))?;
                 }
-                vec![]
             }
             Self::Tap(s) => {
                 for msg in msgs {
-                    s.send(msg.clone())
+                    s.send((*msg).clone())
                         .map_err(|_| BrokerError::SendQueue("send_tap_async".into()))?;
                 }
-                vec![]
             }
-            Self::Handler(h) => {
-                let ret = h.messages(msgs).await;
-                ret.into_iter()
-                    .map(|m| (Destination::Handled(index), m))
-                    .collect()
+            Self::TapFiltered(s, filter) => {
+                for msg in msgs.iter().filter(|msg| filter(msg)) {
+                    s.send((*msg).clone())
+                        .map_err(|_| BrokerError::SendQueue("send_tap_filtered".into()))?;
+                }
             }
-            Self::Callback(h) => h(msgs).await,
-            _ => vec![],
-        })
+            _ => {}
+        }
+        Ok(())
     }
 
     fn is_tap(&self) -> bool {
-        matches!(self, Self::TapSync(_)) || matches!(self, Self::Tap(_))
+        matches!(self, Self::TapSync(_))
+            || matches!(self, Self::Tap(_))
+            || matches!(self, Self::TapFiltered(_, _))
     }
 
     fn is_translator(&self) -> bool {
@@ -625,7 +1087,9 @@ impl<T: Async + Clone + fmt::Debug> Subsystem<T> {
     }
 
     fn is_handler(&self) -> bool {
-        matches!(self, Self::Handler(_)) || matches!(self, Self::Callback(_))
+        matches!(self, Self::Handler(_))
+            || matches!(self, Self::Callback(_))
+            || matches!(self, Self::Supervised(_))
     }
 
     async fn settle(&mut self, callers: Vec<BrokerID>) -> Result<(), BrokerError> {
@@ -642,10 +1106,12 @@ impl<T> fmt::Debug for Subsystem<T> {
         match self {
             Self::TapSync(_) => write!(f, "Tap"),
             Self::Tap(_) => write!(f, "TapAsync"),
+            Self::TapFiltered(_, _) => write!(f, "TapFiltered"),
             Self::Handler(_) => write!(f, "Handler"),
             Self::Translator(_) => write!(f, "Translator"),
             Self::TranslatorCallback(_) => write!(f, "TranslatorCallback"),
             Self::Callback(_) => write!(f, "Callback"),
+            Self::Supervised(_) => write!(f, "Supervised"),
         }
     }
 }
@@ -667,11 +1133,93 @@ type SubsystemCallback<T> = Box<dyn Fn(Vec<T>) -> BoxFuture<'static, Vec<(Destin
 type SubsystemCallback<T> =
     Box<dyn Fn(Vec<T>) -> BoxFuture<'static, Vec<(Destination, T)>> + Send + Sync>;
 #[cfg(target_family = "wasm")]
+/// Decides which messages are sent to a [`Subsystem::TapFiltered`].
+pub type TapFilter<T> = Box<dyn Fn(&T) -> bool>;
+#[cfg(target_family = "unix")]
+/// Decides which messages are sent to a [`Subsystem::TapFiltered`].
+pub type TapFilter<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+#[cfg(target_family = "wasm")]
 type SubsystemTranslatorCallback<T> = Box<dyn Fn(Vec<BrokerID>, T) -> BoxFuture<'static, bool>>;
 #[cfg(target_family = "unix")]
 type SubsystemTranslatorCallback<T> =
     Box<dyn Fn(Vec<BrokerID>, T) -> BoxFuture<'static, bool> + Send + Sync>;
 
+#[cfg(target_family = "wasm")]
+/// Creates a new handler for a [`Subsystem::Supervised`].
+pub type HandlerFactory<T> = Box<dyn Fn() -> Box<dyn SubsystemHandler<T>>>;
+#[cfg(target_family = "unix")]
+/// Creates a new handler for a [`Subsystem::Supervised`].
+pub type HandlerFactory<T> = Box<dyn Fn() -> Box<dyn SubsystemHandler<T> + Send> + Send>;
+
+#[cfg(target_family = "wasm")]
+type SupervisedHandler<T> = Box<dyn SubsystemHandler<T>>;
+#[cfg(target_family = "unix")]
+type SupervisedHandler<T> = Box<dyn SubsystemHandler<T> + Send>;
+
+/// How long a supervised handler has to run without crashing before its
+/// backoff starts again at the initial value.
+pub const SUPERVISOR_DECAY_MS: i64 = 10 * 60_000;
+
+/// Holds a handler and re-creates it with the factory after it panicked.
+/// The restart happens with the first messages arriving after the backoff,
+/// which doubles with every crash.
+/// The crashes are forgotten if the handler didn't crash for [`SUPERVISOR_DECAY_MS`],
+/// so a handler crashing once in a while is not delayed by the maximum backoff.
+/// Panics can only be caught on unix, on wasm they abort the wasm instance.
+pub struct Supervisor<T> {
+    factory: HandlerFactory<T>,
+    handler: Option<SupervisedHandler<T>>,
+    restart_at: i64,
+    backoff_ms: i64,
+    max_backoff_ms: i64,
+    // The crashes since the last pause of SUPERVISOR_DECAY_MS.
+    recent_crashes: u32,
+    last_crash: i64,
+}
+
+impl<T> Supervisor<T> {
+    /// Creates a new supervisor with a backoff starting at 1 second and
+    /// going up to 1 minute.
+    pub fn new(factory: HandlerFactory<T>) -> Self {
+        Self::with_backoff(factory, 1000, 60_000)
+    }
+
+    /// Creates a new supervisor with the given backoff times in milliseconds.
+    pub fn with_backoff(factory: HandlerFactory<T>, backoff_ms: i64, max_backoff_ms: i64) -> Self {
+        Self {
+            handler: Some(factory()),
+            factory,
+            restart_at: 0,
+            backoff_ms,
+            max_backoff_ms,
+            recent_crashes: 0,
+            last_crash: 0,
+        }
+    }
+
+    fn crashed(&mut self, now: i64) {
+        self.handler = None;
+        if now - self.last_crash > SUPERVISOR_DECAY_MS {
+            self.recent_crashes = 0;
+        }
+        self.last_crash = now;
+        let backoff = self
+            .backoff_ms
+            .saturating_mul(1 << self.recent_crashes.min(30))
+            .min(self.max_backoff_ms);
+        self.recent_crashes += 1;
+        self.restart_at = now + backoff;
+    }
+
+    fn handler(&mut self) -> Option<&mut SupervisedHandler<T>> {
+        if self.handler.is_none() && now() >= self.restart_at {
+            log::info!("Restarting supervised handler");
+            self.handler = Some((self.factory)());
+        }
+        self.handler.as_mut()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{start_logging, start_logging_filter_level};
@@ -873,6 +1421,112 @@ mod tests {
         Ok(())
     }
 
+    struct Panicker {}
+
+    #[platform_async_trait()]
+    impl SubsystemHandler<MessageA> for Panicker {
+        async fn messages(&mut self, msgs: Vec<MessageA>) -> Vec<MessageA> {
+            if msgs.contains(&MessageA::Four) {
+                panic!("Got four");
+            }
+            msgs.iter()
+                .filter(|msg| msg == &&MessageA::One)
+                .map(|_| MessageA::Two)
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_supervised() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging_filter_level(vec![], log::LevelFilter::Info);
+
+        let mut b = Broker::new();
+        let plain = b
+            .add_subsystem(Subsystem::Handler(Box::new(Panicker {})))
+            .await?;
+        let supervised = b
+            .add_subsystem(Subsystem::Supervised(Supervisor::with_backoff(
+                Box::new(|| Box::new(Panicker {})),
+                0,
+                0,
+            )))
+            .await?;
+        let mut crashes = b.get_crash_tap().await?;
+        let (tap, _) = b.get_tap_sync().await?;
+
+        b.settle_msg(MessageA::Four).await?;
+        let mut crashed = [crashes.recv().await.unwrap(), crashes.recv().await.unwrap()];
+        crashed.sort_by_key(|c| c.subsystem);
+        assert_eq!(plain, crashed[0].subsystem);
+        assert!(!crashed[0].restart);
+        assert_eq!(supervised, crashed[1].subsystem);
+        assert!(crashed[1].restart);
+        assert_eq!("Got four", crashed[1].reason);
+
+        let health = b.health().await?;
+        assert_eq!(Some(&1), health.crashes.get(&plain));
+        assert_eq!(Some(&1), health.crashes.get(&supervised));
+        assert_eq!(2, health.subsystems);
+
+        // The broker still works, and the supervised handler has been restarted.
+        tap.try_iter().count();
+        b.settle_msg(MessageA::One).await?;
+        assert_eq!(
+            vec![MessageA::One, MessageA::Two],
+            tap.try_iter().collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_supervisor_decay() {
+        let mut sup: Supervisor<MessageA> =
+            Supervisor::with_backoff(Box::new(|| Box::new(Panicker {})), 1000, 60_000);
+        let start = SUPERVISOR_DECAY_MS * 2;
+        for (crash, backoff) in [(0, 1000), (1, 2000), (2, 4000)] {
+            sup.crashed(start + crash);
+            assert_eq!(start + crash + backoff, sup.restart_at);
+        }
+        for _ in 0..10 {
+            sup.crashed(start);
+        }
+        assert_eq!(start + 60_000, sup.restart_at);
+
+        // After a quiet period the backoff starts again.
+        let later = start + SUPERVISOR_DECAY_MS + 1;
+        sup.crashed(later);
+        assert_eq!(later + 1000, sup.restart_at);
+    }
+
+    #[tokio::test]
+    async fn test_registry() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging_filter_level(vec![], log::LevelFilter::Info);
+
+        let registry = BrokerRegistry::new();
+        let mut a = Broker::<MessageA>::new();
+        a.get_tap_sync().await?;
+        let b = Broker::<MessageB>::new();
+        registry.add("a", &a).await;
+        registry.add("b", &b).await;
+
+        let list = registry.list().await;
+        assert_eq!(2, list.len());
+        assert_eq!("a", list[0].name);
+        assert!(list[0].type_name.ends_with("MessageA"));
+        assert_eq!(1, list[0].health.subsystems);
+        assert_eq!(
+            format!("a: 1 subsystems, 0 pending, 0 crashes ({})", list[0].type_name),
+            list[0].to_string()
+        );
+        assert_eq!("b", list[1].name);
+
+        drop(b);
+        let list = registry.list().await;
+        assert_eq!(1, list.len());
+        assert_eq!("a", list[0].name);
+        Ok(())
+    }
+
     async fn test_translator_cb(msg: MessageA) -> bool {
         matches!(msg, MessageA::One)
     }
@@ -893,4 +1547,80 @@ mod tests {
         assert_eq!(MessageA::Two, tap.0.recv()?);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_priority() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut b = Broker::<usize>::new();
+        let (tap, _) = b.get_tap_sync().await?;
+        for i in 100..110 {
+            b.enqueue_msg_priority(Priority::Bulk, i)?;
+        }
+        for i in 0..20 {
+            b.enqueue_msg_priority(Priority::Interactive, i)?;
+        }
+        b.enqueue_msg_priority(Priority::Control, 1000)?;
+        b.settle(vec![]).await?;
+
+        let mut expected = vec![1000];
+        expected.extend(0..8);
+        expected.push(100);
+        expected.extend(8..16);
+        expected.push(101);
+        expected.extend(16..20);
+        expected.extend(102..110);
+        assert_eq!(expected, tap.try_iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unlink() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut a = Broker::<usize>::new();
+        let mut b = Broker::<usize>::new();
+        let (tap, _) = b.get_tap_sync().await?;
+        let link = a
+            .forward_priority(b.clone(), Priority::Control, Box::new(Some))
+            .await;
+        a.settle_msg(1).await?;
+        a.unlink(link)?;
+        a.settle_msg(2).await?;
+        b.settle(vec![]).await?;
+        assert_eq!(vec![1], tap.try_iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tap_filtered() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut b = Broker::new();
+        let (mut tap, _) = b.get_tap_filtered(|msg| msg == &MessageA::Two).await?;
+        b.settle_msg(MessageA::One).await?;
+        b.settle_msg(MessageA::Two).await?;
+        b.settle_msg(MessageA::Four).await?;
+        assert_eq!(Some(MessageA::Two), tap.recv().await);
+        assert!(tap.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut b = Broker::new();
+        let (mut tap, _) = b.get_tap().await?;
+        let registry = BrokerRegistry::new();
+        registry.add("test", &b).await;
+        b.emit_msg(MessageA::One)?;
+        registry.stop_all().await;
+        assert_eq!(Some(MessageA::One), tap.recv().await);
+        assert_eq!(None, tap.recv().await);
+        assert!(b.emit_msg(MessageA::Two).is_err());
+        assert!(b.stop().is_err());
+        assert!(registry.list().await.is_empty());
+        Ok(())
+    }
 }
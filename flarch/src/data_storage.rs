@@ -1,9 +1,12 @@
 use std::{
     collections::HashMap,
+    future::Future,
     sync::{Arc, Mutex},
 };
 use thiserror::Error;
 use async_trait::async_trait;
+use flarch_macro::platform_async_trait;
+use futures::future::{AbortHandle, Abortable};
 
 #[cfg(all(target_family="wasm", feature = "node"))]
 mod node;
@@ -22,6 +25,8 @@ pub use libc::*;
 pub enum StorageError {
     #[error("From the underlying storage: {0}")]
     Underlying(String),
+    #[error("The storage operation has been cancelled")]
+    Cancelled,
 }
 
 /// The DataStorage trait allows access to a persistent storage.
@@ -36,6 +41,77 @@ pub trait DataStorage {
     fn clone(&self) -> Box<dyn DataStorage + Send>;
 }
 
+/// The asynchronous version of [`DataStorage`], for backends which must not block
+/// the executor, like IndexedDB or SQLite.
+/// Dropping the future of an operation cancels it, but a `set` or `remove` which
+/// already reached the backend might still be applied.
+/// Use [`cancellable`] to cancel an operation from somewhere else.
+#[platform_async_trait()]
+pub trait DataStorageAsync {
+    async fn get(&self, key: &str) -> Result<String, StorageError>;
+
+    async fn set(&mut self, key: &str, value: &str) -> Result<(), StorageError>;
+
+    async fn remove(&mut self, key: &str) -> Result<(), StorageError>;
+
+    fn clone_async(&self) -> Box<dyn DataStorageAsync + Send>;
+}
+
+/// Runs a storage operation which can be cancelled using the returned [`AbortHandle`].
+/// A cancelled operation returns [`StorageError::Cancelled`].
+pub fn cancellable<T, F: Future<Output = Result<T, StorageError>>>(
+    op: F,
+) -> (impl Future<Output = Result<T, StorageError>>, AbortHandle) {
+    let (handle, registration) = AbortHandle::new_pair();
+    let op = Abortable::new(op, registration);
+    (
+        async move { op.await.unwrap_or(Err(StorageError::Cancelled)) },
+        handle,
+    )
+}
+
+/// Adapter to use a synchronous [`DataStorage`] where a [`DataStorageAsync`] is needed.
+/// The operations are done directly in the async call, so it should only be used
+/// for fast backends.
+pub struct DataStorageSync {
+    ds: Arc<Mutex<Box<dyn DataStorage + Send>>>,
+}
+
+impl DataStorageSync {
+    pub fn new(ds: Box<dyn DataStorage + Send>) -> Self {
+        Self {
+            ds: Arc::new(Mutex::new(ds)),
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Box<dyn DataStorage + Send>>, StorageError> {
+        self.ds
+            .lock()
+            .map_err(|e| StorageError::Underlying(e.to_string()))
+    }
+}
+
+#[platform_async_trait()]
+impl DataStorageAsync for DataStorageSync {
+    async fn get(&self, key: &str) -> Result<String, StorageError> {
+        self.lock()?.get(key)
+    }
+
+    async fn set(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.lock()?.set(key, value)
+    }
+
+    async fn remove(&mut self, key: &str) -> Result<(), StorageError> {
+        self.lock()?.remove(key)
+    }
+
+    fn clone_async(&self) -> Box<dyn DataStorageAsync + Send> {
+        Box::new(Self {
+            ds: Arc::clone(&self.ds),
+        })
+    }
+}
+
 /// A temporary DataStorage that keeps the data only during its lifetime.
 pub struct DataStorageTemp {
     kvs: Arc<Mutex<HashMap<String, String>>>,
@@ -97,4 +173,19 @@ mod tests {
         assert_eq!("three", ds2.get("two")?);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_storage_async() -> Result<(), Box<dyn std::error::Error>> {
+        let ds = DataStorageTemp::new();
+        let mut dsa = DataStorageSync::new(ds.clone());
+        dsa.set("two", "three").await?;
+        assert_eq!("three", ds.get("two")?);
+        assert_eq!("three", dsa.clone_async().get("two").await?);
+
+        let (op, handle) = cancellable(dsa.set("two", "four"));
+        handle.abort();
+        assert!(matches!(op.await, Err(StorageError::Cancelled)));
+        assert_eq!("three", dsa.get("two").await?);
+        Ok(())
+    }
 }
\ No newline at end of file
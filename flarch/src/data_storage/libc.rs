@@ -13,6 +13,14 @@ impl DataStorageLocal {
         panic!("Only implemented for target_family=wasm");
     }
 
+    pub fn new_session(_: &str) -> Box<dyn DataStorage + Send> {
+        panic!("Only implemented for target_family=wasm");
+    }
+
+    pub fn clear(_: &str) -> Result<(), StorageError> {
+        panic!("Only implemented for target_family=wasm");
+    }
+
     pub fn clone() -> Box<dyn DataStorage + Send> {
         panic!("Only implemented for target_family=wasm");
     }
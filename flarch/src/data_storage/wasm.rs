@@ -1,20 +1,72 @@
 use async_trait::async_trait;
-use web_sys::window;
+use web_sys::{window, Storage};
 
 use crate::data_storage::{DataStorage, StorageError};
 
+/// Stores the data in the localStorage of the browser, or in the sessionStorage,
+/// which is removed once the browser tab is closed.
 pub struct DataStorageLocal {
     base: String,
+    session: bool,
 }
 
 impl DataStorageLocal {
     pub fn new(base_str: &str) -> Box<dyn DataStorage + Send> {
-        let base = if base_str.is_empty() {
+        Box::new(DataStorageLocal {
+            base: Self::base(base_str),
+            session: false,
+        })
+    }
+
+    /// Returns a storage which only keeps the data during the browser session.
+    pub fn new_session(base_str: &str) -> Box<dyn DataStorage + Send> {
+        Box::new(DataStorageLocal {
+            base: Self::base(base_str),
+            session: true,
+        })
+    }
+
+    /// Removes all entries of this base from the localStorage.
+    pub fn clear(base_str: &str) -> Result<(), StorageError> {
+        let base = Self::base(base_str);
+        let storage = DataStorageLocal {
+            base: base.clone(),
+            session: false,
+        }
+        .storage()?;
+        let len = storage
+            .length()
+            .map_err(|e| StorageError::Underlying(format!("{e:?}")))?;
+        let keys: Vec<String> = (0..len)
+            .filter_map(|i| storage.key(i).ok().flatten())
+            .filter(|key| key.starts_with(&base))
+            .collect();
+        for key in keys {
+            storage
+                .remove_item(&key)
+                .map_err(|e| StorageError::Underlying(format!("{e:?}")))?;
+        }
+        Ok(())
+    }
+
+    fn base(base_str: &str) -> String {
+        if base_str.is_empty() {
             "".to_string()
         } else {
             base_str.to_string() + "_"
+        }
+    }
+
+    fn storage(&self) -> Result<Storage, StorageError> {
+        let window = window().unwrap();
+        let storage = if self.session {
+            window.session_storage()
+        } else {
+            window.local_storage()
         };
-        Box::new(DataStorageLocal { base })
+        Ok(storage
+            .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))?
+            .unwrap())
     }
 }
 
@@ -22,11 +74,8 @@ impl DataStorageLocal {
 impl DataStorage for DataStorageLocal {
     fn get(&self, key: &str) -> Result<String, StorageError> {
         let key_entry = format!("{}{}", self.base, key);
-        Ok(window()
-            .unwrap()
-            .local_storage()
-            .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))?
-            .unwrap()
+        Ok(self
+            .storage()?
             .get(&key_entry)
             .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))?
             .unwrap_or_else(|| "".to_string()))
@@ -34,27 +83,22 @@ impl DataStorage for DataStorageLocal {
 
     fn set(&mut self, key: &str, value: &str) -> Result<(), StorageError> {
         let key_entry = format!("{}{}", self.base, key);
-        window()
-            .unwrap()
-            .local_storage()
-            .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))?
-            .unwrap()
+        self.storage()?
             .set(&key_entry, value)
             .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))
     }
 
     fn remove(&mut self, key: &str) -> Result<(), StorageError> {
         let key_entry = format!("{}{}", self.base, key);
-        window()
-            .unwrap()
-            .local_storage()
-            .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))?
-            .unwrap()
+        self.storage()?
             .remove_item(&key_entry)
             .map_err(|e| StorageError::Underlying(e.as_string().unwrap()))
     }
 
     fn clone(&self) -> Box<dyn DataStorage + Send> {
-        Box::new(DataStorageLocal {base: self.base.clone()})
+        Box::new(DataStorageLocal {
+            base: self.base.clone(),
+            session: self.session,
+        })
     }
 }
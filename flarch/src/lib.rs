@@ -1,5 +1,6 @@
 pub mod broker;
 pub mod data_storage;
+pub mod metrics;
 pub mod nodeids;
 pub mod tasks;
 pub mod web_rtc;
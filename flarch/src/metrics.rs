@@ -0,0 +1,285 @@
+//! Counters and gauges in the Prometheus text format, so a fleet of nodes and
+//! signalling servers can be scraped uniformly.
+//!
+//! [`Metrics`] collects the values and writes them in the
+//! [exposition format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+//! With the `metrics` feature, `serve` answers `GET /metrics` with the latest
+//! text sent over a [`tokio::sync::watch`] channel.
+//! `serve_with_health` also answers `GET /health` with a second text, e.g., the
+//! state of the brokers of a node.
+//! The HTTP server is only available for libc.
+
+use std::fmt::{self, Write};
+
+/// The content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The types of metrics supported by [`Metrics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    /// A value which only goes up, e.g., the number of requests.
+    Counter,
+    /// A value which goes up and down, e.g., the number of connections.
+    Gauge,
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricType::Counter => write!(f, "counter"),
+            MetricType::Gauge => write!(f, "gauge"),
+        }
+    }
+}
+
+/// One value of a metric, with its labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+impl Sample {
+    /// A sample with one label.
+    pub fn label(name: &str, value: &str, sample: f64) -> Self {
+        Self {
+            labels: vec![(name.into(), value.into())],
+            value: sample,
+        }
+    }
+
+    fn unlabeled(value: f64) -> Self {
+        Self {
+            labels: vec![],
+            value,
+        }
+    }
+}
+
+/// Collects metrics and writes them in the Prometheus text format.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    out: String,
+}
+
+impl Metrics {
+    /// Adds a counter without labels.
+    pub fn counter(&mut self, name: &str, help: &str, value: f64) -> &mut Self {
+        self.family(MetricType::Counter, name, help, &[Sample::unlabeled(value)])
+    }
+
+    /// Adds a gauge without labels.
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) -> &mut Self {
+        self.family(MetricType::Gauge, name, help, &[Sample::unlabeled(value)])
+    }
+
+    /// Adds a metric with one line per sample.
+    /// If there are no samples, only the description is written.
+    pub fn family(
+        &mut self,
+        typ: MetricType,
+        name: &str,
+        help: &str,
+        samples: &[Sample],
+    ) -> &mut Self {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        // Writing to a String never fails.
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} {typ}");
+        for sample in samples {
+            let _ = write!(self.out, "{name}");
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}=\"{}\"", escape_label(v)))
+                    .collect();
+                let _ = write!(self.out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(self.out, " {}", format_value(sample.value));
+        }
+        self
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.out)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0. { "+Inf" } else { "-Inf" }.into()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(all(target_family = "unix", feature = "metrics"))]
+mod server {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::watch,
+    };
+
+    use super::CONTENT_TYPE;
+
+    // Requests bigger than this are cut, as only the first line is needed.
+    const REQUEST_MAX: usize = 8192;
+
+    /// Listens on `addr`, e.g., `0.0.0.0:9090`, and answers `GET /metrics` with
+    /// the latest value of `metrics`.
+    /// Returns once the port is bound, the server itself runs in the background
+    /// until `metrics` is closed.
+    pub async fn serve(addr: &str, metrics: watch::Receiver<String>) -> std::io::Result<()> {
+        start(addr, metrics, None).await
+    }
+
+    /// Like [`serve`], but also answers `GET /health` with the latest value of `health`.
+    pub async fn serve_with_health(
+        addr: &str,
+        metrics: watch::Receiver<String>,
+        health: watch::Receiver<String>,
+    ) -> std::io::Result<()> {
+        start(addr, metrics, Some(health)).await
+    }
+
+    async fn start(
+        addr: &str,
+        mut metrics: watch::Receiver<String>,
+        health: Option<watch::Receiver<String>>,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    conn = listener.accept() => match conn {
+                        Ok((stream, _)) => {
+                            let text = metrics.borrow().clone();
+                            let health = health.as_ref().map(|h| h.borrow().clone());
+                            tokio::spawn(async move {
+                                if let Err(e) = answer(stream, &text, health.as_deref()).await {
+                                    log::debug!("Couldn't answer metrics request: {e}");
+                                }
+                            });
+                        }
+                        Err(e) => log::warn!("Couldn't accept metrics connection: {e}"),
+                    },
+                    changed = metrics.changed() => {
+                        if changed.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn answer(
+        mut stream: TcpStream,
+        metrics: &str,
+        health: Option<&str>,
+    ) -> std::io::Result<()> {
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < REQUEST_MAX {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let response = response(&String::from_utf8_lossy(&request), metrics, health);
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    pub(super) fn response(request: &str, metrics: &str, health: Option<&str>) -> String {
+        let mut first = request.lines().next().unwrap_or("").split_whitespace();
+        let (status, typ, body) = match (first.next(), first.next(), health) {
+            (Some("GET"), Some("/metrics"), _) => ("200 OK", CONTENT_TYPE, metrics),
+            (Some("GET"), Some("/health"), Some(health)) => ("200 OK", "text/plain", health),
+            (Some("GET"), _, _) => ("404 Not Found", "text/plain", "Not found\n"),
+            _ => (
+                "405 Method Not Allowed",
+                "text/plain",
+                "Only GET is allowed\n",
+            ),
+        };
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {typ}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+#[cfg(all(target_family = "unix", feature = "metrics"))]
+pub use server::{serve, serve_with_health};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let mut m = Metrics::default();
+        m.counter("fl_requests_total", "Requests\nreceived", 12.)
+            .gauge("fl_offset_ms", "Clock offset", -1.5)
+            .family(
+                MetricType::Gauge,
+                "fl_events",
+                "Events per category",
+                &[
+                    Sample::label("category", "Text\"Message", 3.),
+                    Sample::label("category", "NodeInfo", 2.),
+                ],
+            )
+            .gauge("fl_none", "Nothing", f64::NAN);
+        assert_eq!(
+            "# HELP fl_requests_total Requests\\nreceived
+# TYPE fl_requests_total counter
+fl_requests_total 12
+# HELP fl_offset_ms Clock offset
+# TYPE fl_offset_ms gauge
+fl_offset_ms -1.5
+# HELP fl_events Events per category
+# TYPE fl_events gauge
+fl_events{category=\"Text\\\"Message\"} 3
+fl_events{category=\"NodeInfo\"} 2
+# HELP fl_none Nothing
+# TYPE fl_none gauge
+fl_none NaN
+",
+            m.to_string()
+        );
+    }
+
+    #[cfg(all(target_family = "unix", feature = "metrics"))]
+    #[test]
+    fn test_response() {
+        let ok = server::response("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n", "a 1\n", None);
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.ends_with("\r\n\r\na 1\n"));
+        assert!(server::response("GET / HTTP/1.1\r\n\r\n", "", None).contains(" 404 "));
+        assert!(server::response("POST /metrics HTTP/1.1\r\n\r\n", "", None).contains(" 405 "));
+        let health = "GET /health HTTP/1.1\r\n\r\n";
+        assert!(server::response(health, "", None).contains(" 404 "));
+        assert!(server::response(health, "", Some("net\n")).ends_with("\r\n\r\nnet\n"));
+    }
+}
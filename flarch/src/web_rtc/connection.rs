@@ -1,23 +1,34 @@
+use super::throttle::ThrottleConfig;
+
 /// A configuration to set up a WebRTC connection.
 #[derive(Debug, Clone)]
 pub struct ConnectionConfig {
     /// The signalling server, defaults to "ws://localhost:8765"
     signal_server: Option<String>,
+    /// More signalling servers to connect to at the same time, by default none
+    extra_signal_servers: Vec<String>,
     /// The STUN server, defaults to "stun:stun.l.google.com:19302"
     stun_server: Option<HostLogin>,
+    /// A second STUN server to detect symmetric NATs, defaults to "stun:stun1.l.google.com:19302"
+    nat_stun_server: Option<HostLogin>,
     /// The TURN server, by default none
     turn_server: Option<HostLogin>,
+    /// The bandwidth limits, by default none
+    throttle: ThrottleConfig,
 }
 
 impl Default for ConnectionConfig {
     fn default() -> Self {
         Self {
             signal_server: Some("ws://localhost:8765".into()),
+            extra_signal_servers: vec![],
             stun_server: Some(HostLogin {
                 url: "stun:stun.l.google.com:19302".into(),
                 login: None,
             }),
+            nat_stun_server: Some(HostLogin::from_url("stun:stun1.l.google.com:19302")),
             turn_server: None,
+            throttle: ThrottleConfig::default(),
         }
     }
 }
@@ -30,8 +41,11 @@ impl ConnectionConfig {
     ) -> Self {
         Self {
             signal_server,
+            extra_signal_servers: vec![],
             stun_server,
+            nat_stun_server: None,
             turn_server,
+            throttle: ThrottleConfig::default(),
         }
     }
 
@@ -39,8 +53,11 @@ impl ConnectionConfig {
     pub fn from_signal(url: &str) -> Self {
         Self {
             signal_server: Some(url.into()),
+            extra_signal_servers: vec![],
             stun_server: None,
+            nat_stun_server: None,
             turn_server: None,
+            throttle: ThrottleConfig::default(),
         }
     }
 
@@ -52,6 +69,19 @@ impl ConnectionConfig {
             .clone()
     }
 
+    /// Returns a ConnectionConfig which also connects to the given signalling servers.
+    pub fn with_extra_signals(mut self, urls: Vec<String>) -> Self {
+        self.extra_signal_servers = urls;
+        self
+    }
+
+    /// Returns all signalling servers, starting with [`ConnectionConfig::signal`].
+    pub fn signals(&self) -> Vec<String> {
+        let mut signals = vec![self.signal()];
+        signals.extend(self.extra_signal_servers.iter().cloned());
+        signals
+    }
+
     /// Returns the STUN server, or the default if none set.
     pub fn stun(&self) -> HostLogin {
         self.stun_server
@@ -60,10 +90,36 @@ impl ConnectionConfig {
             .clone()
     }
 
+    /// Returns a ConnectionConfig with the given second STUN server.
+    pub fn with_nat_stun(mut self, nat_stun: HostLogin) -> Self {
+        self.nat_stun_server = Some(nat_stun);
+        self
+    }
+
+    /// Returns the second STUN server used to detect symmetric NATs, or the
+    /// default if none set. See [`crate::web_rtc::nat`].
+    pub fn nat_stun(&self) -> HostLogin {
+        self.nat_stun_server
+            .as_ref()
+            .unwrap_or(&HostLogin::from_url("stun:stun1.l.google.com:19302"))
+            .clone()
+    }
+
     /// Returns an Option to the turn server, as there is no default available publicly.
     pub fn turn(&self) -> Option<HostLogin> {
         self.turn_server.clone()
     }
+
+    /// Returns a ConnectionConfig with the given bandwidth limits.
+    pub fn with_throttle(mut self, throttle: ThrottleConfig) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Returns the bandwidth limits.
+    pub fn throttle(&self) -> ThrottleConfig {
+        self.throttle
+    }
 }
 
 /// A URL with an optional username/password.
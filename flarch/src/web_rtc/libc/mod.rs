@@ -1,3 +1,4 @@
+pub mod tls;
 pub mod web_rtc_setup;
 pub mod web_socket_client;
 pub mod web_socket_server;
@@ -0,0 +1,219 @@
+//! TLS for the [`super::web_socket_server::WebSocketServer`], so it can serve
+//! wss:// without a reverse proxy.
+//!
+//! The certificate and the key are read from PEM files, and read again when
+//! one of the files changes.
+//! So a certificate renewed by, e.g., certbot is used for the next connections
+//! without restarting the server.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use thiserror::Error;
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::ring::{default_provider, sign::any_supported_type},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+#[derive(Error, Debug)]
+/// Errors when reading the certificate and the key
+pub enum TlsError {
+    #[error("No certificate in {0:?}")]
+    NoCertificate(PathBuf),
+    #[error("No private key in {0:?}")]
+    NoKey(PathBuf),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
+
+/// The PEM files with the certificate chain and the private key of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Returns an acceptor using the certificate and the key of the files.
+    /// Fails if the files cannot be read at startup.
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let resolver = CertReloader::new(self.clone())?;
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        [&self.cert, &self.key]
+            .iter()
+            .filter_map(|path| path.metadata().and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    fn load(&self) -> Result<CertifiedKey, TlsError> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificate(self.cert.clone()));
+        }
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.key)?))?
+            .ok_or_else(|| TlsError::NoKey(self.key.clone()))?;
+        let key = any_supported_type(&key)?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+}
+
+/// Gives the current certificate to every new connection, and reloads it
+/// if the files changed since the last time.
+#[derive(Debug)]
+struct CertReloader {
+    files: TlsFiles,
+    current: RwLock<(Option<SystemTime>, Arc<CertifiedKey>)>,
+}
+
+impl CertReloader {
+    fn new(files: TlsFiles) -> Result<Self, TlsError> {
+        let current = RwLock::new((files.modified(), Arc::new(files.load()?)));
+        Ok(Self { files, current })
+    }
+
+    fn current(&self) -> Option<Arc<CertifiedKey>> {
+        let modified = self.files.modified();
+        {
+            let current = self.current.read().ok()?;
+            if current.0 == modified {
+                return Some(current.1.clone());
+            }
+        }
+        let mut current = self.current.write().ok()?;
+        match self.files.load() {
+            Ok(key) => {
+                log::info!("Reloaded the certificate from {:?}", self.files.cert);
+                *current = (modified, Arc::new(key));
+            }
+            // The files might be in the middle of being written, so keep the old
+            // certificate and try again with the next connection.
+            Err(e) => log::warn!("Couldn't reload the certificate: {e}"),
+        }
+        Some(current.1.clone())
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a new certificate and sets the modification time of the files to
+    // `modified` seconds after the epoch, as the file system might not store
+    // the time precisely enough to see a new write.
+    fn write_cert(dir: &std::path::Path, modified: u64) -> TlsFiles {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let files = TlsFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        std::fs::write(&files.cert, cert.cert.pem()).unwrap();
+        std::fs::write(&files.key, cert.key_pair.serialize_pem()).unwrap();
+        set_modified(&files, modified);
+        files
+    }
+
+    fn set_modified(files: &TlsFiles, modified: u64) {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(modified);
+        for path in [&files.cert, &files.key] {
+            File::options()
+                .write(true)
+                .open(path)
+                .and_then(|f| f.set_modified(time))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reload() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("flarch-tls-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir)?;
+        let reloader = CertReloader::new(write_cert(&dir, 1000))?;
+        let first = reloader.current().unwrap();
+        assert!(Arc::ptr_eq(&first, &reloader.current().unwrap()));
+
+        write_cert(&dir, 2000);
+        let second = reloader.current().unwrap();
+        assert_ne!(first.cert, second.cert);
+
+        // A broken file keeps the old certificate.
+        std::fs::write(&reloader.files.cert, "")?;
+        set_modified(&reloader.files, 3000);
+        assert_eq!(second.cert, reloader.current().unwrap().cert);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wss() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::web_rtc::{
+            web_socket_server::WebSocketServer,
+            websocket::{WSServerMessage, WSServerOutput},
+        };
+        use futures::SinkExt;
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+        use tokio_tungstenite::{tungstenite::Message, Connector};
+
+        let dir = std::env::temp_dir().join(format!("flarch-wss-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir)?;
+        let files = write_cert(&dir, 1000);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let mut server = WebSocketServer::new_with_listener(listener, Some(files.clone())).await?;
+        let (tap, _) = server.get_tap_sync().await?;
+
+        // A client which never starts the TLS handshake doesn't block the others.
+        let _stalled = TcpStream::connect(("127.0.0.1", port)).await?;
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(&files.cert)?)) {
+            roots.add(cert?)?;
+        }
+        let client = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let (mut ws, _) = tokio_tungstenite::connect_async_tls_with_config(
+            format!("wss://localhost:{port}"),
+            None,
+            false,
+            Some(Connector::Rustls(Arc::new(client))),
+        )
+        .await?;
+        ws.send(Message::Text("hello".into())).await?;
+        assert!(tap
+            .iter()
+            .any(|msg| msg == WSServerMessage::Output(WSServerOutput::Message(1, "hello".into()))));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,15 +1,21 @@
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
 };
 
-use crate::broker::{Broker, Subsystem, SubsystemHandler};
+use crate::broker::{Broker, Priority, Subsystem, SubsystemHandler};
 use async_trait::async_trait;
 use futures::lock::Mutex;
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors, media_engine::MediaEngine, setting_engine::SettingEngine, APIBuilder
-    }, data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel}, ice::mdns::MulticastDnsMode, ice_transport::{
+    }, data_channel::{
+        data_channel_init::RTCDataChannelInit, data_channel_message::DataChannelMessage,
+        data_channel_state::RTCDataChannelState, RTCDataChannel,
+    }, ice::mdns::MulticastDnsMode, ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
         ice_connection_state::RTCIceConnectionState,
         ice_credential_type::RTCIceCredentialType,
@@ -19,14 +25,15 @@ use webrtc::{
         peer_connection_state::RTCPeerConnectionState,
         sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
         RTCPeerConnection,
-    }
+    }, stats::StatsReportType,
 };
 
 use crate::web_rtc::{
     connection::{ConnectionConfig, HostLogin},
     messages::{
-        ConnType, ConnectionStateMap, DataChannelState, PeerMessage, SetupError, SignalingState,
-        WebRTCInput, WebRTCMessage, WebRTCOutput, WebRTCSpawner,
+        Channel, ConnType, ConnectionStateMap, DataChannelState, PeerMessage, SetupError,
+        SignalingState, TransportStats, WebRTCInput, WebRTCMessage, WebRTCOutput, WebRTCSpawner,
+        BUFFER_HIGH_BYTES, BUFFER_LOW_BYTES,
     },
     node_connection::Direction,
 };
@@ -47,10 +54,12 @@ fn get_ice_server(host: HostLogin) -> RTCIceServer {
 
 pub struct WebRTCConnectionSetupLibc {
     connection: RTCPeerConnection,
-    rtc_data: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+    channels: Arc<Mutex<HashMap<Channel, Arc<RTCDataChannel>>>>,
     broker: Broker<WebRTCMessage>,
     // While the connection is not up, queue up messages in here.
-    queue: Vec<String>,
+    queue: Vec<(Channel, String)>,
+    // Whether the offers open all channels.
+    multi_channel: bool,
     direction: Option<Direction>,
     resets: Arc<AtomicU32>,
     connection_cfg: ConnectionConfig,
@@ -62,9 +71,10 @@ impl WebRTCConnectionSetupLibc {
     ) -> Result<Broker<WebRTCMessage>, SetupError> {
         let mut web_rtc = Box::new(WebRTCConnectionSetupLibc {
             connection: Self::make_connection(connection_cfg.clone()).await?,
-            rtc_data: Arc::new(Mutex::new(None)),
+            channels: Arc::new(Mutex::new(HashMap::new())),
             broker: Broker::new(),
             queue: vec![],
+            multi_channel: false,
             direction: None,
             resets: Arc::new(AtomicU32::new(0)),
             connection_cfg,
@@ -109,7 +119,10 @@ impl WebRTCConnectionSetupLibc {
             .build();
 
         // Prepare the configuration
-        let mut ice_servers = vec![get_ice_server(connection_cfg.stun())];
+        let mut ice_servers = vec![
+            get_ice_server(connection_cfg.stun()),
+            get_ice_server(connection_cfg.nat_stun()),
+        ];
         if let Some(turn) = connection_cfg.turn() {
             ice_servers.push(get_ice_server(turn));
         }
@@ -138,9 +151,12 @@ impl WebRTCConnectionSetupLibc {
                     if let Some(ice) = ice_op {
                         let ice_str = ice.to_json().unwrap().candidate;
                         broker_cl
-                            .emit_msg(WebRTCMessage::Output(WebRTCOutput::Setup(
-                                PeerMessage::IceCandidate(ice_str),
-                            )))
+                            .enqueue_msg_priority(
+                                Priority::Control,
+                                WebRTCMessage::Output(WebRTCOutput::Setup(
+                                    PeerMessage::IceCandidate(ice_str),
+                                )),
+                            )
                             .err()
                             .map(|e| log::warn!("Ice candidate queued but not processed: {:?}", e));
                     }
@@ -168,7 +184,7 @@ impl WebRTCConnectionSetupLibc {
                         _ => WebRTCMessage::Input(WebRTCInput::UpdateState),
                     };
                     broker_cl
-                        .emit_msg(msg)
+                        .enqueue_msg_priority(Priority::Control, msg)
                         .err()
                         .map(|e| log::warn!("UpdateState queued but not processed: {:?}", e));
                 })
@@ -191,19 +207,32 @@ impl WebRTCConnectionSetupLibc {
             self.reset().await?;
         }
         self.direction = Some(Direction::Outgoing);
-        let data_channel = self
-            .connection
-            .create_data_channel("data", None)
-            .await
-            .map_err(to_error)?;
-
-        Self::register_data_channel(
-            Arc::clone(&self.rtc_data),
-            data_channel,
-            self.broker.clone(),
-            Arc::clone(&self.resets),
-        )
-        .await;
+        let channels: &[Channel] = if self.multi_channel {
+            &Channel::ALL
+        } else {
+            &[Channel::Control]
+        };
+        for channel in channels {
+            let init = RTCDataChannelInit {
+                ordered: Some(channel.ordered()),
+                max_retransmits: channel.max_retransmits(),
+                ..Default::default()
+            };
+            let data_channel = self
+                .connection
+                .create_data_channel(channel.label(), Some(init))
+                .await
+                .map_err(to_error)?;
+
+            Self::register_data_channel(
+                Arc::clone(&self.channels),
+                *channel,
+                data_channel,
+                self.broker.clone(),
+                Arc::clone(&self.resets),
+            )
+            .await;
+        }
         let offer = self.connection.create_offer(None).await.map_err(to_error)?;
         self.connection
             .set_local_description(offer.clone())
@@ -236,7 +265,7 @@ impl WebRTCConnectionSetupLibc {
             .map_err(to_error)?;
 
         // Register data channel creation handling
-        let rtc_data = Arc::clone(&self.rtc_data);
+        let channels = Arc::clone(&self.channels);
         let broker = self.broker.clone();
         let resets = Arc::clone(&self.resets);
         let resets_current = resets.load(Ordering::Relaxed);
@@ -248,12 +277,16 @@ impl WebRTCConnectionSetupLibc {
                 }
 
                 log::trace!("New DataChannel {} {}", rdc.label(), rdc.id());
-                let rtc_data = Arc::clone(&rtc_data.clone());
+                let Some(channel) = Channel::from_label(rdc.label()) else {
+                    log::warn!("Ignoring unknown DataChannel {}", rdc.label());
+                    return Box::pin(async {});
+                };
+                let channels = Arc::clone(&channels);
                 // Register channel opening handling
                 let broker = broker.clone();
                 let resets_cl = Arc::clone(&resets);
                 Box::pin(async move {
-                    Self::register_data_channel(rtc_data, rdc, broker, resets_cl).await;
+                    Self::register_data_channel(channels, channel, rdc, broker, resets_cl).await;
                 })
             }));
 
@@ -324,26 +357,80 @@ impl WebRTCConnectionSetupLibc {
             rx_bytes: 0,
             tx_bytes: 0,
             delay_ms: 0,
+            delayed: 0,
+            dropped: 0,
+            nat: None,
         })
     }
 
-    async fn send(&mut self, msg: String) -> Result<(), SetupError> {
-        self.queue.push(msg);
+    /// Return the transport statistics of the nominated candidate pair
+    async fn get_stats(&self) -> TransportStats {
+        let reports = self.connection.get_stats().await.reports;
+        let conn_type = |id: &str| match reports.get(id) {
+            Some(StatsReportType::LocalCandidate(c) | StatsReportType::RemoteCandidate(c)) => {
+                ConnType::from_candidate_type(&c.candidate_type.to_string())
+            }
+            _ => ConnType::Unknown,
+        };
+        let mut stats = TransportStats {
+            time: crate::tasks::now(),
+            ..Default::default()
+        };
+        for report in reports.values() {
+            if let StatsReportType::CandidatePair(pair) = report {
+                if pair.nominated {
+                    stats.bytes_sent = pair.bytes_sent;
+                    stats.bytes_received = pair.bytes_received;
+                    stats.rtt_ms = Some((pair.current_round_trip_time * 1000.) as u32);
+                    stats.type_local = conn_type(&pair.local_candidate_id);
+                    stats.type_remote = conn_type(&pair.remote_candidate_id);
+                }
+            }
+        }
+        for dc in self.channels.lock().await.values() {
+            stats.buffered_bytes += dc.buffered_amount().await as u64;
+        }
+        stats
+    }
+
+    async fn send(&mut self, channel: Channel, msg: String) -> Result<(), SetupError> {
+        self.queue.push((channel, msg));
         self.send_queue().await
     }
 
+    // Messages for a channel which is not open go over the control channel.
+    // Once a data channel buffers more than BUFFER_HIGH_BYTES, its messages stay
+    // in the queue until it fires on_buffered_amount_low.
     async fn send_queue(&mut self) -> Result<(), SetupError> {
         let state_open = self.get_state().await?.data_connection == Some(DataChannelState::Open);
         if state_open || self.direction == Some(Direction::Incoming) {
-            let rtc_data = self.rtc_data.lock().await;
-            if let Some(ref mut data_channel) = rtc_data.as_ref() {
-                for msg_queue in self.queue.drain(..) {
-                    data_channel
-                        .send_text(msg_queue)
-                        .await
-                        .map_err(|e| SetupError::Send(e.to_string()))?;
+            let channels = self.channels.lock().await;
+            if let Some(control) = channels.get(&Channel::Control) {
+                let mut congested = HashSet::new();
+                let mut kept = vec![];
+                let mut res = Ok(());
+                for (channel, msg_queue) in self.queue.drain(..) {
+                    let (used, dc) = match channels
+                        .get(&channel)
+                        .filter(|dc| dc.ready_state() == RTCDataChannelState::Open)
+                    {
+                        Some(dc) => (channel, dc),
+                        None => (Channel::Control, control),
+                    };
+                    if res.is_err()
+                        || congested.contains(&used)
+                        || dc.buffered_amount().await > BUFFER_HIGH_BYTES
+                    {
+                        congested.insert(used);
+                        kept.push((channel, msg_queue));
+                        continue;
+                    }
+                    if let Err(e) = dc.send_text(msg_queue).await {
+                        res = Err(SetupError::Send(e.to_string()));
+                    }
                 }
-                return Ok(());
+                self.queue = kept;
+                return res;
             }
         }
         Ok(())
@@ -364,8 +451,11 @@ impl WebRTCConnectionSetupLibc {
         })
     }
 
+    // Only the control channel makes the connection usable, the other channels
+    // are used once they are open.
     async fn register_data_channel(
-        rtc_data: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+        channels: Arc<Mutex<HashMap<Channel, Arc<RTCDataChannel>>>>,
+        channel: Channel,
         data_channel: Arc<RTCDataChannel>,
         broker: Broker<WebRTCMessage>,
         resets: Arc<AtomicU32>,
@@ -379,10 +469,16 @@ impl WebRTCConnectionSetupLibc {
                 return Box::pin(async {});
             }
 
-            log::trace!("DataChannel is opened");
+            log::trace!("DataChannel {} is opened", channel.label());
+            if channel != Channel::Control {
+                return Box::pin(async {});
+            }
             Box::pin(async move {
                 broker_cl
-                    .emit_msg(WebRTCMessage::Output(WebRTCOutput::Connected))
+                    .enqueue_msg_priority(
+                        Priority::Control,
+                        WebRTCMessage::Output(WebRTCOutput::Connected),
+                    )
                     .err()
                     .map(|e| log::warn!("Connected queued but not processed: {:?}", e));
                 broker_cl
@@ -391,6 +487,22 @@ impl WebRTCConnectionSetupLibc {
                     .map(|e| log::warn!("Flush queued but not processed: {:?}", e));
             })
         }));
+        data_channel
+            .set_buffered_amount_low_threshold(BUFFER_LOW_BYTES)
+            .await;
+        let broker_cl = broker.clone();
+        let resets_cl = Arc::clone(&resets);
+        data_channel
+            .on_buffered_amount_low(Box::new(move || {
+                if resets_cl.load(Ordering::Relaxed) == resets_current {
+                    let flush = WebRTCMessage::Input(WebRTCInput::Flush);
+                    if let Err(e) = broker_cl.clone().emit_msg(flush) {
+                        log::warn!("Flush queued but not processed: {:?}", e);
+                    }
+                }
+                Box::pin(async {})
+            }))
+            .await;
         data_channel.on_message(Box::new(move |msg: DataChannelMessage| {
             if resets.load(Ordering::Relaxed) != resets_current {
                 log::warn!("Got message for deprecated on_message");
@@ -405,17 +517,17 @@ impl WebRTCConnectionSetupLibc {
                     .map(|e| log::warn!("Text queued but not processed: {:?}", e));
             })
         }));
-        if let Some(dc) = rtc_data.lock().await.take() {
+        let old = channels.lock().await.insert(channel, data_channel);
+        if let Some(dc) = old {
             if let Err(e) = dc.close().await {
                 log::warn!("While closing datachannel: {e:?}");
             }
         }
-        rtc_data.lock().await.replace(data_channel);
     }
 
     async fn msg_in(&mut self, msg: WebRTCInput) -> Result<Option<WebRTCMessage>, SetupError> {
         match msg {
-            WebRTCInput::Text(s) => self.send(s).await?,
+            WebRTCInput::Text(ch, s) => self.send(ch, s).await?,
             WebRTCInput::Setup(s) => {
                 if let Some(msg) = self.setup(s).await? {
                     return Ok(Some(WebRTCMessage::Output(WebRTCOutput::Setup(msg))));
@@ -429,6 +541,11 @@ impl WebRTCConnectionSetupLibc {
                     self.get_state().await?,
                 ))));
             }
+            WebRTCInput::GetStats => {
+                return Ok(Some(WebRTCMessage::Output(WebRTCOutput::Stats(
+                    self.get_stats().await,
+                ))));
+            }
             WebRTCInput::Disconnect => {
                 if let Err(e) = self.reset().await {
                     log::warn!("While closing old connection: {e:?}");
@@ -439,6 +556,7 @@ impl WebRTCConnectionSetupLibc {
                     self.reset().await?;
                 }
             }
+            WebRTCInput::MultiChannel => self.multi_channel = true,
         }
         Ok(None)
     }
@@ -450,12 +568,12 @@ impl WebRTCConnectionSetupLibc {
         self.direction = None;
 
         // Replacing all listeners with empty listeners
-        if let Some(mut rd) = self.rtc_data.try_lock() {
-            if let Some(ref mut dc) = rd.as_ref() {
+        if let Some(mut channels) = self.channels.try_lock() {
+            for dc in channels.values() {
                 dc.on_message(Box::new(|_: DataChannelMessage| Box::pin(async {})));
                 dc.on_open(Box::new(|| Box::pin(async {})));
             }
-            *rd = None;
+            channels.clear();
         }
         self.connection
             .on_data_channel(Box::new(|_: Arc<RTCDataChannel>| Box::pin(async {})));
@@ -502,3 +620,57 @@ fn to_error(e: webrtc::error::Error) -> SetupError {
 pub fn web_rtc_spawner(config: ConnectionConfig) -> WebRTCSpawner {
     Box::new(move || Box::new(Box::pin(WebRTCConnectionSetupLibc::new_box(config.clone()))))
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::start_logging;
+
+    fn setup_link(msg: WebRTCMessage) -> Option<WebRTCMessage> {
+        match msg {
+            WebRTCMessage::Output(WebRTCOutput::Setup(pm)) => {
+                Some(WebRTCMessage::Input(WebRTCInput::Setup(pm)))
+            }
+            _ => None,
+        }
+    }
+
+    // Sets up a connection with all channels, and sends a message over each of them.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_channels() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+        let config = ConnectionConfig::from_signal("ws://localhost:8765");
+        let mut outgoing = WebRTCConnectionSetupLibc::new_box(config.clone()).await?;
+        let mut incoming = WebRTCConnectionSetupLibc::new_box(config).await?;
+        outgoing
+            .forward(incoming.clone(), Box::new(setup_link))
+            .await;
+        incoming
+            .forward(outgoing.clone(), Box::new(setup_link))
+            .await;
+        let (mut tap_in, _) = incoming.get_tap().await?;
+
+        outgoing.emit_msg(WebRTCMessage::Input(WebRTCInput::MultiChannel))?;
+        outgoing.emit_msg(WebRTCMessage::Input(WebRTCInput::Setup(PeerMessage::Init)))?;
+        for channel in Channel::ALL {
+            outgoing.emit_msg(WebRTCMessage::Input(WebRTCInput::Text(
+                channel,
+                channel.label().into(),
+            )))?;
+        }
+        let mut texts = vec![];
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while texts.len() < Channel::ALL.len() {
+                if let Some(WebRTCMessage::Output(WebRTCOutput::Text(s))) = tap_in.recv().await {
+                    texts.push(s);
+                }
+            }
+        })
+        .await?;
+        texts.sort();
+        assert_eq!(vec!["bulk", "data", "realtime"], texts);
+        Ok(())
+    }
+}
@@ -32,31 +32,31 @@ impl WebSocketClient {
         Ok(broker)
     }
 
+    /// Reads the messages from the websocket until it is closed.
+    /// Then a [`WSClientOutput::Disconnect`] is sent, and it's up to the user
+    /// to send a [`WSClientInput::Connect`].
     fn listen(&mut self, mut read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>) {
         let mut broker_cl = self.broker.clone();
         tokio::spawn(async move {
             wait_ms(1000).await;
-            loop {
-                if let Some(msg) = read.next().await {
-                    match msg {
-                        Ok(msg) => {
-                            if msg.is_text() {
-                                broker_cl
-                                    .emit_msg(WSClientOutput::Message(msg.to_string()).into())
-                                    .expect("Failed to emit message");
-                            }
-                        }
-                        Err(e) => {
-                            log::warn!("Closing connection because of error: {:?}", e);
-                            log::warn!("Trying to reconnect");
+            while let Some(msg) = read.next().await {
+                match msg {
+                    Ok(msg) => {
+                        if msg.is_text() {
                             broker_cl
-                                .emit_msg(WSClientMessage::Input(WSClientInput::Connect))
-                                .expect("tried to reconnect");
-                            return;
+                                .emit_msg(WSClientOutput::Message(msg.to_string()).into())
+                                .expect("Failed to emit message");
                         }
                     }
+                    Err(e) => {
+                        log::warn!("Closing connection because of error: {:?}", e);
+                        break;
+                    }
                 }
             }
+            broker_cl
+                .emit_msg(WSClientOutput::Disconnect.into())
+                .expect("Failed to emit disconnect");
         });
     }
 
@@ -108,9 +108,15 @@ impl SubsystemHandler<WSClientMessage> for WebSocketClient {
                         }
                         return vec![WSClientOutput::Disconnect.into()];
                     }
-                    WSClientInput::Connect => {
-                        if let Err(e) = self.connect_ws().await {
-                            log::error!("Couldn't connect: {e}");
+                    WSClientInput::Connect => match self.connect_ws().await {
+                        Ok(_) => return vec![WSClientOutput::Connected.into()],
+                        Err(e) => log::error!("Couldn't connect: {e}"),
+                    },
+                    WSClientInput::ConnectTo(url) => {
+                        self.url = url;
+                        match self.connect_ws().await {
+                            Ok(_) => return vec![WSClientOutput::Connected.into()],
+                            Err(e) => log::error!("Couldn't connect: {e}"),
                         }
                     }
                 }
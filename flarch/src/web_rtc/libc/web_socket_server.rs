@@ -4,49 +4,83 @@ use futures::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
     select,
     sync::oneshot,
     task::JoinHandle,
+    time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
 
+use super::tls::TlsFiles;
 use crate::broker::{Broker, Subsystem, SubsystemHandler};
 use crate::web_rtc::websocket::{
     WSError, WSSError, WSServerInput, WSServerMessage, WSServerOutput,
 };
 
+/// Configuration of the [`WebSocketServer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketServerConfig {
+    pub port: u16,
+    /// If set, the server only accepts wss:// connections, using these files.
+    pub tls: Option<TlsFiles>,
+}
+
+/// Connections which didn't finish the TLS and websocket handshakes in this
+/// time are closed.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A plain or a TLS connection.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
 pub struct WebSocketServer {
-    connections: Arc<Mutex<Vec<WSConnection>>>,
+    connections: Arc<Mutex<HashMap<usize, WSConnection>>>,
     conn_thread: JoinHandle<()>,
 }
 
 impl WebSocketServer {
+    /// Starts a ws:// server on the given port.
     pub async fn new(port: u16) -> Result<Broker<WSServerMessage>, WSSError> {
-        let server = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-        let connections = Arc::new(Mutex::new(Vec::new()));
+        Self::new_with_config(WebSocketServerConfig { port, tls: None }).await
+    }
+
+    /// Starts a server with the given configuration.
+    pub async fn new_with_config(
+        config: WebSocketServerConfig,
+    ) -> Result<Broker<WSServerMessage>, WSSError> {
+        let server = TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
+        Self::new_with_listener(server, config.tls).await
+    }
+
+    /// Starts a server on a bound listener, e.g., to let the system choose the port.
+    /// Every connection does its handshakes in its own task, so a slow client
+    /// doesn't block the others.
+    pub async fn new_with_listener(
+        server: TcpListener,
+        tls: Option<TlsFiles>,
+    ) -> Result<Broker<WSServerMessage>, WSSError> {
+        let acceptor = tls.as_ref().map(TlsFiles::acceptor).transpose()?;
+        let connections = Arc::new(Mutex::new(HashMap::new()));
         let connections_cl = Arc::clone(&connections);
         let mut broker = Broker::new();
-        let mut broker_cl = broker.clone();
+        let broker_cl = broker.clone();
         let conn_thread = tokio::spawn(async move {
             let mut connection_id = 0;
             loop {
-                if let Ok((stream, _)) = server.accept().await {
-                    let broker_cl2 = broker_cl.clone();
-                    match WSConnection::new(stream, broker_cl2, connection_id).await {
-                        Ok(conn) => {
-                            log::trace!("Got new connection");
-                            connections_cl.lock().await.push(conn);
-                            broker_cl
-                                .emit_msg(WSServerMessage::Output(WSServerOutput::NewConnection(
-                                    connection_id,
-                                )))
-                                .expect("Error sending connect message");
-                        }
-                        Err(e) => log::error!("Error while getting connection: {:?}", e),
-                    }
+                if let Ok((stream, addr)) = server.accept().await {
+                    tokio::spawn(Self::handshake(
+                        stream,
+                        addr,
+                        acceptor.clone(),
+                        broker_cl.clone(),
+                        Arc::clone(&connections_cl),
+                        connection_id,
+                    ));
                     connection_id += 1;
                 }
             }
@@ -61,6 +95,48 @@ impl WebSocketServer {
 
         Ok(broker)
     }
+
+    // Does the TLS and the websocket handshakes, and adds the connection if both
+    // succeed within HANDSHAKE_TIMEOUT.
+    async fn handshake(
+        stream: TcpStream,
+        addr: SocketAddr,
+        acceptor: Option<TlsAcceptor>,
+        mut broker: Broker<WSServerMessage>,
+        connections: Arc<Mutex<HashMap<usize, WSConnection>>>,
+        id: usize,
+    ) {
+        let conn = timeout(HANDSHAKE_TIMEOUT, async {
+            let stream: Box<dyn Stream> = match acceptor {
+                Some(acceptor) => Box::new(
+                    acceptor
+                        .accept(stream)
+                        .await
+                        .map_err(|e| WSError::Underlying(format!("TLS handshake: {e}")))?,
+                ),
+                None => Box::new(stream),
+            };
+            WSConnection::new(stream, broker.clone(), id).await
+        })
+        .await;
+        match conn {
+            Ok(Ok(conn)) => {
+                log::trace!("Got new connection from {addr}");
+                connections.lock().await.insert(id, conn);
+                broker
+                    .emit_msg(WSServerMessage::Output(WSServerOutput::RemoteAddr(
+                        id,
+                        addr.ip(),
+                    )))
+                    .expect("Error sending address message");
+                broker
+                    .emit_msg(WSServerMessage::Output(WSServerOutput::NewConnection(id)))
+                    .expect("Error sending connect message");
+            }
+            Ok(Err(e)) => log::warn!("Couldn't accept connection from {addr}: {e}"),
+            Err(_) => log::warn!("Handshake with {addr} timed out"),
+        }
+    }
 }
 
 #[async_trait]
@@ -71,19 +147,18 @@ impl SubsystemHandler<WSServerMessage> for WebSocketServer {
                 match msg_in {
                     WSServerInput::Message(id, msg) => {
                         let mut connections = self.connections.lock().await;
-                        if let Some(conn) = connections.get_mut(id) {
+                        if let Some(conn) = connections.get_mut(&id) {
                             if let Err(e) = conn.send(msg).await {
                                 log::error!("Error while sending: {e}");
                                 conn.close();
-                                connections.remove(id);
+                                connections.remove(&id);
                             }
                         }
                     }
                     WSServerInput::Close(id) => {
                         let mut connections = self.connections.lock().await;
-                        if let Some(conn) = connections.get_mut(id) {
+                        if let Some(mut conn) = connections.remove(&id) {
                             conn.close();
-                            connections.remove(id);
                         }
                     }
                     WSServerInput::Stop => {
@@ -99,13 +174,13 @@ impl SubsystemHandler<WSServerMessage> for WebSocketServer {
 }
 
 pub struct WSConnection {
-    websocket: SplitSink<WebSocketStream<TcpStream>, Message>,
+    websocket: SplitSink<WebSocketStream<Box<dyn Stream>>, Message>,
     tx: Option<oneshot::Sender<bool>>,
 }
 
 impl WSConnection {
     async fn new(
-        stream: TcpStream,
+        stream: Box<dyn Stream>,
         broker: Broker<WSServerMessage>,
         id: usize,
     ) -> Result<WSConnection, WSError> {
@@ -125,7 +200,7 @@ impl WSConnection {
 
     async fn loop_read(
         mut broker: Broker<WSServerMessage>,
-        mut ws: SplitStream<WebSocketStream<TcpStream>>,
+        mut ws: SplitStream<WebSocketStream<Box<dyn Stream>>>,
         mut rx: oneshot::Receiver<bool>,
         id: usize,
     ) {
@@ -235,19 +310,27 @@ mod tests {
     #[tokio::test(flavor = "multi_thread")]
     async fn test_server() {
         start_logging();
-        let mut server = WebSocketServer::new(8080).await.unwrap();
-        let (server_tap, _) = server.get_tap_sync().await.unwrap();
-
-        let mut client1 = WebSocketClient::connect("ws://localhost:8080")
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://localhost:{}", listener.local_addr().unwrap().port());
+        let mut server = WebSocketServer::new_with_listener(listener, None)
             .await
             .unwrap();
+        let (server_tap, _) = server.get_tap_sync().await.unwrap();
+
+        let mut client1 = WebSocketClient::connect(&url).await.unwrap();
         let (client1_tap, _) = client1.get_tap_sync().await.unwrap();
+        assert!(matches!(
+            server_tap.recv(),
+            Ok(WSServerMessage::Output(WSServerOutput::RemoteAddr(0, addr))) if addr.is_loopback()
+        ));
         log::debug!("Server reply from client 1: {:?}", server_tap.recv());
 
-        let mut client2 = WebSocketClient::connect("ws://localhost:8080")
-            .await
-            .unwrap();
+        let mut client2 = WebSocketClient::connect(&url).await.unwrap();
         let (client2_tap, _) = client2.get_tap_sync().await.unwrap();
+        assert!(matches!(
+            server_tap.recv(),
+            Ok(WSServerMessage::Output(WSServerOutput::RemoteAddr(1, addr))) if addr.is_loopback()
+        ));
         log::debug!("Server reply from client 2: {:?}", server_tap.recv());
 
         for _ in 1..=2 {
@@ -12,7 +12,7 @@ use thiserror::Error;
 
 use crate::{broker::{Broker, BrokerError}, nodeids::NodeID};
 
-use super::node_connection::Direction;
+use super::{nat::NatType, node_connection::Direction};
 
 #[derive(Debug, Error)]
 /// Error messages for failing setups
@@ -59,6 +59,8 @@ pub enum WebRTCOutput {
     Setup(PeerMessage),
     /// Current state of the connection
     State(ConnectionStateMap),
+    /// Transport statistics of the connection
+    Stats(TransportStats),
     /// Generic error
     Error(String),
 }
@@ -66,18 +68,77 @@ pub enum WebRTCOutput {
 #[derive(Debug, Clone, PartialEq)]
 /// Command for the WebRTC subsystem
 pub enum WebRTCInput {
-    /// Send a text message
-    Text(String),
+    /// Send a text message over the given channel, or over [`Channel::Control`]
+    /// if the channel is not open
+    Text(Channel, String),
     /// Treat a setup message
     Setup(PeerMessage),
     /// Flush all pending messages
     Flush,
     /// Send the current state of the connection
     UpdateState,
+    /// Send the transport statistics of the connection
+    GetStats,
     /// Try to reconnect, or throw an error if incoming connection
     Reset,
     /// Disconnect this node
     Disconnect,
+    /// Open all [`Channel`]s with the next offer, as the remote node supports them
+    MultiChannel,
+}
+
+/// The data channels of a connection.
+/// Every connection has a [`Channel::Control`] channel. The other channels
+/// are only opened if the remote node supports them, else their messages go
+/// over the [`Channel::Control`] channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Channel {
+    /// Reliable and ordered, for all messages which don't ask for another channel
+    #[default]
+    Control,
+    /// Reliable and ordered, but on its own stream, so big transfers don't
+    /// block the control messages
+    Bulk,
+    /// Unreliable and unordered, for messages which are useless when late
+    Realtime,
+}
+
+impl Channel {
+    /// All channels, in the order they are opened.
+    pub const ALL: [Channel; 3] = [Channel::Control, Channel::Bulk, Channel::Realtime];
+
+    /// The label of the data channel.
+    /// [`Channel::Control`] keeps the label of the single channel of older nodes.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Channel::Control => "data",
+            Channel::Bulk => "bulk",
+            Channel::Realtime => "realtime",
+        }
+    }
+
+    /// Returns the channel with the given label.
+    /// Older wasm nodes use `data-channel` for their single channel.
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "data-channel" => Some(Channel::Control),
+            _ => Self::ALL.into_iter().find(|ch| ch.label() == label),
+        }
+    }
+
+    /// Whether the messages of this channel arrive in the order they are sent.
+    pub fn ordered(&self) -> bool {
+        !matches!(self, Channel::Realtime)
+    }
+
+    /// How often a message is retransmitted before it is dropped.
+    /// `None` means it is retransmitted until it arrives.
+    pub fn max_retransmits(&self) -> Option<u16> {
+        match self {
+            Channel::Realtime => Some(0),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(target_family="unix")]
@@ -107,6 +168,8 @@ pub enum ConnType {
     STUNServer,
     /// Connection going through a TURN server, so no direct node-to-node connection
     TURN,
+    /// No WebRTC connection at all, the messages are relayed by the signalling server
+    Relay,
 }
 
 #[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
@@ -167,6 +230,12 @@ pub struct ConnectionStateMap {
     pub rx_bytes: u64,
     pub tx_bytes: u64,
     pub delay_ms: u32,
+    /// Messages delayed by the [`crate::web_rtc::throttle::Throttle`].
+    pub delayed: u64,
+    /// Messages dropped by the [`crate::web_rtc::throttle::Throttle`].
+    pub dropped: u64,
+    /// The NAT type detected from the local ICE candidates of this connection.
+    pub nat: Option<NatType>,
 }
 
 impl Default for ConnectionStateMap {
@@ -181,6 +250,113 @@ impl Default for ConnectionStateMap {
             rx_bytes: 0,
             tx_bytes: 0,
             delay_ms: 0,
+            delayed: 0,
+            dropped: 0,
+            nat: None,
+        }
+    }
+}
+
+impl ConnType {
+    /// Returns the connection type of an ICE candidate type as used by `getStats`.
+    pub fn from_candidate_type(candidate_type: &str) -> Self {
+        match candidate_type {
+            "host" => ConnType::Host,
+            "srflx" => ConnType::STUNServer,
+            "prflx" => ConnType::STUNPeer,
+            "relay" => ConnType::TURN,
+            _ => ConnType::Unknown,
+        }
+    }
+}
+
+/// The transport statistics of a connection, as returned by `getStats` for the
+/// selected candidate pair.
+/// Not all fields are available in all implementations.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TransportStats {
+    /// When the statistics have been taken, in milliseconds.
+    pub time: i64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Lost packets, if reported by the implementation.
+    pub packets_lost: Option<u64>,
+    /// Sent and received bits per second since the previous statistics.
+    pub bitrate_bps: u64,
+    /// Current round-trip time of the selected candidate pair.
+    pub rtt_ms: Option<u32>,
+    /// Type of the local candidate of the selected candidate pair.
+    pub type_local: ConnType,
+    /// Type of the remote candidate of the selected candidate pair.
+    pub type_remote: ConnType,
+    /// Bytes queued in the data channels and not yet sent, the sum of their
+    /// `bufferedAmount`.
+    pub buffered_bytes: u64,
+}
+
+/// Above this `bufferedAmount`, a data channel doesn't get any new messages until
+/// it drains to [`BUFFER_LOW_BYTES`].
+pub const BUFFER_HIGH_BYTES: usize = 1024 * 1024;
+/// `bufferedAmount` at which the data channel fires the `bufferedamountlow` event,
+/// and sending resumes.
+pub const BUFFER_LOW_BYTES: usize = 256 * 1024;
+
+/// Advice on when to send a message over a connection.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SendAdvice {
+    /// The connection has room for the message.
+    Now,
+    /// The data channels are congested, and should drain during the given
+    /// milliseconds before sending the message.
+    After(u32),
+}
+
+impl TransportStats {
+    /// Returns true if the selected candidate pair goes through a TURN server.
+    pub fn turn(&self) -> bool {
+        self.type_local == ConnType::TURN || self.type_remote == ConnType::TURN
+    }
+
+    /// Returns whether a message of `bytes` can be sent now, or how long the data
+    /// channels need to drain to [`BUFFER_HIGH_BYTES`] at the current bitrate.
+    /// Without a bitrate, one second is advised.
+    pub fn can_send(&self, bytes: usize) -> SendAdvice {
+        let pending = self.buffered_bytes + bytes as u64;
+        let high = BUFFER_HIGH_BYTES as u64;
+        if pending <= high {
+            return SendAdvice::Now;
+        }
+        let ms = match self.bitrate_bps {
+            0 => 1000,
+            bps => (pending - high) * 8 * 1000 / bps,
+        };
+        SendAdvice::After(ms.clamp(1, u32::MAX as u64) as u32)
+    }
+
+    /// Sets the bitrate from the bytes transferred since the `previous` statistics.
+    pub fn with_bitrate(mut self, previous: &TransportStats) -> Self {
+        let bytes = (self.bytes_sent + self.bytes_received)
+            .saturating_sub(previous.bytes_sent + previous.bytes_received);
+        let duration_ms = self.time - previous.time;
+        if duration_ms > 0 {
+            self.bitrate_bps = bytes * 8 * 1000 / duration_ms as u64;
+        }
+        self
+    }
+}
+
+impl Default for TransportStats {
+    fn default() -> Self {
+        Self {
+            time: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_lost: None,
+            bitrate_bps: 0,
+            rtt_ms: None,
+            type_local: ConnType::Unknown,
+            type_remote: ConnType::Unknown,
+            buffered_bytes: 0,
         }
     }
 }
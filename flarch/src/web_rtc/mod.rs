@@ -14,24 +14,82 @@
 //!
 //! This is necessary, as it is always possible that two nodes want to start
 //! connecting to each other concurrently.
+//!
+//! # Watchdog
+//!
+//! Sometimes a connection stays open, but no messages go through anymore.
+//! The [`WebRTCConn`] keeps track of the messages passed to a connection which have
+//! not been followed by any message from that node, in either direction of the connection.
+//! Messages waiting in the throttle queues are not counted.
+//! If the oldest of these messages is older than the timeout, checked when sending and
+//! with every [`WebRTCConnMessage::Tick`], the old connection is unlinked and closed,
+//! a [`NCOutput::Disconnected`] is sent, and a new connection is set up.
+//!
+//! # Throttling
+//!
+//! The text messages to and from other nodes can be limited in bytes per second,
+//! both per node and for all nodes together.
+//! Messages over the limit are queued and sent with the next
+//! [`WebRTCConnMessage::Tick`], or dropped if the queue is full.
+//! See [`throttle`] for the details.
+//!
+//! # Data channels
+//!
+//! A connection has one reliable and ordered data channel, [`messages::Channel::Control`].
+//! If the remote node supports it, announced by [`WebRTCConnMessage::MultiChannel`],
+//! the outgoing connection also opens a [`messages::Channel::Bulk`] and an unreliable
+//! [`messages::Channel::Realtime`] channel.
+//! Text messages sent with [`NCInput::TextChannel`] use the given channel, so that
+//! big transfers don't block the small messages behind them.
+//! If the channel is not open, the message goes over the control channel.
+//!
+//! # NAT detection
+//!
+//! The local ICE candidates of every connection are used to find out what kind of
+//! NAT the node is behind.
+//! The result is reported in [`messages::ConnectionStateMap::nat`], see [`nat`]
+//! for the details.
+//!
+//! # Transport statistics
+//!
+//! Sending [`NCInput::GetStats`] to a connection returns a [`NCOutput::Stats`]
+//! for every direction which is set up, with the bytes transferred, the round-trip
+//! time, and the candidate types of the selected candidate pair.
+//! The [`WebRTCConn`] adds the bitrate since the previous statistics of the
+//! same direction.
+//!
+//! # Network probe
+//!
+//! Before connecting to other nodes, [`probe::probe`] can check whether the
+//! signalling server, the STUN and TURN servers, and outgoing UDP work, and
+//! suggests fixes for the problems it finds.
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
 use flarch_macro::platform_async_trait;
 
 use crate::{
-    broker::{Broker, BrokerError, Subsystem, SubsystemHandler, Translate},
+    broker::{Broker, BrokerError, Priority, Subsystem, SubsystemHandler, Translate},
     nodeids::NodeID,
+    tasks::now,
 };
 
 use self::{
-    messages::WebRTCSpawner,
-    node_connection::{NCError, NCInput, NCMessage, NCOutput, NodeConnection},
+    messages::{Channel, PeerMessage, TransportStats, WebRTCSpawner},
+    nat::NatDetector,
+    node_connection::{Direction, NCError, NCInput, NCMessage, NCOutput, NodeConnection},
+    throttle::{Flow, Throttle, ThrottleConfig},
 };
 
 pub mod connection;
 pub mod messages;
+pub mod nat;
 pub mod node_connection;
+pub mod probe;
+pub mod throttle;
 pub mod websocket;
 
 #[cfg(target_family = "windows")]
@@ -58,12 +116,29 @@ pub enum WebRTCConnMessage {
     Connect(NodeID),
     /// Disconnect this node
     Disconnect(NodeID),
+    /// Change the bandwidth limits
+    SetThrottle(ThrottleConfig),
+    /// The node supports all [`Channel`]s, so the outgoing connections to it
+    /// open them
+    MultiChannel(NodeID),
+    /// This message should be sent once a second to send the queued messages.
+    Tick,
 }
 
+/// How long a node can stay silent while messages are sent to it, before
+/// the connection is set up again.
+pub const WATCHDOG_TIMEOUT_MS: i64 = 60_000;
+
 /// The actual implementation of the WebRTC connection setup.
 pub struct WebRTCConn {
     web_rtc: WebRTCSpawner,
     connections: HashMap<NodeID, Broker<NCMessage>>,
+    // The subsystem forwarding the messages of each connection to this broker.
+    links: HashMap<NodeID, usize>,
+    multi_channel: HashSet<NodeID>,
+    watchdogs: Arc<Mutex<HashMap<NodeID, Watchdog>>>,
+    watchdog_timeout_ms: i64,
+    throttle: Arc<Mutex<Throttle>>,
     broker: Broker<WebRTCConnMessage>,
 }
 
@@ -71,10 +146,41 @@ impl WebRTCConn {
     /// Creates a new [`Broker<WebRTCConnMessage>`] that will accept incoming connections and set up
     /// new outgoing connections.
     pub async fn new(web_rtc: WebRTCSpawner) -> Result<Broker<WebRTCConnMessage>, BrokerError> {
+        Self::new_with_watchdog(web_rtc, WATCHDOG_TIMEOUT_MS).await
+    }
+
+    /// Creates a new [`Broker<WebRTCConnMessage>`] with a given watchdog timeout
+    /// instead of [`WATCHDOG_TIMEOUT_MS`].
+    pub async fn new_with_watchdog(
+        web_rtc: WebRTCSpawner,
+        watchdog_timeout_ms: i64,
+    ) -> Result<Broker<WebRTCConnMessage>, BrokerError> {
+        Self::start(web_rtc, watchdog_timeout_ms, ThrottleConfig::default()).await
+    }
+
+    /// Creates a new [`Broker<WebRTCConnMessage>`] with the given bandwidth limits.
+    /// The limits can be changed later using [`WebRTCConnMessage::SetThrottle`].
+    pub async fn new_with_throttle(
+        web_rtc: WebRTCSpawner,
+        throttle: ThrottleConfig,
+    ) -> Result<Broker<WebRTCConnMessage>, BrokerError> {
+        Self::start(web_rtc, WATCHDOG_TIMEOUT_MS, throttle).await
+    }
+
+    async fn start(
+        web_rtc: WebRTCSpawner,
+        watchdog_timeout_ms: i64,
+        throttle: ThrottleConfig,
+    ) -> Result<Broker<WebRTCConnMessage>, BrokerError> {
         let mut br = Broker::new();
         br.add_subsystem(Subsystem::Handler(Box::new(Self {
             web_rtc,
             connections: HashMap::new(),
+            links: HashMap::new(),
+            multi_channel: HashSet::new(),
+            watchdogs: Arc::new(Mutex::new(HashMap::new())),
+            watchdog_timeout_ms,
+            throttle: Arc::new(Mutex::new(Throttle::new(throttle))),
             broker: br.clone(),
         })))
         .await?;
@@ -85,17 +191,78 @@ impl WebRTCConn {
     async fn ensure_connection(&mut self, id: &NodeID) -> Result<(), NCError> {
         if !self.connections.contains_key(id) {
             let mut nc = NodeConnection::new(&self.web_rtc).await?;
-            nc.forward(self.broker.clone(), Self::from_nc(id.clone()))
+            let link = nc
+                .forward_priority(
+                    self.broker.clone(),
+                    Priority::default(),
+                    Self::from_nc(*id, self.throttle.clone(), self.watchdogs.clone()),
+                )
                 .await;
             self.connections.insert(*id, nc);
+            self.links.insert(*id, link);
+            if self.multi_channel.contains(id) {
+                self.try_send(*id, NCInput::MultiChannel);
+            }
         }
 
         Ok(())
     }
 
+    /// Returns true if messages have been passed to the connection for longer than
+    /// the watchdog timeout without receiving anything from the node.
+    fn is_stalled(&self, dst: &NodeID) -> bool {
+        self.connections.contains_key(dst)
+            && self
+                .watchdogs
+                .lock()
+                .unwrap()
+                .get(dst)
+                .is_some_and(|wd| wd.stalled(now(), self.watchdog_timeout_ms))
+    }
+
+    /// Closes a stalled connection and starts a new one.
+    async fn restart_connection(&mut self, dst: NodeID) -> Vec<WebRTCConnMessage> {
+        log::warn!("Connection to {dst} is stalled - restarting it");
+        self.close_connection(dst);
+        if let Err(e) = self.ensure_connection(&dst).await {
+            log::error!("When restarting webrtc-connection {e:?}");
+        }
+        vec![WebRTCConnMessage::OutputNC(
+            dst,
+            NCOutput::Disconnected(Direction::Outgoing),
+        )]
+    }
+
+    /// Unlinks the connection from this broker, so that the messages it still sends
+    /// are not taken for messages of a later connection, then closes it.
+    fn close_connection(&mut self, dst: NodeID) {
+        if let (Some(link), Some(nc)) = (self.links.remove(&dst), self.connections.get_mut(&dst)) {
+            if let Err(e) = nc.unlink(link) {
+                log::error!("When unlinking webrtc-connection {e:?}");
+            }
+        }
+        self.try_send(dst, NCInput::Disconnect);
+        self.connections.remove(&dst);
+        self.watchdogs.lock().unwrap().remove(&dst);
+    }
+
+    // Everything except the text messages sets up or controls the connection,
+    // so it goes before the queued text messages.
     fn try_send(&mut self, dst: NodeID, msg: NCInput) {
         if let Some(conn) = self.connections.get_mut(&dst) {
-            conn.emit_msg(NCMessage::Input(msg.clone()))
+            let priority = match msg {
+                NCInput::Text(_) | NCInput::TextChannel(..) => {
+                    self.watchdogs
+                        .lock()
+                        .unwrap()
+                        .entry(dst)
+                        .or_default()
+                        .sent(now());
+                    Priority::Interactive
+                }
+                _ => Priority::Control,
+            };
+            conn.enqueue_msg_priority(priority, NCMessage::Input(msg.clone()))
                 .err()
                 .map(|e| log::error!("When sending message {msg:?} to webrtc: {e:?}"));
         } else {
@@ -103,12 +270,101 @@ impl WebRTCConn {
         }
     }
 
-    fn from_nc(id: NodeID) -> Translate<NCMessage, WebRTCConnMessage> {
-        Box::new(move |msg| {
-            if let NCMessage::Output(ncmsg) = msg {
-                return Some(WebRTCConnMessage::OutputNC(id, ncmsg));
+    /// Remembers that the node supports all channels, and tells its connection.
+    fn set_multi_channel(&mut self, dst: NodeID) {
+        if self.multi_channel.insert(dst) && self.connections.contains_key(&dst) {
+            self.try_send(dst, NCInput::MultiChannel);
+        }
+    }
+
+    /// Sends a text message to the node, or queues it if the bandwidth limit is reached.
+    fn send_text(&mut self, dst: NodeID, channel: Channel, msg: String) {
+        let pass = self
+            .throttle
+            .lock()
+            .unwrap()
+            .pass(dst, Flow::Up, channel, msg, now());
+        if let Some(msg) = pass {
+            self.try_send(dst, NCInput::TextChannel(channel, msg));
+        }
+    }
+
+    /// Restarts the stalled connections and sends all queued messages which are
+    /// within the bandwidth limits.
+    async fn tick(&mut self) -> Vec<WebRTCConnMessage> {
+        let stalled: Vec<NodeID> = self
+            .connections
+            .keys()
+            .filter(|id| self.is_stalled(id))
+            .cloned()
+            .collect();
+        let mut out = vec![];
+        for id in stalled {
+            out.extend(self.restart_connection(id).await);
+        }
+        let queued = self.throttle.lock().unwrap().tick(now());
+        for (id, flow, channel, msg) in queued {
+            match flow {
+                Flow::Up => self.try_send(id, NCInput::TextChannel(channel, msg)),
+                Flow::Down => out.push(WebRTCConnMessage::OutputNC(id, NCOutput::Text(msg))),
+            }
+        }
+        out
+    }
+
+    /// Incoming text messages over the bandwidth limit are queued here, as they
+    /// cannot be stopped once they are in the broker.
+    /// Every incoming text message, queued or not, feeds the watchdog of the node.
+    /// The local ICE candidates of both directions are used to detect the NAT type.
+    fn from_nc(
+        id: NodeID,
+        throttle: Arc<Mutex<Throttle>>,
+        watchdogs: Arc<Mutex<HashMap<NodeID, Watchdog>>>,
+    ) -> Translate<NCMessage, WebRTCConnMessage> {
+        let nat: Mutex<HashMap<Direction, NatDetector>> = Mutex::new(HashMap::new());
+        let previous: Mutex<HashMap<Direction, TransportStats>> = Mutex::new(HashMap::new());
+        Box::new(move |msg| match msg {
+            NCMessage::Output(NCOutput::Text(msg)) => {
+                watchdogs.lock().unwrap().entry(id).or_default().received();
+                throttle
+                    .lock()
+                    .unwrap()
+                    .pass(id, Flow::Down, Channel::Control, msg, now())
+                    .map(|msg| WebRTCConnMessage::OutputNC(id, NCOutput::Text(msg)))
+            }
+            NCMessage::Output(NCOutput::Connected(dir)) => {
+                watchdogs.lock().unwrap().entry(id).or_default().received();
+                Some(WebRTCConnMessage::OutputNC(id, NCOutput::Connected(dir)))
+            }
+            NCMessage::Output(NCOutput::State(dir, mut state)) => {
+                let stats = throttle.lock().unwrap().stats(&id);
+                state.delayed = stats.delayed;
+                state.dropped = stats.dropped;
+                state.nat = nat.lock().unwrap().get(&dir).and_then(|nd| nd.nat_type());
+                Some(WebRTCConnMessage::OutputNC(id, NCOutput::State(dir, state)))
+            }
+            NCMessage::Output(NCOutput::Stats(dir, stats)) => {
+                let mut previous = previous.lock().unwrap();
+                let stats = match previous.get(&dir) {
+                    Some(prev) => stats.with_bitrate(prev),
+                    None => stats,
+                };
+                previous.insert(dir.clone(), stats);
+                Some(WebRTCConnMessage::OutputNC(id, NCOutput::Stats(dir, stats)))
+            }
+            NCMessage::Output(NCOutput::Setup(dir, PeerMessage::IceCandidate(candidate))) => {
+                nat.lock()
+                    .unwrap()
+                    .entry(dir.clone())
+                    .or_default()
+                    .add_candidate(&candidate);
+                Some(WebRTCConnMessage::OutputNC(
+                    id,
+                    NCOutput::Setup(dir, PeerMessage::IceCandidate(candidate)),
+                ))
             }
-            None
+            NCMessage::Output(ncmsg) => Some(WebRTCConnMessage::OutputNC(id, ncmsg)),
+            _ => None,
         })
     }
 }
@@ -116,30 +372,157 @@ impl WebRTCConn {
 #[platform_async_trait()]
 impl SubsystemHandler<WebRTCConnMessage> for WebRTCConn {
     async fn messages(&mut self, msgs: Vec<WebRTCConnMessage>) -> Vec<WebRTCConnMessage> {
+        let mut out = vec![];
         for msg in msgs {
             match msg {
                 WebRTCConnMessage::InputNC(dst, msg_in) => {
-                    self.try_send(dst, msg_in);
+                    if matches!(msg_in, NCInput::Text(_) | NCInput::TextChannel(..))
+                        && self.is_stalled(&dst)
+                    {
+                        out.extend(self.restart_connection(dst).await);
+                    }
+                    match msg_in {
+                        NCInput::Text(msg) => self.send_text(dst, Channel::Control, msg),
+                        NCInput::TextChannel(channel, msg) => self.send_text(dst, channel, msg),
+                        _ => self.try_send(dst, msg_in),
+                    }
                 }
                 WebRTCConnMessage::Disconnect(dst) => {
-                    self.try_send(dst, NCInput::Disconnect);
+                    self.close_connection(dst);
+                    self.throttle.lock().unwrap().remove(&dst);
                 }
+                WebRTCConnMessage::SetThrottle(config) => {
+                    self.throttle.lock().unwrap().set_config(config);
+                }
+                WebRTCConnMessage::Tick => out.extend(self.tick().await),
+                WebRTCConnMessage::MultiChannel(dst) => self.set_multi_channel(dst),
                 WebRTCConnMessage::Connect(dst) => {
                     self.ensure_connection(&dst)
                         .await
                         .err()
                         .map(|e| log::error!("When starting webrtc-connection {e:?}"));
-                    self.try_send(
-                        dst,
-                        NCInput::Setup(
-                            node_connection::Direction::Outgoing,
-                            messages::PeerMessage::Init,
-                        ),
-                    );
+                    self.try_send(dst, NCInput::Setup(Direction::Outgoing, PeerMessage::Init));
                 }
                 _ => {}
             };
         }
-        vec![]
+        out
+    }
+}
+
+/// Keeps track of the messages sent to and received from a node to detect
+/// connections which are open, but don't pass any messages anymore.
+#[derive(Debug, Default)]
+struct Watchdog {
+    unanswered_since: Option<i64>,
+}
+
+impl Watchdog {
+    fn received(&mut self) {
+        self.unanswered_since = None;
+    }
+
+    fn sent(&mut self, now: i64) {
+        self.unanswered_since.get_or_insert(now);
+    }
+
+    /// Returns true if messages have been sent for longer than `timeout_ms` without
+    /// receiving anything from the node.
+    fn stalled(&self, now: i64, timeout_ms: i64) -> bool {
+        self.unanswered_since
+            .is_some_and(|since| now - since > timeout_ms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::web_rtc::messages::{SendAdvice, WebRTCMessage, WebRTCOutput, BUFFER_HIGH_BYTES};
+
+    #[test]
+    fn test_watchdog() {
+        let mut wd = Watchdog::default();
+        assert!(!wd.stalled(1000, 100));
+        wd.sent(1000);
+        wd.sent(1100);
+        assert!(!wd.stalled(1100, 100));
+        assert!(wd.stalled(1101, 100));
+        wd.received();
+        assert!(!wd.stalled(1200, 100));
+        wd.sent(1150);
+        assert!(!wd.stalled(1250, 100));
+        assert!(wd.stalled(1300, 100));
+    }
+
+    #[test]
+    fn test_bitrate() {
+        let first = TransportStats {
+            time: 1000,
+            bytes_sent: 1000,
+            bytes_received: 500,
+            ..Default::default()
+        };
+        assert_eq!(0, first.with_bitrate(&first).bitrate_bps);
+        let second = TransportStats {
+            time: 3000,
+            bytes_sent: 3000,
+            bytes_received: 1500,
+            ..first
+        };
+        assert_eq!(12_000, second.with_bitrate(&first).bitrate_bps);
+    }
+
+    #[test]
+    fn test_can_send() {
+        let mut stats = TransportStats::default();
+        assert_eq!(SendAdvice::Now, stats.can_send(1000));
+        assert_eq!(SendAdvice::After(1000), stats.can_send(BUFFER_HIGH_BYTES + 1));
+        stats.buffered_bytes = BUFFER_HIGH_BYTES as u64;
+        stats.bitrate_bps = 80_000;
+        assert_eq!(SendAdvice::After(100), stats.can_send(1000));
+    }
+
+    // Returns a spawner which keeps the brokers of all connections, to send
+    // messages as if they came from the node.
+    fn spawner() -> (WebRTCSpawner, Arc<Mutex<Vec<Broker<WebRTCMessage>>>>) {
+        let spawned = Arc::new(Mutex::new(vec![]));
+        let spawned_cl = Arc::clone(&spawned);
+        let spawner: WebRTCSpawner = Box::new(move || {
+            let broker = Broker::new();
+            spawned_cl.lock().unwrap().push(broker.clone());
+            Box::new(Box::pin(async move { Ok(broker) }))
+        });
+        (spawner, spawned)
+    }
+
+    fn texts(tap: &std::sync::mpsc::Receiver<WebRTCConnMessage>) -> Vec<String> {
+        tap.try_iter()
+            .filter_map(|msg| match msg {
+                WebRTCConnMessage::OutputNC(_, NCOutput::Text(text)) => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() -> Result<(), Box<dyn std::error::Error>> {
+        let (spawner, spawned) = spawner();
+        let mut conn = WebRTCConn::new(spawner).await?;
+        let (tap, _) = conn.get_tap_sync().await?;
+        let dst = NodeID::rnd();
+        let text = |msg: &str| WebRTCMessage::Output(WebRTCOutput::Text(msg.into()));
+
+        conn.settle_msg(WebRTCConnMessage::Connect(dst)).await?;
+        let mut old = spawned.lock().unwrap()[0].clone();
+        old.settle_msg(text("first")).await?;
+        assert_eq!(vec!["first".to_string()], texts(&tap));
+
+        conn.settle_msg(WebRTCConnMessage::Disconnect(dst)).await?;
+        conn.settle_msg(WebRTCConnMessage::Connect(dst)).await?;
+        let mut new = spawned.lock().unwrap()[2].clone();
+        old.settle_msg(text("stale")).await?;
+        new.settle_msg(text("second")).await?;
+        assert_eq!(vec!["second".to_string()], texts(&tap));
+        Ok(())
     }
 }
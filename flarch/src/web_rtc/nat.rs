@@ -0,0 +1,140 @@
+//! Classification of the NAT a node is behind, using the ICE candidates
+//! gathered by its WebRTC connections.
+//!
+//! The [`crate::web_rtc::connection::ConnectionConfig`] asks two STUN servers
+//! for the public address of every new connection:
+//! - if the public address is one of the local addresses, there is no NAT
+//! - if both STUN servers see the same public port for a local port, the NAT
+//!   uses the same mapping for all destinations (cone NAT)
+//! - if the public ports differ, the NAT uses a new mapping for every destination
+//!   (symmetric NAT), and two such nodes can only connect through a TURN server
+//!
+//! With only one STUN server, a symmetric NAT cannot be detected and is
+//! reported as a cone NAT.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// The types of NAT which can be told apart using STUN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NatType {
+    /// The node has a public address.
+    Open,
+    /// The public port only depends on the local port.
+    Cone,
+    /// The public port depends on the destination.
+    Symmetric,
+}
+
+/// Collects the local ICE candidates of one connection.
+#[derive(Debug, Clone, Default)]
+pub struct NatDetector {
+    host: HashSet<String>,
+    // The public addresses seen by the STUN servers for every local address.
+    mapped: HashMap<String, HashSet<(String, u16)>>,
+}
+
+impl NatDetector {
+    /// Adds a local ICE candidate in the SDP format, e.g.,
+    /// `candidate:1 1 udp 1686052607 1.2.3.4 4000 typ srflx raddr 10.0.0.1 rport 5000`.
+    /// TCP and relay candidates are ignored.
+    pub fn add_candidate(&mut self, candidate: &str) {
+        let fields: Vec<&str> = candidate.split_whitespace().collect();
+        let value = |key: &str| {
+            fields
+                .iter()
+                .position(|f| f == &key)
+                .and_then(|pos| fields.get(pos + 1))
+                .copied()
+        };
+        if !fields.get(2).is_some_and(|p| p.eq_ignore_ascii_case("udp")) {
+            return;
+        }
+        let (Some(ip), Some(port)) = (
+            fields.get(4),
+            fields.get(5).and_then(|p| p.parse::<u16>().ok()),
+        ) else {
+            return;
+        };
+        match value("typ") {
+            Some("host") => {
+                self.host.insert(ip.to_string());
+            }
+            Some("srflx") => {
+                // Browsers can hide the local address, then the candidates are
+                // grouped by their public address.
+                let base = match (value("raddr"), value("rport")) {
+                    (Some(raddr), Some(rport)) if raddr != "0.0.0.0" && raddr != "::" => {
+                        format!("{raddr}:{rport}")
+                    }
+                    _ => ip.to_string(),
+                };
+                self.mapped
+                    .entry(base)
+                    .or_default()
+                    .insert((ip.to_string(), port));
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the NAT type, or `None` if no STUN server answered yet.
+    pub fn nat_type(&self) -> Option<NatType> {
+        if self.mapped.values().any(|mapped| mapped.len() > 1) {
+            return Some(NatType::Symmetric);
+        }
+        let mut public = self.mapped.values().flatten().peekable();
+        public.peek()?;
+        if public.any(|(ip, _)| self.host.contains(ip)) {
+            return Some(NatType::Open);
+        }
+        Some(NatType::Cone)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detect(candidates: &[&str]) -> Option<NatType> {
+        let mut nd = NatDetector::default();
+        for c in candidates {
+            nd.add_candidate(c);
+        }
+        nd.nat_type()
+    }
+
+    #[test]
+    fn test_nat_type() {
+        let host = "candidate:1 1 udp 2122260223 10.0.0.1 5000 typ host";
+        let host_public = "candidate:1 1 udp 2122260223 1.2.3.4 5000 typ host";
+        let srflx = |port: u16, raddr: &str| {
+            format!(
+                "candidate:2 1 udp 1686052607 1.2.3.4 {port} typ srflx raddr {raddr} rport 5000"
+            )
+        };
+
+        assert_eq!(None, detect(&[host]));
+        assert_eq!(
+            Some(NatType::Cone),
+            detect(&[host, &srflx(4000, "10.0.0.1"), &srflx(4000, "10.0.0.1")])
+        );
+        assert_eq!(
+            Some(NatType::Symmetric),
+            detect(&[host, &srflx(4000, "10.0.0.1"), &srflx(4001, "10.0.0.1")])
+        );
+        assert_eq!(
+            Some(NatType::Symmetric),
+            detect(&[&srflx(4000, "0.0.0.0"), &srflx(4001, "0.0.0.0")])
+        );
+        assert_eq!(
+            Some(NatType::Open),
+            detect(&[host_public, &srflx(5000, "1.2.3.4")])
+        );
+        assert_eq!(
+            None,
+            detect(&["candidate:3 1 tcp 1518280447 1.2.3.4 9 typ srflx tcptype active"])
+        );
+    }
+}
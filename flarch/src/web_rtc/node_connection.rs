@@ -8,6 +8,10 @@
 //! The actual calls to the WebRTC code have been abstracted, so that the same
 //! code works for the libc and the wasm implementation.
 //!
+//! Text messages can ask for a [`Channel`]. The outgoing connection only opens
+//! all channels after a [`NCInput::MultiChannel`], as older nodes only handle
+//! a single data channel.
+//!
 //! _If you come here, I hope you're not trying to debug something that doesn't work.
 //! This code is quite obscure, and should be rewritten for the 5th time or so._
 use crate::broker::{Broker, BrokerError, Subsystem, SubsystemHandler};
@@ -15,8 +19,8 @@ use flarch_macro::platform_async_trait;
 use thiserror::Error;
 
 use crate::web_rtc::messages::{
-    ConnectionStateMap, DataChannelState, PeerMessage, WebRTCInput, WebRTCMessage, WebRTCOutput,
-    WebRTCSpawner,
+    Channel, ConnectionStateMap, DataChannelState, PeerMessage, TransportStats, WebRTCInput,
+    WebRTCMessage, WebRTCOutput, WebRTCSpawner,
 };
 
 #[derive(Error, Debug)]
@@ -57,6 +61,8 @@ pub enum NCOutput {
     Text(String),
     /// Return a changed state from one of the connections
     State(Direction, ConnectionStateMap),
+    /// Return the transport statistics from one of the connections
+    Stats(Direction, TransportStats),
     /// Setup message for the connection in the given direction
     Setup(Direction, PeerMessage),
 }
@@ -66,16 +72,23 @@ pub enum NCOutput {
 pub enum NCInput {
     /// Text to be sent over the first available connection
     Text(String),
+    /// Text to be sent over the given channel of the first available connection
+    TextChannel(Channel, String),
+    /// The remote node supports all [`Channel`]s, so the next outgoing
+    /// connection opens them
+    MultiChannel,
     /// Disconnect all connections
     Disconnect,
     /// Return all states
     GetStates,
+    /// Return the transport statistics of all connections
+    GetStats,
     /// Treat the [`PeerMessage`] to setup a new connection with the
     /// given direction
     Setup(Direction, PeerMessage),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// One of the directions for a connection
 pub enum Direction {
     /// Being initiated by the remote peer
@@ -88,7 +101,7 @@ pub enum Direction {
 /// It will do its best to detect when a connection has gone stale and shut
 /// itself down.
 pub struct NodeConnection {
-    msg_queue: Vec<String>,
+    msg_queue: Vec<(Channel, String)>,
     state_incoming: Option<ConnectionStateMap>,
     state_outgoing: Option<ConnectionStateMap>,
 }
@@ -152,7 +165,7 @@ impl NodeConnection {
                     return self
                         .msg_queue
                         .drain(..)
-                        .map(|msg| WebRTCMessage::Input(WebRTCInput::Text(msg)))
+                        .map(|(ch, msg)| WebRTCMessage::Input(WebRTCInput::Text(ch, msg)))
                         .map(|msg| match dir {
                             Direction::Incoming => NCMessage::Incoming(msg),
                             Direction::Outgoing => NCMessage::Outgoing(msg),
@@ -178,8 +191,9 @@ impl NodeConnection {
 
     fn msg_in(&mut self, msg: NCInput) -> Vec<NCMessage> {
         match msg {
-            NCInput::Text(msg_str) => {
-                self.msg_queue.push(msg_str);
+            NCInput::Text(msg_str) => self.msg_in(NCInput::TextChannel(Channel::Control, msg_str)),
+            NCInput::TextChannel(ch, msg_str) => {
+                self.msg_queue.push((ch, msg_str));
                 let mut out = vec![];
                 out.extend(self.send_queue());
                 if self.state_outgoing.is_none() {
@@ -190,6 +204,9 @@ impl NodeConnection {
                 }
                 out
             }
+            NCInput::MultiChannel => vec![NCMessage::Outgoing(WebRTCMessage::Input(
+                WebRTCInput::MultiChannel,
+            ))],
             NCInput::Disconnect => vec![
                 NCMessage::Incoming(WebRTCMessage::Input(WebRTCInput::Disconnect)),
                 NCMessage::Outgoing(WebRTCMessage::Input(WebRTCInput::Disconnect)),
@@ -211,6 +228,20 @@ impl NodeConnection {
                 }
                 out
             }
+            NCInput::GetStats => {
+                let mut out = vec![];
+                if self.state_incoming.is_some() {
+                    out.push(NCMessage::Incoming(WebRTCMessage::Input(
+                        WebRTCInput::GetStats,
+                    )));
+                }
+                if self.state_outgoing.is_some() {
+                    out.push(NCMessage::Outgoing(WebRTCMessage::Input(
+                        WebRTCInput::GetStats,
+                    )));
+                }
+                out
+            }
             NCInput::Setup(dir, pm) => {
                 match dir {
                     Direction::Incoming => vec![NCMessage::Incoming(WebRTCMessage::Input(
@@ -251,6 +282,7 @@ impl NodeConnection {
                     }
                     vec![NCMessage::Output(NCOutput::State(dir, state))]
                 }
+                WebRTCOutput::Stats(stats) => vec![NCMessage::Output(NCOutput::Stats(dir, stats))],
                 WebRTCOutput::Disconnected | WebRTCOutput::Error(_) => {
                     let msg = match dir {
                         Direction::Incoming => {
@@ -0,0 +1,358 @@
+//! Checks the network environment of a node, to find out why it cannot
+//! connect to other nodes.
+//!
+//! [`probe`] connects to the signalling server, and gathers the ICE candidates
+//! of a WebRTC connection which is never used:
+//! - a `srflx` candidate means that a STUN server answered over UDP
+//! - a `relay` candidate means that the TURN server gave out an address
+//!
+//! The [`ProbeReport`] lists the result of every check, together with a
+//! suggestion how to fix it.
+//! As the clock skew can only be measured with other nodes, it needs to be
+//! added by the caller using [`ProbeReport::add_clock_offset`].
+
+use std::fmt;
+
+use crate::tasks::{
+    time::{timeout, Duration},
+    wait_ms,
+};
+
+use super::{
+    connection::ConnectionConfig,
+    messages::{PeerMessage, SetupError, WebRTCInput, WebRTCMessage, WebRTCOutput},
+    nat::{NatDetector, NatType},
+    web_rtc_setup::web_rtc_spawner,
+    web_socket_client::WebSocketClient,
+    websocket::{WSClientInput, WSClientMessage, WSClientOutput},
+};
+
+/// A clock offset to the other nodes bigger than this is reported.
+pub const CLOCK_SKEW_MAX_MS: i64 = 1000;
+
+/// The checks done by [`probe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeCheck {
+    /// Connection to the signalling server
+    Signal,
+    /// Public address found by the STUN servers
+    Stun,
+    /// Relay address given by the TURN server
+    Turn,
+    /// Outgoing UDP traffic
+    Udp,
+    /// Difference between the local clock and the clocks of other nodes
+    ClockSkew,
+}
+
+/// How bad the result of a check is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProbeStatus {
+    Ok,
+    Skipped,
+    Warning,
+    Failed,
+}
+
+/// The result of one check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeResult {
+    pub check: ProbeCheck,
+    pub status: ProbeStatus,
+    pub details: String,
+    /// What the user can do about a problem
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for ProbeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {:?} - {}", self.check, self.status, self.details)?;
+        if let Some(fix) = &self.fix {
+            write!(f, " - {fix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The results of all checks.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProbeReport {
+    pub results: Vec<ProbeResult>,
+}
+
+impl ProbeReport {
+    /// Returns true if no check failed.
+    pub fn ok(&self) -> bool {
+        self.results.iter().all(|r| r.status != ProbeStatus::Failed)
+    }
+
+    /// Logs all results, the problems as warnings.
+    pub fn log(&self) {
+        for result in &self.results {
+            if result.status > ProbeStatus::Skipped {
+                log::warn!("Network probe: {result}");
+            } else {
+                log::info!("Network probe: {result}");
+            }
+        }
+    }
+
+    /// Adds the result of the connection to the signalling server.
+    pub fn add_signal(&mut self, url: &str, connected: Result<(), String>) {
+        self.results.push(match connected {
+            Ok(_) => Self::result(
+                ProbeCheck::Signal,
+                ProbeStatus::Ok,
+                &format!("Connected to {url}"),
+                None,
+            ),
+            Err(e) => Self::result(
+                ProbeCheck::Signal,
+                ProbeStatus::Failed,
+                &format!("Couldn't connect to {url}: {e}"),
+                Some("Check the URL, and that outgoing websocket connections are allowed"),
+            ),
+        });
+    }
+
+    /// Adds the results of the STUN, TURN, and UDP checks, given the local
+    /// ICE candidates of a connection.
+    pub fn add_candidates(&mut self, config: &ConnectionConfig, candidates: &[String]) {
+        let count = |typ: &str| {
+            candidates
+                .iter()
+                .filter(|c| candidate_type(c) == Some(typ))
+                .count()
+        };
+        let (srflx, relay) = (count("srflx"), count("relay"));
+        let mut nd = NatDetector::default();
+        candidates.iter().for_each(|c| nd.add_candidate(c));
+
+        let stun = format!("{} and {}", config.stun().url, config.nat_stun().url);
+        self.results.push(match nd.nat_type() {
+            _ if srflx == 0 => Self::result(
+                ProbeCheck::Stun,
+                ProbeStatus::Failed,
+                &format!("No answer from {stun}"),
+                Some("Check that the STUN servers are reachable"),
+            ),
+            Some(NatType::Symmetric) => Self::result(
+                ProbeCheck::Stun,
+                ProbeStatus::Warning,
+                "This node is behind a symmetric NAT",
+                Some("Use a TURN server to connect to other nodes behind a symmetric NAT"),
+            ),
+            nat => Self::result(
+                ProbeCheck::Stun,
+                ProbeStatus::Ok,
+                &format!("Answer from {stun}, NAT type is {nat:?}"),
+                None,
+            ),
+        });
+
+        self.results.push(match config.turn() {
+            None => Self::result(
+                ProbeCheck::Turn,
+                ProbeStatus::Skipped,
+                "No TURN server configured",
+                Some("Add a TURN server for nodes behind a symmetric NAT or a firewall"),
+            ),
+            Some(turn) if relay == 0 => Self::result(
+                ProbeCheck::Turn,
+                ProbeStatus::Failed,
+                &format!("No relay address from {}", turn.url),
+                Some("Check the URL and the login of the TURN server"),
+            ),
+            Some(turn) => Self::result(
+                ProbeCheck::Turn,
+                ProbeStatus::Ok,
+                &format!("Got a relay address from {}", turn.url),
+                None,
+            ),
+        });
+
+        self.results.push(if srflx > 0 {
+            Self::result(ProbeCheck::Udp, ProbeStatus::Ok, "Outgoing UDP works", None)
+        } else if relay > 0 {
+            Self::result(
+                ProbeCheck::Udp,
+                ProbeStatus::Warning,
+                "Only the TURN server is reachable, all connections will be relayed",
+                Some("Allow outgoing UDP traffic in the firewall"),
+            )
+        } else {
+            Self::result(
+                ProbeCheck::Udp,
+                ProbeStatus::Failed,
+                "Outgoing UDP seems to be blocked",
+                Some("Allow outgoing UDP traffic in the firewall"),
+            )
+        });
+    }
+
+    /// Adds the result of the clock skew check, given the estimated offset of
+    /// the local clock to the clocks of the other nodes.
+    pub fn add_clock_offset(&mut self, offset_ms: Option<i64>) {
+        self.results.push(match offset_ms {
+            None => Self::result(
+                ProbeCheck::ClockSkew,
+                ProbeStatus::Skipped,
+                "No other node to compare the clock with",
+                None,
+            ),
+            Some(offset) if offset.abs() > CLOCK_SKEW_MAX_MS => Self::result(
+                ProbeCheck::ClockSkew,
+                ProbeStatus::Warning,
+                &format!("The clock is off by {offset}ms"),
+                Some("Synchronize the clock of this computer, e.g., with NTP"),
+            ),
+            Some(offset) => Self::result(
+                ProbeCheck::ClockSkew,
+                ProbeStatus::Ok,
+                &format!("The clock is off by {offset}ms"),
+                None,
+            ),
+        });
+    }
+
+    fn result(
+        check: ProbeCheck,
+        status: ProbeStatus,
+        details: &str,
+        fix: Option<&str>,
+    ) -> ProbeResult {
+        ProbeResult {
+            check,
+            status,
+            details: details.into(),
+            fix: fix.map(|f| f.into()),
+        }
+    }
+}
+
+/// Runs the signalling server, STUN, TURN, and UDP checks, waiting at most
+/// `wait` for each of them.
+pub async fn probe(config: &ConnectionConfig, wait: Duration) -> ProbeReport {
+    let mut report = ProbeReport::default();
+    let signal = config.signal();
+    report.add_signal(&signal, probe_signal(&signal, wait).await);
+    let candidates = probe_candidates(config, wait).await.unwrap_or_else(|e| {
+        log::warn!("Couldn't set up a WebRTC connection: {e}");
+        vec![]
+    });
+    report.add_candidates(config, &candidates);
+    report
+}
+
+async fn probe_signal(url: &str, wait: Duration) -> Result<(), String> {
+    let mut ws = WebSocketClient::connect(url)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (mut tap, _) = ws.get_tap().await.map_err(|e| e.to_string())?;
+    let connected = timeout(wait, async {
+        while let Some(msg) = tap.recv().await {
+            match msg {
+                WSClientMessage::Output(WSClientOutput::Connected)
+                | WSClientMessage::Output(WSClientOutput::Message(_)) => return Ok(()),
+                WSClientMessage::Output(WSClientOutput::Error(e)) => return Err(e),
+                _ => {}
+            }
+        }
+        Err("connection closed".to_string())
+    })
+    .await
+    .unwrap_or(Err("timeout".into()));
+    if connected.is_ok() {
+        ws.emit_msg(WSClientInput::Disconnect.into())
+            .map_err(|e| e.to_string())?;
+    }
+    connected
+}
+
+// Starts a connection without a remote node, only to get the local ICE candidates.
+async fn probe_candidates(
+    config: &ConnectionConfig,
+    wait: Duration,
+) -> Result<Vec<String>, SetupError> {
+    let mut conn = web_rtc_spawner(config.clone())().await?;
+    let (mut tap, _) = conn.get_tap().await?;
+    conn.emit_msg(WebRTCMessage::Input(WebRTCInput::Setup(PeerMessage::Init)))?;
+    wait_ms(wait.as_millis() as u64).await;
+    let mut candidates = vec![];
+    while let Ok(msg) = tap.try_recv() {
+        if let WebRTCMessage::Output(WebRTCOutput::Setup(PeerMessage::IceCandidate(c))) = msg {
+            candidates.push(c);
+        }
+    }
+    conn.emit_msg(WebRTCMessage::Input(WebRTCInput::Disconnect))?;
+    Ok(candidates)
+}
+
+fn candidate_type(candidate: &str) -> Option<&str> {
+    let mut fields = candidate.split_whitespace();
+    fields.find(|f| f == &"typ")?;
+    fields.next()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn status(report: &ProbeReport, check: ProbeCheck) -> Option<ProbeStatus> {
+        report
+            .results
+            .iter()
+            .find(|r| r.check == check)
+            .map(|r| r.status)
+    }
+
+    #[test]
+    fn test_report() {
+        let config = ConnectionConfig::from_signal("ws://localhost:8765");
+        let host = "candidate:1 1 udp 2122260223 10.0.0.1 5000 typ host".to_string();
+        let srflx = |port: u16| {
+            format!(
+                "candidate:2 1 udp 1686052607 1.2.3.4 {port} typ srflx raddr 10.0.0.1 rport 5000"
+            )
+        };
+
+        let mut report = ProbeReport::default();
+        report.add_signal("ws://localhost:8765", Ok(()));
+        report.add_candidates(&config, &[host.clone(), srflx(4000)]);
+        report.add_clock_offset(Some(200));
+        assert!(report.ok());
+        assert_eq!(Some(ProbeStatus::Ok), status(&report, ProbeCheck::Stun));
+        assert_eq!(
+            Some(ProbeStatus::Skipped),
+            status(&report, ProbeCheck::Turn)
+        );
+        assert_eq!(
+            Some(ProbeStatus::Ok),
+            status(&report, ProbeCheck::ClockSkew)
+        );
+
+        let mut report = ProbeReport::default();
+        report.add_candidates(&config, &[host.clone(), srflx(4000), srflx(4001)]);
+        report.add_clock_offset(Some(-5000));
+        assert!(report.ok());
+        assert_eq!(
+            Some(ProbeStatus::Warning),
+            status(&report, ProbeCheck::Stun)
+        );
+        assert_eq!(
+            Some(ProbeStatus::Warning),
+            status(&report, ProbeCheck::ClockSkew)
+        );
+
+        let mut report = ProbeReport::default();
+        report.add_signal("ws://localhost:8765", Err("timeout".into()));
+        report.add_candidates(&config, &[host]);
+        assert!(!report.ok());
+        assert_eq!(
+            Some(ProbeStatus::Failed),
+            status(&report, ProbeCheck::Signal)
+        );
+        assert_eq!(Some(ProbeStatus::Failed), status(&report, ProbeCheck::Stun));
+        assert_eq!(Some(ProbeStatus::Failed), status(&report, ProbeCheck::Udp));
+    }
+}
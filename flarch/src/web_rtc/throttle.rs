@@ -0,0 +1,357 @@
+//! Bandwidth limits for the [`crate::web_rtc::WebRTCConn`].
+//!
+//! Every node has a token bucket for each direction, and there is one global
+//! token bucket for each direction.
+//! A message is passed if both the bucket of the node and the global bucket
+//! have some bytes left, else it is queued until the next tick.
+//! If the queue is full, the message is dropped, counted in [`ThrottleStats`],
+//! and logged.
+//! The buckets hold up to one second worth of bytes, so short bursts are possible.
+//!
+//! The queued messages are sent round-robin, one message per node at a time,
+//! so that a node with a long queue cannot use up the global bucket and stall
+//! the other nodes.
+//! Queued messages keep their [`Channel`].
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::nodeids::NodeID;
+
+use super::messages::Channel;
+
+/// How many messages per node and direction are queued before new messages
+/// are dropped.
+pub const THROTTLE_QUEUE_MAX: usize = 100;
+
+/// The bandwidth limits in bytes per second. `None` means there is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThrottleConfig {
+    /// Outgoing bytes per second to each node.
+    pub up_per_node: Option<u64>,
+    /// Incoming bytes per second from each node.
+    pub down_per_node: Option<u64>,
+    /// Outgoing bytes per second to all nodes.
+    pub up_total: Option<u64>,
+    /// Incoming bytes per second from all nodes.
+    pub down_total: Option<u64>,
+}
+
+/// The direction of a message going through the [`Throttle`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Up,
+    Down,
+}
+
+/// How many messages have been delayed or dropped for a node.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThrottleStats {
+    pub delayed: u64,
+    pub dropped: u64,
+}
+
+/// Keeps the buckets and the queues of all nodes.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    up: Bucket,
+    down: Bucket,
+    nodes: HashMap<NodeID, NodeThrottle>,
+    // Every tick starts with the next node, so no node is always served first.
+    round: usize,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            up: Bucket::new(config.up_total),
+            down: Bucket::new(config.down_total),
+            nodes: HashMap::new(),
+            round: 0,
+        }
+    }
+
+    /// Changes the limits. Queued messages and stats are kept.
+    pub fn set_config(&mut self, config: ThrottleConfig) {
+        self.config = config;
+        self.up = Bucket::new(config.up_total);
+        self.down = Bucket::new(config.down_total);
+        for node in self.nodes.values_mut() {
+            node.up = Bucket::new(config.up_per_node);
+            node.down = Bucket::new(config.down_per_node);
+        }
+    }
+
+    /// Returns the message if it can be passed now.
+    /// Else the message is queued or dropped.
+    pub fn pass(
+        &mut self,
+        id: NodeID,
+        flow: Flow,
+        channel: Channel,
+        msg: String,
+        now: i64,
+    ) -> Option<String> {
+        let config = self.config;
+        let node = self
+            .nodes
+            .entry(id)
+            .or_insert_with(|| NodeThrottle::new(&config));
+        let (bucket, bucket_total, queue) = match flow {
+            Flow::Up => (&mut node.up, &mut self.up, &mut node.queue_up),
+            Flow::Down => (&mut node.down, &mut self.down, &mut node.queue_down),
+        };
+        if queue.is_empty() && Bucket::take(bucket, bucket_total, msg.len(), now) {
+            return Some(msg);
+        }
+        if queue.len() >= THROTTLE_QUEUE_MAX {
+            node.stats.dropped += 1;
+            // Only every 100th drop is logged, so a flooding node doesn't flood the logs.
+            if node.stats.dropped % 100 == 1 {
+                log::warn!(
+                    "Queue of {flow:?} messages for {id} is full, dropped {} messages so far",
+                    node.stats.dropped
+                );
+            }
+        } else {
+            node.stats.delayed += 1;
+            queue.push_back((channel, msg));
+        }
+        None
+    }
+
+    /// Returns all queued messages which can be passed now.
+    /// The nodes take turns, so the global bucket is shared fairly.
+    pub fn tick(&mut self, now: i64) -> Vec<(NodeID, Flow, Channel, String)> {
+        let mut ids: Vec<NodeID> = self.nodes.keys().copied().collect();
+        if ids.is_empty() {
+            return vec![];
+        }
+        ids.sort_by_key(|id| id.to_bytes());
+        let start = self.round % ids.len();
+        ids.rotate_left(start);
+        self.round = self.round.wrapping_add(1);
+
+        let mut out = vec![];
+        for flow in [Flow::Up, Flow::Down] {
+            let mut passed = true;
+            while passed {
+                passed = false;
+                for id in &ids {
+                    let node = self.nodes.get_mut(id).expect("ids are taken from nodes");
+                    let (bucket, bucket_total, queue) = match flow {
+                        Flow::Up => (&mut node.up, &mut self.up, &mut node.queue_up),
+                        Flow::Down => (&mut node.down, &mut self.down, &mut node.queue_down),
+                    };
+                    let Some(len) = queue.front().map(|(_, msg)| msg.len()) else {
+                        continue;
+                    };
+                    if Bucket::take(bucket, bucket_total, len, now) {
+                        let (channel, msg) = queue.pop_front().unwrap();
+                        out.push((*id, flow, channel, msg));
+                        passed = true;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Returns the stats of the given node.
+    pub fn stats(&self, id: &NodeID) -> ThrottleStats {
+        self.nodes
+            .get(id)
+            .map(|node| node.stats)
+            .unwrap_or_default()
+    }
+
+    /// Forgets about a node, dropping all queued messages.
+    pub fn remove(&mut self, id: &NodeID) {
+        self.nodes.remove(id);
+    }
+}
+
+#[derive(Debug)]
+struct NodeThrottle {
+    up: Bucket,
+    down: Bucket,
+    queue_up: VecDeque<(Channel, String)>,
+    queue_down: VecDeque<(Channel, String)>,
+    stats: ThrottleStats,
+}
+
+impl NodeThrottle {
+    fn new(config: &ThrottleConfig) -> Self {
+        Self {
+            up: Bucket::new(config.up_per_node),
+            down: Bucket::new(config.down_per_node),
+            queue_up: VecDeque::new(),
+            queue_down: VecDeque::new(),
+            stats: ThrottleStats::default(),
+        }
+    }
+}
+
+/// A token bucket which refills `rate` bytes per second.
+/// The content is stored in millibytes, so that frequent refills don't get lost
+/// to rounding.
+/// It can go negative, so that messages bigger than the rate can still be sent.
+#[derive(Debug, Default)]
+struct Bucket {
+    rate: Option<u64>,
+    millibytes: i64,
+    last: Option<i64>,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            millibytes: rate.unwrap_or(0) as i64 * 1000,
+            last: None,
+        }
+    }
+
+    fn available(&mut self, now: i64) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        let rate = rate as i64;
+        let elapsed = now - *self.last.get_or_insert(now);
+        self.millibytes = (self.millibytes + rate * elapsed).min(rate * 1000);
+        self.last = Some(now);
+        self.millibytes > 0
+    }
+
+    fn consume(&mut self, len: usize) {
+        if self.rate.is_some() {
+            self.millibytes -= len as i64 * 1000;
+        }
+    }
+
+    /// Takes `len` bytes from both buckets, if both have some bytes left.
+    fn take(node: &mut Bucket, total: &mut Bucket, len: usize, now: i64) -> bool {
+        if node.available(now) && total.available(now) {
+            node.consume(len);
+            total.consume(len);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let (a, b) = (NodeID::rnd(), NodeID::rnd());
+        let mut th = Throttle::new(ThrottleConfig {
+            up_per_node: Some(100),
+            up_total: Some(120),
+            ..Default::default()
+        });
+        let msg = "0123456789".repeat(6);
+
+        // Node a can send two messages, the second one takes the bucket below 0.
+        assert!(th
+            .pass(a, Flow::Up, Channel::Control, msg.clone(), 0)
+            .is_some());
+        assert!(th
+            .pass(a, Flow::Up, Channel::Control, msg.clone(), 0)
+            .is_some());
+        assert!(th
+            .pass(a, Flow::Up, Channel::Control, msg.clone(), 0)
+            .is_none());
+        // Node b has its own bucket, but the global bucket is empty.
+        assert!(th
+            .pass(b, Flow::Up, Channel::Bulk, msg.clone(), 0)
+            .is_none());
+        // No limits for incoming messages.
+        assert!(th
+            .pass(b, Flow::Down, Channel::Control, msg.clone(), 0)
+            .is_some());
+        assert_eq!(1, th.stats(&a).delayed);
+        assert_eq!(1, th.stats(&b).delayed);
+
+        // After one second both queued messages can be sent, on their channels.
+        assert!(th.tick(0).is_empty());
+        let passed = th.tick(1000);
+        assert_eq!(2, passed.len());
+        assert!(passed.contains(&(b, Flow::Up, Channel::Bulk, msg.clone())));
+
+        th.set_config(ThrottleConfig {
+            up_per_node: Some(1),
+            ..Default::default()
+        });
+        th.pass(a, Flow::Up, Channel::Control, msg.clone(), 2000);
+        for _ in 0..THROTTLE_QUEUE_MAX + 1 {
+            th.pass(a, Flow::Up, Channel::Control, msg.clone(), 2000);
+        }
+        assert_eq!(
+            ThrottleStats {
+                delayed: THROTTLE_QUEUE_MAX as u64 + 1,
+                dropped: 1
+            },
+            th.stats(&a)
+        );
+
+        // Incoming messages are counted the same way.
+        th.set_config(ThrottleConfig {
+            down_per_node: Some(1),
+            ..Default::default()
+        });
+        th.pass(b, Flow::Down, Channel::Control, msg.clone(), 3000);
+        for _ in 0..THROTTLE_QUEUE_MAX + 2 {
+            assert!(th
+                .pass(b, Flow::Down, Channel::Control, msg.clone(), 3000)
+                .is_none());
+        }
+        assert_eq!(
+            ThrottleStats {
+                delayed: THROTTLE_QUEUE_MAX as u64 + 1,
+                dropped: 2
+            },
+            th.stats(&b)
+        );
+    }
+
+    #[test]
+    fn test_tick_fair() {
+        let nodes = [NodeID::rnd(), NodeID::rnd(), NodeID::rnd()];
+        let mut th = Throttle::new(ThrottleConfig {
+            up_total: Some(1),
+            ..Default::default()
+        });
+        let msg = "0123456789".to_string();
+
+        // The first message empties the global bucket, all others are queued.
+        assert!(th
+            .pass(nodes[0], Flow::Up, Channel::Control, msg.clone(), 0)
+            .is_some());
+        for id in &nodes {
+            for _ in 0..5 {
+                assert!(th
+                    .pass(*id, Flow::Up, Channel::Control, msg.clone(), 0)
+                    .is_none());
+            }
+        }
+
+        // Every tick only allows one message, which goes to the nodes in turn.
+        let mut sent: Vec<NodeID> = vec![];
+        for second in 1..=6 {
+            let passed = th.tick(second * 10_000);
+            assert_eq!(1, passed.len());
+            sent.push(passed[0].0);
+        }
+        for id in &nodes {
+            assert_eq!(2, sent.iter().filter(|s| s == &id).count());
+        }
+
+        // Without a global limit, all queues are emptied at once.
+        th.set_config(ThrottleConfig::default());
+        assert_eq!(9, th.tick(100_000).len());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod web_rtc_setup;
 pub mod web_socket_client;
+pub mod web_socket_server;
@@ -2,33 +2,38 @@ use async_trait::async_trait;
 use futures::lock::Mutex;
 use js_sys::Reflect;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 use wasm_bindgen::{prelude::*, JsCast};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    Event, MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelEvent,
+    Event, MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelEvent, RtcDataChannelInit,
     RtcDataChannelState, RtcIceCandidate, RtcIceCandidateInit, RtcIceConnectionState,
     RtcIceGatheringState, RtcPeerConnection, RtcPeerConnectionIceEvent, RtcSdpType,
     RtcSessionDescriptionInit, RtcSignalingState,
 };
 
-use crate::broker::{Broker, Subsystem, SubsystemHandler};
+use crate::broker::{Broker, Priority, Subsystem, SubsystemHandler};
 use crate::web_rtc::{
     connection::{ConnectionConfig, HostLogin},
     messages::{
-        ConnType, ConnectionStateMap, DataChannelState, IceConnectionState, IceGatheringState,
-        PeerMessage, SetupError, SignalingState, WebRTCInput, WebRTCMessage, WebRTCOutput,
-        WebRTCSpawner,
+        Channel, ConnType, ConnectionStateMap, DataChannelState, IceConnectionState,
+        IceGatheringState, PeerMessage, SetupError, SignalingState, TransportStats, WebRTCInput,
+        WebRTCMessage, WebRTCOutput, WebRTCSpawner, BUFFER_HIGH_BYTES, BUFFER_LOW_BYTES,
     },
     node_connection::Direction,
 };
 
 pub struct WebRTCConnectionSetup {
     pub rp_conn: RtcPeerConnection,
-    rtc_data: Arc<Mutex<Option<RtcDataChannel>>>,
+    channels: Arc<Mutex<HashMap<Channel, RtcDataChannel>>>,
     broker: Broker<WebRTCMessage>,
     // While the connection is not up, queue up messages in here.
-    queue: Vec<String>,
+    queue: Vec<(Channel, String)>,
+    // Whether the offers open all channels.
+    multi_channel: bool,
     direction: Option<Direction>,
     config: ConnectionConfig,
 }
@@ -57,9 +62,10 @@ impl WebRTCConnectionSetup {
     ) -> Result<WebRTCConnectionSetup, SetupError> {
         Ok(WebRTCConnectionSetup {
             rp_conn: Self::create_rp_conn(config.clone())?,
-            rtc_data: Arc::new(Mutex::new(None)),
+            channels: Arc::new(Mutex::new(HashMap::new())),
             broker,
             queue: vec![],
+            multi_channel: false,
             direction: None,
             config,
         })
@@ -71,7 +77,10 @@ impl WebRTCConnectionSetup {
         // If no stun server is configured, only local IPs will be sent in the browser.
         // At least the node webrtc does the correct thing...
         let config = RtcConfiguration::new();
-        let mut servers_obj = vec![get_ice_server(connection_cfg.stun())];
+        let mut servers_obj = vec![
+            get_ice_server(connection_cfg.stun()),
+            get_ice_server(connection_cfg.nat_stun()),
+        ];
         if let Some(turn) = connection_cfg.turn() {
             servers_obj.push(get_ice_server(turn));
         }
@@ -92,10 +101,11 @@ impl WebRTCConnectionSetup {
             log::warn!("Got callback after reset");
         }) as Box<dyn FnMut(MessageEvent)>);
 
-        if let Some(rtc_data_opt) = self.rtc_data.try_lock() {
-            if let Some(rtc_data) = rtc_data_opt.as_ref() {
+        if let Some(channels) = self.channels.try_lock() {
+            for rtc_data in channels.values() {
                 rtc_data.set_onmessage(Some(empty_callback.as_ref().unchecked_ref()));
                 rtc_data.set_onopen(Some(empty_callback.as_ref().unchecked_ref()));
+                rtc_data.set_onclose(Some(empty_callback.as_ref().unchecked_ref()));
             }
         }
         self.rp_conn
@@ -113,9 +123,9 @@ impl WebRTCConnectionSetup {
 
     fn close(&mut self) {
         self.rp_conn.close();
-        if let Some(mut rd) = self.rtc_data.try_lock() {
-            rd.as_ref().map(|r| r.close());
-            *rd = None;
+        if let Some(mut channels) = self.channels.try_lock() {
+            channels.values().for_each(|r| r.close());
+            channels.clear();
         }
     }
 
@@ -128,9 +138,12 @@ impl WebRTCConnectionSetup {
                     let cand = format!("{}", candidate.candidate());
                     wasm_bindgen_futures::spawn_local(async move {
                         broker
-                            .emit_msg(WebRTCMessage::Output(WebRTCOutput::Setup(
-                                PeerMessage::IceCandidate(cand),
-                            )))
+                            .enqueue_msg_priority(
+                                Priority::Control,
+                                WebRTCMessage::Output(WebRTCOutput::Setup(
+                                    PeerMessage::IceCandidate(cand),
+                                )),
+                            )
                             .err()
                             .map(|e| log::error!("While sending ICE candidate: {:?}", e));
                     });
@@ -151,7 +164,7 @@ impl WebRTCConnectionSetup {
                 let mut broker = broker_cl.clone();
                 wasm_bindgen_futures::spawn_local(async move {
                     broker
-                        .emit_msg(msg)
+                        .enqueue_msg_priority(Priority::Control, msg)
                         .err()
                         .map(|e| log::error!("While sending ICE candidate: {:?}", e));
                 });
@@ -169,8 +182,22 @@ impl WebRTCConnectionSetup {
         };
         self.direction = Some(Direction::Outgoing);
 
-        let dc = self.rp_conn.create_data_channel("data-channel");
-        Self::dc_set_onopen(self.broker.clone(), self.rtc_data.clone(), dc);
+        let channels: &[Channel] = if self.multi_channel {
+            &Channel::ALL
+        } else {
+            &[Channel::Control]
+        };
+        for channel in channels {
+            let init = RtcDataChannelInit::new();
+            init.set_ordered(channel.ordered());
+            if let Some(max) = channel.max_retransmits() {
+                init.set_max_retransmits(max);
+            }
+            let dc = self
+                .rp_conn
+                .create_data_channel_with_data_channel_dict(channel.label(), &init);
+            Self::dc_set_onopen(self.broker.clone(), self.channels.clone(), *channel, dc);
+        }
 
         let co = self.rp_conn.create_offer();
         let offer = JsFuture::from(co)
@@ -273,44 +300,75 @@ impl WebRTCConnectionSetup {
         .map_err(|js| SetupError::SetupFail(js.to_string()))
     }
 
-    pub async fn send(&mut self, msg: String) -> Result<(), SetupError> {
-        self.queue.push(msg);
+    pub async fn send(&mut self, channel: Channel, msg: String) -> Result<(), SetupError> {
+        self.queue.push((channel, msg));
         self.send_queue().await
     }
 
+    // Messages for a channel which is not open go over the control channel.
+    // Once a data channel buffers more than BUFFER_HIGH_BYTES, its messages stay
+    // in the queue until it fires onbufferedamountlow.
     pub async fn send_queue(&mut self) -> Result<(), SetupError> {
         let state = self.get_state().await?;
         if let Some(state) = state.data_connection {
             if state == DataChannelState::Open {
-                let rtc_data = self.rtc_data.try_lock().unwrap();
-                if let Some(ref mut data_channel) = rtc_data.as_ref() {
-                    for msg_queue in self.queue.drain(..) {
-                        data_channel
-                            .send_with_str(&msg_queue)
-                            .map_err(|e| SetupError::Send(format!("{e:?}")))?;
+                let channels = self.channels.try_lock().unwrap();
+                if let Some(control) = channels.get(&Channel::Control) {
+                    let mut congested = HashSet::new();
+                    let mut kept = vec![];
+                    let mut res = Ok(());
+                    for (channel, msg_queue) in self.queue.drain(..) {
+                        let (used, dc) = match channels
+                            .get(&channel)
+                            .filter(|dc| dc.ready_state() == RtcDataChannelState::Open)
+                        {
+                            Some(dc) => (channel, dc),
+                            None => (Channel::Control, control),
+                        };
+                        if res.is_err()
+                            || congested.contains(&used)
+                            || dc.buffered_amount() as usize > BUFFER_HIGH_BYTES
+                        {
+                            congested.insert(used);
+                            kept.push((channel, msg_queue));
+                            continue;
+                        }
+                        if let Err(e) = dc.send_with_str(&msg_queue) {
+                            res = Err(SetupError::Send(format!("{e:?}")));
+                        }
                     }
-                    return Ok(());
+                    self.queue = kept;
+                    return res;
                 }
             }
         }
         Ok(())
     }
 
+    // Only the control channel makes the connection usable, the other channels
+    // are used once they are open.
     fn dc_set_onopen(
         broker: Broker<WebRTCMessage>,
-        rtc_data: Arc<Mutex<Option<RtcDataChannel>>>,
+        channels: Arc<Mutex<HashMap<Channel, RtcDataChannel>>>,
+        channel: Channel,
         dc: RtcDataChannel,
     ) {
         let dc_clone = dc.clone();
         let ondatachannel_open = Closure::wrap(Box::new(move |_ev: Event| {
-            log::trace!("DataChannel is opened");
+            log::trace!("DataChannel {} is opened", channel.label());
             let mut broker_clone = broker.clone();
-            let rtc_data = Arc::clone(&rtc_data);
+            let channels = Arc::clone(&channels);
             let dc_clone2 = dc_clone.clone();
             wasm_bindgen_futures::spawn_local(async move {
-                rtc_data.lock().await.replace(dc_clone2.clone());
+                channels.lock().await.insert(channel, dc_clone2.clone());
+                if channel != Channel::Control {
+                    return;
+                }
                 broker_clone
-                    .emit_msg(WebRTCMessage::Output(WebRTCOutput::Connected))
+                    .enqueue_msg_priority(
+                        Priority::Control,
+                        WebRTCMessage::Output(WebRTCOutput::Connected),
+                    )
                     .err()
                     .map(|e| log::error!("While sending connection: {:?}", e));
             });
@@ -330,6 +388,20 @@ impl WebRTCConnectionSetup {
             dc_clone.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
             onmessage_callback.forget();
 
+            let broker_cl = broker.clone();
+            let onbufferedlow_callback = Closure::wrap(Box::new(move |_: Event| {
+                let flush = WebRTCMessage::Input(WebRTCInput::Flush);
+                if let Err(e) = broker_cl.clone().emit_msg(flush) {
+                    log::error!("While sending flush: {:?}", e);
+                }
+            }) as Box<dyn FnMut(Event)>);
+            dc_clone.set_buffered_amount_low_threshold(BUFFER_LOW_BYTES as u32);
+            dc_clone.set_onbufferedamountlow(Some(onbufferedlow_callback.as_ref().unchecked_ref()));
+            onbufferedlow_callback.forget();
+            if channel != Channel::Control {
+                return;
+            }
+
             let broker_cl = broker.clone();
             let onclose_callback = Closure::wrap(Box::new(move |_: MessageEvent| {
                 let mut broker = broker_cl.clone();
@@ -349,9 +421,13 @@ impl WebRTCConnectionSetup {
 
     fn dc_create_follow(&self) {
         let broker = self.broker.clone();
-        let rtc_data = self.rtc_data.clone();
+        let channels = self.channels.clone();
         let ondatachannel_callback = Closure::wrap(Box::new(move |ev: RtcDataChannelEvent| {
-            Self::dc_set_onopen(broker.clone(), rtc_data.clone(), ev.channel());
+            let dc = ev.channel();
+            match Channel::from_label(&dc.label()) {
+                Some(channel) => Self::dc_set_onopen(broker.clone(), channels.clone(), channel, dc),
+                None => log::warn!("Ignoring unknown DataChannel {}", dc.label()),
+            }
         })
             as Box<dyn FnMut(RtcDataChannelEvent)>);
         self.rp_conn
@@ -406,8 +482,8 @@ impl WebRTCConnectionSetup {
         };
 
         let mut data_connection = None;
-        if let Some(rtc_data) = self.rtc_data.try_lock() {
-            if let Some(rtc_data_ref) = rtc_data.as_ref() {
+        if let Some(channels) = self.channels.try_lock() {
+            if let Some(rtc_data_ref) = channels.get(&Channel::Control) {
                 data_connection = Some(match rtc_data_ref.ready_state() {
                     RtcDataChannelState::Connecting => DataChannelState::Connecting,
                     RtcDataChannelState::Open => DataChannelState::Open,
@@ -428,8 +504,60 @@ impl WebRTCConnectionSetup {
             rx_bytes: 0,
             type_remote,
             type_local: type_remote,
+            delayed: 0,
+            dropped: 0,
+            nat: None,
         })
     }
+
+    /// Returns the transport statistics of the nominated candidate pair.
+    pub async fn get_stats(&self) -> Result<TransportStats, SetupError> {
+        let reports: js_sys::Map = JsFuture::from(self.rp_conn.get_stats())
+            .await
+            .map_err(|e| SetupError::SetupFail(format!("{e:?}")))?
+            .into();
+        let get = |obj: &JsValue, key: &str| Reflect::get(obj, &key.into()).ok();
+        let get_f64 = |obj: &JsValue, key: &str| get(obj, key).and_then(|v| v.as_f64());
+        let get_str = |obj: &JsValue, key: &str| get(obj, key).and_then(|v| v.as_string());
+
+        let mut pair = None;
+        let mut packets_lost = None;
+        reports.for_each(&mut |report, _| match get_str(&report, "type").as_deref() {
+            Some("candidate-pair")
+                if get(&report, "nominated").and_then(|v| v.as_bool()) == Some(true) =>
+            {
+                pair = Some(report)
+            }
+            Some("inbound-rtp") | Some("remote-inbound-rtp") => {
+                if let Some(lost) = get_f64(&report, "packetsLost") {
+                    packets_lost = Some(packets_lost.unwrap_or(0) + lost as u64);
+                }
+            }
+            _ => {}
+        });
+
+        let mut stats = TransportStats {
+            time: crate::tasks::now(),
+            packets_lost,
+            ..Default::default()
+        };
+        if let Some(pair) = pair {
+            let conn_type = |key: &str| {
+                get_str(&pair, key)
+                    .and_then(|id| get_str(&reports.get(&id.into()), "candidateType"))
+                    .map_or(ConnType::Unknown, |t| ConnType::from_candidate_type(&t))
+            };
+            stats.bytes_sent = get_f64(&pair, "bytesSent").unwrap_or_default() as u64;
+            stats.bytes_received = get_f64(&pair, "bytesReceived").unwrap_or_default() as u64;
+            stats.rtt_ms = get_f64(&pair, "currentRoundTripTime").map(|rtt| (rtt * 1000.) as u32);
+            stats.type_local = conn_type("localCandidateId");
+            stats.type_remote = conn_type("remoteCandidateId");
+        }
+        for dc in self.channels.lock().await.values() {
+            stats.buffered_bytes += dc.buffered_amount() as u64;
+        }
+        Ok(stats)
+    }
 }
 
 pub struct WebRTCConnection {
@@ -469,7 +597,7 @@ impl WebRTCConnection {
 
     async fn msg_in(&mut self, msg: WebRTCInput) -> Result<Option<WebRTCMessage>, SetupError> {
         match msg {
-            WebRTCInput::Text(s) => self.setup.send(s).await?,
+            WebRTCInput::Text(ch, s) => self.setup.send(ch, s).await?,
             WebRTCInput::Setup(s) => {
                 if let Some(msg) = self.setup(s).await? {
                     return Ok(Some(WebRTCMessage::Output(WebRTCOutput::Setup(msg))));
@@ -483,8 +611,14 @@ impl WebRTCConnection {
                     self.setup.get_state().await?,
                 ))));
             }
+            WebRTCInput::GetStats => {
+                return Ok(Some(WebRTCMessage::Output(WebRTCOutput::Stats(
+                    self.setup.get_stats().await?,
+                ))));
+            }
             WebRTCInput::Disconnect => self.setup.reset()?,
             WebRTCInput::Reset => self.setup.reset()?,
+            WebRTCInput::MultiChannel => self.setup.multi_channel = true,
         }
         Ok(None)
     }
@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
-use web_sys::{ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{CloseEvent, ErrorEvent, MessageEvent, WebSocket};
 
 use crate::broker::{Broker, Subsystem, SubsystemHandler};
 
@@ -41,6 +41,7 @@ impl WebSocketClient {
             ws.set_onmessage(None);
             ws.set_onerror(None);
             ws.set_onopen(None);
+            ws.set_onclose(None);
             ws.close()
                 .map_err(|e| WSClientError::Connection(format!("{:?}", e)))?;
         } else {
@@ -105,6 +106,16 @@ impl WebSocketClient {
             }) as Box<dyn FnMut(JsValue)>);
             ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
             onopen_callback.forget();
+
+            let mut broker_clone = self.broker.clone();
+            let onclose_callback = Closure::wrap(Box::new(move |_: CloseEvent| {
+                broker_clone
+                    .emit_msg(WSClientMessage::Output(WSClientOutput::Disconnect))
+                    .err()
+                    .map(|e| log::error!("On_close_callback error: {e:?}"));
+            }) as Box<dyn FnMut(CloseEvent)>);
+            ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+            onclose_callback.forget();
         }
     }
 }
@@ -137,6 +148,13 @@ impl SubsystemHandler<WSClientMessage> for WebSocketClient {
                             log::warn!("While reconnecting: {e}");
                         }
                     }
+                    WSClientInput::ConnectTo(url) => {
+                        self.url = url;
+                        self.last_connection = 0;
+                        if let Err(e) = self.connect_ws().await {
+                            log::warn!("While connecting: {e}");
+                        }
+                    }
                 }
             }
         }
@@ -0,0 +1,139 @@
+//! A websocket server for wasm runtimes where the host accepts the websockets,
+//! like serverless workers or deno.
+//! The host hands every accepted websocket to [`WebSocketServer::accept`], and the
+//! server passes its messages to the [`Broker<WSServerMessage>`].
+//!
+//! On Cloudflare Workers, the `fetch` handler creates a `WebSocketPair`, calls
+//! `accept()` on the server side, passes it to [`WebSocketServer::accept`] with the
+//! `CF-Connecting-IP` header, and returns the client side in the response.
+
+use std::{cell::RefCell, collections::HashMap, net::IpAddr, rc::Rc};
+
+use async_trait::async_trait;
+use wasm_bindgen::{prelude::Closure, JsCast};
+use web_sys::{CloseEvent, MessageEvent, WebSocket};
+
+use crate::broker::{Broker, Subsystem, SubsystemHandler};
+use crate::web_rtc::websocket::{
+    WSError, WSSError, WSServerInput, WSServerMessage, WSServerOutput,
+};
+
+/// Passes the websockets accepted by the host to the [`Broker<WSServerMessage>`].
+/// It can be cloned to accept websockets from different handlers of the host.
+#[derive(Clone)]
+pub struct WebSocketServer {
+    connections: Rc<RefCell<HashMap<usize, WebSocket>>>,
+    next_id: Rc<RefCell<usize>>,
+    broker: Broker<WSServerMessage>,
+}
+
+impl WebSocketServer {
+    /// Creates a new server without any connections.
+    /// The returned broker can be used like the one of the libc server, e.g., for
+    /// a `SignalServer`.
+    pub async fn new() -> Result<(Self, Broker<WSServerMessage>), WSSError> {
+        let server = WebSocketServer {
+            connections: Rc::new(RefCell::new(HashMap::new())),
+            next_id: Rc::new(RefCell::new(0)),
+            broker: Broker::new(),
+        };
+        let mut broker = server.broker.clone();
+        broker
+            .add_subsystem(Subsystem::Handler(Box::new(server.clone())))
+            .await?;
+        Ok((server, broker))
+    }
+
+    /// Adds a websocket accepted by the host and returns the index of the connection.
+    /// If the host knows the IP address of the remote end, it is sent as
+    /// [`WSServerOutput::RemoteAddr`] for the rate limits of the signalling server.
+    pub fn accept(&mut self, ws: WebSocket, addr: Option<IpAddr>) -> Result<usize, WSSError> {
+        let id = {
+            let mut next_id = self.next_id.borrow_mut();
+            *next_id += 1;
+            *next_id - 1
+        };
+        self.attach_callbacks(&ws, id)?;
+        self.connections.borrow_mut().insert(id, ws);
+        if let Some(addr) = addr {
+            self.broker
+                .emit_msg(WSServerOutput::RemoteAddr(id, addr).into())?;
+        }
+        self.broker
+            .emit_msg(WSServerOutput::NewConnection(id).into())?;
+        Ok(id)
+    }
+
+    // Uses event listeners instead of the `on*` properties, as not all runtimes
+    // support the latter on the server side.
+    fn attach_callbacks(&self, ws: &WebSocket, id: usize) -> Result<(), WSSError> {
+        let mut broker = self.broker.clone();
+        let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| match e.data().as_string() {
+            Some(msg) => {
+                if let Err(e) = broker.emit_msg(WSServerOutput::Message(id, msg).into()) {
+                    log::error!("On_message_callback error: {e:?}");
+                }
+            }
+            None => log::warn!("Ignoring non-text message from connection {id}"),
+        }) as Box<dyn FnMut(MessageEvent)>);
+        ws.add_event_listener_with_callback("message", onmessage.as_ref().unchecked_ref())
+            .map_err(|e| WSError::Underlying(format!("{e:?}")))?;
+        onmessage.forget();
+
+        let mut broker = self.broker.clone();
+        let connections = Rc::clone(&self.connections);
+        let onclose = Closure::wrap(Box::new(move |_: CloseEvent| {
+            if connections.borrow_mut().remove(&id).is_some() {
+                broker
+                    .emit_msg(WSServerOutput::Disconnection(id).into())
+                    .err()
+                    .map(|e| log::error!("On_close_callback error: {e:?}"));
+            }
+        }) as Box<dyn FnMut(CloseEvent)>);
+        ws.add_event_listener_with_callback("close", onclose.as_ref().unchecked_ref())
+            .map_err(|e| WSError::Underlying(format!("{e:?}")))?;
+        onclose.forget();
+        Ok(())
+    }
+
+    fn close(&mut self, id: usize) {
+        if let Some(ws) = self.connections.borrow_mut().remove(&id) {
+            ws.close()
+                .err()
+                .map(|e| log::warn!("While closing connection {id}: {e:?}"));
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl SubsystemHandler<WSServerMessage> for WebSocketServer {
+    async fn messages(&mut self, msgs: Vec<WSServerMessage>) -> Vec<WSServerMessage> {
+        for msg in msgs {
+            if let WSServerMessage::Input(msg_in) = msg {
+                match msg_in {
+                    WSServerInput::Message(id, msg) => {
+                        let failed = self
+                            .connections
+                            .borrow()
+                            .get(&id)
+                            .map(|ws| ws.send_with_str(&msg))
+                            .is_some_and(|res| res.is_err());
+                        if failed {
+                            log::error!("Error while sending to connection {id}");
+                            self.close(id);
+                        }
+                    }
+                    WSServerInput::Close(id) => self.close(id),
+                    WSServerInput::Stop => {
+                        let ids: Vec<usize> = self.connections.borrow().keys().copied().collect();
+                        for id in ids {
+                            self.close(id);
+                        }
+                        return vec![WSServerMessage::Output(WSServerOutput::Stopped)];
+                    }
+                }
+            }
+        }
+        vec![]
+    }
+}
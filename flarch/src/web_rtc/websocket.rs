@@ -7,6 +7,8 @@
 //! This is similar to a trait that is implemented in either wasm or libc, but
 //! allows to connect the broker directly to other brokers.
 
+use std::net::IpAddr;
+
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -47,6 +49,13 @@ pub enum WSSError {
     /// Generic IO error
     #[error(transparent)]
     IO(#[from] std::io::Error),
+    /// Error from the websockets of the wasm runtime
+    #[error(transparent)]
+    Underlying(#[from] WSError),
+    /// The TLS certificate or key couldn't be used
+    #[cfg(target_family="unix")]
+    #[error(transparent)]
+    Tls(#[from] crate::web_rtc::tls::TlsError),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -68,6 +77,9 @@ pub enum WSClientInput {
     Disconnect,
     /// Connect the websocket - this starts or resets the connection
     Connect,
+    /// Connect the websocket to another URL, which is also used for all
+    /// further connections
+    ConnectTo(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -96,9 +108,10 @@ impl From<WSClientOutput> for WSClientMessage {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-/// The implementation for the websocket server, which only exists for the
-/// libc implementation, as wasm in the browser cannot have a websocket server.
-/// The libc implementation will return a [`crate::broker::Broker<WSServerMessage>`].
+/// The implementation for the websocket server.
+/// The libc implementation listens on a port, while the wasm implementation gets the
+/// websockets accepted by the host, as wasm in the browser cannot have a websocket server.
+/// Both implementations return a [`crate::broker::Broker<WSServerMessage>`].
 pub enum WSServerMessage {
     /// Messages sent by the websocket server
     Output(WSServerOutput),
@@ -113,6 +126,9 @@ pub enum WSServerOutput {
     Message(usize, String),
     /// A new connection is available with index `usize`
     NewConnection(usize),
+    /// The IP address of the connection with index `usize`. It is sent just before
+    /// [`WSServerOutput::NewConnection`], if the server knows it.
+    RemoteAddr(usize, IpAddr),
     /// The connection with index `usize` has been closed. There will be no other connection
     /// with the same index unless the websocket server is restarted.
     Disconnection(usize),
@@ -1,8 +1,9 @@
 use anyhow::{anyhow, Result};
 use chrono::{prelude::DateTime, Utc};
 use flmodules::{
+    gossip_events::core::Category,
     nodeconfig::NodeInfo,
-    ping::core::{PingStat, PingStorage},
+    ping::core::PingStat,
     Modules,
 };
 use js_sys::JsString;
@@ -17,13 +18,19 @@ use wasm_bindgen::{
     prelude::{wasm_bindgen, Closure},
     JsCast, JsValue,
 };
-use web_sys::{window, Document, Event, HtmlDivElement, HtmlInputElement, HtmlTextAreaElement};
+use web_sys::{
+    window, Document, Event, HtmlDivElement, HtmlInputElement, HtmlSelectElement,
+    HtmlTextAreaElement,
+};
 
 use flarch::{
-    data_storage::DataStorageLocal,
+    data_storage::{DataStorage, DataStorageLocal, DataStorageTemp, StorageError},
     nodeids::U256,
     tasks::{spawn_local_nosend, wait_ms},
-    web_rtc::connection::{ConnectionConfig, HostLogin, Login},
+    web_rtc::{
+        connection::{ConnectionConfig, HostLogin, Login},
+        probe::probe,
+    },
 };
 use flmodules::network::messages::NetworkConnectionState;
 use flmodules::network::network_broker_start;
@@ -39,7 +46,130 @@ const URL: &str = "ws://localhost:8765";
 enum Button {
     SendMsg,
     DownloadData,
+    DownloadChat,
     WebProxy,
+    StorageMode,
+    Probe,
+}
+
+/// Where the identity and the other data of the node are stored.
+/// On shared computers, the user can choose to not keep the identity
+/// after the browser tab is closed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StorageMode {
+    /// Kept in the localStorage of the browser
+    Persistent,
+    /// Kept in the sessionStorage and removed when the tab is closed
+    Session,
+    /// Only kept in memory, a new identity is created on every reload
+    Ephemeral,
+}
+
+const STORAGE_BASE: &str = "fledger";
+const STORAGE_CONFIG: &str = "nodeConfig";
+const SETTINGS_BASE: &str = "flbrowser";
+const SETTINGS_STORAGE_MODE: &str = "storage_mode";
+// Passes the identity to the ephemeral storage over the reload of the page.
+const SETTINGS_HANDOFF: &str = "identity_handoff";
+
+impl StorageMode {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "persistent" => Some(Self::Persistent),
+            "session" => Some(Self::Session),
+            "ephemeral" => Some(Self::Ephemeral),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Persistent => "persistent",
+            Self::Session => "session",
+            Self::Ephemeral => "ephemeral",
+        }
+    }
+
+    /// Returns the stored mode, or asks the user on the first run.
+    fn get() -> Self {
+        let settings = DataStorageLocal::new(SETTINGS_BASE);
+        if let Some(mode) = settings
+            .get(SETTINGS_STORAGE_MODE)
+            .ok()
+            .and_then(|m| Self::from_str(&m))
+        {
+            return mode;
+        }
+        // Existing users already have their identity in the localStorage.
+        let mode = if DataStorageLocal::new(STORAGE_BASE)
+            .get(STORAGE_CONFIG)
+            .is_ok_and(|c| !c.is_empty())
+            || window()
+                .unwrap()
+                .confirm_with_message(
+                    "Keep your fledger identity in this browser? \
+                    Choose 'Cancel' on a shared computer to remove it when the tab is closed.",
+                )
+                .unwrap_or(true)
+        {
+            Self::Persistent
+        } else {
+            Self::Session
+        };
+        mode.set();
+        mode
+    }
+
+    /// Copies the node configuration, which holds the keypair, to the storage of
+    /// the new mode, and only then switches to it.
+    /// If the configuration cannot be copied, the mode is not changed, so the
+    /// identity is never lost.
+    fn switch(&self, config: &str) -> Result<(), StorageError> {
+        match self {
+            Self::Ephemeral => DataStorageLocal::new_session(SETTINGS_BASE)
+                .set(SETTINGS_HANDOFF, config)?,
+            _ => self.data_storage().set(STORAGE_CONFIG, config)?,
+        }
+        self.set();
+        Ok(())
+    }
+
+    /// Stores the new mode. When leaving the persistent mode, the data in the
+    /// localStorage is removed.
+    fn set(&self) {
+        if let Err(e) =
+            DataStorageLocal::new(SETTINGS_BASE).set(SETTINGS_STORAGE_MODE, self.as_str())
+        {
+            log::error!("Couldn't store storage mode: {e}");
+        }
+        if self != &Self::Persistent {
+            if let Err(e) = DataStorageLocal::clear(STORAGE_BASE) {
+                log::error!("Couldn't clear the persistent storage: {e}");
+            }
+        }
+    }
+
+    fn data_storage(&self) -> Box<dyn DataStorage + Send> {
+        match self {
+            Self::Persistent => DataStorageLocal::new(STORAGE_BASE),
+            Self::Session => DataStorageLocal::new_session(STORAGE_BASE),
+            Self::Ephemeral => {
+                let mut storage = DataStorageTemp::new();
+                let mut handoff = DataStorageLocal::new_session(SETTINGS_BASE);
+                if let Ok(config) = handoff.get(SETTINGS_HANDOFF) {
+                    if !config.is_empty() {
+                        if let Err(e) = storage
+                            .set(STORAGE_CONFIG, &config)
+                            .and_then(|_| handoff.remove(SETTINGS_HANDOFF))
+                        {
+                            log::error!("Couldn't take over the identity: {e}");
+                        }
+                    }
+                }
+                Box::new(storage)
+            }
+        }
+    }
 }
 
 #[wasm_bindgen(module = "/src/main.js")]
@@ -59,12 +189,20 @@ pub fn main() {
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Button>();
         web.link_btn(tx.clone(), Button::SendMsg, "send_msg");
         web.link_btn(tx.clone(), Button::DownloadData, "get_data");
-        web.link_btn(tx, Button::WebProxy, "proxy_request");
+        web.link_btn(tx.clone(), Button::DownloadChat, "get_chat");
+        web.link_btn(tx.clone(), Button::WebProxy, "proxy_request");
+        web.link_btn(tx.clone(), Button::StorageMode, "storage_mode_save");
+        web.link_btn(tx.clone(), Button::Probe, "run_probe");
+        // Check the network once at startup
+        tx.send(Button::Probe).expect("Should start network probe");
 
         // Link to some elements
         let your_message: HtmlTextAreaElement = web.get_element("your_message");
         let proxy_div: HtmlDivElement = web.get_element("proxy_div");
         let proxy_url: HtmlInputElement = web.get_element("proxy_url");
+        let storage_mode: HtmlSelectElement = web.get_element("storage_mode");
+        let probe_div: HtmlDivElement = web.get_element("network_probe");
+        storage_mode.set_value(web.storage_mode.as_str());
         let webproxy = web.node.webproxy.as_mut().unwrap().clone();
 
         loop {
@@ -85,6 +223,15 @@ pub fn main() {
                         let data = web.node.gossip.as_ref().unwrap().storage.get().unwrap();
                         downloadFile("gossip_event.toml".into(), data.into());
                     }
+                    Button::DownloadChat => {
+                        let archive = web.node.gossip.as_ref().unwrap().export(
+                            &web.node.node_config,
+                            Category::TextMessage,
+                            0,
+                            i64::MAX,
+                        );
+                        downloadFile("chat_history.yaml".into(), archive.encode().unwrap().into());
+                    }
                     Button::WebProxy => {
                         let proxy_div = proxy_div.clone();
                         let proxy_url = proxy_url.value();
@@ -120,6 +267,44 @@ pub fn main() {
                             }
                         });
                     }
+                    Button::Probe => {
+                        let probe_div = probe_div.clone();
+                        let offset = web
+                            .node
+                            .ping
+                            .as_ref()
+                            .and_then(|p| p.storage.clock_offset_ms());
+                        spawn_local_nosend(async move {
+                            probe_div.set_inner_html("Checking the network...");
+                            let mut report =
+                                probe(&connection_config(), Duration::from_secs(5)).await;
+                            report.add_clock_offset(offset);
+                            report.log();
+                            let items: String = report
+                                .results
+                                .iter()
+                                .map(|r| format!("<li>{r}</li>"))
+                                .collect();
+                            probe_div.set_inner_html(&format!("<ul>{items}</ul>"));
+                        });
+                    }
+                    Button::StorageMode => {
+                        if let Some(mode) = StorageMode::from_str(&storage_mode.value()) {
+                            if mode != web.storage_mode {
+                                match mode.switch(&web.node.node_config.encode()) {
+                                    Ok(()) => window()
+                                        .unwrap()
+                                        .location()
+                                        .reload()
+                                        .expect("Should reload page"),
+                                    Err(e) => {
+                                        log::error!("Couldn't move the identity: {e}");
+                                        storage_mode.set_value(web.storage_mode.as_str());
+                                    }
+                                }
+                            }
+                        }
+                    }
                 }
             }
             if let Some(state) = web.tick().await {
@@ -156,27 +341,45 @@ fn update_table(web: &FledgerWeb, state: &FledgerState) -> Result<(), JsValue> {
     Ok(())
 }
 
+fn connection_config() -> ConnectionConfig {
+    ConnectionConfig::new(
+        Some(URL.into()),
+        None,
+        Some(HostLogin {
+            url: "turn:web.fledg.re:3478".into(),
+            login: Some(Login {
+                user: "something".into(),
+                pass: "something".into(),
+            }),
+        }),
+    )
+}
+
 /// FledgerWeb is a nearly generic handler for the FledgerNode.
 pub struct FledgerWeb {
     node: Node,
     document: Document,
     counter: u32,
+    storage_mode: StorageMode,
 }
 
 impl FledgerWeb {
     pub async fn new() -> Result<Self> {
         console_error_panic_hook::set_once();
-        FledgerWeb::set_data_storage();
+        let storage_mode = StorageMode::get();
+        let storage = storage_mode.data_storage();
+        FledgerWeb::set_data_storage(storage.clone());
 
         wasm_logger::init(wasm_logger::Config::new(log::Level::Debug));
-        log::info!("Starting new FledgerWeb on {URL}");
+        log::info!("Starting new FledgerWeb on {URL} with {storage_mode:?} storage");
 
         // Get a link to the document
         let window = web_sys::window().expect("no global `window` exists");
         Ok(Self {
-            node: Self::node_start().await?,
+            node: Self::node_start(storage).await?,
             document: window.document().expect("should have a document on window"),
             counter: 0u32,
+            storage_mode,
         })
     }
 
@@ -226,36 +429,23 @@ impl FledgerWeb {
         fs
     }
 
-    async fn node_start() -> Result<Node> {
-        let my_storage = DataStorageLocal::new("fledger");
+    async fn node_start(my_storage: Box<dyn DataStorage + Send>) -> Result<Node> {
         let mut node_config = Node::get_config(my_storage.clone())?;
-        let config = ConnectionConfig::new(
-            Some(URL.into()),
-            None,
-            Some(HostLogin {
-                url: "turn:web.fledg.re:3478".into(),
-                login: Some(Login {
-                    user: "something".into(),
-                    pass: "something".into(),
-                }),
-            }),
-        );
-        let network = network_broker_start(node_config.clone(), config).await?;
+        let network = network_broker_start(node_config.clone(), connection_config()).await?;
         node_config.info.modules = Modules::all() - Modules::ENABLE_WEBPROXY_REQUESTS;
         Ok(Node::start(my_storage, node_config, network)
             .await
             .map_err(|e| anyhow!("Couldn't create node: {:?}", e))?)
     }
 
-    fn set_data_storage() {
+    fn set_data_storage(storage: Box<dyn DataStorage + Send>) {
         if let Ok(loc) = window().unwrap().location().href() {
             if loc.contains('#') {
                 let reg = Regex::new(r".*?#").unwrap();
                 let data_enc = reg.replace(&loc, "");
                 if data_enc != "" {
                     if let Ok(data) = urlencoding::decode(&data_enc) {
-                        if let Err(err) = Node::set_config(DataStorageLocal::new("fledger"), &data)
-                        {
+                        if let Err(err) = Node::set_config(storage, &data) {
                             log::warn!("Got error while saving config: {}", err);
                         }
                     }
@@ -269,7 +459,7 @@ pub struct FledgerState {
     info: NodeInfo,
     nodes_info: HashMap<U256, NodeInfo>,
     states: HashMap<U256, NetworkConnectionState>,
-    pings: PingStorage,
+    pings: HashMap<U256, PingStat>,
     msgs: FledgerMessages,
     pub msgs_system: usize,
     pub msgs_local: usize,
@@ -310,17 +500,18 @@ impl FledgerState {
         let info = node.node_config.info.clone();
         let msgs = node.gossip.as_ref().unwrap().chat_events();
         let nodes_info = node.nodes_info_all()?;
+        let snapshot = node.snapshot().borrow().clone();
         Ok(Self {
             info,
-            nodes_online: node.nodes_online()?.len(),
-            nodes_connected: node.nodes_connected()?.len(),
+            nodes_online: snapshot.nodes_online,
+            nodes_connected: snapshot.nodes_connected,
             msgs_system: 0,
             msgs_local: msgs.len(),
             mana: 0,
             msgs: FledgerMessages::new(msgs, &nodes_info.clone().into_values().collect()),
             nodes_info,
-            states: node.stat.as_ref().unwrap().states.clone(),
-            pings: node.ping.as_ref().unwrap().storage.clone(),
+            states: snapshot.connections,
+            pings: snapshot.pings,
         })
     }
 
@@ -336,7 +527,7 @@ impl FledgerState {
                 .map(|s| format!("{:?}", s.s.type_local))
                 .unwrap_or("n/a".into());
             let info = ni.name.clone();
-            if let Some(ping) = self.pings.stats.get(id) {
+            if let Some(ping) = self.pings.get(id) {
                 out.push(NodeDesc {
                     info,
                     ping: ping.clone(),
@@ -356,6 +547,10 @@ impl FledgerState {
                     node.info,
                     format!("rx:{} tx:{}", node.ping.rx, node.ping.tx),
                     node.ping.lastping.to_string(),
+                    match (node.ping.rtt_ms, node.ping.jitter_ms) {
+                        (Some(rtt), Some(jitter)) => format!("{rtt}±{jitter} ms"),
+                        _ => "n/a".into(),
+                    },
                     node.stat,
                 ]
                 .join("</td><td>")
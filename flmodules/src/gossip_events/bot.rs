@@ -0,0 +1,233 @@
+//! Bots answering the chat messages, without handling the gossip events directly.
+//!
+//! A [`Bot`] has handlers for commands like `/weather Lausanne`, and for mentions
+//! like `@weather`.
+//! Once added with [`super::broker::GossipBroker::add_bot`], it gets every new
+//! chat message, and the replies of the handlers are sent as new chat messages.
+//! Every node can only trigger [`Bot::replies_per_minute`] replies per minute, so
+//! a bot cannot be used to flood the chat.
+
+use std::collections::{HashMap, HashSet};
+
+use flarch::{
+    broker::SubsystemHandler,
+    nodeids::{NodeID, U256},
+    platform_async_trait,
+    tasks::now,
+};
+
+use crate::nodeconfig::NodeConfig;
+
+use super::{
+    core::{Category, Event},
+    messages::{GossipIn, GossipMessage, GossipOut},
+};
+
+/// What a handler answers to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    /// A message starting with `/name`, the rest of the message are the arguments.
+    Command(String),
+    /// A message containing `@name`, the whole message are the arguments.
+    Mention(String),
+}
+
+/// A chat message which triggered a handler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub event: Event,
+    pub args: String,
+}
+
+/// Returns the reply to a request, if any.
+pub type Handler = Box<dyn FnMut(&Request) -> Option<String> + Send>;
+
+pub struct Bot {
+    /// How many replies a single node can trigger per minute.
+    pub replies_per_minute: usize,
+    id: NodeID,
+    node_config: NodeConfig,
+    started: i64,
+    handlers: Vec<(Trigger, Handler)>,
+    seen: HashSet<U256>,
+    replies: HashMap<NodeID, Vec<i64>>,
+}
+
+impl Bot {
+    /// Creates a bot replying as the node of `node_config`, which signs the replies.
+    /// Only the messages created after this call are answered.
+    pub fn new(node_config: NodeConfig) -> Self {
+        Self {
+            replies_per_minute: 5,
+            id: node_config.info.get_id(),
+            node_config,
+            started: now(),
+            handlers: vec![],
+            seen: HashSet::new(),
+            replies: HashMap::new(),
+        }
+    }
+
+    /// Answers the messages starting with `/name`.
+    pub fn command(
+        mut self,
+        name: &str,
+        handler: impl FnMut(&Request) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.handlers
+            .push((Trigger::Command(name.into()), Box::new(handler)));
+        self
+    }
+
+    /// Answers the messages mentioning `@name`.
+    pub fn mention(
+        mut self,
+        name: &str,
+        handler: impl FnMut(&Request) -> Option<String> + Send + 'static,
+    ) -> Self {
+        self.handlers
+            .push((Trigger::Mention(name.into()), Box::new(handler)));
+        self
+    }
+
+    /// Runs the handlers on the new chat messages in `events`, and returns the replies
+    /// as new events created at `time`.
+    /// Messages of this node, messages created before the bot, and messages already
+    /// processed are ignored.
+    pub fn process(&mut self, events: Vec<Event>, time: i64) -> Vec<Event> {
+        self.seen
+            .retain(|id| events.iter().any(|ev| &ev.get_id() == id));
+        let mut out = vec![];
+        for event in events {
+            if event.category != Category::TextMessage
+                || event.src == self.id
+                || event.created < self.started
+                || !self.seen.insert(event.get_id())
+            {
+                continue;
+            }
+            let Some(reply) = self.handle(&event) else {
+                continue;
+            };
+            let replies = self.replies.entry(event.src).or_default();
+            replies.retain(|t| *t > time - 60_000);
+            if replies.len() >= self.replies_per_minute {
+                log::warn!("Too many requests from {}, not replying", event.src);
+                continue;
+            }
+            replies.push(time);
+            out.push(
+                Event {
+                    category: Category::TextMessage,
+                    src: self.id,
+                    created: time,
+                    msg: reply,
+                    signature: None,
+                }
+                .signed(&self.node_config),
+            );
+        }
+        out
+    }
+
+    fn handle(&mut self, event: &Event) -> Option<String> {
+        let (handler, args) = self
+            .handlers
+            .iter_mut()
+            .find_map(|(trigger, handler)| trigger.args(&event.msg).map(|args| (handler, args)))?;
+        handler(&Request {
+            event: event.clone(),
+            args,
+        })
+    }
+}
+
+impl Trigger {
+    /// Returns the arguments if the message triggers this handler.
+    fn args(&self, msg: &str) -> Option<String> {
+        match self {
+            Trigger::Command(name) => {
+                let rest = msg
+                    .trim_start()
+                    .strip_prefix('/')?
+                    .strip_prefix(name.as_str())?;
+                (rest.is_empty() || rest.starts_with(char::is_whitespace))
+                    .then(|| rest.trim().to_string())
+            }
+            Trigger::Mention(name) => msg
+                .split_whitespace()
+                .filter_map(|word| word.strip_prefix('@'))
+                .any(|word| word.trim_end_matches(|c: char| c.is_ascii_punctuation()) == name)
+                .then(|| msg.to_string()),
+        }
+    }
+}
+
+#[platform_async_trait()]
+impl SubsystemHandler<GossipMessage> for Bot {
+    async fn messages(&mut self, msgs: Vec<GossipMessage>) -> Vec<GossipMessage> {
+        let mut out = vec![];
+        for msg in msgs {
+            if let GossipMessage::Output(GossipOut::Storage(storage)) = msg {
+                out.extend(
+                    self.process(storage.events(Category::TextMessage), now())
+                        .into_iter()
+                        .map(|ev| GossipIn::AddEvent(ev).into()),
+                );
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chat(src: NodeID, created: i64, msg: &str) -> Event {
+        Event {
+            category: Category::TextMessage,
+            src,
+            created,
+            msg: msg.into(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_bot() {
+        let (nc, user) = (NodeConfig::new(), NodeID::rnd());
+        let id = nc.info.get_id();
+        let mut bot = Bot::new(nc)
+            .command("weather", |req| Some(format!("Sunny in {}", req.args)))
+            .mention("poll", |_| Some("Poll started".into()));
+        let time = bot.started;
+
+        let mut events = vec![
+            chat(user, time - 1, "/weather Old"),
+            chat(user, time, "/weather Lausanne"),
+            chat(user, time, "/weathervane"),
+            chat(user, time + 1, "Hey @poll, lunch?"),
+            chat(user, time + 2, "@pollster"),
+            chat(id, time + 3, "/weather Home"),
+        ];
+        let replies = bot.process(events.clone(), time + 10);
+        assert!(replies.iter().all(|ev| ev.src == id && ev.verify()));
+        assert_eq!(
+            vec!["Sunny in Lausanne", "Poll started"],
+            replies.into_iter().map(|ev| ev.msg).collect::<Vec<_>>()
+        );
+        assert!(bot.process(events.clone(), time + 10).is_empty());
+
+        // Only 5 replies per minute for every node.
+        events = (0..10)
+            .map(|i| chat(user, time + 10 + i, "/weather Bern"))
+            .collect();
+        assert_eq!(3, bot.process(events.clone(), time + 20).len());
+        events.push(chat(user, time + 30, "/weather Bern"));
+        assert!(bot.process(events.clone(), time + 30).is_empty());
+        events.push(chat(NodeID::rnd(), time + 40, "/weather Bern"));
+        events.push(chat(user, time + 50, "/weather Bern"));
+        assert_eq!(2, bot.process(events, time + 60_011).len());
+    }
+}
@@ -4,13 +4,17 @@ use flarch::{
     broker::{Broker, BrokerError, Subsystem, SubsystemHandler},
     nodeids::{NodeID, U256},
     platform_async_trait,
+    tasks::now,
+    web_rtc::messages::Channel,
 };
 
 use super::{
-    core::{Category, Event, EventsStorage},
-    messages::{Config, GossipEvents, GossipIn, GossipMessage, GossipOut},
+    bot::Bot,
+    core::{Category, Event, EventsArchive, EventsLimits, EventsStorage},
+    messages::{Config, GossipEvents, GossipIn, GossipMessage, GossipOut, ModuleMessage},
 };
 use crate::{
+    nodeconfig::NodeConfig,
     overlay::messages::NetworkWrapper,
     random_connections::messages::{RandomIn, RandomMessage, RandomOut},
     timer::TimerMessage,
@@ -66,6 +70,21 @@ impl GossipBroker {
         Ok(())
     }
 
+    /// Changes the quotas and the eviction policy of the storage.
+    pub async fn set_limits(&mut self, limits: EventsLimits) -> Result<(), BrokerError> {
+        self.broker
+            .emit_msg(GossipMessage::Input(GossipIn::SetLimits(limits)))?;
+        Ok(())
+    }
+
+    /// Adds a bot which answers the new chat messages.
+    pub async fn add_bot(&mut self, bot: Bot) -> Result<(), BrokerError> {
+        self.broker
+            .add_subsystem(Subsystem::Handler(Box::new(bot)))
+            .await?;
+        Ok(())
+    }
+
     /// Gets a copy of all chat events stored in the module.
     pub fn chat_events(&self) -> Vec<Event> {
         self.storage.events(Category::TextMessage)
@@ -85,6 +104,32 @@ impl GossipBroker {
     pub fn events(&self, cat: Category) -> Vec<Event> {
         self.storage.events(cat)
     }
+
+    /// Returns an archive of the events of the category created in the range
+    /// `from..to`, signed by this node.
+    pub fn export(
+        &self,
+        node_config: &NodeConfig,
+        cat: Category,
+        from: i64,
+        to: i64,
+    ) -> EventsArchive {
+        EventsArchive::new(node_config, now(), self.storage.export(cat, from, to))
+    }
+
+    /// Adds the valid events of a verified archive which are not stored yet, and
+    /// returns how many events have been added.
+    /// The new events will automatically be propagated to all connected nodes.
+    pub async fn import(&mut self, archive: &EventsArchive) -> Result<usize, BrokerError> {
+        let mut added = 0;
+        for event in archive.valid_events() {
+            if self.storage.event(&event.get_id()).is_none() {
+                self.add_event(event).await?;
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
 }
 
 struct Translate {
@@ -120,7 +165,7 @@ impl Translate {
             match msg_out {
                 RandomOut::NodeIDsConnected(list) => Some(GossipIn::NodeList(list.into()).into()),
                 RandomOut::NetworkWrapperFromNetwork(id, msg) => msg
-                    .unwrap_yaml(MODULE_NAME)
+                    .unwrap(MODULE_NAME)
                     .map(|msg| GossipIn::FromNetwork(id, msg).into()),
                 _ => None,
             }
@@ -129,12 +174,20 @@ impl Translate {
         }
     }
 
+    // The events go over the bulk channel, so a big synchronization doesn't
+    // delay the other messages.
     fn link_gossip_rnd(msg: GossipMessage) -> Option<RandomMessage> {
         if let GossipMessage::Output(GossipOut::ToNetwork(id, msg_node)) = msg {
+            let channel = match msg_node {
+                ModuleMessage::Events(_) => Channel::Bulk,
+                _ => Channel::Control,
+            };
             Some(
                 RandomIn::NetworkMapperToNetwork(
                     id,
-                    NetworkWrapper::wrap_yaml(MODULE_NAME, &msg_node).unwrap(),
+                    NetworkWrapper::wrap(MODULE_NAME, &msg_node)
+                        .unwrap()
+                        .on_channel(channel),
                 )
                 .into(),
             )
@@ -185,6 +238,7 @@ mod tests {
 
     use crate::gossip_events::core::{Category, Event};
     use crate::gossip_events::messages::ModuleMessage;
+    use crate::nodeconfig::NodeConfig;
     use flarch::nodeids::NodeID;
     use flarch::{start_logging, tasks::now};
 
@@ -198,7 +252,8 @@ mod tests {
         let mut broker_rnd = Broker::new();
         let mut gossip = GossipBroker::start(id, broker_rnd.clone()).await?;
 
-        let id2 = NodeID::rnd();
+        let nc2 = NodeConfig::new();
+        let id2 = nc2.info.get_id();
         let (tap_rnd, _) = broker_rnd.get_tap_sync().await?;
         broker_rnd
             .settle_msg(RandomMessage::Output(RandomOut::NodeIDsConnected(
@@ -212,13 +267,15 @@ mod tests {
             src: id2,
             created: now(),
             msg: "test_msg".into(),
-        };
+            signature: None,
+        }
+        .signed(&nc2);
         let msg = ModuleMessage::Events(vec![event.clone()]);
         broker_rnd
             .settle_msg(
                 RandomOut::NetworkWrapperFromNetwork(
                     id2,
-                    NetworkWrapper::wrap_yaml(MODULE_NAME, &msg).unwrap(),
+                    NetworkWrapper::wrap(MODULE_NAME, &msg).unwrap(),
                 )
                 .into(),
             )
@@ -231,6 +288,39 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_bot() -> Result<(), Box<dyn Error>> {
+        let nc = NodeConfig::new();
+        let id = nc.info.get_id();
+        let mut gossip = GossipBroker::start(id, Broker::new()).await?;
+        gossip
+            .add_bot(Bot::new(nc).command("echo", |req| Some(req.args.clone())))
+            .await?;
+
+        let user = NodeConfig::new();
+        let event = Event {
+            category: Category::TextMessage,
+            src: user.info.get_id(),
+            created: now(),
+            msg: "/echo hello".into(),
+            signature: None,
+        }
+        .signed(&user);
+        gossip
+            .broker
+            .settle_msg(GossipIn::AddEvent(event).into())
+            .await?;
+        gossip.update();
+        let replies: Vec<Event> = gossip
+            .chat_events()
+            .into_iter()
+            .filter(|ev| ev.src == id)
+            .collect();
+        assert_eq!(1, replies.len());
+        assert_eq!("hello", replies[0].msg);
+        Ok(())
+    }
+
     fn assert_msg_reid(tap: &Receiver<RandomMessage>, id2: &NodeID) -> Result<(), Box<dyn Error>> {
         for msg in tap.try_iter() {
             if let RandomMessage::Input(RandomIn::NetworkMapperToNetwork(id, msg_mod)) = msg {
@@ -1,16 +1,33 @@
+use ed25519_compact::{PublicKey, Signature};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use thiserror::Error;
 
 use flarch::nodeids::{NodeID, U256};
 
+use crate::nodeconfig::{NodeConfig, NodeInfo};
+
 /// This holds a number of Events from of different categories. Every category can
 /// have its own configuration with regard of whether its events are unique to a
 /// node (e.g., NodeInfo), or can be many per node (e.g., TextMessage).
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct EventsStorage {
     storage: HashMap<Category, Events>,
+    /// Maximum number of events over all categories.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_total: Option<usize>,
+    #[serde(default, skip_serializing_if = "Eviction::is_oldest_first")]
+    eviction: Eviction,
+    /// How many events have been evicted in each category since the start.
+    #[serde(skip)]
+    evicted: HashMap<Category, u64>,
+    /// How many events without a valid signature have been dropped in each
+    /// category since the start.
+    #[serde(skip)]
+    invalid: HashMap<Category, u64>,
 }
 
 impl EventsStorage {
@@ -37,15 +54,117 @@ impl EventsStorage {
                 events: HashMap::new(),
             },
         );
-        Self { storage }
+        Self {
+            storage,
+            max_total: None,
+            eviction: Eviction::default(),
+            evicted: HashMap::new(),
+            invalid: HashMap::new(),
+        }
     }
 
+    /// Adds the event and returns true if it is new and still stored after
+    /// the limits have been applied.
+    /// Events without a valid signature from their `src` are dropped.
     pub fn add_event(&mut self, msg: Event) -> bool {
-        let mut modified = false;
-        self.storage.entry(msg.category).and_modify(|msgs| {
-            modified = msgs.insert(msg);
-        });
-        modified
+        let (cat, id) = (msg.category, msg.get_id());
+        let Some(msgs) = self.storage.get_mut(&cat) else {
+            return false;
+        };
+        if !msg.verify() {
+            *self.invalid.entry(cat).or_default() += 1;
+            return false;
+        }
+        if !msgs.insert(msg) {
+            return false;
+        }
+        self.limit();
+        self.storage
+            .get(&cat)
+            .is_some_and(|msgs| msgs.events.contains_key(&id))
+    }
+
+    /// Changes the limits of the storage, and evicts the events which don't
+    /// fit anymore.
+    pub fn set_limits(&mut self, limits: EventsLimits) {
+        for (cat, max_events) in limits.quotas {
+            if let Some(msgs) = self.storage.get_mut(&cat) {
+                msgs.config.max_events = max_events;
+            }
+        }
+        self.max_total = limits.max_total;
+        self.eviction = limits.eviction;
+        self.limit();
+    }
+
+    /// Returns the current limits of the storage.
+    pub fn limits(&self) -> EventsLimits {
+        EventsLimits {
+            quotas: self
+                .storage
+                .iter()
+                .map(|(cat, msgs)| (*cat, msgs.config.max_events))
+                .collect(),
+            max_total: self.max_total,
+            eviction: self.eviction,
+        }
+    }
+
+    /// Removes the events of the category created before `time`, except the
+    /// events of the nodes in `keep`.
+    /// Returns how many events have been removed.
+    pub fn remove_before(&mut self, cat: Category, time: i64, keep: &[NodeID]) -> usize {
+        let Some(msgs) = self.storage.get_mut(&cat) else {
+            return 0;
+        };
+        let before = msgs.events.len();
+        msgs.events
+            .retain(|_, ev| ev.created >= time || keep.contains(&ev.src));
+        before - msgs.events.len()
+    }
+
+    /// Returns how many events have been evicted in each category.
+    pub fn evicted(&self) -> HashMap<Category, u64> {
+        self.evicted.clone()
+    }
+
+    /// Returns how many events without a valid signature have been dropped
+    /// in each category.
+    pub fn invalid(&self) -> HashMap<Category, u64> {
+        self.invalid.clone()
+    }
+
+    // Applies the quotas of all categories, then the maximum number of events
+    // over all categories.
+    fn limit(&mut self) {
+        for (cat, msgs) in self.storage.iter_mut() {
+            let removed = msgs.limit();
+            if removed > 0 {
+                *self.evicted.entry(*cat).or_default() += removed as u64;
+            }
+        }
+        let Some(max_total) = self.max_total else {
+            return;
+        };
+        let mut events: Vec<(U256, Category, i64)> = self
+            .storage
+            .values()
+            .flat_map(|msgs| msgs.events.iter())
+            .map(|(id, ev)| (*id, ev.category, ev.created))
+            .collect();
+        match self.eviction {
+            Eviction::OldestFirst => events.sort_by_key(|&(_, _, created)| created),
+            Eviction::LowestPriorityFirst => {
+                events.sort_by_key(|&(_, cat, created)| (cat.priority(), created))
+            }
+        }
+        let remove = events.len().saturating_sub(max_total);
+        for (id, cat, _) in events.into_iter().take(remove) {
+            if let Some(msgs) = self.storage.get_mut(&cat) {
+                msgs.events.remove(&id);
+            }
+            *self.evicted.entry(cat).or_default() += 1;
+        }
     }
 
     pub fn event(&self, id: &U256) -> Option<Event> {
@@ -81,12 +200,25 @@ impl EventsStorage {
             .collect()
     }
 
+    /// Returns all events of the category created in the range `from..to`,
+    /// sorted by creation time.
+    pub fn export(&self, cat: Category, from: i64, to: i64) -> Vec<Event> {
+        self.events(cat)
+            .into_iter()
+            .filter(|ev| (from..to).contains(&ev.created))
+            .sorted_by_key(|ev| ev.created)
+            .collect()
+    }
+
     pub fn get(&self) -> Result<String, serde_yaml::Error> {
         serde_yaml::to_string(&EventsStorageSave::V4(self.clone()))
     }
 
     pub fn set(&mut self, data: &str) -> Result<(), serde_yaml::Error> {
-        self.storage = EventsStorageSave::from_str(data)?.storage;
+        let es = EventsStorageSave::from_str(data)?;
+        self.storage = es.storage;
+        self.max_total = es.max_total;
+        self.eviction = es.eviction;
         Ok(())
     }
 }
@@ -159,10 +291,9 @@ impl Events {
         self.insert_simple(msg)
     }
 
-    // Insert the message in the events and make sure the limits are kept.
+    // Insert the message in the events. The limits are kept by the EventsStorage.
     fn insert_simple(&mut self, msg: Event) -> bool {
         self.events.insert(msg.get_id(), msg);
-        self.limit();
         true
     }
 
@@ -187,10 +318,11 @@ impl Events {
     }
 
     // Ensures that there are no more than max_events stored.
-    // Deletes the oldest messages if there are more.
-    fn limit(&mut self) {
+    // Deletes the oldest messages if there are more, and returns how many
+    // messages have been deleted.
+    fn limit(&mut self) -> usize {
         if self.events.len() <= self.config.max_events {
-            return;
+            return 0;
         }
         let ids: Vec<U256> = self
             .events
@@ -201,9 +333,90 @@ impl Events {
             .map(|(k, _)| k)
             .cloned()
             .collect();
-        for id in ids {
-            self.events.remove(&id);
+        for id in &ids {
+            self.events.remove(id);
         }
+        ids.len()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error("The signature of the archive doesn't match")]
+    InvalidSignature,
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+/// A list of events signed by the node which exported them.
+/// It can be stored outside of fledger, and later be verified and imported
+/// again by any node.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EventsArchive {
+    pub signer: NodeInfo,
+    pub created: i64,
+    pub events: Vec<Event>,
+    #[serde_as(as = "Base64")]
+    pub signature: Vec<u8>,
+}
+
+impl EventsArchive {
+    /// Creates a new archive of the events, signed by the node.
+    pub fn new(node_config: &NodeConfig, created: i64, events: Vec<Event>) -> Self {
+        let mut archive = Self {
+            signer: node_config.info.clone(),
+            created,
+            events,
+            signature: vec![],
+        };
+        archive.signature = node_config.sign(archive.hash());
+        archive
+    }
+
+    /// Returns true if the archive has been signed by the signer.
+    pub fn verify(&self) -> bool {
+        self.signer.verify(&self.hash(), &self.signature)
+    }
+
+    /// Returns the events which are signed by the node which created them.
+    /// The signature of the archive only tells who exported the events, so
+    /// every event has to be verified before it is imported.
+    pub fn valid_events(&self) -> Vec<Event> {
+        self.events
+            .iter()
+            .filter(|ev| {
+                let valid = ev.verify();
+                if !valid {
+                    log::warn!("Skipping event {} with an invalid signature", ev.get_id());
+                }
+                valid
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn encode(&self) -> Result<String, ArchiveError> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// Decodes an archive and verifies its signature.
+    pub fn decode(data: &str) -> Result<Self, ArchiveError> {
+        let archive: Self = serde_yaml::from_str(data)?;
+        if !archive.verify() {
+            return Err(ArchiveError::InvalidSignature);
+        }
+        Ok(archive)
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(self.signer.get_id());
+        hash.update(self.created.to_le_bytes());
+        for ev in &self.events {
+            hash.update(ev.get_id());
+        }
+        hash.finalize().into()
     }
 }
 
@@ -213,6 +426,46 @@ pub enum Category {
     NodeInfo,
 }
 
+impl Category {
+    /// Events of categories with a lower priority are evicted first with
+    /// [`Eviction::LowestPriorityFirst`].
+    pub fn priority(&self) -> u8 {
+        match self {
+            Category::TextMessage => 0,
+            Category::NodeInfo => 1,
+        }
+    }
+}
+
+/// Which events are evicted if there are more than `max_total` events in the
+/// storage.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Eviction {
+    /// The oldest events of all categories are evicted first.
+    #[default]
+    OldestFirst,
+    /// The events of the category with the lowest priority are evicted first,
+    /// starting with the oldest.
+    LowestPriorityFirst,
+}
+
+impl Eviction {
+    fn is_oldest_first(&self) -> bool {
+        self == &Eviction::OldestFirst
+    }
+}
+
+/// The limits of the [`EventsStorage`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct EventsLimits {
+    /// Maximum number of events per category. Categories which are not
+    /// listed keep their current quota.
+    pub quotas: HashMap<Category, usize>,
+    /// Maximum number of events over all categories.
+    pub max_total: Option<usize>,
+    pub eviction: Eviction,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CategoryConfig {
     // Only one event per node
@@ -221,12 +474,19 @@ pub struct CategoryConfig {
     max_events: usize,
 }
 
+/// An event created and signed by the `src` node.
+/// Events stored before the signatures were introduced have no `signature`:
+/// they are still loaded, but not accepted anymore from other nodes.
+#[serde_as]
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub category: Category,
     pub src: NodeID,
     pub created: i64,
     pub msg: String,
+    #[serde_as(as = "Option<Base64>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Vec<u8>>,
 }
 
 impl Event {
@@ -238,6 +498,30 @@ impl Event {
         id.update(&self.msg);
         id.finalize().into()
     }
+
+    /// Returns the event signed by the node, which must be the `src` of the event.
+    pub fn signed(mut self, node_config: &NodeConfig) -> Self {
+        self.signature = Some(node_config.sign(self.hash()));
+        self
+    }
+
+    /// Returns true if the event has a signature from the `src` node.
+    pub fn verify(&self) -> bool {
+        let Some(signature) = &self.signature else {
+            return false;
+        };
+        let Ok(pubkey) = PublicKey::from_slice(self.src.as_ref()) else {
+            return false;
+        };
+        Signature::from_slice(signature).is_ok_and(|sig| pubkey.verify(self.hash(), &sig).is_ok())
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(b"fledger gossip event");
+        hash.update(self.get_id());
+        hash.finalize().into()
+    }
 }
 
 // Migration events.
@@ -313,6 +597,7 @@ impl EventsStorageV2 {
                 .into_iter()
                 .map(|(k, v)| (k, v.to_latest()))
                 .collect(),
+            ..Default::default()
         }
     }
 }
@@ -343,6 +628,7 @@ impl EventsStorageV3 {
                     )
                 })
                 .collect(),
+            ..Default::default()
         }
     }
 }
@@ -366,6 +652,7 @@ impl EventsV2 {
                         src: v.src,
                         created: v.created as i64,
                         msg: v.msg,
+                        signature: None,
                     };
                     (e.get_id(), e)
                 })
@@ -390,6 +677,19 @@ mod tests {
 
     use super::*;
 
+    // Returns an event signed by a new node.
+    fn signed_event(category: Category, created: i64, msg: &str) -> Event {
+        let nc = NodeConfig::new();
+        Event {
+            category,
+            src: nc.info.get_id(),
+            created,
+            msg: msg.into(),
+            signature: None,
+        }
+        .signed(&nc)
+    }
+
     #[test]
     fn test_double_unique() {
         let mut evs = Events {
@@ -405,12 +705,14 @@ mod tests {
             src: NodeID::rnd(),
             created: 1,
             msg: "foo".into(),
+            signature: None,
         };
         let e2 = Event {
             category: Category::NodeInfo,
             src: e1.src,
             created: 2,
             msg: "bar".into(),
+            signature: None,
         };
 
         assert_eq!(0, evs.events.len());
@@ -438,12 +740,14 @@ mod tests {
             src: NodeID::rnd(),
             created: 1,
             msg: "foo".into(),
+            signature: None,
         };
         let e2 = Event {
             category: Category::NodeInfo,
             src: e1.src,
             created: 2,
             msg: "bar".into(),
+            signature: None,
         };
 
         assert_eq!(0, evs.events.len());
@@ -467,15 +771,150 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_archive() -> Result<(), Box<dyn Error>> {
+        let mut es = EventsStorage::default();
+        let nc = NodeConfig::new();
+        for created in [30, 10, 20, 40] {
+            es.add_event(
+                Event {
+                    category: Category::TextMessage,
+                    src: nc.info.get_id(),
+                    created,
+                    msg: format!("msg {created}"),
+                    signature: None,
+                }
+                .signed(&nc),
+            );
+        }
+        let events = es.export(Category::TextMessage, 10, 40);
+        assert_eq!(
+            vec![10, 20, 30],
+            events.iter().map(|ev| ev.created).collect::<Vec<_>>()
+        );
+
+        let archive = EventsArchive::new(&nc, 50, events.clone());
+        assert_eq!(archive, EventsArchive::decode(&archive.encode()?)?);
+        assert_eq!(events, archive.valid_events());
+
+        // A correctly signed archive can still hold events which are not signed
+        // by their creator.
+        let mut tampered = events[0].clone();
+        tampered.msg = "tampered".into();
+        let other = NodeConfig::new();
+        let wrong_signer = events[2].clone().signed(&other);
+        let mixed = EventsArchive::new(&other, 50, vec![tampered, events[1].clone(), wrong_signer]);
+        assert!(mixed.verify());
+        assert_eq!(vec![events[1].clone()], mixed.valid_events());
+
+        let mut forged = archive.clone();
+        forged.events[0].msg = "forged".into();
+        assert!(matches!(
+            EventsArchive::decode(&forged.encode()?),
+            Err(ArchiveError::InvalidSignature)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_event() -> Result<(), Box<dyn Error>> {
+        let nc = NodeConfig::new();
+        let unsigned = Event {
+            category: Category::TextMessage,
+            src: nc.info.get_id(),
+            created: 0,
+            msg: "signed".into(),
+            signature: None,
+        };
+        let signed = unsigned.clone().signed(&nc);
+        assert!(!unsigned.verify());
+        assert!(signed.verify());
+        assert_eq!(unsigned.get_id(), signed.get_id());
+        assert_eq!(
+            signed,
+            serde_yaml::from_str(&serde_yaml::to_string(&signed)?)?
+        );
+
+        let mut forged = signed.clone();
+        forged.msg = "forged".into();
+        let impersonated = Event {
+            msg: "impersonated".into(),
+            ..unsigned.clone()
+        }
+        .signed(&NodeConfig::new());
+
+        let mut es = EventsStorage::default();
+        assert!(!es.add_event(unsigned));
+        assert!(!es.add_event(forged));
+        assert!(!es.add_event(impersonated));
+        assert!(es.add_event(signed));
+        assert_eq!(Some(&3), es.invalid().get(&Category::TextMessage));
+        assert_eq!(1, es.events(Category::TextMessage).len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<(), Box<dyn Error>> {
+        let mut es = EventsStorage::default();
+        let event = |category, created| signed_event(category, created, "msg");
+        for created in 0..5 {
+            es.add_event(event(Category::TextMessage, created));
+            es.add_event(event(Category::NodeInfo, created + 10));
+        }
+
+        es.set_limits(EventsLimits {
+            quotas: [(Category::TextMessage, 3)].into(),
+            ..Default::default()
+        });
+        assert_eq!(3, es.events(Category::TextMessage).len());
+        assert_eq!(Some(&2), es.evicted().get(&Category::TextMessage));
+        assert!(!es.add_event(event(Category::TextMessage, 1)));
+
+        es.set_limits(EventsLimits {
+            max_total: Some(6),
+            ..Default::default()
+        });
+        assert_eq!(1, es.events(Category::TextMessage).len());
+        assert_eq!(5, es.events(Category::NodeInfo).len());
+
+        es.set_limits(EventsLimits {
+            max_total: Some(4),
+            eviction: Eviction::LowestPriorityFirst,
+            ..Default::default()
+        });
+        assert_eq!(0, es.events(Category::TextMessage).len());
+        assert_eq!(4, es.events(Category::NodeInfo).len());
+        assert_eq!(Some(&6), es.evicted().get(&Category::TextMessage));
+        assert_eq!(Some(&1), es.evicted().get(&Category::NodeInfo));
+
+        let mut es_loaded = EventsStorage::default();
+        es_loaded.set(&es.get()?)?;
+        assert_eq!(es.limits(), es_loaded.limits());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_before() {
+        let mut es = EventsStorage::default();
+        let events: Vec<Event> = (0..3)
+            .map(|created| signed_event(Category::NodeInfo, created, "info"))
+            .collect();
+        let keep = events[1].src;
+        for event in events {
+            es.add_event(event);
+        }
+        es.add_event(signed_event(Category::TextMessage, 0, "msg"));
+
+        assert_eq!(1, es.remove_before(Category::NodeInfo, 2, &[keep]));
+        assert_eq!(2, es.events(Category::NodeInfo).len());
+        assert_eq!(1, es.events(Category::TextMessage).len());
+        assert_eq!(2, es.remove_before(Category::NodeInfo, 3, &[]));
+    }
+
     impl EventsStorage {
         fn test() -> Self {
             let mut es = EventsStorage::default();
-            es.add_event(Event {
-                category: Category::NodeInfo,
-                src: NodeID::rnd(),
-                created: now(),
-                msg: "Some info here".into(),
-            });
+            es.add_event(signed_event(Category::NodeInfo, now(), "Some info here"));
             es
         }
     }
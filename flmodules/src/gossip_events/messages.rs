@@ -25,6 +25,10 @@ pub enum GossipIn {
     GetStorage,
     AddEvent(Event),
     NodeList(NodeIDs),
+    SetLimits(EventsLimits),
+    /// Removes the events of the category created before the given time,
+    /// except the events of the given nodes.
+    RemoveBefore(Category, i64, Vec<NodeID>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -94,6 +98,16 @@ impl GossipEvents {
                 self.storage = data;
                 vec![]
             }
+            GossipIn::SetLimits(limits) => {
+                self.storage.set_limits(limits);
+                vec![GossipOut::Storage(self.storage.clone())]
+            }
+            GossipIn::RemoveBefore(cat, time, keep) => {
+                if self.storage.remove_before(cat, time, &keep) == 0 {
+                    return Ok(vec![]);
+                }
+                vec![GossipOut::Storage(self.storage.clone())]
+            }
         })
     }
 
@@ -1,3 +1,4 @@
+pub mod bot;
 pub mod broker;
 pub mod core;
 pub mod messages;
@@ -12,6 +12,41 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Optional features of the connection between two nodes.
+    /// A node only uses a feature with another node if both announce it.
+    #[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Hash)]
+    pub struct Capabilities: u32 {
+        /// Big messages are compressed, see [`overlay::compression`].
+        const COMPRESS_DEFLATE = 0x1;
+        /// Web proxy responses wait for acknowledgments of the requester,
+        /// see [`web_proxy::core::STREAM_WINDOW`].
+        const WEBPROXY_STREAM = 0x2;
+        /// Big messages are compressed with zstd, see [`overlay::compression`].
+        /// Not available in wasm.
+        const COMPRESS_ZSTD = 0x4;
+        /// Messages are encrypted end-to-end, see [`overlay::encryption`].
+        /// Only announced if the node enables it.
+        const ENCRYPT = 0x8;
+        /// The WebRTC connections can open more than one data channel,
+        /// see [`flarch::web_rtc::messages::Channel`].
+        const WEBRTC_CHANNELS = 0x10;
+    }
+}
+
+impl Capabilities {
+    /// Returns the capabilities this node can announce on the current platform.
+    /// Optional capabilities like [`Self::ENCRYPT`] are not included.
+    pub fn supported() -> Self {
+        if cfg!(target_family = "wasm") {
+            Self::all() - Self::COMPRESS_ZSTD - Self::ENCRYPT
+        } else {
+            Self::all() - Self::ENCRYPT
+        }
+    }
+}
+
+mod macros;
 pub mod nodeconfig;
 pub mod template;
 pub mod random_connections;
@@ -21,3 +56,5 @@ pub mod ping;
 pub mod web_proxy;
 pub mod network;
 pub mod overlay;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
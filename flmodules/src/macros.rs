@@ -0,0 +1,132 @@
+//! Macros to write the glue of a new module, see [`crate::template`] for how
+//! to use them.
+
+/// Creates the message enum `$message` with the two variants `Input($in)` and
+/// `Output($out)`, and the `From` implementations for both.
+#[macro_export]
+macro_rules! module_message {
+    ($(#[$meta:meta])* $vis:vis $message:ident, $in:ident, $out:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug)]
+        $vis enum $message {
+            Input($in),
+            Output($out),
+        }
+
+        /// Convenience method to reduce long lines.
+        impl From<$in> for $message {
+            fn from(msg: $in) -> Self {
+                $message::Input(msg)
+            }
+        }
+
+        /// Convenience method to reduce long lines.
+        impl From<$out> for $message {
+            fn from(msg: $out) -> Self {
+                $message::Output(msg)
+            }
+        }
+    };
+}
+
+/// Creates the `$translate` structure, which links a module to the
+/// [`crate::random_connections`] module.
+/// Its `start(random, messages)` method returns the broker of the module:
+/// - the `$in` messages are given to `messages.process_messages`, and the returned
+///   `$out` messages are emitted
+/// - the connected nodes are sent as `$in::UpdateNodeList(NodeIDs)`
+/// - the messages from other nodes for the module `$name` are sent as
+///   `$in::FromNetwork(NodeID, ModuleMessage)`
+/// - `$out::ToNetwork(NodeID, ModuleMessage)` are sent to the other nodes
+///
+/// `$message` is an enum created with [`module_message`].
+#[macro_export]
+macro_rules! random_translate {
+    (
+        name: $name:expr,
+        message: $message:ident,
+        input: $in:ident,
+        output: $out:ident,
+        messages: $messages:ty,
+        translate: $vis:vis $translate:ident $(,)?
+    ) => {
+        /// Translates the messages to/from the RandomMessage and calls `process_messages`.
+        $vis struct $translate {
+            messages: $messages,
+        }
+
+        impl $translate {
+            $vis async fn start(
+                random: ::flarch::broker::Broker<
+                    $crate::random_connections::messages::RandomMessage,
+                >,
+                messages: $messages,
+            ) -> Result<::flarch::broker::Broker<$message>, ::flarch::broker::BrokerError> {
+                let mut broker = ::flarch::broker::Broker::new();
+                broker
+                    .add_subsystem(::flarch::broker::Subsystem::Handler(Box::new(Self {
+                        messages,
+                    })))
+                    .await?;
+                broker
+                    .link_bi(
+                        random,
+                        Box::new(Self::link_rnd_module),
+                        Box::new(Self::link_module_rnd),
+                    )
+                    .await?;
+                Ok(broker)
+            }
+
+            fn link_rnd_module(
+                msg: $crate::random_connections::messages::RandomMessage,
+            ) -> Option<$message> {
+                use $crate::random_connections::messages::{RandomMessage, RandomOut};
+                match msg {
+                    RandomMessage::Output(RandomOut::NodeIDsConnected(list)) => {
+                        Some($in::UpdateNodeList(list.into()).into())
+                    }
+                    RandomMessage::Output(RandomOut::NetworkWrapperFromNetwork(id, msg)) => msg
+                        .unwrap($name)
+                        .map(|msg| $in::FromNetwork(id, msg).into()),
+                    _ => None,
+                }
+            }
+
+            fn link_module_rnd(
+                msg: $message,
+            ) -> Option<$crate::random_connections::messages::RandomMessage> {
+                if let $message::Output($out::ToNetwork(id, msg_node)) = msg {
+                    Some(
+                        $crate::random_connections::messages::RandomIn::NetworkMapperToNetwork(
+                            id,
+                            $crate::overlay::messages::NetworkWrapper::wrap($name, &msg_node)
+                                .unwrap(),
+                        )
+                        .into(),
+                    )
+                } else {
+                    None
+                }
+            }
+        }
+
+        #[::flarch::platform_async_trait()]
+        impl ::flarch::broker::SubsystemHandler<$message> for $translate {
+            async fn messages(&mut self, msgs: Vec<$message>) -> Vec<$message> {
+                let msgs_in = msgs
+                    .into_iter()
+                    .filter_map(|msg| match msg {
+                        $message::Input(msg_in) => Some(msg_in),
+                        $message::Output(_) => None,
+                    })
+                    .collect();
+                self.messages
+                    .process_messages(msgs_in)
+                    .into_iter()
+                    .map(|o| o.into())
+                    .collect()
+            }
+        }
+    };
+}
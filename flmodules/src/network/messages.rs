@@ -8,10 +8,58 @@
 //!
 //! Both of these structures are best created with [`crate::network_start`] and
 //! [`crate::network_broker_start`].
+//!
+//! # Reconnection
+//!
+//! If the websocket to the signalling server closes, the [`NetworkBroker`] tries
+//! to reconnect with an exponential backoff, announces itself again, and sets up
+//! the connections to the nodes which were lost in the meantime.
+//! Every step is reported with a [`NetworkOut::SignalServer`].
+//!
+//! If the signalling server goes down for maintenance, the node switches to the
+//! alternate server given by the signalling server.
+//! Without an alternate server, it disconnects and reconnects as above, until the
+//! signalling server is back.
+//!
+//! # Multiple signalling servers
+//!
+//! A node can be connected to more than one signalling server, e.g., for redundancy
+//! or to bridge two communities.
+//! The node lists of all servers are merged, and the connection to another node is
+//! set up through the first server which knows about that node.
+//!
+//! # Relayed connections
+//!
+//! Connections going through a TURN server cost bandwidth on the shared relay.
+//! Every [`TURN_UPGRADE_SEC`] seconds, and whenever the NAT type of this node
+//! changes, the relayed connections are set up again, in case a direct connection
+//! is possible by now.
+//!
+//! # Relay through the signalling server
+//!
+//! Some networks block WebRTC entirely.
+//! After [`RELAY_AFTER_FAILURES`] failed connection setups to a node, its messages
+//! are sent through the websocket of the signalling server instead, see
+//! [`crate::network::signal`].
+//! Such a connection is reported with [`ConnType::Relay`] in the
+//! [`NetworkConnectionState`].
+//! A node receiving a relayed message also relays its messages to the sender.
+//! Every [`TURN_UPGRADE_SEC`] seconds, a WebRTC connection is tried again, and the
+//! relay is only used until it succeeds.
+//!
+//! # Data channels
+//!
+//! [`NetworkIn::MessageToNodeChannel`] sends a message over another WebRTC [`Channel`].
+//! The connections to nodes announcing [`Capabilities::WEBRTC_CHANNELS`] open all
+//! channels, the other connections send everything over [`Channel::Control`].
 
 use core::panic;
-use itertools::concat;
-use std::{fmt, time::Duration};
+use itertools::{concat, Itertools};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    time::Duration,
+};
 use thiserror::Error;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio_stream::StreamExt;
@@ -22,8 +70,10 @@ use flarch::{
     platform_async_trait,
     tasks::Interval,
     web_rtc::{
-        messages::{ConnType, PeerInfo, SetupError, SignalingState},
+        messages::{Channel, ConnType, PeerInfo, SetupError, SignalingState, TransportStats},
+        nat::NatType,
         node_connection::{Direction, NCError, NCInput, NCOutput},
+        throttle::ThrottleConfig,
         websocket::{WSClientInput, WSClientMessage, WSClientOutput},
         WebRTCConnMessage,
     },
@@ -31,24 +81,26 @@ use flarch::{
 
 use crate::{
     network::signal::{
-        MessageAnnounce, NodeStat, WSSignalMessageFromNode, WSSignalMessageToNode, SIGNAL_VERSION,
+        rendezvous_id, MessageAnnounce, NodeStat, WSSignalMessageFromNode, WSSignalMessageToNode,
+        SIGNAL_VERSION,
     },
     nodeconfig::{NodeConfig, NodeInfo},
+    Capabilities,
 };
 
-
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq)]
 /// A Message to/from the [`NetworkBroker`].
 /// The [`NetworkMessage::Call`] and [`NetworkMessage::Output`] messages are directly related to the [`NetworkBroker`], while
 /// the [`NetworkMessage::WebSocket`] and [`NetworkMessage::WebRTC`] messages are used to link to those modules.
+/// The websockets are indexed in the order the signalling servers are given to [`NetworkBroker::start_multi`].
 pub enum NetworkMessage {
     /// A message to the [`NetworkBroker`]
     Input(NetworkIn),
     /// A return message from the [`NetworkBroker`]
     Output(NetworkOut),
-    /// Messages to/from the WebSocket.
-    WebSocket(WSClientMessage),
+    /// Messages to/from the WebSocket of the signalling server with the given index.
+    WebSocket(usize, WSClientMessage),
     /// Messages to/from the WebRTC subsystem.
     WebRTC(WebRTCConnMessage),
 }
@@ -61,8 +113,12 @@ pub enum NetworkIn {
     /// Sends a new text message to the node.
     /// The [`NetworkBroker`] will try to set up a connection with the remote node,
     /// if no such connection exists yet.
-    /// If the node is not connected to the signalling handler, nothing happens.
+    /// If the node is not connected to the signalling server, the message is queued
+    /// until the node shows up, see [`NetworkOut::Delivered`] and [`NetworkOut::Expired`].
     MessageToNode(NodeID, String),
+    /// Like [`NetworkIn::MessageToNode`], but the message goes over the given
+    /// WebRTC [`Channel`] if the remote node announced [`Capabilities::WEBRTC_CHANNELS`].
+    MessageToNodeChannel(NodeID, Channel, String),
     /// Sends some stats to the signalling server to monitor the overall health of
     /// the system.
     StatsToWS(Vec<NodeStat>),
@@ -75,8 +131,22 @@ pub enum NetworkIn {
     /// Manually disconnect from the given node.
     /// If there is no connection to this node, no error is produced.
     Disconnect(NodeID),
+    /// Waits at the signalling server for another node using the same phrase.
+    /// Once the other node arrives, a [`NetworkOut::Rendezvous`] is sent.
+    Rendezvous(String),
+    /// Changes the bandwidth limits of the WebRTC connections.
+    SetThrottle(ThrottleConfig),
+    /// Asks the WebRTC connection to the given node for its transport statistics,
+    /// which are returned in a [`NetworkOut::ConnectionStats`] per direction.
+    /// If there is no WebRTC connection to this node, nothing is returned.
+    /// The statistics of all connections are also requested every 10 [`NetworkIn::Tick`]s.
+    GetConnectionStats(NodeID),
     /// This message should be sent once a second to allow calculations of timeouts.
     Tick,
+    /// Closes all connections to other nodes and to the signalling servers,
+    /// before the node stops.
+    /// Afterwards no reconnection is attempted.
+    Shutdown,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -85,7 +155,8 @@ pub enum NetworkIn {
 pub enum NetworkOut {
     /// A new message has been received from the given node.
     MessageFromNode(NodeID, String),
-    /// An updated list coming from the signalling server.
+    /// An updated list coming from the signalling servers.
+    /// If there are multiple signalling servers, this is the merged list of all servers.
     NodeListFromWS(Vec<NodeInfo>),
     /// Whenever the state of a connection changes, this message is
     /// sent to the user.
@@ -94,6 +165,51 @@ pub enum NetworkOut {
     Connected(NodeID),
     /// A node has been disconnected.
     Disconnected(NodeID),
+    /// Another node used the same rendezvous phrase.
+    Rendezvous(String, NodeInfo),
+    /// How many connections are being set up, and how many are established.
+    SetupStats(SetupStats),
+    /// The connection to the signalling server with the given index changed.
+    SignalServer(usize, SignalServerState),
+    /// The NAT type of this node has been detected, or changed.
+    /// It is sent to the signalling servers with the next announcement.
+    NatType(NatType),
+    /// The given number of queued messages have been sent to the node, which
+    /// is now available.
+    Delivered(NodeID, usize),
+    /// The given number of queued messages to the node have been dropped,
+    /// because they were queued for longer than [`QUEUE_TTL_SEC`], or because
+    /// more than [`QUEUE_MAX_MESSAGES`] messages were queued.
+    Expired(NodeID, usize),
+    /// The transport statistics of the WebRTC connection to a node, as requested
+    /// by [`NetworkIn::GetConnectionStats`].
+    ConnectionStats(NodeID, Direction, TransportStats),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The phases of a reconnection to the signalling server.
+pub enum SignalServerState {
+    /// The websocket to the signalling server closed.
+    Disconnected,
+    /// Trying to reconnect, with the number of the attempt.
+    Reconnecting(u32),
+    /// The node is connected and announced to the signalling server again.
+    Reconnected,
+    /// The connections to these nodes were lost while the signalling server was
+    /// down, and are set up again.
+    PeersRestored(Vec<NodeID>),
+    /// The signalling server goes down for maintenance, and the node switches to
+    /// the given signalling server, if any.
+    Maintenance(Option<String>),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// The number of connections in the [`NetworkBroker`].
+pub struct SetupStats {
+    /// Connections which have been requested, but are not connected yet.
+    pub pending: usize,
+    /// Connections which have been connected at least once.
+    pub established: usize,
 }
 
 /// This is a user-friendly version of [`NetworkBroker`].
@@ -157,6 +273,11 @@ impl Network {
     pub fn send_list_request(&mut self) -> Result<(), BrokerError> {
         self.send(NetworkIn::WSUpdateListRequest)
     }
+
+    /// Waits for another node using the same phrase at the signalling server.
+    pub fn rendezvous(&mut self, phrase: &str) -> Result<(), BrokerError> {
+        self.send(NetworkIn::Rendezvous(phrase.into()))
+    }
 }
 
 #[derive(Error, Debug)]
@@ -189,9 +310,67 @@ pub struct NetworkBroker {
     node_config: NodeConfig,
     get_update: usize,
     connections: Vec<NodeID>,
+    // Seconds left for the connection setup to succeed.
+    pending: HashMap<NodeID, usize>,
+    rendezvous: HashMap<U256, String>,
+    // The phrases waiting for a rendezvous, sent to every signalling server once
+    // its salt is known.
+    rendezvous_phrases: HashSet<String>,
+    rendezvous_salts: HashMap<usize, U256>,
+    // The node lists of all signalling servers.
+    node_lists: Vec<Vec<NodeInfo>>,
+    // The signalling server used to set up the connection to a node.
+    node_server: HashMap<NodeID, usize>,
+    reconnect: HashMap<usize, Reconnect>,
+    // Set by [`NetworkIn::Shutdown`], so the closed signalling servers are not reconnected.
+    shutdown: bool,
+    // Connections going through a TURN server.
+    relayed: HashSet<NodeID>,
+    // Seconds left until the relayed connections are set up again.
+    upgrade: usize,
+    // Connection setups which timed out, per node.
+    failures: HashMap<NodeID, usize>,
+    // Nodes whose messages go through the signalling server.
+    relay: HashSet<NodeID>,
+    // Messages to nodes which are not available, oldest first.
+    queue: VecDeque<Queued>,
+}
+
+/// A message waiting for its destination to become available.
+struct Queued {
+    dst: NodeID,
+    channel: Channel,
+    msg: String,
+    // Seconds left until the message is dropped.
+    ttl: usize,
+}
+
+/// Keeps track of the reconnection to the signalling server.
+struct Reconnect {
+    attempt: u32,
+    // Seconds left until the next attempt.
+    wait: usize,
+    // The websocket is connected, but the node didn't get a challenge yet.
+    connected: bool,
+    // Nodes whose connections were lost while the signalling server was down.
+    peers: Vec<NodeID>,
 }
 
 const UPDATE_INTERVAL: usize = 10;
+/// The maximum number of seconds between two reconnection attempts.
+pub const RECONNECT_MAX_SEC: usize = 60;
+/// How many seconds a connection setup can take before it is abandoned.
+pub const SETUP_TIMEOUT_SEC: usize = 30;
+/// How often the connections going through a TURN server are set up again.
+pub const TURN_UPGRADE_SEC: usize = 600;
+/// How many connection setups to a node can time out before its messages are
+/// relayed by the signalling server.
+pub const RELAY_AFTER_FAILURES: usize = 3;
+/// How many seconds a message to a node which is not available is kept.
+pub const QUEUE_TTL_SEC: usize = 300;
+/// How many messages to nodes which are not available are kept. If there are
+/// more, the oldest messages are dropped.
+pub const QUEUE_MAX_MESSAGES: usize = 1000;
 
 impl NetworkBroker {
     /// Starts a new [`NetworkBroker`] and returns a [`Broker<NetworkMessage>`] which can be linked
@@ -201,6 +380,15 @@ impl NetworkBroker {
         node_config: NodeConfig,
         ws: Broker<WSClientMessage>,
         web_rtc: Broker<WebRTCConnMessage>,
+    ) -> Result<Broker<NetworkMessage>, NetworkError> {
+        Self::start_multi(node_config, vec![ws], web_rtc).await
+    }
+
+    /// Starts a new [`NetworkBroker`] which is connected to multiple signalling servers.
+    pub async fn start_multi(
+        node_config: NodeConfig,
+        ws: Vec<Broker<WSClientMessage>>,
+        web_rtc: Broker<WebRTCConnMessage>,
     ) -> Result<Broker<NetworkMessage>, NetworkError> {
         let mut broker = Broker::new();
         broker
@@ -208,11 +396,30 @@ impl NetworkBroker {
                 node_config,
                 get_update: UPDATE_INTERVAL,
                 connections: vec![],
+                pending: HashMap::new(),
+                rendezvous: HashMap::new(),
+                rendezvous_phrases: HashSet::new(),
+                rendezvous_salts: HashMap::new(),
+                node_lists: vec![vec![]; ws.len()],
+                node_server: HashMap::new(),
+                reconnect: HashMap::new(),
+                shutdown: false,
+                relayed: HashSet::new(),
+                upgrade: TURN_UPGRADE_SEC,
+                failures: HashMap::new(),
+                relay: HashSet::new(),
+                queue: VecDeque::new(),
             })))
             .await?;
-        broker
-            .link_bi(ws, Box::new(Self::from_ws), Box::new(Self::to_ws))
-            .await?;
+        for (index, ws) in ws.into_iter().enumerate() {
+            broker
+                .link_bi(
+                    ws,
+                    Box::new(move |msg| Self::from_ws(index, msg)),
+                    Box::new(move |msg| Self::to_ws(index, msg)),
+                )
+                .await?;
+        }
         broker
             .link_bi(
                 web_rtc,
@@ -226,14 +433,20 @@ impl NetworkBroker {
     /// Processes incoming messages from the signalling server.
     /// This can be either messages requested by this node, or connection
     /// setup requests from another node.
-    async fn msg_ws(&mut self, msg: WSClientOutput) -> Vec<NetworkMessage> {
+    async fn msg_ws(&mut self, index: usize, msg: WSClientOutput) -> Vec<NetworkMessage> {
         let msg_node_str = match msg {
             WSClientOutput::Message(msg) => msg,
             WSClientOutput::Error(e) => {
                 log::warn!("Websocket client error: {e}");
                 return vec![];
             }
-            _ => return vec![],
+            WSClientOutput::Disconnect => return self.ws_disconnected(index),
+            WSClientOutput::Connected => {
+                if let Some(rc) = self.reconnect.get_mut(&index) {
+                    rc.connected = true;
+                }
+                return vec![];
+            }
         };
         let msg_node =
             if let Ok(msg_node) = serde_json::from_str::<WSSignalMessageToNode>(&msg_node_str) {
@@ -254,13 +467,47 @@ impl NetworkBroker {
                     node_info: self.node_config.info.clone(),
                     signature: self.node_config.sign(challenge.to_bytes()),
                 };
-                vec![
-                    WSSignalMessageFromNode::Announce(ma).into(),
-                    WSSignalMessageFromNode::ListIDsRequest.into(),
-                ]
+                concat(vec![
+                    vec![
+                        Self::to_server(index, WSSignalMessageFromNode::Announce(ma)),
+                        Self::to_server(index, WSSignalMessageFromNode::ListIDsRequest),
+                    ],
+                    self.reconnected(index),
+                ])
             }
             WSSignalMessageToNode::ListIDsReply(list) => {
-                vec![NetworkOut::NodeListFromWS(list).into()]
+                if let Some(node_list) = self.node_lists.get_mut(index) {
+                    *node_list = list;
+                }
+                let mut out = vec![NetworkOut::NodeListFromWS(self.node_list()).into()];
+                out.extend(self.deliver_queued());
+                out
+            }
+            WSSignalMessageToNode::RendezvousReply(rv, info) => match self.rendezvous.remove(&rv) {
+                Some(phrase) => {
+                    self.rendezvous.retain(|_, p| p != &phrase);
+                    self.rendezvous_phrases.remove(&phrase);
+                    vec![NetworkOut::Rendezvous(phrase, info).into()]
+                }
+                None => vec![],
+            },
+            WSSignalMessageToNode::RendezvousSalt(salt) => {
+                self.rendezvous_salts.insert(index, salt);
+                let phrases = self.rendezvous_phrases.clone();
+                phrases
+                    .iter()
+                    .map(|phrase| self.rendezvous_at(index, phrase))
+                    .collect()
+            }
+            WSSignalMessageToNode::Maintenance(alternate) => self.ws_maintenance(index, alternate),
+            WSSignalMessageToNode::Relay(from, msg) => {
+                self.node_server.insert(from, index);
+                let mut out = vec![];
+                if !self.connections.contains(&from) || self.pending.contains_key(&from) {
+                    out.extend(self.start_relay(from, Direction::Incoming));
+                }
+                out.push(NetworkOut::MessageFromNode(from, msg).into());
+                out
             }
             WSSignalMessageToNode::PeerSetup(pi) => {
                 let own_id = self.node_config.info.get_id();
@@ -271,6 +518,8 @@ impl NetworkBroker {
                         return vec![];
                     }
                 };
+                // Answer through the same signalling server.
+                self.node_server.insert(remote_node, index);
                 concat(vec![
                     if !self.connections.contains(&remote_node) {
                         self.connect(&remote_node)
@@ -289,58 +538,108 @@ impl NetworkBroker {
     async fn msg_call(&mut self, msg: NetworkIn) -> Result<Vec<NetworkMessage>, NetworkError> {
         match msg {
             NetworkIn::MessageToNode(id, msg_str) => {
-                log::trace!(
-                    "msg_call: {}->{}: {:?} / {:?}",
-                    self.node_config.info.get_id(),
-                    id,
-                    msg_str,
-                    self.connections
-                );
-
-                Ok(concat(vec![
-                    if !self.connections.contains(&id) {
-                        self.connect(&id)
-                    } else {
-                        vec![]
-                    },
-                    vec![NetworkMessage::from_nc(NCInput::Text(msg_str), id)],
-                ]))
+                Ok(self.message_to_node(id, Channel::Control, msg_str))
+            }
+            NetworkIn::MessageToNodeChannel(id, channel, msg_str) => {
+                Ok(self.message_to_node(id, channel, msg_str))
+            }
+            NetworkIn::StatsToWS(ss) => Ok(self.to_servers(WSSignalMessageFromNode::NodeStats(ss))),
+            NetworkIn::WSUpdateListRequest => {
+                Ok(self.to_servers(WSSignalMessageFromNode::ListIDsRequest))
             }
-            NetworkIn::StatsToWS(ss) => Ok(WSSignalMessageFromNode::NodeStats(ss.clone()).into()),
-            NetworkIn::WSUpdateListRequest => Ok(WSSignalMessageFromNode::ListIDsRequest.into()),
             NetworkIn::Connect(id) => Ok(self.connect(&id)),
             NetworkIn::Disconnect(id) => Ok(self.disconnect(&id).await),
+            NetworkIn::Rendezvous(phrase) => {
+                self.rendezvous_phrases.insert(phrase.clone());
+                let indexes: Vec<usize> = self.rendezvous_salts.keys().copied().collect();
+                Ok(indexes
+                    .into_iter()
+                    .map(|index| self.rendezvous_at(index, &phrase))
+                    .collect())
+            }
+            NetworkIn::SetThrottle(config) => Ok(vec![NetworkMessage::WebRTC(
+                WebRTCConnMessage::SetThrottle(config),
+            )]),
+            NetworkIn::GetConnectionStats(id) => Ok(self.get_connection_stats(&id)),
+            NetworkIn::Shutdown => Ok(self.shutdown()),
             NetworkIn::Tick => {
+                let mut out = self.expire_setups();
+                out.extend(self.expire_queued());
+                out.extend(self.reconnect_tick());
+                self.upgrade -= 1;
+                if self.upgrade == 0 {
+                    self.upgrade = TURN_UPGRADE_SEC;
+                    out.extend(self.upgrade_relayed());
+                }
+                out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Tick));
                 self.get_update -= 1;
-                Ok((self.get_update == 0)
-                    .then(|| {
-                        self.get_update = UPDATE_INTERVAL;
-                        vec![WSSignalMessageFromNode::ListIDsRequest.into()]
-                    })
-                    .unwrap_or(vec![]))
+                if self.get_update == 0 {
+                    self.get_update = UPDATE_INTERVAL;
+                    out.extend(self.to_servers(WSSignalMessageFromNode::ListIDsRequest));
+                    out.push(NetworkOut::SetupStats(self.setup_stats()).into());
+                    for id in self.connections.clone() {
+                        out.extend(self.get_connection_stats(&id));
+                    }
+                }
+                Ok(out)
             }
         }
     }
 
     async fn msg_node(&mut self, id: U256, msg_nc: NCOutput) -> Vec<NetworkMessage> {
         match msg_nc {
-            NCOutput::Connected(_) => vec![NetworkOut::Connected(id).into()],
-            NCOutput::Disconnected(_) => vec![NetworkOut::Disconnected(id).into()],
+            NCOutput::Connected(_) => {
+                self.pending.remove(&id);
+                self.failures.remove(&id);
+                if self.relay.remove(&id) {
+                    log::info!("WebRTC connection to {id} works, stopping the relay");
+                }
+                let mut out = vec![NetworkOut::Connected(id).into()];
+                out.extend(self.deliver_queued());
+                out
+            }
+            NCOutput::Disconnected(_) if self.relay.contains(&id) => vec![],
+            NCOutput::Disconnected(_) => {
+                let index = self.server_for(&id);
+                if let Some(rc) = self.reconnect.get_mut(&index) {
+                    // The connection cannot be set up again without the signalling server.
+                    self.connections.retain(|c| c != &id);
+                    self.pending.remove(&id);
+                    if !rc.peers.contains(&id) {
+                        rc.peers.push(id);
+                    }
+                }
+                vec![NetworkOut::Disconnected(id).into()]
+            }
             NCOutput::Text(msg) => vec![NetworkOut::MessageFromNode(id, msg).into()],
             NCOutput::State(dir, state) => {
-                vec![NetworkOut::ConnectionState(NetworkConnectionState {
-                    id,
-                    dir,
-                    s: ConnStats {
-                        type_local: state.type_local,
-                        type_remote: state.type_remote,
-                        signaling: state.signaling,
-                        rx_bytes: state.rx_bytes,
-                        tx_bytes: state.tx_bytes,
-                        delay_ms: state.delay_ms,
-                    },
-                })
-                .into()]
+                let s = ConnStats {
+                    type_local: state.type_local,
+                    type_remote: state.type_remote,
+                    signaling: state.signaling,
+                    rx_bytes: state.rx_bytes,
+                    tx_bytes: state.tx_bytes,
+                    delay_ms: state.delay_ms,
+                    delayed: state.delayed,
+                    dropped: state.dropped,
+                    nat: state.nat,
+                };
+                if s.relayed() {
+                    self.relayed.insert(id);
+                } else {
+                    self.relayed.remove(&id);
+                }
+                let mut out = vec![];
+                if state.nat.is_some() && state.nat != self.node_config.info.nat {
+                    self.node_config.info.nat = state.nat;
+                    out.extend(state.nat.map(|nat| NetworkOut::NatType(nat).into()));
+                    out.extend(self.upgrade_relayed());
+                }
+                out.push(NetworkOut::ConnectionState(NetworkConnectionState { id, dir, s }).into());
+                out
+            }
+            NCOutput::Stats(dir, stats) => {
+                vec![NetworkOut::ConnectionStats(id, dir, stats).into()]
             }
             NCOutput::Setup(dir, pm) => {
                 let mut id_init = self.node_config.info.get_id();
@@ -348,16 +647,130 @@ impl NetworkBroker {
                 if dir == Direction::Incoming {
                     (id_init, id_follow) = (id_follow, id_init);
                 }
-                vec![WSSignalMessageFromNode::PeerSetup(PeerInfo {
-                    id_init,
-                    id_follow,
-                    message: pm,
-                })
-                .into()]
+                let index = self.server_for(&id);
+                self.node_server.insert(id, index);
+                vec![Self::to_server(
+                    index,
+                    WSSignalMessageFromNode::PeerSetup(PeerInfo {
+                        id_init,
+                        id_follow,
+                        message: pm,
+                    }),
+                )]
             }
         }
     }
 
+    /// Sends the message to the node, or queues it if the node is not available.
+    fn message_to_node(
+        &mut self,
+        id: NodeID,
+        channel: Channel,
+        msg_str: String,
+    ) -> Vec<NetworkMessage> {
+        log::trace!(
+            "msg_call: {}->{}: {:?} / {:?}",
+            self.node_config.info.get_id(),
+            id,
+            msg_str,
+            self.connections
+        );
+
+        if !self.relay.contains(&id) && !self.available(&id) {
+            return self.enqueue(id, channel, msg_str);
+        }
+        self.send_to(id, channel, msg_str)
+    }
+
+    /// Sends the message through the signalling server if the node is relayed,
+    /// else through WebRTC, setting up the connection if needed.
+    fn send_to(&mut self, id: NodeID, channel: Channel, msg_str: String) -> Vec<NetworkMessage> {
+        if self.relay.contains(&id) {
+            return vec![Self::to_server(
+                self.server_for(&id),
+                WSSignalMessageFromNode::Relay(id, msg_str),
+            )];
+        }
+        concat(vec![
+            if !self.connections.contains(&id) {
+                self.connect(&id)
+            } else {
+                vec![]
+            },
+            vec![NetworkMessage::from_nc(
+                match channel {
+                    Channel::Control => NCInput::Text(msg_str),
+                    _ => NCInput::TextChannel(channel, msg_str),
+                },
+                id,
+            )],
+        ])
+    }
+
+    /// A node is available if it is connected, if it set up a connection through
+    /// a signalling server, or if a signalling server knows it.
+    fn available(&self, id: &NodeID) -> bool {
+        self.connections.contains(id)
+            || self.node_server.contains_key(id)
+            || self
+                .node_lists
+                .iter()
+                .flatten()
+                .any(|ni| &ni.get_id() == id)
+    }
+
+    /// Queues a message to a node which is not available, and drops the oldest
+    /// messages if the queue is full.
+    fn enqueue(&mut self, dst: NodeID, channel: Channel, msg: String) -> Vec<NetworkMessage> {
+        self.queue.push_back(Queued {
+            dst,
+            channel,
+            msg,
+            ttl: QUEUE_TTL_SEC,
+        });
+        let full = self.queue.len().saturating_sub(QUEUE_MAX_MESSAGES);
+        let dropped: Vec<Queued> = self.queue.drain(..full).collect();
+        Self::count_queued(dropped, NetworkOut::Expired)
+    }
+
+    /// Sends the queued messages of all nodes which are available now.
+    fn deliver_queued(&mut self) -> Vec<NetworkMessage> {
+        let queue = std::mem::take(&mut self.queue);
+        let (ready, waiting): (VecDeque<_>, _) =
+            queue.into_iter().partition(|q| self.available(&q.dst));
+        self.queue = waiting;
+        let mut out = vec![];
+        for q in &ready {
+            out.extend(self.send_to(q.dst, q.channel, q.msg.clone()));
+        }
+        out.extend(Self::count_queued(ready, NetworkOut::Delivered));
+        out
+    }
+
+    /// Drops the queued messages which are too old.
+    fn expire_queued(&mut self) -> Vec<NetworkMessage> {
+        for q in self.queue.iter_mut() {
+            q.ttl = q.ttl.saturating_sub(1);
+        }
+        let queue = std::mem::take(&mut self.queue);
+        let (expired, waiting): (VecDeque<_>, _) = queue.into_iter().partition(|q| q.ttl == 0);
+        self.queue = waiting;
+        Self::count_queued(expired, NetworkOut::Expired)
+    }
+
+    // Returns one message per node with the number of queued messages.
+    fn count_queued(
+        queued: impl IntoIterator<Item = Queued>,
+        out: fn(NodeID, usize) -> NetworkOut,
+    ) -> Vec<NetworkMessage> {
+        queued
+            .into_iter()
+            .counts_by(|q| q.dst)
+            .into_iter()
+            .map(|(dst, count)| out(dst, count).into())
+            .collect()
+    }
+
     /// Connect to the given node.
     fn connect(&mut self, dst: &U256) -> Vec<NetworkMessage> {
         let mut out = vec![NetworkOut::Connected(*dst).into()];
@@ -365,6 +778,14 @@ impl NetworkBroker {
             log::warn!("Already connected to {}", dst);
         } else {
             self.connections.push(dst.clone());
+            self.pending.insert(*dst, SETUP_TIMEOUT_SEC);
+            if self.node_list().iter().any(|ni| {
+                &ni.get_id() == dst && ni.capabilities.contains(Capabilities::WEBRTC_CHANNELS)
+            }) {
+                out.push(NetworkMessage::WebRTC(WebRTCConnMessage::MultiChannel(
+                    *dst,
+                )));
+            }
             out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Connect(*dst)));
         }
         out
@@ -377,22 +798,316 @@ impl NetworkBroker {
             log::warn!("Already disconnected from {}", dst);
         } else {
             self.connections.retain(|id| id != dst);
+            self.pending.remove(dst);
+            self.node_server.remove(dst);
+            self.relay.remove(dst);
             out.push(NetworkMessage::from_nc(NCInput::Disconnect, *dst));
         }
         out
     }
 
+    /// Abandons all connection setups which didn't succeed in time, so that
+    /// the WebRTC connection can free its resources.
+    /// After [`RELAY_AFTER_FAILURES`] failures, the messages to the node are relayed.
+    fn expire_setups(&mut self) -> Vec<NetworkMessage> {
+        let mut expired = vec![];
+        self.pending.retain(|id, left| {
+            *left = left.saturating_sub(1);
+            if *left == 0 {
+                expired.push(*id);
+            }
+            *left > 0
+        });
+        let mut out = vec![];
+        for id in expired {
+            log::debug!("Setup of connection to {id} timed out");
+            out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Disconnect(id)));
+            let failures = self.failures.entry(id).or_default();
+            *failures += 1;
+            if *failures >= RELAY_AFTER_FAILURES {
+                out.extend(self.start_relay(id, Direction::Outgoing));
+            } else {
+                self.connections.retain(|c| c != &id);
+                self.node_server.remove(&id);
+                out.push(NetworkOut::Disconnected(id).into());
+            }
+        }
+        out
+    }
+
+    /// Sends the messages to this node through the signalling server.
+    fn start_relay(&mut self, id: NodeID, dir: Direction) -> Vec<NetworkMessage> {
+        let mut out = vec![];
+        if self.pending.remove(&id).is_some() {
+            out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Disconnect(id)));
+        }
+        if !self.relay.insert(id) {
+            return out;
+        }
+        log::info!("Relaying the messages to {id} through the signalling server");
+        if !self.connections.contains(&id) {
+            self.connections.push(id);
+        }
+        let s = ConnStats {
+            type_local: ConnType::Relay,
+            type_remote: ConnType::Relay,
+            signaling: SignalingState::Stable,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            delay_ms: 0,
+            delayed: 0,
+            dropped: 0,
+            nat: None,
+        };
+        out.push(NetworkOut::Connected(id).into());
+        out.push(NetworkConnectionState { id, dir, s }.into());
+        out
+    }
+
+    /// Sets up the connections going through a TURN server again, so they can
+    /// use a direct connection if possible.
+    /// For the nodes relayed by the signalling server, a WebRTC connection is tried again.
+    fn upgrade_relayed(&mut self) -> Vec<NetworkMessage> {
+        let relayed: Vec<NodeID> = self
+            .relayed
+            .drain()
+            .filter(|id| self.connections.contains(id) && !self.pending.contains_key(id))
+            .collect();
+        let mut out = vec![];
+        for id in relayed {
+            log::debug!("Setting up the relayed connection to {id} again");
+            self.connections.retain(|c| c != &id);
+            out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Disconnect(id)));
+            out.extend(self.connect(&id));
+        }
+        let relay: Vec<NodeID> = self
+            .relay
+            .iter()
+            .filter(|id| !self.pending.contains_key(id))
+            .copied()
+            .collect();
+        for id in relay {
+            // The relay is used until the WebRTC connection works, and a single
+            // failure is enough to keep it.
+            log::debug!("Trying a WebRTC connection to the relayed node {id}");
+            self.failures.insert(id, RELAY_AFTER_FAILURES - 1);
+            self.pending.insert(id, SETUP_TIMEOUT_SEC);
+            out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Connect(id)));
+        }
+        out
+    }
+
+    /// Disconnects from all nodes and signalling servers.
+    fn shutdown(&mut self) -> Vec<NetworkMessage> {
+        log::info!("Shutting down the network");
+        self.shutdown = true;
+        self.reconnect.clear();
+        self.pending.clear();
+        self.node_server.clear();
+        self.relay.clear();
+        self.failures.clear();
+        let mut out: Vec<NetworkMessage> = self
+            .connections
+            .drain(..)
+            .flat_map(|id| {
+                [
+                    NetworkMessage::from_nc(NCInput::Disconnect, id),
+                    NetworkOut::Disconnected(id).into(),
+                ]
+            })
+            .collect();
+        out.extend(
+            (0..self.node_lists.len())
+                .map(|index| NetworkMessage::WebSocket(index, WSClientInput::Disconnect.into())),
+        );
+        out
+    }
+
+    /// Starts the reconnection to the signalling server.
+    fn ws_disconnected(&mut self, index: usize) -> Vec<NetworkMessage> {
+        if self.shutdown || self.reconnect.contains_key(&index) {
+            return vec![];
+        }
+        log::warn!("Lost connection to signalling server {index}");
+        self.reconnect.insert(
+            index,
+            Reconnect {
+                attempt: 0,
+                wait: 1,
+                connected: false,
+                peers: vec![],
+            },
+        );
+        vec![NetworkOut::SignalServer(index, SignalServerState::Disconnected).into()]
+    }
+
+    /// Leaves a signalling server going down for maintenance.
+    fn ws_maintenance(&mut self, index: usize, alternate: Option<String>) -> Vec<NetworkMessage> {
+        if alternate.is_none() && self.reconnect.contains_key(&index) {
+            // Refused while trying to reconnect.
+            return vec![];
+        }
+        log::info!(
+            "Signalling server {index} goes down for maintenance, switching to {alternate:?}"
+        );
+        let mut out = vec![NetworkOut::SignalServer(
+            index,
+            SignalServerState::Maintenance(alternate.clone()),
+        )
+        .into()];
+        match alternate {
+            Some(url) => {
+                // Only try again if the alternate server doesn't answer.
+                self.reconnect.insert(
+                    index,
+                    Reconnect {
+                        attempt: 0,
+                        wait: RECONNECT_MAX_SEC,
+                        connected: false,
+                        peers: vec![],
+                    },
+                );
+                out.push(NetworkMessage::WebSocket(
+                    index,
+                    WSClientInput::ConnectTo(url).into(),
+                ));
+            }
+            None => {
+                out.extend(self.ws_disconnected(index));
+                out.push(NetworkMessage::WebSocket(
+                    index,
+                    WSClientInput::Disconnect.into(),
+                ));
+            }
+        }
+        out
+    }
+
+    /// Tries to reconnect to the signalling servers, doubling the time between
+    /// two attempts up to [`RECONNECT_MAX_SEC`].
+    fn reconnect_tick(&mut self) -> Vec<NetworkMessage> {
+        let mut out = vec![];
+        for (index, rc) in self.reconnect.iter_mut().filter(|(_, rc)| !rc.connected) {
+            rc.wait -= 1;
+            if rc.wait > 0 {
+                continue;
+            }
+            rc.attempt += 1;
+            rc.wait = 2usize.saturating_pow(rc.attempt).min(RECONNECT_MAX_SEC);
+            log::info!(
+                "Reconnecting to signalling server {index}, attempt {}",
+                rc.attempt
+            );
+            out.push(NetworkMessage::WebSocket(
+                *index,
+                WSClientInput::Connect.into(),
+            ));
+            out.push(
+                NetworkOut::SignalServer(*index, SignalServerState::Reconnecting(rc.attempt))
+                    .into(),
+            );
+        }
+        out
+    }
+
+    /// Called when the signalling server sends a challenge: if this is after a
+    /// reconnection, the lost connections are set up again.
+    fn reconnected(&mut self, index: usize) -> Vec<NetworkMessage> {
+        let Some(rc) = self.reconnect.remove(&index) else {
+            return vec![];
+        };
+        log::info!("Reconnected to signalling server {index}");
+        let mut out = vec![NetworkOut::SignalServer(index, SignalServerState::Reconnected).into()];
+        if !rc.peers.is_empty() {
+            for id in &rc.peers {
+                out.push(NetworkMessage::WebRTC(WebRTCConnMessage::Disconnect(*id)));
+                out.extend(self.connect(id));
+            }
+            out.push(
+                NetworkOut::SignalServer(index, SignalServerState::PeersRestored(rc.peers)).into(),
+            );
+        }
+        out
+    }
+
+    /// Returns the node lists of all signalling servers, without duplicates.
+    fn node_list(&self) -> Vec<NodeInfo> {
+        let mut ids = vec![];
+        self.node_lists
+            .iter()
+            .flatten()
+            .filter(|ni| {
+                let id = ni.get_id();
+                let new = !ids.contains(&id);
+                ids.push(id);
+                new
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the index of the signalling server to use for the connection to
+    /// this node: either the one already used, or the first one which knows the node.
+    fn server_for(&self, id: &NodeID) -> usize {
+        self.node_server.get(id).copied().unwrap_or_else(|| {
+            self.node_lists
+                .iter()
+                .position(|list| list.iter().any(|ni| &ni.get_id() == id))
+                .unwrap_or(0)
+        })
+    }
+
+    /// Returns the message to send to the signalling server with the given index.
+    fn to_server(index: usize, msg: WSSignalMessageFromNode) -> NetworkMessage {
+        NetworkMessage::WebSocket(
+            index,
+            WSClientInput::Message(serde_json::to_string(&msg).unwrap()).into(),
+        )
+    }
+
+    /// Returns the messages to send to all signalling servers.
+    fn to_servers(&self, msg: WSSignalMessageFromNode) -> Vec<NetworkMessage> {
+        (0..self.node_lists.len())
+            .map(|index| Self::to_server(index, msg.clone()))
+            .collect()
+    }
+
+    // Waits for the phrase at the signalling server, which must have sent its salt.
+    fn rendezvous_at(&mut self, index: usize, phrase: &str) -> NetworkMessage {
+        let rv = rendezvous_id(phrase, &self.rendezvous_salts[&index]);
+        self.rendezvous.insert(rv, phrase.into());
+        Self::to_server(index, WSSignalMessageFromNode::Rendezvous(rv))
+    }
+
+    // Relayed nodes have no WebRTC connection to ask for statistics.
+    fn get_connection_stats(&self, id: &NodeID) -> Vec<NetworkMessage> {
+        if self.connections.contains(id) && !self.relay.contains(id) {
+            vec![NetworkMessage::from_nc(NCInput::GetStats, *id)]
+        } else {
+            vec![]
+        }
+    }
+
+    fn setup_stats(&self) -> SetupStats {
+        SetupStats {
+            pending: self.pending.len(),
+            established: self.connections.len().saturating_sub(self.pending.len()),
+        }
+    }
+
     // Translator functions
 
-    fn to_ws(msg: NetworkMessage) -> Option<WSClientMessage> {
+    fn to_ws(index: usize, msg: NetworkMessage) -> Option<WSClientMessage> {
         match msg {
-            NetworkMessage::WebSocket(msg) => matches!(msg, WSClientMessage::Input(_)).then(|| msg),
+            NetworkMessage::WebSocket(i, msg) if i == index => {
+                matches!(msg, WSClientMessage::Input(_)).then(|| msg)
+            }
             _ => None,
         }
     }
 
-    fn from_ws(msg: WSClientMessage) -> Option<NetworkMessage> {
-        matches!(msg, WSClientMessage::Output(_)).then(|| NetworkMessage::WebSocket(msg))
+    fn from_ws(index: usize, msg: WSClientMessage) -> Option<NetworkMessage> {
+        matches!(msg, WSClientMessage::Output(_)).then(|| NetworkMessage::WebSocket(index, msg))
     }
 
     fn to_web_rtc(msg: NetworkMessage) -> Option<WebRTCConnMessage> {
@@ -422,8 +1137,8 @@ impl SubsystemHandler<NetworkMessage> for NetworkBroker {
             );
             match msg {
                 NetworkMessage::Input(c) => out.extend(self.msg_call(c).await.unwrap()),
-                NetworkMessage::WebSocket(WSClientMessage::Output(ws)) => {
-                    out.extend(self.msg_ws(ws).await)
+                NetworkMessage::WebSocket(index, WSClientMessage::Output(ws)) => {
+                    out.extend(self.msg_ws(index, ws).await)
                 }
                 NetworkMessage::WebRTC(WebRTCConnMessage::OutputNC(id, msg)) => {
                     out.extend(self.msg_node(id, msg).await)
@@ -435,13 +1150,12 @@ impl SubsystemHandler<NetworkMessage> for NetworkBroker {
     }
 }
 
-
 impl fmt::Display for NetworkMessage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             NetworkMessage::Input(c) => write!(f, "Call({})", c),
             NetworkMessage::Output(r) => write!(f, "Reply({})", r),
-            NetworkMessage::WebSocket(_) => write!(f, "WebSocket()"),
+            NetworkMessage::WebSocket(_, _) => write!(f, "WebSocket()"),
             NetworkMessage::WebRTC(_) => write!(f, "WebRTC()"),
         }
     }
@@ -454,15 +1168,31 @@ impl NetworkMessage {
     }
 }
 
+impl NetworkIn {
+    /// Returns a [`NetworkIn::MessageToNode`], or a [`NetworkIn::MessageToNodeChannel`]
+    /// if the message should not go over [`Channel::Control`].
+    pub fn to_node(dst: NodeID, channel: Channel, msg: String) -> Self {
+        match channel {
+            Channel::Control => NetworkIn::MessageToNode(dst, msg),
+            _ => NetworkIn::MessageToNodeChannel(dst, channel, msg),
+        }
+    }
+}
+
 impl fmt::Display for NetworkIn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             NetworkIn::MessageToNode(_, _) => write!(f, "MessageToNode()"),
+            NetworkIn::MessageToNodeChannel(_, _, _) => write!(f, "MessageToNodeChannel()"),
             NetworkIn::StatsToWS(_) => write!(f, "StatsToWS()"),
             NetworkIn::WSUpdateListRequest => write!(f, "WSUpdateListRequest"),
             NetworkIn::Connect(_) => write!(f, "Connect()"),
             NetworkIn::Disconnect(_) => write!(f, "Disconnect()"),
+            NetworkIn::Rendezvous(_) => write!(f, "Rendezvous()"),
+            NetworkIn::SetThrottle(_) => write!(f, "SetThrottle()"),
+            NetworkIn::GetConnectionStats(_) => write!(f, "GetConnectionStats()"),
             NetworkIn::Tick => write!(f, "Tick"),
+            NetworkIn::Shutdown => write!(f, "Shutdown"),
         }
     }
 }
@@ -473,14 +1203,6 @@ impl From<NetworkIn> for NetworkMessage {
     }
 }
 
-impl From<WSSignalMessageFromNode> for Vec<NetworkMessage> {
-    fn from(msg: WSSignalMessageFromNode) -> Self {
-        vec![NetworkMessage::WebSocket(
-            WSClientInput::Message(serde_json::to_string(&msg).unwrap()).into(),
-        )]
-    }
-}
-
 impl fmt::Display for NetworkOut {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -489,6 +1211,13 @@ impl fmt::Display for NetworkOut {
             NetworkOut::ConnectionState(_) => write!(f, "ConnectionState()"),
             NetworkOut::Connected(_) => write!(f, "Connected()"),
             NetworkOut::Disconnected(_) => write!(f, "Disconnected()"),
+            NetworkOut::Rendezvous(_, _) => write!(f, "Rendezvous()"),
+            NetworkOut::SetupStats(_) => write!(f, "SetupStats()"),
+            NetworkOut::SignalServer(_, _) => write!(f, "SignalServer()"),
+            NetworkOut::NatType(_) => write!(f, "NatType()"),
+            NetworkOut::Delivered(_, _) => write!(f, "Delivered()"),
+            NetworkOut::Expired(_, _) => write!(f, "Expired()"),
+            NetworkOut::ConnectionStats(_, _, _) => write!(f, "ConnectionStats()"),
         }
     }
 }
@@ -516,19 +1245,6 @@ impl From<NetworkConnectionState> for NetworkMessage {
     }
 }
 
-impl From<WSSignalMessageFromNode> for NetworkMessage {
-    fn from(msg: WSSignalMessageFromNode) -> Self {
-        Into::<WSClientMessage>::into(WSClientInput::Message(serde_json::to_string(&msg).unwrap()))
-            .into()
-    }
-}
-
-impl From<WSClientMessage> for NetworkMessage {
-    fn from(msg: WSClientMessage) -> Self {
-        NetworkMessage::WebSocket(msg)
-    }
-}
-
 #[derive(Debug, Clone, PartialEq)]
 /// Some statistics on the connection.
 /// Not all fields are usable in all implementations.
@@ -545,11 +1261,27 @@ pub struct ConnStats {
     pub tx_bytes: u64,
     /// Round-trip time of the connection
     pub delay_ms: u32,
+    /// Messages delayed because of the bandwidth limits
+    pub delayed: u64,
+    /// Messages dropped because of the bandwidth limits
+    pub dropped: u64,
+    /// The NAT type detected by this connection
+    pub nat: Option<NatType>,
+}
+
+impl ConnStats {
+    /// Returns true if the connection goes through a TURN server.
+    pub fn relayed(&self) -> bool {
+        self.type_local == ConnType::TURN || self.type_remote == ConnType::TURN
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use flarch::start_logging;
+    use flarch::{
+        start_logging,
+        web_rtc::messages::{ConnectionStateMap, PeerMessage},
+    };
 
     use super::*;
 
@@ -567,4 +1299,647 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_expire_setup() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut web_rtc = Broker::new();
+        let mut net =
+            NetworkBroker::start(NodeConfig::new(), Broker::new(), web_rtc.clone()).await?;
+        let (tap_web_rtc, _) = web_rtc.get_tap_sync().await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let (pending, connected) = (U256::rnd(), U256::rnd());
+        net.settle_msg(NetworkIn::Connect(pending).into()).await?;
+        net.settle_msg(NetworkIn::Connect(connected).into()).await?;
+        web_rtc
+            .settle_msg(WebRTCConnMessage::OutputNC(
+                connected,
+                NCOutput::Connected(Direction::Outgoing),
+            ))
+            .await?;
+
+        for _ in 0..SETUP_TIMEOUT_SEC {
+            net.settle_msg(NetworkIn::Tick.into()).await?;
+        }
+        let disconnects: Vec<NodeID> = tap_web_rtc
+            .try_iter()
+            .filter_map(|msg| match msg {
+                WebRTCConnMessage::Disconnect(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![pending], disconnects);
+        let stats: Vec<SetupStats> = tap_net
+            .try_iter()
+            .filter_map(|msg| match msg {
+                NetworkMessage::Output(NetworkOut::SetupStats(s)) => Some(s),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            Some(&SetupStats {
+                pending: 0,
+                established: 1
+            }),
+            stats.last()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nat_type() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut web_rtc = Broker::new();
+        let net = NetworkBroker::start(NodeConfig::new(), Broker::new(), web_rtc.clone()).await?;
+        let (tap_net, _) = net.clone().get_tap_sync().await?;
+        for nat in [
+            None,
+            Some(NatType::Cone),
+            Some(NatType::Cone),
+            Some(NatType::Symmetric),
+        ] {
+            web_rtc
+                .settle_msg(WebRTCConnMessage::OutputNC(
+                    U256::rnd(),
+                    NCOutput::State(
+                        Direction::Outgoing,
+                        ConnectionStateMap {
+                            nat,
+                            ..Default::default()
+                        },
+                    ),
+                ))
+                .await?;
+        }
+        let nats: Vec<NatType> = tap_net
+            .try_iter()
+            .filter_map(|msg| match msg {
+                NetworkMessage::Output(NetworkOut::NatType(nat)) => Some(nat),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![NatType::Cone, NatType::Symmetric], nats);
+        Ok(())
+    }
+
+    // Sets up a connection to the node with the given connection type.
+    async fn connect_typ(
+        net: &mut Broker<NetworkMessage>,
+        web_rtc: &mut Broker<WebRTCConnMessage>,
+        id: NodeID,
+        type_local: ConnType,
+        nat: Option<NatType>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        net.settle_msg(NetworkIn::Connect(id).into()).await?;
+        let state = ConnectionStateMap {
+            type_local,
+            nat,
+            ..Default::default()
+        };
+        for msg in [
+            NCOutput::Connected(Direction::Outgoing),
+            NCOutput::State(Direction::Outgoing, state),
+        ] {
+            web_rtc
+                .settle_msg(WebRTCConnMessage::OutputNC(id, msg))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_relayed() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut web_rtc = Broker::new();
+        let mut net =
+            NetworkBroker::start(NodeConfig::new(), Broker::new(), web_rtc.clone()).await?;
+        let (tap_web_rtc, _) = web_rtc.get_tap_sync().await?;
+        let (relayed, direct) = (U256::rnd(), U256::rnd());
+        let resetups = || -> Vec<NodeID> {
+            tap_web_rtc
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    WebRTCConnMessage::Disconnect(id) => Some(id),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        connect_typ(&mut net, &mut web_rtc, relayed, ConnType::TURN, None).await?;
+        connect_typ(&mut net, &mut web_rtc, direct, ConnType::Host, None).await?;
+        for _ in 0..TURN_UPGRADE_SEC {
+            net.settle_msg(NetworkIn::Tick.into()).await?;
+        }
+        assert_eq!(vec![relayed], resetups());
+
+        // A new NAT type also sets up the relayed connections again.
+        let nat = Some(NatType::Cone);
+        connect_typ(&mut net, &mut web_rtc, relayed, ConnType::TURN, None).await?;
+        connect_typ(&mut net, &mut web_rtc, direct, ConnType::Host, nat).await?;
+        assert_eq!(vec![relayed], resetups());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_stats() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut web_rtc = Broker::new();
+        let mut net =
+            NetworkBroker::start(NodeConfig::new(), Broker::new(), web_rtc.clone()).await?;
+        let (tap_web_rtc, _) = web_rtc.get_tap_sync().await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let (connected, unknown) = (U256::rnd(), U256::rnd());
+        connect_typ(&mut net, &mut web_rtc, connected, ConnType::TURN, None).await?;
+        tap_web_rtc.try_iter().count();
+
+        net.settle_msg(NetworkIn::GetConnectionStats(unknown).into())
+            .await?;
+        net.settle_msg(NetworkIn::GetConnectionStats(connected).into())
+            .await?;
+        let requests: Vec<NodeID> = tap_web_rtc
+            .try_iter()
+            .filter_map(|msg| match msg {
+                WebRTCConnMessage::InputNC(id, NCInput::GetStats) => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![connected], requests);
+
+        let stats = TransportStats {
+            bytes_sent: 1000,
+            type_local: ConnType::TURN,
+            ..Default::default()
+        };
+        web_rtc
+            .settle_msg(WebRTCConnMessage::OutputNC(
+                connected,
+                NCOutput::Stats(Direction::Outgoing, stats),
+            ))
+            .await?;
+        let replies: Vec<TransportStats> = tap_net
+            .try_iter()
+            .filter_map(|msg| match msg {
+                NetworkMessage::Output(NetworkOut::ConnectionStats(id, _, s)) => {
+                    (id == connected).then_some(s)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![stats], replies);
+        assert!(replies[0].turn());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_salt() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = Broker::new();
+        let mut net = NetworkBroker::start(NodeConfig::new(), ws.clone(), Broker::new()).await?;
+        let (tap_ws, _) = ws.get_tap_sync().await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let rendezvous = || -> Vec<U256> {
+            tap_ws
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    WSClientMessage::Input(WSClientInput::Message(msg)) => {
+                        match serde_json::from_str(&msg) {
+                            Ok(WSSignalMessageFromNode::Rendezvous(rv)) => Some(rv),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // The phrase waits for the salt of the signalling server.
+        net.settle_msg(NetworkIn::Rendezvous("blue elephant".into()).into())
+            .await?;
+        assert_eq!(0, rendezvous().len());
+        let salt = U256::rnd();
+        let rv = rendezvous_id("blue elephant", &salt);
+        ws.settle_msg(
+            WSClientOutput::Message(serde_json::to_string(
+                &WSSignalMessageToNode::RendezvousSalt(salt),
+            )?)
+            .into(),
+        )
+        .await?;
+        assert_eq!(vec![rv], rendezvous());
+
+        let info = NodeConfig::new().info;
+        ws.settle_msg(
+            WSClientOutput::Message(serde_json::to_string(
+                &WSSignalMessageToNode::RendezvousReply(rv, info.clone()),
+            )?)
+            .into(),
+        )
+        .await?;
+        let found: Vec<(String, NodeInfo)> = tap_net
+            .try_iter()
+            .filter_map(|msg| match msg {
+                NetworkMessage::Output(NetworkOut::Rendezvous(phrase, info)) => {
+                    Some((phrase, info))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![("blue elephant".to_string(), info)], found);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = Broker::new();
+        let mut web_rtc = Broker::new();
+        let mut net = NetworkBroker::start(NodeConfig::new(), ws.clone(), web_rtc.clone()).await?;
+        let (tap_ws, _) = ws.get_tap_sync().await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let (lost, kept) = (U256::rnd(), U256::rnd());
+        net.settle_msg(NetworkIn::Connect(lost).into()).await?;
+        net.settle_msg(NetworkIn::Connect(kept).into()).await?;
+
+        ws.settle_msg(WSClientOutput::Disconnect.into()).await?;
+        web_rtc
+            .settle_msg(WebRTCConnMessage::OutputNC(
+                lost,
+                NCOutput::Disconnected(Direction::Outgoing),
+            ))
+            .await?;
+        // Attempts after 1, 2, 4, and 8 seconds.
+        for _ in 0..15 {
+            net.settle_msg(NetworkIn::Tick.into()).await?;
+        }
+        let connects = tap_ws
+            .try_iter()
+            .filter(|msg| matches!(msg, WSClientMessage::Input(WSClientInput::Connect)))
+            .count();
+        assert_eq!(4, connects);
+
+        ws.settle_msg(WSClientOutput::Connected.into()).await?;
+        for _ in 0..20 {
+            net.settle_msg(NetworkIn::Tick.into()).await?;
+        }
+        ws.settle_msg(
+            WSClientOutput::Message(serde_json::to_string(&WSSignalMessageToNode::Challenge(
+                SIGNAL_VERSION,
+                U256::rnd(),
+            ))?)
+            .into(),
+        )
+        .await?;
+
+        let states: Vec<SignalServerState> = tap_net
+            .try_iter()
+            .filter_map(|msg| match msg {
+                NetworkMessage::Output(NetworkOut::SignalServer(0, s)) => Some(s),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                SignalServerState::Disconnected,
+                SignalServerState::Reconnecting(1),
+                SignalServerState::Reconnecting(2),
+                SignalServerState::Reconnecting(3),
+                SignalServerState::Reconnecting(4),
+                SignalServerState::Reconnected,
+                SignalServerState::PeersRestored(vec![lost]),
+            ],
+            states
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = Broker::new();
+        let mut web_rtc = Broker::new();
+        let mut net = NetworkBroker::start(NodeConfig::new(), ws.clone(), web_rtc.clone()).await?;
+        let (tap_ws, _) = ws.get_tap_sync().await?;
+        let (tap_web_rtc, _) = web_rtc.get_tap_sync().await?;
+        let node = U256::rnd();
+        net.settle_msg(NetworkIn::Connect(node).into()).await?;
+
+        net.settle_msg(NetworkIn::Shutdown.into()).await?;
+        ws.settle_msg(WSClientOutput::Disconnect.into()).await?;
+        for _ in 0..5 {
+            net.settle_msg(NetworkIn::Tick.into()).await?;
+        }
+        let disconnects: Vec<NodeID> = tap_web_rtc
+            .try_iter()
+            .filter_map(|msg| match msg {
+                WebRTCConnMessage::InputNC(id, NCInput::Disconnect) => Some(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![node], disconnects);
+        let ws_msgs: Vec<WSClientInput> = tap_ws
+            .try_iter()
+            .filter_map(|msg| match msg {
+                WSClientMessage::Input(WSClientInput::Message(_)) => None,
+                WSClientMessage::Input(input) => Some(input),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![WSClientInput::Disconnect], ws_msgs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi_signal() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = [Broker::new(), Broker::new()];
+        let mut web_rtc = Broker::new();
+        let mut net =
+            NetworkBroker::start_multi(NodeConfig::new(), ws.to_vec(), web_rtc.clone()).await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let (tap_ws1, _) = ws[1].get_tap_sync().await?;
+        let (both, second) = (NodeConfig::new().info, NodeConfig::new().info);
+        for (ws, list) in ws
+            .iter_mut()
+            .zip([vec![both.clone()], vec![second.clone(), both.clone()]])
+        {
+            ws.settle_msg(
+                WSClientOutput::Message(serde_json::to_string(
+                    &WSSignalMessageToNode::ListIDsReply(list),
+                )?)
+                .into(),
+            )
+            .await?;
+        }
+        let lists: Vec<Vec<NodeInfo>> = tap_net
+            .try_iter()
+            .filter_map(|msg| match msg {
+                NetworkMessage::Output(NetworkOut::NodeListFromWS(list)) => Some(list),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(Some(&vec![both, second.clone()]), lists.last());
+
+        web_rtc
+            .settle_msg(WebRTCConnMessage::OutputNC(
+                second.get_id(),
+                NCOutput::Setup(Direction::Outgoing, PeerMessage::Init),
+            ))
+            .await?;
+        let setups = tap_ws1
+            .try_iter()
+            .filter(|msg| match msg {
+                WSClientMessage::Input(WSClientInput::Message(msg)) => {
+                    matches!(
+                        serde_json::from_str(msg),
+                        Ok(WSSignalMessageFromNode::PeerSetup(_))
+                    )
+                }
+                _ => false,
+            })
+            .count();
+        assert_eq!(1, setups);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = Broker::new();
+        let mut web_rtc = Broker::new();
+        let mut net = NetworkBroker::start(NodeConfig::new(), ws.clone(), web_rtc.clone()).await?;
+        let (tap_ws, _) = ws.get_tap_sync().await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let (blocked, other) = (U256::rnd(), U256::rnd());
+        let relayed = || -> Vec<NodeID> {
+            tap_ws
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    WSClientMessage::Input(WSClientInput::Message(s)) => {
+                        match serde_json::from_str(&s) {
+                            Ok(WSSignalMessageFromNode::Relay(id, _)) => Some(id),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let relay_states = || -> Vec<NodeID> {
+            tap_net
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    NetworkMessage::Output(NetworkOut::ConnectionState(state))
+                        if state.s.type_local == ConnType::Relay =>
+                    {
+                        Some(state.id)
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        for _ in 0..RELAY_AFTER_FAILURES {
+            net.settle_msg(NetworkIn::Connect(blocked).into()).await?;
+            for _ in 0..SETUP_TIMEOUT_SEC {
+                net.settle_msg(NetworkIn::Tick.into()).await?;
+            }
+        }
+        assert_eq!(vec![blocked], relay_states());
+        net.settle_msg(NetworkIn::MessageToNode(blocked, "hello".into()).into())
+            .await?;
+        assert_eq!(vec![blocked], relayed());
+
+        // A relayed message from another node also relays the answers.
+        ws.settle_msg(
+            WSClientOutput::Message(serde_json::to_string(&WSSignalMessageToNode::Relay(
+                other,
+                "hi".into(),
+            ))?)
+            .into(),
+        )
+        .await?;
+        assert_eq!(vec![other], relay_states());
+        net.settle_msg(NetworkIn::MessageToNode(other, "hello".into()).into())
+            .await?;
+        assert_eq!(vec![other], relayed());
+
+        // Once WebRTC works, the relay is not used anymore.
+        web_rtc
+            .settle_msg(WebRTCConnMessage::OutputNC(
+                other,
+                NCOutput::Connected(Direction::Incoming),
+            ))
+            .await?;
+        net.settle_msg(NetworkIn::MessageToNode(other, "hello".into()).into())
+            .await?;
+        assert!(relayed().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        for alternate in [Some("wss://other.fledg.re".to_string()), None] {
+            let mut ws = Broker::new();
+            let net = NetworkBroker::start(NodeConfig::new(), ws.clone(), Broker::new()).await?;
+            let (tap_ws, _) = ws.get_tap_sync().await?;
+            let (tap_net, _) = net.clone().get_tap_sync().await?;
+            ws.settle_msg(
+                WSClientOutput::Message(serde_json::to_string(
+                    &WSSignalMessageToNode::Maintenance(alternate.clone()),
+                )?)
+                .into(),
+            )
+            .await?;
+
+            let inputs: Vec<WSClientInput> = tap_ws
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    WSClientMessage::Input(input) => Some(input),
+                    _ => None,
+                })
+                .collect();
+            let states: Vec<SignalServerState> = tap_net
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    NetworkMessage::Output(NetworkOut::SignalServer(0, s)) => Some(s),
+                    _ => None,
+                })
+                .collect();
+            match alternate {
+                Some(url) => {
+                    assert_eq!(vec![WSClientInput::ConnectTo(url.clone())], inputs);
+                    assert_eq!(vec![SignalServerState::Maintenance(Some(url))], states);
+                }
+                None => {
+                    assert_eq!(vec![WSClientInput::Disconnect], inputs);
+                    assert_eq!(
+                        vec![
+                            SignalServerState::Maintenance(None),
+                            SignalServerState::Disconnected
+                        ],
+                        states
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_queue() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = Broker::new();
+        let mut web_rtc = Broker::new();
+        let mut net = NetworkBroker::start(NodeConfig::new(), ws.clone(), web_rtc.clone()).await?;
+        let (tap_net, _) = net.get_tap_sync().await?;
+        let (tap_rtc, _) = web_rtc.get_tap_sync().await?;
+        let (later, never) = (NodeConfig::new().info, U256::rnd());
+        let queued = || -> Vec<NetworkOut> {
+            tap_net
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    NetworkMessage::Output(out @ NetworkOut::Delivered(_, _))
+                    | NetworkMessage::Output(out @ NetworkOut::Expired(_, _)) => Some(out),
+                    _ => None,
+                })
+                .collect()
+        };
+        let texts = || -> Vec<NodeID> {
+            tap_rtc
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    WebRTCConnMessage::InputNC(id, NCInput::Text(_)) => Some(id),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        for dst in [later.get_id(), later.get_id(), never] {
+            net.settle_msg(NetworkIn::MessageToNode(dst, "hello".into()).into())
+                .await?;
+        }
+        assert!(texts().is_empty());
+
+        ws.settle_msg(
+            WSClientOutput::Message(serde_json::to_string(
+                &WSSignalMessageToNode::ListIDsReply(vec![later.clone()]),
+            )?)
+            .into(),
+        )
+        .await?;
+        assert_eq!(vec![later.get_id(); 2], texts());
+        assert_eq!(vec![NetworkOut::Delivered(later.get_id(), 2)], queued());
+
+        for _ in 0..QUEUE_TTL_SEC {
+            net.settle_msg(NetworkIn::Tick.into()).await?;
+        }
+        assert_eq!(vec![NetworkOut::Expired(never, 1)], queued());
+
+        for _ in 0..=QUEUE_MAX_MESSAGES {
+            net.settle_msg(NetworkIn::MessageToNode(never, "hello".into()).into())
+                .await?;
+        }
+        assert_eq!(vec![NetworkOut::Expired(never, 1)], queued());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_channels() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut ws = Broker::new();
+        let mut web_rtc = Broker::new();
+        let mut net = NetworkBroker::start(NodeConfig::new(), ws.clone(), web_rtc.clone()).await?;
+        let (tap_rtc, _) = web_rtc.get_tap_sync().await?;
+        let multi = NodeConfig::new().info;
+        let mut single = NodeConfig::new().info;
+        single.capabilities = Capabilities::empty();
+        ws.settle_msg(
+            WSClientOutput::Message(serde_json::to_string(
+                &WSSignalMessageToNode::ListIDsReply(vec![multi.clone(), single.clone()]),
+            )?)
+            .into(),
+        )
+        .await?;
+
+        for dst in [multi.get_id(), single.get_id()] {
+            net.settle_msg(NetworkIn::to_node(dst, Channel::Bulk, "hello".into()).into())
+                .await?;
+        }
+        let msgs: Vec<WebRTCConnMessage> = tap_rtc.try_iter().collect();
+        let connect = |id: NodeID| {
+            msgs.iter()
+                .position(|msg| msg == &WebRTCConnMessage::Connect(id))
+                .unwrap()
+        };
+        // Only the node announcing the channels gets them, before the connection is set up.
+        assert_eq!(
+            Some(connect(multi.get_id()) - 1),
+            msgs.iter()
+                .position(|msg| msg == &WebRTCConnMessage::MultiChannel(multi.get_id()))
+        );
+        assert!(!msgs.contains(&WebRTCConnMessage::MultiChannel(single.get_id())));
+        connect(single.get_id());
+        // The channel is kept for both nodes, the WebRTC connection falls back to the
+        // control channel if the bulk channel is not open.
+        for id in [multi.get_id(), single.get_id()] {
+            assert!(msgs.contains(&WebRTCConnMessage::InputNC(
+                id,
+                NCInput::TextChannel(Channel::Bulk, "hello".into())
+            )));
+        }
+        Ok(())
+    }
 }
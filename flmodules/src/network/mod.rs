@@ -197,7 +197,7 @@ pub enum NetworkSetupError {
     WebSocketServer(#[from] flarch::web_rtc::websocket::WSSError),
 }
 
-#[cfg(feature = "testing")]
+#[cfg(any(test, feature = "testing"))]
 pub mod testing;
 
 /// Starts a new [`broker::Broker<NetworkMessage>`] with a given `node`- and `connection`-configuration.
@@ -218,9 +218,13 @@ pub async fn network_broker_start(
 ) -> Result<Broker<NetworkMessage>, NetworkSetupError> {
     use crate::network::messages::NetworkBroker;
 
-    let ws = WebSocketClient::connect(&connection.signal()).await?;
-    let webrtc = WebRTCConn::new(web_rtc_spawner(connection)).await?;
-    Ok(NetworkBroker::start(node.clone(), ws, webrtc).await?)
+    let mut ws = vec![];
+    for url in connection.signals() {
+        ws.push(WebSocketClient::connect(&url).await?);
+    }
+    let throttle = connection.throttle();
+    let webrtc = WebRTCConn::new_with_throttle(web_rtc_spawner(connection), throttle).await?;
+    Ok(NetworkBroker::start_multi(node.clone(), ws, webrtc).await?)
 }
 
 /// Starts a new connection to the signalling server using the `node`-
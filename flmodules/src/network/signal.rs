@@ -42,29 +42,103 @@
 //! After the connections are set up, only the `IceCandidate` messages are exchanged between the
 //! nodes.
 //!
+//! # Rendezvous
+//!
+//! Two nodes which don't know each other's ID can meet using a short phrase.
+//! The signalling server chooses a random salt when it starts, and sends it with a
+//! [`WSSignalMessageToNode::RendezvousSalt`] after the challenge.
+//! Both nodes send a [`WSSignalMessageFromNode::Rendezvous`] with the [`rendezvous_id`]
+//! of the phrase and the salt, so the IDs can't be precomputed for a list of phrases.
+//! The first node waits at the signalling server, and once the second node arrives,
+//! both get a [`WSSignalMessageToNode::RendezvousReply`] with the [`NodeInfo`] of the
+//! other node.
+//! The phrase itself is never sent to the signalling server.
+//!
+//! # Relaying messages
+//!
+//! Some networks block WebRTC entirely.
+//! Two announced nodes can then exchange their messages through the signalling server:
+//! a node sends a [`WSSignalMessageFromNode::Relay`] with the ID of the destination,
+//! and the server forwards it as a [`WSSignalMessageToNode::Relay`] with the ID of
+//! the sender.
+//! As this uses the bandwidth of the server, every node can only relay
+//! [`RELAY_MAX_PER_MINUTE`] messages per minute, the others are dropped.
+//!
+//! # Rate limiting
+//!
+//! To protect a public signalling server, the announcements, list requests and
+//! peer setup messages are limited per minute, both per IP address and per node,
+//! as configured in [`SignalConfig`].
+//! An announcement only counts for its node once the signature is verified,
+//! before that it counts for the connection.
+//! Messages over the limit are dropped, and the number of dropped messages is sent as
+//! [`SignalOutput::RateLimited`] once per minute.
+//! Behind a reverse proxy, all nodes share the IP address of the proxy, so the
+//! limits per IP address need to be raised.
+//!
+//! # Maintenance
+//!
+//! Before the signalling server is stopped, e.g., for a redeploy, it can be put in
+//! maintenance mode with [`SignalInput::Maintenance`]:
+//!
+//! - all connected nodes get a [`WSSignalMessageToNode::Maintenance`], optionally with
+//!   the URL of another signalling server to switch to
+//! - new connections get the same message and are closed right away
+//! - once all nodes are gone, the server sends [`SignalOutput::Drained`] and can be stopped
+//!
+//! The WebRTC connections between the nodes are not affected.
+//!
 //! # Usage of the signalling server
 //!
 //! You can find an example of how the signalling server is used in
 //! <https://github.com/ineiti/fledger/tree/0.7.0/cli/flsignal/src/main.rs>
+//!
+//! # Running on other runtimes
+//!
+//! The server only talks to a `Broker<WSServerMessage>`, so any websocket server
+//! implementing these messages can be used.
+//! For wasm runtimes which accept the websockets themselves, like serverless workers
+//! or deno, the wasm build of flarch has a `WebSocketServer` in
+//! `flarch::web_rtc::web_socket_server`:
+//!
+//! ```ignore
+//! let (mut ws, ws_broker) = WebSocketServer::new().await?;
+//! let signal = SignalServer::new_with_timer(ws_broker, 2, timer).await?;
+//! // For every websocket upgrade request of the host:
+//! ws.accept(server_side_websocket, remote_ip)?;
+//! ```
+//!
+//! On runtimes which can't keep a periodic task running, like serverless workers,
+//! use [`SignalServer::new_with_timer`] and emit a [`TimerMessage::Minute`] from the
+//! host, e.g., from a scheduled trigger.
+//! The state of the signalling server only lives as long as the wasm instance, so
+//! the host needs to send all websockets to the same instance, e.g., with a
+//! Cloudflare Durable Object.
 
 use bimap::BiMap;
+use itertools::concat;
 use serde::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
     fmt::{Error, Formatter},
+    net::IpAddr,
 };
 
-use flarch::{
-    broker::{Broker, BrokerError, Subsystem, SubsystemHandler}, nodeids::{NodeID, U256}, platform_async_trait, web_rtc::{
-        messages::PeerInfo,
-        websocket::{WSServerInput, WSServerMessage, WSServerOutput},
-    }
-};
 use crate::{
     nodeconfig::NodeInfo,
     timer::{TimerBroker, TimerMessage},
 };
+use flarch::{
+    broker::{Broker, BrokerError, Subsystem, SubsystemHandler},
+    nodeids::{NodeID, U256},
+    platform_async_trait,
+    web_rtc::{
+        messages::PeerInfo,
+        websocket::{WSServerInput, WSServerMessage, WSServerOutput},
+    },
+};
 
 #[derive(Clone, Debug)]
 /// The possible messages for the signalling server broker, including the
@@ -83,6 +157,9 @@ pub enum SignalMessage {
 pub enum SignalInput {
     /// One minute timer clock for removing stale connections
     Timer,
+    /// Stops accepting new nodes, and asks the connected nodes to switch to
+    /// the given signalling server, if any.
+    Maintenance(Option<String>),
 }
 
 #[derive(Clone, Debug)]
@@ -92,8 +169,77 @@ pub enum SignalOutput {
     NodeStats(Vec<NodeStat>),
     /// Whenever a new node has joined the signalling server
     NewNode(NodeID),
+    /// The number of announced nodes, whenever it changes
+    NodeCount(usize),
     /// If the server has been stopped
     Stopped,
+    /// All nodes left the server after [`SignalInput::Maintenance`]
+    Drained,
+    /// The total number of messages dropped by the rate limits, whenever it changes
+    RateLimited(RateLimited),
+}
+
+/// How many messages of one kind are accepted per minute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// From all connections with the same IP address.
+    pub per_ip: usize,
+    /// From a single node.
+    pub per_node: usize,
+}
+
+/// The configuration of the [`SignalServer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignalConfig {
+    /// The minimum time an idle node will be kept in the list.
+    pub ttl_minutes: u64,
+    /// Limits the [`WSSignalMessageFromNode::Announce`] messages.
+    pub announce: RateLimit,
+    /// Limits the [`WSSignalMessageFromNode::ListIDsRequest`] messages.
+    pub list_ids: RateLimit,
+    /// Limits the [`WSSignalMessageFromNode::PeerSetup`] messages.
+    pub peer_setup: RateLimit,
+}
+
+impl Default for SignalConfig {
+    fn default() -> Self {
+        Self {
+            ttl_minutes: 2,
+            announce: RateLimit {
+                per_ip: 100,
+                per_node: 10,
+            },
+            list_ids: RateLimit {
+                per_ip: 1000,
+                per_node: 60,
+            },
+            peer_setup: RateLimit {
+                per_ip: 10_000,
+                per_node: 1000,
+            },
+        }
+    }
+}
+
+/// How many messages have been dropped by the rate limits since the start.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimited {
+    pub announce: u64,
+    pub list_ids: u64,
+    pub peer_setup: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Limited {
+    Announce,
+    ListIDs,
+    PeerSetup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Source {
+    Ip(IpAddr),
+    Node(U256),
 }
 
 /// This implements a signalling server.
@@ -108,11 +254,27 @@ pub struct SignalServer {
     info: HashMap<U256, NodeInfo>,
     ttl: HashMap<usize, u64>,
     ttl_minutes: u64,
+    rendezvous: HashMap<U256, usize>,
+    rendezvous_salt: U256,
+    // Messages relayed during the current minute, per connection.
+    relayed: HashMap<usize, usize>,
+    // Set by [`SignalInput::Maintenance`], with the URL of the other signalling server.
+    maintenance: Option<Option<String>>,
+    drained: bool,
+    config: SignalConfig,
+    addrs: HashMap<usize, IpAddr>,
+    // Messages received during the current minute.
+    counts: HashMap<(Limited, Source), usize>,
+    rate_limited: RateLimited,
+    rate_reported: RateLimited,
 }
 
 /// Our current version - will change if the API is incompatible.
 pub const SIGNAL_VERSION: u64 = 3;
 
+/// How many messages a node can relay through the signalling server per minute.
+pub const RELAY_MAX_PER_MINUTE: usize = 600;
+
 impl SignalServer {
     /// Creates a new [`SignalServer`].
     /// `ttl_minutes` is the minimum time an idle node will be
@@ -120,6 +282,29 @@ impl SignalServer {
     pub async fn new(
         ws_server: Broker<WSServerMessage>,
         ttl_minutes: u64,
+    ) -> Result<Broker<SignalMessage>, BrokerError> {
+        Self::new_with_timer(ws_server, ttl_minutes, TimerBroker::start().await?).await
+    }
+
+    /// Creates a new [`SignalServer`] which uses the given timer to remove idle
+    /// nodes, instead of starting its own [`TimerBroker`].
+    pub async fn new_with_timer(
+        ws_server: Broker<WSServerMessage>,
+        ttl_minutes: u64,
+        timer: Broker<TimerMessage>,
+    ) -> Result<Broker<SignalMessage>, BrokerError> {
+        let config = SignalConfig {
+            ttl_minutes,
+            ..SignalConfig::default()
+        };
+        Self::new_with_config(ws_server, config, timer).await
+    }
+
+    /// Creates a new [`SignalServer`] with the given configuration and timer.
+    pub async fn new_with_config(
+        ws_server: Broker<WSServerMessage>,
+        config: SignalConfig,
+        mut timer: Broker<TimerMessage>,
     ) -> Result<Broker<SignalMessage>, BrokerError> {
         let mut broker = Broker::new();
         broker
@@ -129,7 +314,17 @@ impl SignalServer {
                 ttl: HashMap::new(),
                 // Add 2 to the ttl_minutes to make sure that nodes are kept at least
                 // 1 minute in the list.
-                ttl_minutes: ttl_minutes + 2,
+                ttl_minutes: config.ttl_minutes + 2,
+                rendezvous: HashMap::new(),
+                rendezvous_salt: U256::rnd(),
+                relayed: HashMap::new(),
+                maintenance: None,
+                drained: false,
+                config,
+                addrs: HashMap::new(),
+                counts: HashMap::new(),
+                rate_limited: RateLimited::default(),
+                rate_reported: RateLimited::default(),
             })))
             .await?;
         broker
@@ -139,8 +334,7 @@ impl SignalServer {
                 Box::new(Self::link_ss_wss),
             )
             .await?;
-        TimerBroker::start()
-            .await?
+        timer
             .forward(
                 broker.clone(),
                 Box::new(|msg| {
@@ -167,8 +361,8 @@ impl SignalServer {
     fn msg_in(&mut self, msg_in: SignalInput) -> Vec<SignalMessage> {
         match msg_in {
             SignalInput::Timer => self.msg_in_timer(),
+            SignalInput::Maintenance(alternate) => self.msg_in_maintenance(alternate),
         }
-        vec![]
     }
 
     fn msg_wss(&mut self, msg: WSServerOutput) -> Vec<SignalMessage> {
@@ -182,13 +376,31 @@ impl SignalServer {
                 }
             }
             WSServerOutput::NewConnection(index) => return self.msg_ws_connect(index),
+            WSServerOutput::RemoteAddr(index, addr) => {
+                self.addrs.insert(index, addr);
+            }
             WSServerOutput::Disconnection(id) => self.remove_node(id),
             WSServerOutput::Stopped => return vec![SignalMessage::Output(SignalOutput::Stopped)],
         }
         vec![]
     }
 
-    fn msg_in_timer(&mut self) {
+    fn msg_in_maintenance(&mut self, alternate: Option<String>) -> Vec<SignalMessage> {
+        log::info!(
+            "Starting maintenance, {} nodes connected, alternate server: {alternate:?}",
+            self.connection_ids.len()
+        );
+        self.maintenance = Some(alternate.clone());
+        let msg = WSSignalMessageToNode::Maintenance(alternate);
+        self.connection_ids
+            .right_values()
+            .flat_map(|index| self.send_msg_node(*index, msg.clone()))
+            .collect()
+    }
+
+    fn msg_in_timer(&mut self) -> Vec<SignalMessage> {
+        self.relayed.clear();
+        self.counts.clear();
         let mut to_remove = Vec::new();
         for (index, ttl) in self.ttl.iter_mut() {
             *ttl -= 1;
@@ -200,6 +412,54 @@ impl SignalServer {
         for id in to_remove {
             self.remove_node(id);
         }
+        if self.rate_limited == self.rate_reported {
+            return vec![];
+        }
+        self.rate_reported = self.rate_limited.clone();
+        vec![SignalOutput::RateLimited(self.rate_limited.clone()).into()]
+    }
+
+    // Returns false if the IP address or the node of the connection already sent
+    // too many messages of this kind in the current minute.
+    // Before the announcement, the connection is counted by its challenge.
+    fn rate_limit(&mut self, index: usize, kind: Limited) -> bool {
+        let sources = [
+            self.addrs.get(&index).map(|ip| Source::Ip(*ip)),
+            self.connection_ids
+                .get_by_right(&index)
+                .map(|id| Source::Node(*id)),
+        ];
+        self.rate_limit_sources(kind, sources.into_iter().flatten().collect())
+    }
+
+    // Returns false if one of the sources already sent too many messages of this
+    // kind in the current minute. Only the accepted messages are counted.
+    fn rate_limit_sources(&mut self, kind: Limited, sources: Vec<Source>) -> bool {
+        let limit = match kind {
+            Limited::Announce => self.config.announce,
+            Limited::ListIDs => self.config.list_ids,
+            Limited::PeerSetup => self.config.peer_setup,
+        };
+        let max = |source: &Source| match source {
+            Source::Ip(_) => limit.per_ip,
+            Source::Node(_) => limit.per_node,
+        };
+        if sources
+            .iter()
+            .any(|source| self.counts.get(&(kind, *source)).unwrap_or(&0) >= &max(source))
+        {
+            log::debug!("Dropping {kind:?} from {sources:?}");
+            *match kind {
+                Limited::Announce => &mut self.rate_limited.announce,
+                Limited::ListIDs => &mut self.rate_limited.list_ids,
+                Limited::PeerSetup => &mut self.rate_limited.peer_setup,
+            } += 1;
+            return false;
+        }
+        for source in sources {
+            *self.counts.entry((kind, source)).or_default() += 1;
+        }
+        true
     }
 
     // The id is the challange until the announcement succeeds. Then ws_announce calls
@@ -210,10 +470,19 @@ impl SignalServer {
             WSSignalMessageFromNode::ListIDsRequest => self.ws_list_ids(index),
             WSSignalMessageFromNode::PeerSetup(pi) => self.ws_peer_setup(index, pi),
             WSSignalMessageFromNode::NodeStats(ns) => self.ws_node_stats(ns),
+            WSSignalMessageFromNode::Rendezvous(rv) => self.ws_rendezvous(index, rv),
+            WSSignalMessageFromNode::Relay(dst, msg) => self.ws_relay(index, dst, msg),
         }
     }
 
     fn msg_ws_connect(&mut self, index: usize) -> Vec<SignalMessage> {
+        if let Some(alternate) = self.maintenance.clone() {
+            log::debug!("Refusing new connection during maintenance");
+            return concat(vec![
+                self.send_msg_node(index, WSSignalMessageToNode::Maintenance(alternate)),
+                vec![WSServerInput::Close(index).into()],
+            ]);
+        }
         log::debug!("Sending challenge to new connection");
         let challenge = U256::rnd();
         self.connection_ids.insert(challenge, index);
@@ -221,10 +490,21 @@ impl SignalServer {
         let challenge_msg =
             serde_json::to_string(&WSSignalMessageToNode::Challenge(SIGNAL_VERSION, challenge))
                 .unwrap();
-        vec![WSServerInput::Message(index, challenge_msg).into()]
+        concat(vec![
+            vec![WSServerInput::Message(index, challenge_msg).into()],
+            self.send_msg_node(
+                index,
+                WSSignalMessageToNode::RendezvousSalt(self.rendezvous_salt),
+            ),
+        ])
     }
 
     fn ws_announce(&mut self, index: usize, msg: MessageAnnounce) -> Vec<SignalMessage> {
+        // The ID of the node is only counted once the signature is verified, else
+        // anybody could use up the quota of another node.
+        if !self.rate_limit(index, Limited::Announce) {
+            return vec![];
+        }
         let challenge = match self.connection_ids.get_by_right(&index) {
             Some(id) => id,
             None => {
@@ -237,7 +517,15 @@ impl SignalServer {
             return vec![];
         }
         let id = msg.node_info.get_id();
+        if !self.rate_limit_sources(Limited::Announce, vec![Source::Node(id)]) {
+            return vec![];
+        }
         self.connection_ids.insert(id, index);
+        // If the node announced itself on another connection before, that connection
+        // lost its ID and cannot wait for a rendezvous anymore.
+        let ids = &self.connection_ids;
+        self.rendezvous
+            .retain(|_, waiting| ids.contains_right(waiting));
 
         log::info!("Registration of node-id {}: {}", id, msg.node_info.name);
         self.info.insert(id, msg.node_info);
@@ -245,6 +533,9 @@ impl SignalServer {
     }
 
     fn ws_list_ids(&mut self, id: usize) -> Vec<SignalMessage> {
+        if !self.rate_limit(id, Limited::ListIDs) {
+            return vec![];
+        }
         log::info!("Current list is: {:?}", self.info.values());
         self.send_msg_node(
             id,
@@ -253,6 +544,9 @@ impl SignalServer {
     }
 
     fn ws_peer_setup(&mut self, index: usize, pi: PeerInfo) -> Vec<SignalMessage> {
+        if !self.rate_limit(index, Limited::PeerSetup) {
+            return vec![];
+        }
         let id = match self.connection_ids.get_by_right(&index) {
             Some(id) => id,
             None => {
@@ -273,6 +567,64 @@ impl SignalServer {
         vec![SignalOutput::NodeStats(ns).into()]
     }
 
+    fn ws_rendezvous(&mut self, index: usize, rv: U256) -> Vec<SignalMessage> {
+        let info = match self.connection_ids.get_by_right(&index) {
+            Some(id) if self.info.contains_key(id) => self.info[id].clone(),
+            _ => {
+                log::warn!("Got a rendezvous message from an unannounced node.");
+                return vec![];
+            }
+        };
+        let other = self.rendezvous.remove(&rv).filter(|other| *other != index);
+        let other_info = other.and_then(|other| {
+            self.connection_ids
+                .get_by_right(&other)
+                .and_then(|id| self.info.get(id))
+                .cloned()
+        });
+        match (other, other_info) {
+            (Some(other), Some(other_info)) => {
+                log::debug!("Rendezvous between {} and {}", info.name, other_info.name);
+                concat(vec![
+                    self.send_msg_node(
+                        index,
+                        WSSignalMessageToNode::RendezvousReply(rv, other_info),
+                    ),
+                    self.send_msg_node(other, WSSignalMessageToNode::RendezvousReply(rv, info)),
+                ])
+            }
+            (other, _) => {
+                if let Some(other) = other {
+                    log::warn!("Dropping stale rendezvous of connection {other}");
+                }
+                self.rendezvous.insert(rv, index);
+                vec![]
+            }
+        }
+    }
+
+    fn ws_relay(&mut self, index: usize, dst: NodeID, msg: String) -> Vec<SignalMessage> {
+        let id = match self.connection_ids.get_by_right(&index) {
+            Some(id) if self.info.contains_key(id) => *id,
+            _ => {
+                log::warn!("Got a relay message from an unannounced node.");
+                return vec![];
+            }
+        };
+        let count = self.relayed.entry(index).or_default();
+        if *count >= RELAY_MAX_PER_MINUTE {
+            log::debug!("Node {id} relays too many messages");
+            return vec![];
+        }
+        *count += 1;
+        match self.connection_ids.get_by_left(&dst) {
+            Some(dst_index) => {
+                self.send_msg_node(*dst_index, WSSignalMessageToNode::Relay(id, msg))
+            }
+            None => vec![],
+        }
+    }
+
     fn send_msg_node(&self, index: usize, msg: WSSignalMessageToNode) -> Vec<SignalMessage> {
         vec![WSServerInput::Message(index, serde_json::to_string(&msg).unwrap()).into()]
     }
@@ -282,6 +634,9 @@ impl SignalServer {
             self.info.remove(&id);
         }
         self.ttl.remove(&index);
+        self.relayed.remove(&index);
+        self.addrs.remove(&index);
+        self.rendezvous.retain(|_, waiting| *waiting != index);
     }
 }
 
@@ -289,6 +644,7 @@ impl SignalServer {
 impl SubsystemHandler<SignalMessage> for SignalServer {
     async fn messages(&mut self, from_broker: Vec<SignalMessage>) -> Vec<SignalMessage> {
         let mut out = vec![];
+        let nodes = self.info.len();
         for msg in from_broker {
             match msg {
                 SignalMessage::Input(msg_in) => out.extend(self.msg_in(msg_in)),
@@ -298,6 +654,14 @@ impl SubsystemHandler<SignalMessage> for SignalServer {
                 _ => {}
             }
         }
+        if self.info.len() != nodes {
+            out.push(SignalOutput::NodeCount(self.info.len()).into());
+        }
+        if self.maintenance.is_some() && !self.drained && self.connection_ids.is_empty() {
+            log::info!("All nodes left, the server can be stopped");
+            self.drained = true;
+            out.push(SignalOutput::Drained.into());
+        }
         out
     }
 }
@@ -321,6 +685,15 @@ pub enum WSSignalMessageToNode {
     ListIDsReply(Vec<NodeInfo>),
     /// Information for setting up a WebRTC connection
     PeerSetup(PeerInfo),
+    /// Another node used the same rendezvous ID
+    RendezvousReply(U256, NodeInfo),
+    /// The salt to use in [`rendezvous_id`] for this signalling server
+    RendezvousSalt(U256),
+    /// A message relayed from the node with the given ID
+    Relay(NodeID, String),
+    /// The signalling server is going down for maintenance, and the node should
+    /// switch to the given signalling server, if any
+    Maintenance(Option<String>),
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -336,6 +709,21 @@ pub enum WSSignalMessageFromNode {
     PeerSetup(PeerInfo),
     /// Some statistics about its connections from the remote node
     NodeStats(Vec<NodeStat>),
+    /// Wait for another node with the same rendezvous ID, as created by [`rendezvous_id`]
+    Rendezvous(U256),
+    /// A message to relay to the node with the given ID, if WebRTC doesn't work
+    Relay(NodeID, String),
+}
+
+/// Returns the rendezvous ID of a phrase with the salt of the signalling server.
+/// Leading and trailing whitespaces, as well as the case, are ignored, so that
+/// users can easily type the same phrase.
+pub fn rendezvous_id(phrase: &str, salt: &U256) -> U256 {
+    let mut hash = Sha256::new();
+    hash.update("fledger-rendezvous");
+    hash.update(salt);
+    hash.update(phrase.trim().to_lowercase());
+    hash.finalize().into()
 }
 
 impl std::fmt::Display for WSSignalMessageToNode {
@@ -344,6 +732,10 @@ impl std::fmt::Display for WSSignalMessageToNode {
             WSSignalMessageToNode::Challenge(_, _) => write!(f, "Challenge"),
             WSSignalMessageToNode::ListIDsReply(_) => write!(f, "ListIDsReply"),
             WSSignalMessageToNode::PeerSetup(_) => write!(f, "PeerSetup"),
+            WSSignalMessageToNode::RendezvousReply(_, _) => write!(f, "RendezvousReply"),
+            WSSignalMessageToNode::RendezvousSalt(_) => write!(f, "RendezvousSalt"),
+            WSSignalMessageToNode::Relay(_, _) => write!(f, "Relay"),
+            WSSignalMessageToNode::Maintenance(_) => write!(f, "Maintenance"),
         }
     }
 }
@@ -355,6 +747,8 @@ impl std::fmt::Display for WSSignalMessageFromNode {
             WSSignalMessageFromNode::ListIDsRequest => write!(f, "ListIDsRequest"),
             WSSignalMessageFromNode::PeerSetup(_) => write!(f, "PeerSetup"),
             WSSignalMessageFromNode::NodeStats(_) => write!(f, "NodeStats"),
+            WSSignalMessageFromNode::Rendezvous(_) => write!(f, "Rendezvous"),
+            WSSignalMessageFromNode::Relay(_, _) => write!(f, "Relay"),
         }
     }
 }
@@ -395,6 +789,7 @@ impl std::fmt::Debug for SignalInput {
         match self {
             // SignalInput::WebSocket(_) => write!(f, "WebSocket"),
             SignalInput::Timer => write!(f, "Timer"),
+            SignalInput::Maintenance(alternate) => write!(f, "Maintenance({alternate:?})"),
         }
     }
 }
@@ -428,3 +823,391 @@ impl From<SignalOutput> for SignalMessage {
         SignalMessage::Output(msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use flarch::start_logging;
+
+    use crate::nodeconfig::NodeConfig;
+
+    use super::*;
+
+    fn to_node(msg: WSServerMessage) -> Option<(usize, WSSignalMessageToNode)> {
+        if let WSServerMessage::Input(WSServerInput::Message(index, msg)) = msg {
+            return serde_json::from_str(&msg).ok().map(|msg| (index, msg));
+        }
+        None
+    }
+
+    fn from_node(index: usize, msg: WSSignalMessageFromNode) -> WSServerMessage {
+        WSServerMessage::Output(WSServerOutput::Message(
+            index,
+            serde_json::to_string(&msg).unwrap(),
+        ))
+    }
+
+    async fn list(
+        wss: &mut Broker<WSServerMessage>,
+        tap: &std::sync::mpsc::Receiver<WSServerMessage>,
+        index: usize,
+    ) -> Vec<NodeInfo> {
+        tap.try_iter().count();
+        wss.settle_msg(from_node(index, WSSignalMessageFromNode::ListIDsRequest))
+            .await
+            .unwrap();
+        match tap.try_iter().find_map(to_node) {
+            Some((_, WSSignalMessageToNode::ListIDsReply(list))) => list,
+            _ => panic!("Didn't get list"),
+        }
+    }
+
+    async fn announce(
+        wss: &mut Broker<WSServerMessage>,
+        tap: &std::sync::mpsc::Receiver<WSServerMessage>,
+        index: usize,
+    ) -> NodeConfig {
+        announce_nc(wss, tap, index, NodeConfig::new()).await
+    }
+
+    async fn announce_nc(
+        wss: &mut Broker<WSServerMessage>,
+        tap: &std::sync::mpsc::Receiver<WSServerMessage>,
+        index: usize,
+        nc: NodeConfig,
+    ) -> NodeConfig {
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::NewConnection(
+            index,
+        )))
+        .await
+        .unwrap();
+        let challenge = match tap.try_iter().find_map(to_node) {
+            Some((i, WSSignalMessageToNode::Challenge(_, challenge))) if i == index => challenge,
+            _ => panic!("Didn't get challenge"),
+        };
+        let announce = MessageAnnounce {
+            version: SIGNAL_VERSION,
+            challenge,
+            node_info: nc.info.clone(),
+            signature: nc.sign(challenge.to_bytes()),
+        };
+        wss.settle_msg(from_node(
+            index,
+            WSSignalMessageFromNode::Announce(announce),
+        ))
+        .await
+        .unwrap();
+        // Skip the rendezvous salt.
+        tap.try_iter().count();
+        nc
+    }
+
+    #[tokio::test]
+    async fn test_timer() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let mut timer = Broker::new();
+        let mut server = SignalServer::new_with_timer(wss.clone(), 0, timer.clone()).await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let (tap_server, _) = server.get_tap_sync().await?;
+        let node_counts = || {
+            tap_server
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    SignalMessage::Output(SignalOutput::NodeCount(count)) => Some(count),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let nc = announce(&mut wss, &tap, 1).await;
+        assert_eq!(vec![1], node_counts());
+
+        assert_eq!(vec![nc.info.clone()], list(&mut wss, &tap, 1).await);
+
+        // The request above refreshed the ttl of the node, so it takes two
+        // minutes for the node to be removed.
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::NewConnection(2)))
+            .await?;
+        timer.settle_msg(TimerMessage::Minute).await?;
+        assert_eq!(vec![nc.info.clone()], list(&mut wss, &tap, 2).await);
+        timer.settle_msg(TimerMessage::Minute).await?;
+        timer.settle_msg(TimerMessage::Minute).await?;
+        assert_eq!(0, list(&mut wss, &tap, 2).await.len());
+        assert_eq!(vec![0], node_counts());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let _server = SignalServer::new_with_timer(wss.clone(), 1, Broker::new()).await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let nc1 = announce(&mut wss, &tap, 1).await;
+        let nc2 = announce(&mut wss, &tap, 2).await;
+        let salt = U256::rnd();
+        let rv = rendezvous_id("Blue Elephant ", &salt);
+        assert_eq!(rv, rendezvous_id("blue elephant", &salt));
+        assert_ne!(rv, rendezvous_id("blue elephant", &U256::rnd()));
+
+        wss.settle_msg(from_node(1, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        wss.settle_msg(from_node(1, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        assert_eq!(0, tap.try_iter().filter_map(to_node).count());
+
+        wss.settle_msg(from_node(2, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        let mut replies: Vec<(usize, WSSignalMessageToNode)> =
+            tap.try_iter().filter_map(to_node).collect();
+        replies.sort_by_key(|(i, _)| *i);
+        assert_eq!(
+            vec![
+                (1, WSSignalMessageToNode::RendezvousReply(rv, nc2.info)),
+                (2, WSSignalMessageToNode::RendezvousReply(rv, nc1.info)),
+            ],
+            replies
+        );
+
+        // A disconnected node doesn't wait anymore.
+        wss.settle_msg(from_node(1, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::Disconnection(1)))
+            .await?;
+        wss.settle_msg(from_node(2, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        assert_eq!(0, tap.try_iter().filter_map(to_node).count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_reannounce() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let _server = SignalServer::new_with_timer(wss.clone(), 1, Broker::new()).await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let nc1 = announce(&mut wss, &tap, 1).await;
+        let nc2 = announce(&mut wss, &tap, 2).await;
+        let rv = U256::rnd();
+
+        // The waiting node announces itself again on another connection, so the
+        // first connection doesn't wait anymore.
+        wss.settle_msg(from_node(1, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        announce_nc(&mut wss, &tap, 3, nc1.clone()).await;
+        wss.settle_msg(from_node(2, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        assert_eq!(0, tap.try_iter().filter_map(to_node).count());
+
+        wss.settle_msg(from_node(3, WSSignalMessageFromNode::Rendezvous(rv)))
+            .await?;
+        let mut replies: Vec<(usize, WSSignalMessageToNode)> =
+            tap.try_iter().filter_map(to_node).collect();
+        replies.sort_by_key(|(i, _)| *i);
+        assert_eq!(
+            vec![
+                (2, WSSignalMessageToNode::RendezvousReply(rv, nc1.info)),
+                (3, WSSignalMessageToNode::RendezvousReply(rv, nc2.info)),
+            ],
+            replies
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relay() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let mut timer = Broker::new();
+        let _server = SignalServer::new_with_timer(wss.clone(), 1, timer.clone()).await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let nc1 = announce(&mut wss, &tap, 1).await;
+        let nc2 = announce(&mut wss, &tap, 2).await;
+        let relay = WSSignalMessageFromNode::Relay(nc2.info.get_id(), "hello".into());
+
+        wss.settle_msg(from_node(1, relay.clone())).await?;
+        assert_eq!(
+            vec![(
+                2,
+                WSSignalMessageToNode::Relay(nc1.info.get_id(), "hello".into())
+            )],
+            tap.try_iter().filter_map(to_node).collect::<Vec<_>>()
+        );
+
+        // Unannounced nodes cannot relay.
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::NewConnection(3)))
+            .await?;
+        tap.try_iter().count();
+        wss.settle_msg(from_node(3, relay.clone())).await?;
+        assert_eq!(0, tap.try_iter().filter_map(to_node).count());
+
+        // Too many messages are dropped until the next minute.
+        for _ in 1..RELAY_MAX_PER_MINUTE + 10 {
+            wss.settle_msg(from_node(1, relay.clone())).await?;
+        }
+        assert_eq!(
+            RELAY_MAX_PER_MINUTE - 1,
+            tap.try_iter().filter_map(to_node).count()
+        );
+        timer.settle_msg(TimerMessage::Minute).await?;
+        wss.settle_msg(from_node(1, relay)).await?;
+        assert_eq!(1, tap.try_iter().filter_map(to_node).count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let mut timer = Broker::new();
+        let config = SignalConfig {
+            list_ids: RateLimit {
+                per_ip: 3,
+                per_node: 2,
+            },
+            ..SignalConfig::default()
+        };
+        let mut server = SignalServer::new_with_config(wss.clone(), config, timer.clone()).await?;
+        let (tap_server, _) = server.get_tap_sync().await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        for index in 1..=3 {
+            let addr = (index < 3).then_some(ip).unwrap_or([192, 0, 2, 2].into());
+            wss.settle_msg(WSServerMessage::Output(WSServerOutput::RemoteAddr(
+                index, addr,
+            )))
+            .await?;
+            announce(&mut wss, &tap, index).await;
+        }
+        tap.try_iter().count();
+        let list = |index| from_node(index, WSSignalMessageFromNode::ListIDsRequest);
+
+        // Per node, and then per IP address.
+        for (index, count) in [(1, 3), (2, 2), (3, 3)] {
+            for _ in 0..count {
+                wss.settle_msg(list(index)).await?;
+            }
+        }
+        let replies: Vec<usize> = tap
+            .try_iter()
+            .filter_map(to_node)
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(vec![1, 1, 2, 3, 3], replies);
+
+        timer.settle_msg(TimerMessage::Minute).await?;
+        let limited = RateLimited {
+            list_ids: 3,
+            ..RateLimited::default()
+        };
+        assert!(tap_server.try_iter().any(|msg| matches!(
+            msg,
+            SignalMessage::Output(SignalOutput::RateLimited(rl)) if rl == limited
+        )));
+        wss.settle_msg(list(2)).await?;
+        assert_eq!(1, tap.try_iter().filter_map(to_node).count());
+        Ok(())
+    }
+
+    // Announcements with a wrong signature don't count for the node they claim to be.
+    #[tokio::test]
+    async fn test_rate_limit_announce() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let config = SignalConfig {
+            announce: RateLimit {
+                per_ip: 10,
+                per_node: 1,
+            },
+            ..SignalConfig::default()
+        };
+        let mut server = SignalServer::new_with_config(wss.clone(), config, Broker::new()).await?;
+        let (tap_server, _) = server.get_tap_sync().await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let victim = NodeConfig::new();
+
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::NewConnection(1)))
+            .await?;
+        let challenge = match tap.try_iter().find_map(to_node) {
+            Some((1, WSSignalMessageToNode::Challenge(_, challenge))) => challenge,
+            _ => panic!("Didn't get challenge"),
+        };
+        for _ in 0..3 {
+            let forged = MessageAnnounce {
+                version: SIGNAL_VERSION,
+                challenge,
+                node_info: victim.info.clone(),
+                signature: vec![0; 64],
+            };
+            wss.settle_msg(from_node(1, WSSignalMessageFromNode::Announce(forged)))
+                .await?;
+        }
+        tap.try_iter().count();
+
+        let new_node = |tap: &std::sync::mpsc::Receiver<SignalMessage>| {
+            tap.try_iter()
+                .any(|msg| matches!(msg, SignalMessage::Output(SignalOutput::NewNode(_))))
+        };
+        announce_nc(&mut wss, &tap, 2, victim.clone()).await;
+        assert!(new_node(&tap_server));
+        announce_nc(&mut wss, &tap, 3, victim).await;
+        assert!(!new_node(&tap_server));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut wss = Broker::new();
+        let mut server = SignalServer::new_with_timer(wss.clone(), 1, Broker::new()).await?;
+        let (tap, _) = wss.get_tap_sync().await?;
+        let (tap_server, _) = server.get_tap_sync().await?;
+        announce(&mut wss, &tap, 1).await;
+        announce(&mut wss, &tap, 2).await;
+        tap.try_iter().count();
+        let alternate = Some("wss://other.fledg.re".to_string());
+        let maintenance = WSSignalMessageToNode::Maintenance(alternate.clone());
+
+        server
+            .settle_msg(SignalInput::Maintenance(alternate).into())
+            .await?;
+        let mut msgs: Vec<(usize, WSSignalMessageToNode)> =
+            tap.try_iter().filter_map(to_node).collect();
+        msgs.sort_by_key(|(i, _)| *i);
+        assert_eq!(
+            vec![(1, maintenance.clone()), (2, maintenance.clone())],
+            msgs
+        );
+
+        // New nodes are sent away.
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::NewConnection(3)))
+            .await?;
+        let msgs: Vec<WSServerMessage> = tap.try_iter().collect();
+        assert_eq!(
+            vec![(3, maintenance)],
+            msgs.iter().cloned().filter_map(to_node).collect::<Vec<_>>()
+        );
+        assert!(msgs.contains(&WSServerInput::Close(3).into()));
+
+        let drained = || {
+            tap_server
+                .try_iter()
+                .filter(|msg| matches!(msg, SignalMessage::Output(SignalOutput::Drained)))
+                .count()
+        };
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::Disconnection(1)))
+            .await?;
+        assert_eq!(0, drained());
+        wss.settle_msg(WSServerMessage::Output(WSServerOutput::Disconnection(2)))
+            .await?;
+        assert_eq!(1, drained());
+        Ok(())
+    }
+}
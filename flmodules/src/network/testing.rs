@@ -1,3 +1,5 @@
+use std::sync::{Arc, Mutex};
+
 use flarch::{
     broker::{Broker, BrokerError, Subsystem, SubsystemHandler, Translate},
     nodeids::U256,
@@ -7,14 +9,27 @@ use flarch::{
 use super::messages::{NetworkIn, NetworkOut, NetworkMessage};
 use crate::nodeconfig::{NodeConfig, NodeInfo};
 
+/// Connects the network brokers of simulated nodes with each other.
+/// Connections are set up immediately, and messages are delivered to the
+/// destination node without any delay.
 pub struct NetworkBrokerSimul {
     nsh_broker: Broker<NSHubMessage>,
+    messages: Arc<Mutex<u64>>,
 }
 
 impl NetworkBrokerSimul {
     pub async fn new() -> Result<Self, BrokerError> {
-        let nsh_broker = NSHub::new().await?;
-        Ok(Self { nsh_broker })
+        let messages = Arc::new(Mutex::new(0));
+        let nsh_broker = NSHub::new(messages.clone()).await?;
+        Ok(Self {
+            nsh_broker,
+            messages,
+        })
+    }
+
+    /// Returns how many messages have been sent between the nodes.
+    pub fn messages(&self) -> u64 {
+        *self.messages.lock().unwrap()
     }
 
     pub async fn new_node(&mut self) -> Result<(NodeConfig, Broker<NetworkMessage>), BrokerError> {
@@ -30,7 +45,8 @@ impl NetworkBrokerSimul {
             )
             .await?;
         self.nsh_broker
-            .emit_msg(NSHubMessage::NewClient(nc.info.clone()))?;
+            .settle_msg(NSHubMessage::NewClient(nc.info.clone()))
+            .await?;
 
         Ok((nc, nm_broker))
     }
@@ -60,25 +76,43 @@ enum NSHubMessage {
 
 struct NSHub {
     nodes: Vec<NodeInfo>,
+    messages: Arc<Mutex<u64>>,
 }
 
 impl NSHub {
-    async fn new() -> Result<Broker<NSHubMessage>, BrokerError> {
+    async fn new(messages: Arc<Mutex<u64>>) -> Result<Broker<NSHubMessage>, BrokerError> {
         let mut b = Broker::new();
-        b.add_subsystem(Subsystem::Handler(Box::new(Self { nodes: vec![] })))
-            .await?;
+        b.add_subsystem(Subsystem::Handler(Box::new(Self {
+            nodes: vec![],
+            messages,
+        })))
+        .await?;
         Ok(b)
     }
 
     fn net_msg(&self, id: U256, net_msg: NetworkMessage) -> Vec<NSHubMessage> {
         if let NetworkMessage::Input(msg) = net_msg {
             match msg {
-                NetworkIn::MessageToNode(id_dst, msg_node) => {
+                NetworkIn::MessageToNode(id_dst, msg_node)
+                | NetworkIn::MessageToNodeChannel(id_dst, _, msg_node) => {
+                    *self.messages.lock().unwrap() += 1;
                     vec![NSHubMessage::ToClient(
                         id_dst,
                         NetworkMessage::Output(NetworkOut::MessageFromNode(id, msg_node)),
                     )]
                 }
+                NetworkIn::Connect(id_dst) => {
+                    vec![
+                        NSHubMessage::ToClient(id, NetworkOut::Connected(id_dst).into()),
+                        NSHubMessage::ToClient(id_dst, NetworkOut::Connected(id).into()),
+                    ]
+                }
+                NetworkIn::Disconnect(id_dst) => {
+                    vec![
+                        NSHubMessage::ToClient(id, NetworkOut::Disconnected(id_dst).into()),
+                        NSHubMessage::ToClient(id_dst, NetworkOut::Disconnected(id).into()),
+                    ]
+                }
                 NetworkIn::WSUpdateListRequest => {
                     vec![NSHubMessage::ToClient(
                         id,
@@ -5,16 +5,17 @@
 //! based serializations when using text-based serializations like `yaml` or `json`.
 
 use ed25519_compact::{KeyPair, Noise, PublicKey, Seed, Signature};
-use flarch::nodeids::U256;
+use flarch::{nodeids::U256, tasks::now, web_rtc::nat::NatType};
 use serde_derive::{Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
+use sha2::{Digest, Sha256};
 use std::{
     convert::TryFrom,
     fmt::{Debug, Error, Formatter},
 };
 use thiserror::Error;
 
-use crate::Modules;
+use crate::{Capabilities, Modules};
 
 /// Errors to be returned when setting up a new config
 #[derive(Error, Debug)]
@@ -25,6 +26,9 @@ pub enum ConfigError {
     /// Error while decoding the toml string
     #[error("Couldn't decode")]
     NoInfo,
+    /// Only the configuration of a read-only node can be stored without keypair
+    #[error("Keypair missing in the configuration")]
+    KeypairMissing,
     /// Serde error
     #[error(transparent)]
     DecodeToml1(#[from] toml::de::Error),
@@ -47,6 +51,106 @@ pub struct NodeInfo {
     // capabilities of this node
     #[serde(default = "Modules::all")]
     pub modules: Modules,
+    /// Optional features of the connection this node understands.
+    /// Nodes which don't know about capabilities don't send them.
+    #[serde(
+        default = "Capabilities::empty",
+        skip_serializing_if = "Capabilities::is_empty"
+    )]
+    pub capabilities: Capabilities,
+    /// The NAT this node is behind, sent once it has been detected by a connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nat: Option<NatType>,
+    /// The role of a dedicated node, e.g., started with `fledger serve`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<NodeRole>,
+    /// The key this node replaced, see [`NodeConfig::rotate`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotated_from: Option<KeyLink>,
+}
+
+/// Links a node to the key it replaced.
+/// Other nodes use it to replace the old [`NodeInfo`] with the new one.
+#[serde_as]
+#[derive(Debug, Deserialize, Serialize, Clone, Hash, PartialEq)]
+pub struct KeyLink {
+    /// The ID, which is also the public key, of the replaced key.
+    pub previous: U256,
+    /// Signature of the new public key by the replaced key.
+    #[serde_as(as = "Base64")]
+    pub signature: Vec<u8>,
+}
+
+/// How long a [`SignedNodeInfo`] is valid, in milliseconds.
+pub const SIGNED_INFO_TTL_MS: i64 = 60 * 60 * 1000;
+
+/// A [`NodeInfo`] signed by the node itself, so other nodes can pass it on
+/// without being able to change it.
+/// It expires, so an old NodeInfo cannot be passed on forever.
+#[serde_as]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SignedNodeInfo {
+    /// The encoded NodeInfo, so nodes which don't know all of its fields can
+    /// still verify the signature.
+    pub info: String,
+    /// Milliseconds since the epoch after which the NodeInfo is not valid anymore.
+    /// Infos signed by nodes which don't set it are never valid.
+    #[serde(default)]
+    pub expires: i64,
+    #[serde_as(as = "Base64")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedNodeInfo {
+    fn hash(info: &str, expires: i64) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(b"fledger node info");
+        hash.update(info);
+        hash.update(expires.to_le_bytes());
+        hash.finalize().into()
+    }
+
+    /// Returns the NodeInfo if it is signed by its own key and didn't expire.
+    pub fn verify(&self) -> Option<NodeInfo> {
+        if self.expires < now() {
+            return None;
+        }
+        let info = NodeInfo::decode(&self.info).ok()?;
+        info.verify(&Self::hash(&self.info, self.expires), &self.signature)
+            .then_some(info)
+    }
+}
+
+impl KeyLink {
+    fn hash(pubkey: &[u8]) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(b"fledger key rotation");
+        hash.update(pubkey);
+        hash.finalize().into()
+    }
+}
+
+/// The roles a dedicated node can take in the network.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum NodeRole {
+    /// Keeps the gossip events for other nodes.
+    Storage,
+    /// Only keeps connections to other nodes open.
+    Relay,
+    /// Answers web proxy requests from other nodes.
+    Proxy,
+}
+
+impl NodeRole {
+    /// Returns the modules a node with this role runs.
+    pub fn modules(&self) -> Modules {
+        let base = Modules::ENABLE_STAT | Modules::ENABLE_RAND | Modules::ENABLE_PING;
+        match self {
+            NodeRole::Storage => base | Modules::ENABLE_GOSSIP,
+            NodeRole::Relay => base,
+            NodeRole::Proxy => base | Modules::ENABLE_WEBPROXY | Modules::ENABLE_WEBPROXY_REQUESTS,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -70,9 +174,25 @@ impl NodeInfo {
             client: "libc".to_string(),
             pubkey: pubkey.as_ref().to_vec(),
             modules: Modules::all(),
+            capabilities: Capabilities::supported(),
+            nat: None,
+            role: None,
+            rotated_from: None,
         }
     }
 
+    /// Returns the ID of the key this node replaced, if the link is signed
+    /// by the replaced key.
+    pub fn previous_id(&self) -> Option<U256> {
+        let link = self.rotated_from.as_ref()?;
+        let pubkey = PublicKey::from_slice(link.previous.as_ref()).ok()?;
+        let sig = Signature::from_slice(&link.signature).ok()?;
+        pubkey
+            .verify(KeyLink::hash(&self.pubkey), &sig)
+            .ok()
+            .map(|_| link.previous)
+    }
+
     /// Returns the unique id, based on the public key.
     pub fn get_id(&self) -> U256 {
         let a: [u8; PublicKey::BYTES] = self.pubkey.clone().try_into().unwrap();
@@ -135,6 +255,10 @@ impl From<NodeInfoV1> for NodeInfo {
             client: ni.client,
             pubkey: ni.pubkey,
             modules: Modules::empty(),
+            capabilities: Capabilities::empty(),
+            nat: None,
+            role: None,
+            rotated_from: None,
         }
     }
 }
@@ -147,6 +271,10 @@ impl TryFrom<NodeInfoToml> for NodeInfo {
             client: nit.client,
             pubkey: nit.pubkey.ok_or(ConfigError::PublicKeyMissing)?,
             modules: Modules::empty(),
+            capabilities: Capabilities::empty(),
+            nat: None,
+            role: None,
+            rotated_from: None,
         })
     }
 }
@@ -179,7 +307,13 @@ pub struct NodeConfig {
     pub info: NodeInfo,
     /// the cryptographic keypair as a vector of bytes
     #[serde_as(as = "Base64")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub keypair: Vec<u8>,
+    /// A read-only node never publishes anything. Its keypair is only used to
+    /// connect to the signalling server and other nodes, and is never stored,
+    /// so every start uses a new identity.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
 }
 
 impl Default for NodeConfig {
@@ -195,19 +329,77 @@ impl NodeConfig {
         NodeConfig {
             info: NodeInfo::new(keypair.pk),
             keypair: keypair.as_ref().to_vec(),
+            read_only: false,
+        }
+    }
+
+    /// Returns a new NodeConfig for a read-only node.
+    pub fn new_read_only() -> Self {
+        NodeConfig {
+            read_only: true,
+            ..Self::new()
+        }
+    }
+
+    /// Returns the NodeInfo signed by this node, valid for [`SIGNED_INFO_TTL_MS`].
+    pub fn signed_info(&self) -> SignedNodeInfo {
+        self.signed_info_until(now() + SIGNED_INFO_TTL_MS)
+    }
+
+    /// Returns the NodeInfo signed by this node, valid until `expires`.
+    pub fn signed_info_until(&self, expires: i64) -> SignedNodeInfo {
+        let info = self.info.encode();
+        SignedNodeInfo {
+            signature: self.sign(SignedNodeInfo::hash(&info, expires)),
+            info,
+            expires,
+        }
+    }
+
+    /// Returns a new NodeConfig with a new keypair, but otherwise the same info.
+    /// Its NodeInfo links to the current key, signed by the current key, so other
+    /// nodes can replace the current NodeInfo with the new one.
+    pub fn rotate(&self) -> Self {
+        let keypair = KeyPair::from_seed(Seed::default());
+        let mut info = self.info.clone();
+        info.pubkey = keypair.pk.as_ref().to_vec();
+        info.rotated_from = Some(KeyLink {
+            previous: self.info.get_id(),
+            signature: self.sign(KeyLink::hash(&info.pubkey)),
+        });
+        NodeConfig {
+            info,
+            keypair: keypair.as_ref().to_vec(),
+            read_only: self.read_only,
         }
     }
 
     /// Returns a yaml representation of the config.
+    /// The keypair of a read-only node is left out.
     pub fn encode(&self) -> String {
-        serde_yaml::to_string(&NodeConfigSave::NodeConfigV1(self.clone())).unwrap()
+        let mut nc = self.clone();
+        if nc.read_only {
+            nc.keypair = vec![];
+        }
+        serde_yaml::to_string(&NodeConfigSave::NodeConfigV1(nc)).unwrap()
     }
 
     /// Returns the configuration or an error. Correctly handles
     /// old toml-configurations.
+    /// A read-only node gets a new keypair, while any other configuration
+    /// without a keypair is an error, so the node doesn't silently lose its identity.
     pub fn decode(data: &str) -> Result<Self, ConfigError> {
         if let Ok(nc) = serde_yaml::from_str::<NodeConfigSave>(data) {
-            return Ok(nc.to_latest());
+            let mut nc = nc.to_latest();
+            if nc.keypair.is_empty() {
+                if !nc.read_only {
+                    return Err(ConfigError::KeypairMissing);
+                }
+                let keypair = KeyPair::from_seed(Seed::default());
+                nc.info.pubkey = keypair.pk.as_ref().to_vec();
+                nc.keypair = keypair.as_ref().to_vec();
+            }
+            return Ok(nc);
         }
         Self::from_toml(data)
     }
@@ -244,6 +436,7 @@ impl NodeConfig {
         Ok(NodeConfig {
             info: our_node,
             keypair,
+            read_only: false,
         })
     }
 }
@@ -253,6 +446,7 @@ impl Clone for NodeConfig {
         NodeConfig {
             info: self.info.clone(),
             keypair: self.keypair.clone(),
+            read_only: self.read_only,
         }
     }
 }
@@ -331,4 +525,79 @@ mod tests {
         assert_eq!(nc.info.pubkey, nc_clone.info.pubkey);
         Ok(())
     }
+
+    #[test]
+    fn read_only() -> Result<(), ConfigError> {
+        let nc = NodeConfig::new_read_only();
+        let nc_str = nc.encode();
+        assert!(!NodeConfig::new().encode().contains("read_only"));
+        assert!(!nc_str.contains("keypair"));
+        let nc_loaded = NodeConfig::decode(&nc_str)?;
+        assert!(nc_loaded.read_only);
+        assert_ne!(nc.info.get_id(), nc_loaded.info.get_id());
+        let msg = [1u8; 32];
+        assert!(nc_loaded.info.verify(&msg, &nc_loaded.sign(msg)));
+
+        // Only a read-only node may come without a keypair.
+        let mut nc = NodeConfig::new();
+        nc.keypair = vec![];
+        assert!(matches!(
+            NodeConfig::decode(&nc.encode()),
+            Err(ConfigError::KeypairMissing)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn role() -> Result<(), ConfigError> {
+        let mut nc = NodeConfig::new();
+        assert!(!nc.info.encode().contains("role"));
+        nc.info.role = Some(NodeRole::Proxy);
+        nc.info.modules = NodeRole::Proxy.modules();
+        let info = NodeInfo::decode(&nc.info.encode())?;
+        assert_eq!(Some(NodeRole::Proxy), info.role);
+        assert!(info.modules.contains(Modules::ENABLE_WEBPROXY));
+        assert!(!info.modules.contains(Modules::ENABLE_GOSSIP));
+        Ok(())
+    }
+
+    #[test]
+    fn rotate() -> Result<(), ConfigError> {
+        let nc = NodeConfig::new();
+        assert!(!nc.info.encode().contains("rotated_from"));
+        assert_eq!(None, nc.info.previous_id());
+
+        let rotated = NodeConfig::decode(&nc.rotate().encode())?;
+        assert_ne!(nc.info.get_id(), rotated.info.get_id());
+        assert_eq!(nc.info.name, rotated.info.name);
+        let info = NodeInfo::decode(&rotated.info.encode())?;
+        assert_eq!(Some(nc.info.get_id()), info.previous_id());
+        let msg = [1u8; 32];
+        assert!(info.verify(&msg, &rotated.sign(msg)));
+
+        // A link not signed by the previous key is ignored.
+        let mut forged = NodeConfig::new().rotate().info;
+        forged.rotated_from.as_mut().unwrap().previous = nc.info.get_id();
+        assert_eq!(None, forged.previous_id());
+        Ok(())
+    }
+
+    #[test]
+    fn signed_info() {
+        let nc = NodeConfig::new();
+        let signed = nc.signed_info();
+        assert_eq!(Some(nc.info.get_id()), signed.verify().map(|i| i.get_id()));
+
+        let mut forged = signed.clone();
+        forged.info = forged.info.replace(&nc.info.name, "evil");
+        assert_eq!(None, forged.verify());
+        let mut other = NodeConfig::new().signed_info();
+        other.signature = signed.signature.clone();
+        assert_eq!(None, other.verify());
+
+        let mut extended = signed;
+        extended.expires += 1;
+        assert_eq!(None, extended.verify());
+        assert_eq!(None, nc.signed_info_until(now() - 1).verify());
+    }
 }
@@ -47,6 +47,7 @@ impl OverlayRandom {
                         OverlayIn::NetworkWrapperToNetwork(id, module_message) => {
                             RandomIn::NetworkMapperToNetwork(id, module_message)
                         }
+                        OverlayIn::ReplayProtect(module) => RandomIn::ReplayProtect(module),
                     };
                     return Some(RandomMessage::Input(ret));
                 }
@@ -111,11 +112,12 @@ impl OverlayDirect {
                     let ret = match input {
                         OverlayIn::NetworkWrapperToNetwork(id, module_message) => {
                             if let Ok(msg_str) = serde_yaml::to_string(&module_message) {
-                                NetworkIn::MessageToNode(id, msg_str)
+                                NetworkIn::to_node(id, module_message.channel, msg_str)
                             } else {
                                 return None;
                             }
                         }
+                        OverlayIn::ReplayProtect(_) => return None,
                     };
                     return Some(NetworkMessage::Input(ret));
                 }
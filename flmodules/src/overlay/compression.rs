@@ -0,0 +1,222 @@
+//! Compression of the messages sent between two nodes.
+//!
+//! A [`NetworkWrapper`] bigger than [`COMPRESSION_THRESHOLD`] is compressed
+//! if the receiving node announces [`crate::Capabilities::COMPRESS_ZSTD`] or
+//! [`crate::Capabilities::COMPRESS_DEFLATE`] in its [`crate::nodeconfig::NodeInfo`].
+//! Zstd is preferred, but it is only available outside of the browser, so
+//! between a browser and a libc node deflate is used.
+//! Nodes which don't know about compression never receive compressed messages.
+//! Messages which decompress to more than [`MAX_DECOMPRESSED`] bytes are refused.
+//!
+//! The [`CompressionStats`] keep track of how much each module gains from
+//! the compression.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::messages::NetworkWrapper;
+use crate::Capabilities;
+
+/// Messages smaller than this number of bytes are sent as-is.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+/// Compressed messages which expand to more than this number of bytes are refused.
+pub const MAX_DECOMPRESSED: usize = 16 * 1024 * 1024;
+
+/// All compression algorithms available for the [`NetworkWrapper`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Compression {
+    /// Deflate, stored as base64.
+    Deflate,
+    /// Zstandard, stored as base64. Not available in wasm.
+    Zstd,
+}
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("Decompressed message is bigger than {MAX_DECOMPRESSED} bytes")]
+    TooBig,
+    #[error("Compression {0:?} is not available on this platform")]
+    Unsupported(Compression),
+}
+
+impl Compression {
+    /// Returns the best compression which both this node and a node with
+    /// the given capabilities support.
+    pub fn negotiate(remote: Capabilities) -> Option<Compression> {
+        let common = remote & Capabilities::supported();
+        if common.contains(Capabilities::COMPRESS_ZSTD) {
+            Some(Compression::Zstd)
+        } else if common.contains(Capabilities::COMPRESS_DEFLATE) {
+            Some(Compression::Deflate)
+        } else {
+            None
+        }
+    }
+
+    pub fn compress(&self, data: &str) -> Result<String, CompressionError> {
+        match self {
+            Compression::Deflate => {
+                let mut enc = DeflateEncoder::new(vec![], flate2::Compression::default());
+                enc.write_all(data.as_bytes())?;
+                Ok(STANDARD.encode(enc.finish()?))
+            }
+            #[cfg(not(target_family = "wasm"))]
+            Compression::Zstd => Ok(STANDARD.encode(zstd::encode_all(data.as_bytes(), 0)?)),
+            #[cfg(target_family = "wasm")]
+            Compression::Zstd => Err(CompressionError::Unsupported(*self)),
+        }
+    }
+
+    pub fn decompress(&self, data: &str) -> Result<String, CompressionError> {
+        match self {
+            Compression::Deflate => {
+                let bytes = STANDARD.decode(data)?;
+                Self::read_limited(DeflateDecoder::new(bytes.as_slice()))
+            }
+            #[cfg(not(target_family = "wasm"))]
+            Compression::Zstd => {
+                let bytes = STANDARD.decode(data)?;
+                Self::read_limited(zstd::Decoder::new(bytes.as_slice())?)
+            }
+            #[cfg(target_family = "wasm")]
+            Compression::Zstd => Err(CompressionError::Unsupported(*self)),
+        }
+    }
+
+    // Reads at most MAX_DECOMPRESSED bytes, so a small message cannot expand
+    // into a huge allocation.
+    fn read_limited(decoder: impl Read) -> Result<String, CompressionError> {
+        let mut out = vec![];
+        decoder
+            .take(MAX_DECOMPRESSED as u64 + 1)
+            .read_to_end(&mut out)?;
+        if out.len() > MAX_DECOMPRESSED {
+            return Err(CompressionError::TooBig);
+        }
+        Ok(String::from_utf8(out)?)
+    }
+}
+
+/// The bytes of all messages of a module which were big enough to be compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ModuleCompression {
+    pub messages: u64,
+    pub raw_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl ModuleCompression {
+    /// Returns the size of the compressed messages relative to their raw size.
+    /// Smaller is better.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            return 1.;
+        }
+        self.compressed_bytes as f64 / self.raw_bytes as f64
+    }
+}
+
+/// Compresses outgoing messages and keeps the statistics per module.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompressionStats {
+    pub modules: HashMap<String, ModuleCompression>,
+}
+
+impl CompressionStats {
+    /// Compresses the message if it is bigger than [`COMPRESSION_THRESHOLD`].
+    /// If the compressed message is not smaller, the original message is returned.
+    pub fn compress(&mut self, compression: Compression, msg: NetworkWrapper) -> NetworkWrapper {
+        if msg.compression.is_some() || msg.msg.len() < COMPRESSION_THRESHOLD {
+            return msg;
+        }
+        let compressed = match msg.compress(compression) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                log::warn!("Couldn't compress message: {e:?}");
+                return msg;
+            }
+        };
+        let stats = self.modules.entry(msg.module.clone()).or_default();
+        stats.messages += 1;
+        stats.raw_bytes += msg.msg.len() as u64;
+        stats.compressed_bytes += compressed.msg.len().min(msg.msg.len()) as u64;
+        if compressed.msg.len() < msg.msg.len() {
+            compressed
+        } else {
+            msg
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compress() -> Result<(), Box<dyn std::error::Error>> {
+        let mut stats = CompressionStats::default();
+        let small = NetworkWrapper::wrap_yaml("Small", &"short")?;
+        assert_eq!(small, stats.compress(Compression::Deflate, small.clone()));
+
+        let big = NetworkWrapper::wrap_yaml("Big", &vec!["some yaml"; 200])?;
+        let compressed = stats.compress(Compression::Deflate, big.clone());
+        assert_eq!(Some(Compression::Deflate), compressed.compression);
+        assert!(compressed.msg.len() < big.msg.len());
+        assert_eq!(big, compressed.decompress()?);
+
+        let ratio = stats.modules.get("Big").unwrap().ratio();
+        assert!(ratio < 0.1, "ratio is {ratio}");
+        assert!(stats.modules.get("Small").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_limit() -> Result<(), Box<dyn std::error::Error>> {
+        let (max, too_big) = (
+            "0".repeat(MAX_DECOMPRESSED),
+            "0".repeat(MAX_DECOMPRESSED + 1),
+        );
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let bomb = compression.compress(&too_big)?;
+            assert!(bomb.len() < 100_000, "{compression:?}: {}", bomb.len());
+            assert!(matches!(
+                compression.decompress(&bomb),
+                Err(CompressionError::TooBig)
+            ));
+            assert_eq!(max, compression.decompress(&compression.compress(&max)?)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(None, Compression::negotiate(Capabilities::empty()));
+        assert_eq!(
+            Some(Compression::Deflate),
+            Compression::negotiate(Capabilities::COMPRESS_DEFLATE)
+        );
+        assert_eq!(
+            Some(Compression::Zstd),
+            Compression::negotiate(Capabilities::all())
+        );
+
+        let big = NetworkWrapper::wrap_yaml("Big", &vec!["some yaml"; 200])?;
+        let compressed = CompressionStats::default().compress(Compression::Zstd, big.clone());
+        assert_eq!(Some(Compression::Zstd), compressed.compression);
+        assert_eq!(big, compressed.decompress()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! End-to-end encryption of the messages sent between two nodes.
+//!
+//! WebRTC connections are encrypted with DTLS, but the messages can still be
+//! read by a compromised WebRTC stack.
+//! If both nodes announce [`crate::Capabilities::ENCRYPT`], every [`NetworkWrapper`]
+//! is encrypted with a key only these two nodes know, and plain messages are
+//! refused, so a man-in-the-middle cannot strip the encryption.
+//!
+//! The key is derived from the ed25519 keypair of the sending node, and the
+//! public key of the receiving node, which the signalling server hands out in
+//! its [`NodeInfo`].
+//! Both keys are converted to x25519 keys, and the result of their Diffie-Hellman
+//! exchange is hashed into a ChaCha20-Poly1305 key.
+//! The module name and the nonce of [`super::replay`] are authenticated, but not encrypted.
+
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit, Nonce,
+};
+use ed25519_compact::{x25519, KeyPair, PublicKey};
+use flarch::nodeids::NodeID;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::messages::NetworkWrapper;
+use crate::{
+    nodeconfig::{NodeConfig, NodeInfo},
+    Capabilities,
+};
+
+const NONCE_LEN: usize = 12;
+
+/// All encryption algorithms available for the [`NetworkWrapper`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Encryption {
+    /// x25519 key exchange with ChaCha20-Poly1305, nonce and ciphertext stored as base64.
+    X25519ChaCha20Poly1305,
+}
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("Invalid key: {0}")]
+    Key(#[from] ed25519_compact::Error),
+    #[error("Couldn't encrypt or decrypt the message")]
+    Cipher,
+    #[error("Message too short")]
+    Length,
+    #[error("Plain message from a node announcing encryption")]
+    Plaintext,
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+/// The x25519 secret key of this node, and the shared keys with the other nodes.
+pub struct NodeKeys {
+    secret: x25519::SecretKey,
+    shared: HashMap<NodeID, ChaCha20Poly1305>,
+}
+
+impl std::fmt::Debug for NodeKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NodeKeys({} shared)", self.shared.len())
+    }
+}
+
+impl NodeKeys {
+    /// Derives the x25519 secret key from the ed25519 keypair of the node.
+    pub fn new(node_config: &NodeConfig) -> Result<Self, EncryptionError> {
+        let keypair = KeyPair::from_slice(&node_config.keypair)?;
+        Ok(Self {
+            secret: x25519::SecretKey::from_ed25519(&keypair.sk)?,
+            shared: HashMap::new(),
+        })
+    }
+
+    /// Derives the keys shared with the given nodes announcing
+    /// [`crate::Capabilities::ENCRYPT`], so the first message to a node is not delayed.
+    /// Nodes with an invalid public key are skipped.
+    pub fn add_nodes(&mut self, nodes: &[NodeInfo]) {
+        for node in nodes
+            .iter()
+            .filter(|ni| ni.capabilities.contains(Capabilities::ENCRYPT))
+        {
+            if let Err(e) = self.cipher(node) {
+                log::warn!("Couldn't derive the key for {}: {e:?}", node.get_id());
+            }
+        }
+    }
+
+    /// Returns a copy of the message encrypted for the given node.
+    pub fn encrypt(
+        &mut self,
+        remote: &NodeInfo,
+        msg: &NetworkWrapper,
+    ) -> Result<NetworkWrapper, EncryptionError> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let aad = Self::aad(msg);
+        let payload = Payload {
+            msg: msg.msg.as_bytes(),
+            aad: &aad,
+        };
+        let ciphertext = self
+            .cipher(remote)?
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| EncryptionError::Cipher)?;
+        Ok(NetworkWrapper {
+            msg: STANDARD.encode([nonce.as_slice(), &ciphertext].concat()),
+            encryption: Some(Encryption::X25519ChaCha20Poly1305),
+            ..msg.clone()
+        })
+    }
+
+    /// Returns the decrypted message sent by the given node.
+    /// Messages which are not encrypted are returned as-is, unless the node
+    /// announces [`crate::Capabilities::ENCRYPT`].
+    pub fn decrypt(
+        &mut self,
+        remote: &NodeInfo,
+        msg: NetworkWrapper,
+    ) -> Result<NetworkWrapper, EncryptionError> {
+        let Some(Encryption::X25519ChaCha20Poly1305) = msg.encryption else {
+            if remote.capabilities.contains(Capabilities::ENCRYPT) {
+                return Err(EncryptionError::Plaintext);
+            }
+            return Ok(msg);
+        };
+        let bytes = STANDARD.decode(&msg.msg)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(EncryptionError::Length);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let aad = Self::aad(&msg);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+        let plain = self
+            .cipher(remote)?
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| EncryptionError::Cipher)?;
+        Ok(NetworkWrapper {
+            msg: String::from_utf8(plain)?,
+            encryption: None,
+            ..msg
+        })
+    }
+
+    fn aad(msg: &NetworkWrapper) -> Vec<u8> {
+        let mut aad = msg.module.as_bytes().to_vec();
+        if let Some(nonce) = msg.nonce {
+            aad.extend_from_slice(&nonce.to_be_bytes());
+        }
+        aad
+    }
+
+    fn cipher(&mut self, remote: &NodeInfo) -> Result<&ChaCha20Poly1305, EncryptionError> {
+        let id = remote.get_id();
+        if !self.shared.contains_key(&id) {
+            let pk = x25519::PublicKey::from_ed25519(&PublicKey::from_slice(&remote.pubkey)?)?;
+            let dh = pk.dh(&self.secret)?;
+            let key = Sha256::new()
+                .chain_update(b"fledger-e2e")
+                .chain_update(dh.as_slice())
+                .finalize();
+            self.shared.insert(
+                id,
+                ChaCha20Poly1305::new_from_slice(&key).expect("key is 32 bytes"),
+            );
+        }
+        Ok(&self.shared[&id])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encrypt() -> Result<(), Box<dyn std::error::Error>> {
+        let (a, b, c) = (NodeConfig::new(), NodeConfig::new(), NodeConfig::new());
+        let (mut keys_a, mut keys_b, mut keys_c) =
+            (NodeKeys::new(&a)?, NodeKeys::new(&b)?, NodeKeys::new(&c)?);
+        let msg = NetworkWrapper::wrap_yaml("Secret", &"some secret")?;
+
+        let encrypted = keys_a.encrypt(&b.info, &msg)?;
+        assert_eq!(
+            Some(Encryption::X25519ChaCha20Poly1305),
+            encrypted.encryption
+        );
+        assert!(!encrypted.msg.contains("secret"));
+        assert_eq!(msg, keys_b.decrypt(&a.info, encrypted.clone())?);
+        // Another node cannot decrypt, and the module name is authenticated.
+        assert!(keys_c.decrypt(&a.info, encrypted.clone()).is_err());
+        let renamed = NetworkWrapper {
+            module: "Other".into(),
+            ..encrypted
+        };
+        assert!(keys_b.decrypt(&a.info, renamed).is_err());
+        let mut stamped = msg.clone();
+        stamped.nonce = Some(1);
+        let encrypted = keys_a.encrypt(&b.info, &stamped)?;
+        assert_eq!(stamped, keys_b.decrypt(&a.info, encrypted.clone())?);
+        let replayed = NetworkWrapper {
+            nonce: Some(2),
+            ..encrypted
+        };
+        assert!(keys_b.decrypt(&a.info, replayed).is_err());
+        // Plain messages are only passed through from nodes without encryption.
+        assert_eq!(msg, keys_b.decrypt(&a.info, msg.clone())?);
+        let mut a_encrypt = a.info.clone();
+        a_encrypt.capabilities |= Capabilities::ENCRYPT;
+        assert!(matches!(
+            keys_b.decrypt(&a_encrypt, msg.clone()),
+            Err(EncryptionError::Plaintext)
+        ));
+        Ok(())
+    }
+}
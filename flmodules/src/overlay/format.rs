@@ -0,0 +1,103 @@
+//! The serialization formats used by the modules to send their messages
+//! over the network.
+//!
+//! Every module looks up its format in [`MODULE_FORMATS`] when wrapping a
+//! message in a [`crate::overlay::messages::NetworkWrapper`].
+//! The format is stored alongside the message, so that a node can still read
+//! messages in the old format while the network transitions to a new one.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+/// All serialization formats available to wrap a module message.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum WrapperFormat {
+    /// The default format, also used by nodes which don't know about formats.
+    #[default]
+    Yaml,
+    Json,
+    /// Binary format, stored as base64.
+    Bincode,
+    /// Binary format, stored as base64.
+    Cbor,
+}
+
+/// The format each module uses to send its messages. Modules which are not
+/// listed here use [`WrapperFormat::Yaml`].
+/// Changing a format here changes it for the whole network, as all nodes can
+/// read all formats.
+pub const MODULE_FORMATS: &[(&str, WrapperFormat)] = &[];
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+    #[error("Cbor error: {0}")]
+    Cbor(String),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+}
+
+impl WrapperFormat {
+    /// Returns the format registered for this module.
+    pub fn for_module(module: &str) -> Self {
+        MODULE_FORMATS
+            .iter()
+            .find(|(name, _)| *name == module)
+            .map(|(_, format)| *format)
+            .unwrap_or_default()
+    }
+
+    /// Serializes the message into a string using this format.
+    pub fn encode<T: Serialize>(&self, msg: &T) -> Result<String, FormatError> {
+        Ok(match self {
+            WrapperFormat::Yaml => serde_yaml::to_string(msg)?,
+            WrapperFormat::Json => serde_json::to_string(msg)?,
+            WrapperFormat::Bincode => STANDARD.encode(bincode::serialize(msg)?),
+            WrapperFormat::Cbor => {
+                let mut buf = vec![];
+                ciborium::into_writer(msg, &mut buf)
+                    .map_err(|e| FormatError::Cbor(e.to_string()))?;
+                STANDARD.encode(buf)
+            }
+        })
+    }
+
+    /// Deserializes the message from a string using this format.
+    pub fn decode<T: DeserializeOwned>(&self, msg: &str) -> Result<T, FormatError> {
+        Ok(match self {
+            WrapperFormat::Yaml => serde_yaml::from_str(msg)?,
+            WrapperFormat::Json => serde_json::from_str(msg)?,
+            WrapperFormat::Bincode => bincode::deserialize(&STANDARD.decode(msg)?)?,
+            WrapperFormat::Cbor => ciborium::from_reader(STANDARD.decode(msg)?.as_slice())
+                .map_err(|e| FormatError::Cbor(e.to_string()))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use flarch::nodeids::U256;
+
+    use super::*;
+
+    #[test]
+    fn encode_decode() -> Result<(), FormatError> {
+        let msg = (U256::rnd(), "something".to_string(), vec![1u32, 2, 3]);
+        for format in [
+            WrapperFormat::Yaml,
+            WrapperFormat::Json,
+            WrapperFormat::Bincode,
+            WrapperFormat::Cbor,
+        ] {
+            let encoded = format.encode(&msg)?;
+            assert_eq!(msg, format.decode(&encoded)?);
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,38 @@
-use flarch::nodeids::{NodeID, NodeIDs, U256};
+use flarch::{
+    nodeids::{NodeID, NodeIDs, U256},
+    web_rtc::messages::Channel,
+};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::nodeconfig::NodeInfo;
 
+use super::{
+    compression::{Compression, CompressionError},
+    encryption::Encryption,
+    format::{FormatError, WrapperFormat},
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct NetworkWrapper {
     pub module: String,
     pub msg: String,
+    /// Messages from nodes which don't know about formats are in yaml.
+    #[serde(default)]
+    pub format: WrapperFormat,
+    /// Only set if the receiving node announced it can decompress the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// Only set if both nodes announced they can encrypt the messages,
+    /// see [`super::encryption`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// Only set for modules with replay protection, see [`super::replay`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    /// The WebRTC channel this message should be sent over.
+    /// It is not sent to the remote node, see [`NetworkWrapper::on_channel`].
+    #[serde(skip)]
+    pub channel: Channel,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -19,6 +45,9 @@ pub enum OverlayMessage {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum OverlayIn {
     NetworkWrapperToNetwork(NodeID, NetworkWrapper),
+    /// Adds replay protection to all messages of this module, see [`super::replay`].
+    /// Only available with [`super::broker::OverlayRandom`].
+    ReplayProtect(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -55,10 +84,70 @@ impl From<OverlayInternal> for OverlayMessage {
 }
 
 impl NetworkWrapper {
+    /// Wraps the message using the format registered for this module in
+    /// [`super::format::MODULE_FORMATS`].
+    pub fn wrap<T: Serialize>(module: &str, msg: &T) -> Result<Self, FormatError> {
+        let format = WrapperFormat::for_module(module);
+        Ok(Self {
+            module: module.into(),
+            msg: format.encode(msg)?,
+            format,
+            compression: None,
+            encryption: None,
+            nonce: None,
+            channel: Channel::Control,
+        })
+    }
+
+    /// Sends the message over the given WebRTC channel, e.g., [`Channel::Bulk`]
+    /// for big transfers which should not block the other messages.
+    /// If the remote node doesn't support the channel, the message is sent
+    /// over [`Channel::Control`].
+    pub fn on_channel(self, channel: Channel) -> Self {
+        Self { channel, ..self }
+    }
+
+    /// Unwraps the message using the format it has been wrapped with, so
+    /// that messages in an old format can still be read.
+    pub fn unwrap<T: DeserializeOwned>(&self, module: &str) -> Option<T> {
+        if self.module == module {
+            if let Ok(msg) = self.format.decode(&self.msg) {
+                return Some(msg);
+            }
+        }
+        None
+    }
+
+    /// Returns a copy of the message compressed with the given algorithm.
+    pub fn compress(&self, compression: Compression) -> Result<Self, CompressionError> {
+        Ok(Self {
+            msg: compression.compress(&self.msg)?,
+            compression: Some(compression),
+            ..self.clone()
+        })
+    }
+
+    /// Returns the uncompressed message.
+    pub fn decompress(self) -> Result<Self, CompressionError> {
+        match self.compression {
+            Some(compression) => Ok(Self {
+                msg: compression.decompress(&self.msg)?,
+                compression: None,
+                ..self
+            }),
+            None => Ok(self),
+        }
+    }
+
     pub fn wrap_yaml<T: Serialize>(module: &str, msg: &T) -> Result<Self, serde_yaml::Error> {
         Ok(Self {
             module: module.into(),
             msg: serde_yaml::to_string(msg)?,
+            format: WrapperFormat::Yaml,
+            compression: None,
+            encryption: None,
+            nonce: None,
+            channel: Channel::Control,
         })
     }
 
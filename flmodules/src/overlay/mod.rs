@@ -1,2 +1,6 @@
 pub mod broker;
-pub mod messages;
\ No newline at end of file
+pub mod compression;
+pub mod encryption;
+pub mod format;
+pub mod messages;
+pub mod replay;
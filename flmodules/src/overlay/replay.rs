@@ -0,0 +1,158 @@
+//! Replay protection for the modules which need it.
+//!
+//! A module opts in by sending [`super::messages::OverlayIn::ReplayProtect`] with its
+//! name once at startup.
+//! From then on, every [`NetworkWrapper`] of this module sent by `RandomConnections`
+//! gets a nonce, which increases with every message of this node.
+//! The receiving node drops all messages of the module which have no nonce, or a
+//! nonce it already received, or a nonce more than [`REPLAY_WINDOW`] below the
+//! highest nonce received from this node.
+//!
+//! The nonces start at the current time in microseconds, so they keep increasing
+//! when a node restarts.
+//! Only with [`super::encryption`] the nonce is authenticated: without it,
+//! the replay protection only catches messages which are sent twice by accident.
+
+use std::collections::{HashMap, HashSet};
+
+use flarch::{nodeids::NodeID, tasks::now};
+use thiserror::Error;
+
+use super::messages::NetworkWrapper;
+
+/// How many nonces below the highest nonce received from a node are still
+/// accepted, so that messages can arrive out of order.
+pub const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ReplayError {
+    #[error("Message of module {0} has no nonce")]
+    Missing(String),
+    #[error("Nonce {0} was already received or is too old")]
+    Replayed(u64),
+}
+
+/// The nonces received from one node: `seen` has bit `i` set if
+/// `highest - i` has been received.
+#[derive(Debug, Default, Clone, Copy)]
+struct Window {
+    highest: u64,
+    seen: u64,
+}
+
+impl Window {
+    fn accept(&mut self, nonce: u64) -> bool {
+        if nonce > self.highest {
+            let shift = nonce - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = nonce;
+            return true;
+        }
+        let offset = self.highest - nonce;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Adds nonces to the outgoing messages of the protected modules, and checks
+/// the nonces of the incoming messages.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    modules: HashSet<String>,
+    next: u64,
+    windows: HashMap<NodeID, Window>,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self {
+            modules: HashSet::new(),
+            next: now() as u64 * 1000,
+            windows: HashMap::new(),
+        }
+    }
+}
+
+impl ReplayGuard {
+    /// Protects all messages of this module.
+    pub fn protect(&mut self, module: &str) {
+        self.modules.insert(module.to_string());
+    }
+
+    /// Adds a new nonce to the message if its module is protected.
+    pub fn stamp(&mut self, mut msg: NetworkWrapper) -> NetworkWrapper {
+        if self.modules.contains(&msg.module) {
+            self.next += 1;
+            msg.nonce = Some(self.next);
+        }
+        msg
+    }
+
+    /// Checks the nonce of a message from the given node, and remembers it.
+    /// Messages of modules which are not protected are always accepted.
+    pub fn check(&mut self, from: &NodeID, msg: &NetworkWrapper) -> Result<(), ReplayError> {
+        if !self.modules.contains(&msg.module) {
+            return Ok(());
+        }
+        let nonce = msg
+            .nonce
+            .ok_or_else(|| ReplayError::Missing(msg.module.clone()))?;
+        if self.windows.entry(*from).or_default().accept(nonce) {
+            Ok(())
+        } else {
+            Err(ReplayError::Replayed(nonce))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replay() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut a, mut b) = (ReplayGuard::default(), ReplayGuard::default());
+        a.protect("Ledger");
+        b.protect("Ledger");
+        let id_a = NodeID::rnd();
+
+        let plain = NetworkWrapper::wrap_yaml("Gossip", &"hello")?;
+        assert_eq!(None, a.stamp(plain.clone()).nonce);
+        assert_eq!(Ok(()), b.check(&id_a, &plain));
+        assert_eq!(Ok(()), b.check(&id_a, &plain));
+
+        let msg = NetworkWrapper::wrap_yaml("Ledger", &"receipt")?;
+        assert_eq!(
+            Err(ReplayError::Missing("Ledger".into())),
+            b.check(&id_a, &msg)
+        );
+        let msgs: Vec<_> = (0..3).map(|_| a.stamp(msg.clone())).collect();
+        // Out of order is fine, twice is not.
+        assert_eq!(Ok(()), b.check(&id_a, &msgs[1]));
+        assert_eq!(Ok(()), b.check(&id_a, &msgs[0]));
+        assert_eq!(Ok(()), b.check(&id_a, &msgs[2]));
+        assert!(b.check(&id_a, &msgs[0]).is_err());
+        assert!(b.check(&id_a, &msgs[2]).is_err());
+        // The same nonce from another node is accepted.
+        assert_eq!(Ok(()), b.check(&NodeID::rnd(), &msgs[0]));
+
+        // Nonces too far behind are refused, even if never seen.
+        let old = a.stamp(msg.clone());
+        for _ in 0..REPLAY_WINDOW {
+            b.check(&id_a, &a.stamp(msg.clone()))?;
+        }
+        assert_eq!(
+            Err(ReplayError::Replayed(old.nonce.unwrap())),
+            b.check(&id_a, &old)
+        );
+        Ok(())
+    }
+}
@@ -1,11 +1,12 @@
 use std::sync::mpsc::{channel, Receiver, Sender};
 
 use flarch::{
-    broker::{Broker, BrokerError, Subsystem, SubsystemHandler},
+    broker::{Broker, BrokerError, Priority, Subsystem, SubsystemHandler},
     platform_async_trait,
 };
 
 use crate::{
+    nodeconfig::NodeConfig,
     overlay::messages::NetworkWrapper,
     random_connections::messages::{RandomIn, RandomMessage, RandomOut},
     timer::TimerMessage,
@@ -30,9 +31,14 @@ pub struct PingBroker {
 }
 
 impl PingBroker {
-    pub async fn start(config: PingConfig, rc: Broker<RandomMessage>) -> Result<Self, BrokerError> {
+    /// Starts the ping module. The `node_config` signs the answers to the time requests.
+    pub async fn start(
+        config: PingConfig,
+        node_config: NodeConfig,
+        rc: Broker<RandomMessage>,
+    ) -> Result<Self, BrokerError> {
         let (storage_tx, storage_rx) = channel();
-        let broker = Translate::start(rc, config.clone(), storage_tx).await?;
+        let broker = Translate::start(rc, config.clone(), node_config, storage_tx).await?;
         Ok(PingBroker {
             storage: PingStorage::new(config),
             storage_rx,
@@ -42,8 +48,9 @@ impl PingBroker {
 
     pub async fn add_timer(&mut self, mut timer: Broker<TimerMessage>) {
         timer
-            .forward(
+            .forward_priority(
                 self.broker.clone(),
+                Priority::Control,
                 Box::new(|msg: TimerMessage| {
                     matches!(msg, TimerMessage::Second).then(|| PingIn::Tick.into())
                 }),
@@ -65,24 +72,30 @@ struct Translate {
 
 impl Translate {
     async fn start(
-        random: Broker<RandomMessage>,
+        mut random: Broker<RandomMessage>,
         config: PingConfig,
+        node_config: NodeConfig,
         storage_tx: Sender<PingStorage>,
     ) -> Result<Broker<PingMessage>, BrokerError> {
         let mut gossip = Broker::new();
         gossip
             .add_subsystem(Subsystem::Handler(Box::new(Translate {
                 storage_tx,
-                module: Ping::new(config),
+                module: Ping::new(config, node_config),
             })))
             .await?;
+        // The pings and the failures are control messages for the random
+        // connections, so they are not delayed by bigger transfers.
         gossip
-            .link_bi(
-                random,
-                Box::new(Self::link_rnd_ping),
+            .forward_priority(
+                random.clone(),
+                Priority::Control,
                 Box::new(Self::link_ping_rnd),
             )
-            .await?;
+            .await;
+        random
+            .forward(gossip.clone(), Box::new(Self::link_rnd_ping))
+            .await;
         Ok(gossip)
     }
 
@@ -92,7 +105,7 @@ impl Translate {
                 RandomOut::DisconnectNode(id) => Some(PingIn::DisconnectNode(id).into()),
                 RandomOut::NodeIDsConnected(list) => Some(PingIn::NodeList(list.into()).into()),
                 RandomOut::NetworkWrapperFromNetwork(id, msg) => msg
-                    .unwrap_yaml(MODULE_NAME)
+                    .unwrap(MODULE_NAME)
                     .map(|msg| PingIn::FromNetwork(id, msg).into()),
                 _ => None,
             }
@@ -107,7 +120,7 @@ impl Translate {
                 PingOut::ToNetwork(id, msg_node) => Some(
                     RandomIn::NetworkMapperToNetwork(
                         id,
-                        NetworkWrapper::wrap_yaml(MODULE_NAME, &msg_node).unwrap(),
+                        NetworkWrapper::wrap(MODULE_NAME, &msg_node).unwrap(),
                     )
                     .into(),
                 ),
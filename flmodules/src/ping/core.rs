@@ -5,6 +5,9 @@ use serde::{Deserialize, Serialize};
 
 use super::messages::PingConfig;
 
+/// The upper bounds in milliseconds of the buckets of [`PingStat::rtt_histogram`].
+pub const RTT_BUCKETS_MS: [u32; 6] = [10, 50, 100, 250, 500, 1000];
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct PingStorage {
     pub stats: HashMap<NodeID, PingStat>,
@@ -18,6 +21,20 @@ pub struct PingStat {
     pub lastping: u32,
     pub rx: u32,
     pub tx: u32,
+    /// The estimated difference in milliseconds between the clock of the remote
+    /// node and the local clock. Positive if the remote clock is ahead.
+    #[serde(default)]
+    pub clock_offset_ms: Option<i64>,
+    /// The smoothed round-trip time in milliseconds.
+    #[serde(default)]
+    pub rtt_ms: Option<u32>,
+    /// The smoothed variation of the round-trip time in milliseconds.
+    #[serde(default)]
+    pub jitter_ms: Option<u32>,
+    /// How many round-trip times fell into each of the [`RTT_BUCKETS_MS`].
+    /// The last entry counts the round-trip times above all buckets.
+    #[serde(default)]
+    pub rtt_histogram: Vec<u32>,
 }
 
 impl PingStorage {
@@ -40,6 +57,10 @@ impl PingStorage {
                 lastping: 0,
                 rx: 0,
                 tx: 1,
+                clock_offset_ms: None,
+                rtt_ms: None,
+                jitter_ms: None,
+                rtt_histogram: vec![0; RTT_BUCKETS_MS.len() + 1],
             },
         );
         self.ping.push(id);
@@ -55,6 +76,63 @@ impl PingStorage {
         }
     }
 
+    /// Updates the clock offset of the remote node with the local times the request
+    /// has been `sent` and the reply `received`, and the `remote` time of the reply.
+    /// Like in NTP, the remote time is supposed to be taken in the middle of the
+    /// round-trip.
+    /// The round-trip time and its variation are smoothed like in TCP, see RFC 6298.
+    pub fn time_reply(&mut self, id: NodeID, sent: i64, remote: i64, received: i64) {
+        if received < sent {
+            return;
+        }
+        if let Some(stat) = self.stats.get_mut(&id) {
+            let sample = remote - (sent + received) / 2;
+            stat.clock_offset_ms = Some(match stat.clock_offset_ms {
+                Some(offset) => (3 * offset + sample) / 4,
+                None => sample,
+            });
+
+            let rtt = (received - sent).min(u32::MAX as i64) as u32;
+            (stat.rtt_ms, stat.jitter_ms) = match (stat.rtt_ms, stat.jitter_ms) {
+                (Some(srtt), Some(jitter)) => (
+                    Some((7 * srtt as u64 + rtt as u64).div_ceil(8) as u32),
+                    Some((3 * jitter as u64 + srtt.abs_diff(rtt) as u64).div_ceil(4) as u32),
+                ),
+                _ => (Some(rtt), Some(rtt / 2)),
+            };
+            let bucket = RTT_BUCKETS_MS
+                .iter()
+                .position(|&max| rtt <= max)
+                .unwrap_or(RTT_BUCKETS_MS.len());
+            stat.rtt_histogram.resize(RTT_BUCKETS_MS.len() + 1, 0);
+            stat.rtt_histogram[bucket] += 1;
+        }
+    }
+
+    /// Returns the nodes with a known round-trip time, the fastest first, e.g., to
+    /// prefer low-latency nodes.
+    pub fn by_latency(&self) -> Vec<NodeID> {
+        let mut nodes: Vec<(NodeID, u32)> = self
+            .stats
+            .iter()
+            .filter_map(|(id, stat)| stat.rtt_ms.map(|rtt| (*id, rtt)))
+            .collect();
+        nodes.sort_by_key(|(_, rtt)| *rtt);
+        nodes.into_iter().map(|(id, _)| id).collect()
+    }
+
+    /// Returns the median of the clock offsets of all remote nodes, which is the
+    /// estimated difference between the network time and the local clock.
+    pub fn clock_offset_ms(&self) -> Option<i64> {
+        let mut offsets: Vec<i64> = self
+            .stats
+            .values()
+            .filter_map(|stat| stat.clock_offset_ms)
+            .collect();
+        offsets.sort();
+        offsets.get(offsets.len() / 2).copied()
+    }
+
     pub fn tick(&mut self) {
         self.ping.clear();
         self.failed.clear();
@@ -121,4 +199,47 @@ mod tests {
         assert_eq!(1, s.stats.len());
         assert_eq!(1, s.failed.len());
     }
+
+    #[test]
+    fn test_clock_offset() {
+        let mut s = PingStorage::new(PingConfig::default());
+        let (n1, n2, n3) = (NodeID::rnd(), NodeID::rnd(), NodeID::rnd());
+        for n in [n1, n2, n3] {
+            s.new_node(n);
+        }
+        assert_eq!(None, s.clock_offset_ms());
+
+        s.time_reply(n1, 1000, 1600, 1200);
+        assert_eq!(Some(500), s.stats[&n1].clock_offset_ms);
+        s.time_reply(n1, 2000, 2800, 2200);
+        assert_eq!(Some(550), s.stats[&n1].clock_offset_ms);
+        // Replies arriving before the request are ignored.
+        s.time_reply(n1, 2000, 2800, 1000);
+        assert_eq!(Some(550), s.stats[&n1].clock_offset_ms);
+
+        s.time_reply(n2, 1000, 900, 1000);
+        s.time_reply(n3, 1000, 1100, 1000);
+        assert_eq!(Some(100), s.clock_offset_ms());
+    }
+
+    #[test]
+    fn test_rtt() {
+        let mut s = PingStorage::new(PingConfig::default());
+        let (n1, n2, n3) = (NodeID::rnd(), NodeID::rnd(), NodeID::rnd());
+        for n in [n1, n2, n3] {
+            s.new_node(n);
+        }
+
+        s.time_reply(n1, 1000, 1000, 1200);
+        assert_eq!(Some(200), s.stats[&n1].rtt_ms);
+        assert_eq!(Some(100), s.stats[&n1].jitter_ms);
+        s.time_reply(n1, 2000, 2000, 2040);
+        assert_eq!(Some(180), s.stats[&n1].rtt_ms);
+        assert_eq!(Some(115), s.stats[&n1].jitter_ms);
+        s.time_reply(n1, 3000, 3000, 4500);
+        assert_eq!(vec![0, 1, 0, 1, 0, 0, 1], s.stats[&n1].rtt_histogram);
+
+        s.time_reply(n2, 1000, 1000, 1005);
+        assert_eq!(vec![n2, n1], s.by_latency());
+    }
 }
@@ -1,5 +1,13 @@
-use flarch::nodeids::{NodeID, NodeIDs};
+use ed25519_compact::{PublicKey, Signature};
+use flarch::{
+    nodeids::{NodeID, NodeIDs},
+    tasks::now,
+};
 use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
+use sha2::{Digest, Sha256};
+
+use crate::nodeconfig::NodeConfig;
 
 use super::core::PingStorage;
 
@@ -7,6 +15,57 @@ use super::core::PingStorage;
 pub enum ModuleMessage {
     Ping,
     Pong,
+    /// Sent together with a [`ModuleMessage::Ping`] with the local time of the sender.
+    TimeRequest(i64),
+    /// The time of the request, and the local time of the node answering the request,
+    /// signed by that node.
+    TimeReply(SignedTime),
+}
+
+/// The answer to a [`ModuleMessage::TimeRequest`].
+/// It is signed by the answering node, so that the nodes passing on the message
+/// cannot shift the clock offset.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SignedTime {
+    /// The time of the request
+    pub request: i64,
+    /// The local time of the node answering the request
+    pub local: i64,
+    #[serde_as(as = "Base64")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedTime {
+    /// Answers a request sent at `request` with the local time `local`.
+    pub fn new(node_config: &NodeConfig, request: i64, local: i64) -> Self {
+        Self {
+            request,
+            local,
+            signature: node_config.sign(Self::hash(request, local)),
+        }
+    }
+
+    /// Returns true if the answer is signed by the node with the given ID.
+    pub fn verify(&self, signer: &NodeID) -> bool {
+        let (Ok(pubkey), Ok(sig)) = (
+            PublicKey::from_slice(signer.as_ref()),
+            Signature::from_slice(&self.signature),
+        ) else {
+            return false;
+        };
+        pubkey
+            .verify(Self::hash(self.request, self.local), &sig)
+            .is_ok()
+    }
+
+    fn hash(request: i64, local: i64) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(b"fledger ping time");
+        hash.update(request.to_le_bytes());
+        hash.update(local.to_le_bytes());
+        hash.finalize().into()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -40,12 +99,14 @@ pub struct PingConfig {
 
 pub struct Ping {
     pub storage: PingStorage,
+    node_config: NodeConfig,
 }
 
 impl Ping {
-    pub fn new(config: PingConfig) -> Self {
+    pub fn new(config: PingConfig, node_config: NodeConfig) -> Self {
         Self {
             storage: PingStorage::new(config),
+            node_config,
         }
     }
 
@@ -78,6 +139,19 @@ impl Ping {
                 self.storage.pong(id);
                 self.create_messages()
             }
+            ModuleMessage::TimeRequest(sent) => {
+                let reply = SignedTime::new(&self.node_config, sent, now());
+                vec![PingOut::ToNetwork(id, ModuleMessage::TimeReply(reply))]
+            }
+            ModuleMessage::TimeReply(reply) => {
+                if reply.verify(&id) {
+                    self.storage
+                        .time_reply(id, reply.request, reply.local, now());
+                } else {
+                    log::warn!("Ignoring time reply with invalid signature from {id}");
+                }
+                vec![]
+            }
         }
     }
 
@@ -92,6 +166,7 @@ impl Ping {
         let mut out = vec![];
         for id in self.storage.ping.drain(..) {
             out.push(PingOut::ToNetwork(id, ModuleMessage::Ping).into());
+            out.push(PingOut::ToNetwork(id, ModuleMessage::TimeRequest(now())));
         }
         for id in self.storage.failed.drain(..) {
             out.push(PingOut::Failed(id).into());
@@ -121,3 +196,29 @@ impl Default for PingConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_signed_time() {
+        let nc = NodeConfig::new();
+        let id = nc.info.get_id();
+        let reply = SignedTime::new(&nc, 1000, 1600);
+        assert!(reply.verify(&id));
+        assert!(!reply.verify(&NodeConfig::new().info.get_id()));
+        let shifted = SignedTime {
+            local: 2600,
+            ..reply.clone()
+        };
+        assert!(!shifted.verify(&id));
+
+        let mut ping = Ping::new(PingConfig::default(), NodeConfig::new());
+        ping.new_nodes(vec![id].into());
+        ping.message(id, ModuleMessage::TimeReply(shifted));
+        assert_eq!(None, ping.storage.stats[&id].clock_offset_ms);
+        ping.message(id, ModuleMessage::TimeReply(reply));
+        assert!(ping.storage.stats[&id].clock_offset_ms.is_some());
+    }
+}
@@ -4,13 +4,15 @@ use flarch::{
     broker::{self, Broker, BrokerError, Subsystem, SubsystemHandler},
     nodeids::U256,
     platform_async_trait,
+    web_rtc::messages::Channel,
 };
 
 use crate::{
-    network::messages::{NetworkIn, NetworkOut, NetworkMessage},
+    network::messages::{NetworkIn, NetworkMessage, NetworkOut},
+    overlay::encryption::NodeKeys,
     random_connections::{
-        messages::{Config, ModuleMessage, RandomConnections, RandomIn, RandomMessage, RandomOut},
         core::RandomStorage,
+        messages::{Config, ModuleMessage, RandomConnections, RandomIn, RandomMessage, RandomOut},
     },
     timer::TimerMessage,
 };
@@ -23,8 +25,18 @@ pub struct RandomBroker {
 
 impl RandomBroker {
     pub async fn start(id: U256, broker_net: Broker<NetworkMessage>) -> Result<Self, BrokerError> {
+        Self::start_with_keys(id, None, broker_net).await
+    }
+
+    /// Starts a RandomBroker which encrypts the messages to the nodes announcing
+    /// [`crate::Capabilities::ENCRYPT`], see [`crate::overlay::encryption`].
+    pub async fn start_with_keys(
+        id: U256,
+        keys: Option<NodeKeys>,
+        broker_net: Broker<NetworkMessage>,
+    ) -> Result<Self, BrokerError> {
         let (storage_tx, storage_rx) = channel();
-        let broker = Translate::start(broker_net, storage_tx, id).await?;
+        let broker = Translate::start(broker_net, storage_tx, id, keys).await?;
         Ok(Self {
             storage: RandomStorage::default(),
             storage_rx,
@@ -61,11 +73,16 @@ impl Translate {
         broker_net: Broker<NetworkMessage>,
         storage_tx: Sender<RandomStorage>,
         id: U256,
+        keys: Option<NodeKeys>,
     ) -> Result<Broker<RandomMessage>, BrokerError> {
+        let mut module = RandomConnections::new(Config::default());
+        if let Some(keys) = keys {
+            module.set_keys(keys);
+        }
         let mut rc = Broker::new();
         rc.add_subsystem(Subsystem::Handler(Box::new(Translate {
             storage_tx,
-            module: RandomConnections::new(Config::default()),
+            module,
             id,
         })))
         .await?;
@@ -98,6 +115,7 @@ impl Translate {
                         )
                     }
                     NetworkOut::Connected(id) => return Some(RandomIn::NodeConnected(id).into()),
+                    NetworkOut::NatType(nat) => return Some(RandomIn::NatType(nat).into()),
                     NetworkOut::Disconnected(id) => {
                         return Some(RandomIn::NodeDisconnected(id).into())
                     }
@@ -114,8 +132,12 @@ impl Translate {
                 RandomOut::ConnectNode(id) => return Some(NetworkIn::Connect(id).into()),
                 RandomOut::DisconnectNode(id) => return Some(NetworkIn::Disconnect(id).into()),
                 RandomOut::NodeCommToNetwork(id, msg) => {
+                    let channel = match &msg {
+                        ModuleMessage::Module(nw) => nw.channel,
+                        _ => Channel::Control,
+                    };
                     let msg_str = serde_yaml::to_string(&msg).unwrap();
-                    return Some(NetworkIn::MessageToNode(id, msg_str).into());
+                    return Some(NetworkIn::to_node(id, channel, msg_str).into());
                 }
                 _ => {}
             }
@@ -1,13 +1,24 @@
 use std::cmp::{max, min};
 
-use itertools::Itertools;
 use rand::prelude::SliceRandom;
 use serde::{Deserialize, Serialize};
 
-use crate::nodeconfig::NodeInfo;
+use crate::{
+    nodeconfig::NodeInfo,
+    overlay::{
+        compression::{Compression, CompressionStats},
+        messages::NetworkWrapper,
+    },
+};
 
-use super::nodes::Nodes;
-use flarch::nodeids::{NodeIDs, U256};
+use super::{
+    nodes::Nodes,
+    scoring::{self, Scoring},
+};
+use flarch::{
+    nodeids::{NodeIDs, U256},
+    web_rtc::nat::NatType,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct RandomStorage {
@@ -15,6 +26,13 @@ pub struct RandomStorage {
     pub connecting: Nodes,
     pub known: NodeIDs,
     pub infos: Vec<NodeInfo>,
+    /// How much the messages sent to other nodes have been compressed.
+    #[serde(skip)]
+    pub compression: CompressionStats,
+    /// The NAT type of this node. If it is symmetric, no connections to other
+    /// nodes behind a symmetric NAT are set up, as they would need a TURN server.
+    #[serde(default)]
+    pub nat: Option<NatType>,
 }
 
 impl Default for RandomStorage {
@@ -24,6 +42,8 @@ impl Default for RandomStorage {
             connecting: Nodes::new(),
             known: NodeIDs::empty(),
             infos: vec![],
+            compression: CompressionStats::default(),
+            nat: None,
         }
     }
 }
@@ -44,13 +64,14 @@ impl RandomStorage {
                 .collect::<Vec<U256>>()
                 .into(),
         );
-        self.infos = self
-            .infos
-            .iter()
-            .chain(list.iter())
-            .unique()
-            .cloned()
-            .collect();
+        // A node has only one NodeInfo, the latest one replaces the others.
+        for ni in list {
+            let id = ni.get_id();
+            match self.infos.iter_mut().find(|old| old.get_id() == id) {
+                Some(old) => *old = ni,
+                None => self.infos.push(ni),
+            }
+        }
     }
 
     pub fn get_connected_info(&self) -> Vec<NodeInfo> {
@@ -61,6 +82,19 @@ impl RandomStorage {
             .collect()
     }
 
+    /// Compresses the message if the destination node can decompress it.
+    pub fn compress(&mut self, dst: &U256, msg: NetworkWrapper) -> NetworkWrapper {
+        let compression = self
+            .infos
+            .iter()
+            .find(|ni| &ni.get_id() == dst)
+            .and_then(|ni| Compression::negotiate(ni.capabilities));
+        match compression {
+            Some(compression) => self.compression.compress(compression, msg),
+            None => msg,
+        }
+    }
+
     pub fn connect(&mut self, nodes: NodeIDs) {
         self.connected.add_new(nodes.clone().0);
         self.connecting.remove(&nodes.clone().into());
@@ -93,25 +127,58 @@ impl RandomStorage {
         self.connecting.tick();
     }
 
-    pub fn choose_new(&mut self, mut nodes: usize) -> NodeIDs {
+    pub fn choose_new(&mut self, nodes: usize) -> NodeIDs {
+        self.choose_scored(nodes, &scoring::uniform())
+    }
+
+    /// Chooses up to `nodes` new nodes to connect to, weighted by their score.
+    /// Nodes with a score of 0 or less are never chosen.
+    pub fn choose_scored(&mut self, mut nodes: usize, score: &Scoring) -> NodeIDs {
         let mut unused = self.known.clone();
         unused.remove_existing(&self.connected.get_nodes());
         unused.remove_existing(&self.connecting.get_nodes());
-        nodes = min(nodes, unused.0.len());
+        unused.remove_existing(&self.unreachable());
+        let candidates: Vec<(U256, f64)> = unused
+            .0
+            .into_iter()
+            .map(|id| {
+                let info = self.infos.iter().find(|ni| ni.get_id() == id);
+                (id, score(&id, info))
+            })
+            .filter(|(_, s)| s.is_finite() && *s > 0.)
+            .collect();
+        nodes = min(nodes, candidates.len());
         let connecting = NodeIDs {
-            0: unused
-                .0
-                .choose_multiple(&mut rand::thread_rng(), nodes)
-                .cloned()
-                .collect(),
+            0: candidates
+                .choose_multiple_weighted(&mut rand::thread_rng(), nodes, |(_, s)| *s)
+                .map(|chosen| chosen.map(|(id, _)| *id).collect())
+                .unwrap_or_default(),
         };
         self.connecting(connecting.clone());
         connecting
     }
 
+    /// Returns the nodes behind a symmetric NAT if this node is also behind one.
+    pub fn unreachable(&self) -> NodeIDs {
+        if self.nat != Some(NatType::Symmetric) {
+            return NodeIDs::empty();
+        }
+        self.infos
+            .iter()
+            .filter(|ni| ni.nat == Some(NatType::Symmetric))
+            .map(|ni| ni.get_id())
+            .collect::<Vec<U256>>()
+            .into()
+    }
+
     pub fn fill_up(&mut self) -> NodeIDs {
+        self.fill_up_scored(&scoring::uniform())
+    }
+
+    /// Like [`Self::fill_up`], but chooses the nodes weighted by their score.
+    pub fn fill_up_scored(&mut self, score: &Scoring) -> NodeIDs {
         let needed = (self.nodes_needed() as i32 + 1) / 2 - self.total_len() as i32;
-        self.choose_new(max(0, needed) as usize)
+        self.choose_scored(max(0, needed) as usize, score)
     }
 
     pub fn limit_active(&mut self, churn: u32) -> NodeIDs {
@@ -179,4 +246,46 @@ mod tests {
         assert_eq!(20, added.0.len());
         assert!(nodes.slice(20, 20).contains_all(&added));
     }
+
+    #[test]
+    fn choose_nat() {
+        let mut s = RandomStorage::default();
+        let infos: Vec<NodeInfo> = [None, Some(NatType::Cone), Some(NatType::Symmetric)]
+            .into_iter()
+            .map(|nat| {
+                let mut ni = crate::nodeconfig::NodeConfig::new().info;
+                ni.nat = nat;
+                ni
+            })
+            .collect();
+        s.new_infos(infos.clone());
+        s.nat = Some(NatType::Symmetric);
+        let added = s.choose_new(3);
+        assert_eq!(2, added.0.len());
+        assert!(!added.0.contains(&infos[2].get_id()));
+    }
+
+    #[test]
+    fn choose_scored() {
+        let nodes = NodeIDs::new(10);
+        let (fast, excluded) = (nodes.0[0], nodes.0[1]);
+        let score = scoring::operator([(fast, 100.), (excluded, 0.)].into_iter().collect());
+        let mut fast_chosen = 0;
+        for _ in 0..100 {
+            let mut s = RandomStorage::default();
+            s.new_list(nodes.clone());
+            let added = s.choose_scored(2, &score);
+            assert_eq!(2, added.0.len());
+            assert!(!added.0.contains(&excluded));
+            if added.0.contains(&fast) {
+                fast_chosen += 1;
+            }
+        }
+        // Uniformly, `fast` would be chosen about 22 times.
+        assert!(fast_chosen > 90, "fast chosen {fast_chosen} times");
+
+        let mut s = RandomStorage::default();
+        s.new_list(nodes.slice(1, 1));
+        assert_eq!(0, s.choose_scored(1, &score).0.len());
+    }
 }
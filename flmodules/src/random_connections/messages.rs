@@ -1,16 +1,33 @@
+use std::collections::HashMap;
+
 use itertools::concat;
+use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
 
-use flarch::nodeids::{NodeID, NodeIDs, U256};
+use flarch::{
+    nodeids::{NodeID, NodeIDs, U256},
+    tasks::now,
+    web_rtc::nat::NatType,
+};
 
-use crate::{nodeconfig::NodeInfo, overlay::messages::NetworkWrapper};
+use crate::{
+    nodeconfig::{NodeInfo, SignedNodeInfo},
+    overlay::{encryption::NodeKeys, messages::NetworkWrapper, replay::ReplayGuard},
+    Capabilities,
+};
 
-use super::core::RandomStorage;
+use super::{
+    core::RandomStorage,
+    scoring::{self, Scoring},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ModuleMessage {
     Module(NetworkWrapper),
     DropConnection,
+    /// Peer exchange: the signed NodeInfos of some nodes known to the sender.
+    /// Older nodes cannot parse it and ignore it.
+    Peers(Vec<SignedNodeInfo>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -27,6 +44,12 @@ pub enum RandomIn {
     NodeDisconnected(NodeID),
     NodeCommFromNetwork(NodeID, ModuleMessage),
     NetworkMapperToNetwork(NodeID, NetworkWrapper),
+    /// The NAT type of this node.
+    NatType(NatType),
+    /// Adds replay protection to all messages of this module.
+    ReplayProtect(String),
+    /// Enables the peer exchange with the signed NodeInfo of this node.
+    PeerExchange(SignedNodeInfo),
     Tick,
 }
 
@@ -48,6 +71,11 @@ pub struct RandomConnections {
     cfg: Config,
     pub storage: RandomStorage,
     fill: u32,
+    keys: Option<NodeKeys>,
+    replay: ReplayGuard,
+    pex: Option<(NodeID, SignedNodeInfo)>,
+    pex_tick: u32,
+    peers: HashMap<NodeID, SignedNodeInfo>,
 }
 
 impl RandomConnections {
@@ -56,14 +84,28 @@ impl RandomConnections {
             cfg,
             storage: RandomStorage::default(),
             fill: 0,
+            keys: None,
+            replay: ReplayGuard::default(),
+            pex: None,
+            pex_tick: 0,
+            peers: HashMap::new(),
         }
     }
 
+    /// Encrypts the messages to, and decrypts the messages from, the nodes
+    /// announcing [`Capabilities::ENCRYPT`].
+    pub fn set_keys(&mut self, keys: NodeKeys) {
+        self.keys = Some(keys);
+    }
+
     /// Processes one message and returns messages that need to be treated by the
     /// system.
     pub fn process_message(&mut self, msg: RandomIn) -> Vec<RandomOut> {
         let out = match msg {
             RandomIn::NodeList(nodes) => {
+                if let Some(keys) = self.keys.as_mut() {
+                    keys.add_nodes(&nodes);
+                }
                 self.storage.new_infos(nodes);
                 self.new_connection()
             }
@@ -91,16 +133,37 @@ impl RandomConnections {
                     self.need_drop(),
                     self.churn(),
                     self.fill_connection(),
+                    self.peer_exchange(),
                     self.update(),
                 ])
             }
+            RandomIn::NatType(nat) => {
+                self.storage.nat = Some(nat);
+                vec![]
+            }
+            RandomIn::ReplayProtect(module) => {
+                self.replay.protect(&module);
+                vec![]
+            }
+            RandomIn::PeerExchange(info) => {
+                match info.verify() {
+                    Some(ni) => self.pex = Some((ni.get_id(), info)),
+                    None => log::warn!("Own NodeInfo has an invalid signature"),
+                }
+                vec![]
+            }
             RandomIn::NodeCommFromNetwork(id, node_msg) => self.network_msg(id, node_msg),
             RandomIn::NetworkMapperToNetwork(dst, msg) => {
                 if self.storage.connected.contains(&dst) {
-                    vec![RandomOut::NodeCommToNetwork(
-                        dst,
-                        ModuleMessage::Module(msg),
-                    )]
+                    let msg = self.replay.stamp(msg);
+                    let msg = self.storage.compress(&dst, msg);
+                    match self.encrypt(&dst, msg) {
+                        Some(msg) => vec![RandomOut::NodeCommToNetwork(
+                            dst,
+                            ModuleMessage::Module(msg),
+                        )],
+                        None => vec![],
+                    }
                 } else {
                     log::warn!(
                         "{self:p} Dropping message to unconnected node {dst} - making sure we're disconnected"
@@ -118,12 +181,132 @@ impl RandomConnections {
     /// Processes one message from the network.
     pub fn network_msg(&mut self, id: U256, msg: ModuleMessage) -> Vec<RandomOut> {
         match msg {
-            ModuleMessage::Module(msg_mod) => vec![RandomOut::NetworkWrapperFromNetwork(id, msg_mod)],
+            ModuleMessage::Module(msg_mod) => {
+                let Some(msg_mod) = self.decrypt(&id, msg_mod) else {
+                    return vec![];
+                };
+                if let Err(e) = self.replay.check(&id, &msg_mod) {
+                    log::warn!("Dropping message from {id}: {e}");
+                    return vec![];
+                }
+                match msg_mod.decompress() {
+                    Ok(msg_mod) => vec![RandomOut::NetworkWrapperFromNetwork(id, msg_mod)],
+                    Err(e) => {
+                        log::warn!("Couldn't decompress message from {id}: {e:?}");
+                        vec![]
+                    }
+                }
+            }
             ModuleMessage::DropConnection => {
                 self.storage.disconnect((&vec![id]).into());
                 concat([vec![RandomOut::DisconnectNode(id)], self.new_connection()])
             }
+            ModuleMessage::Peers(peers) => self.new_peers(peers),
+        }
+    }
+
+    // Adds the correctly signed NodeInfos to the known nodes, and keeps them to
+    // pass them on to other nodes.
+    // At most `pex_max` nodes are learned this way, the others are ignored.
+    fn new_peers(&mut self, peers: Vec<SignedNodeInfo>) -> Vec<RandomOut> {
+        let Some((our_id, _)) = self.pex.as_ref() else {
+            return vec![];
+        };
+        let now = now();
+        if self.peers.len() >= self.cfg.pex_max {
+            self.peers.retain(|_, peer| peer.expires >= now);
+        }
+        let mut infos: Vec<NodeInfo> = vec![];
+        for peer in peers.into_iter().take(self.cfg.pex_size) {
+            let Some(info) = peer.verify() else {
+                continue;
+            };
+            let id = info.get_id();
+            if &id == our_id || infos.iter().any(|ni| ni.get_id() == id) {
+                continue;
+            }
+            if self.peers.len() >= self.cfg.pex_max && !self.peers.contains_key(&id) {
+                continue;
+            }
+            self.peers.insert(id, peer);
+            infos.push(info);
+        }
+        if infos.is_empty() {
+            return vec![];
+        }
+        if let Some(keys) = self.keys.as_mut() {
+            keys.add_nodes(&infos);
+        }
+        self.storage.new_infos(infos);
+        self.new_connection()
+    }
+
+    // Every `pex_interval` ticks, sends the NodeInfo of this node and some
+    // of the known peers to all connected nodes.
+    fn peer_exchange(&mut self) -> Vec<RandomOut> {
+        let Some((_, own)) = self.pex.as_ref() else {
+            return vec![];
+        };
+        self.pex_tick += 1;
+        if self.pex_tick < self.cfg.pex_interval {
+            return vec![];
+        }
+        self.pex_tick = 0;
+        let now = now();
+        let mut rng = rand::thread_rng();
+        self.storage
+            .connected
+            .get_nodes()
+            .0
+            .into_iter()
+            .map(|dst| {
+                let peers = std::iter::once(own.clone())
+                    .chain(
+                        self.peers
+                            .iter()
+                            .filter(|(id, peer)| **id != dst && peer.expires >= now)
+                            .map(|(_, peer)| peer.clone())
+                            .choose_multiple(&mut rng, self.cfg.pex_size.saturating_sub(1)),
+                    )
+                    .collect();
+                RandomOut::NodeCommToNetwork(dst, ModuleMessage::Peers(peers))
+            })
+            .collect()
+    }
+
+    // Encrypts the message if both nodes can. If the encryption fails, the
+    // message is dropped instead of being sent in the clear.
+    fn encrypt(&mut self, dst: &U256, msg: NetworkWrapper) -> Option<NetworkWrapper> {
+        let (Some(keys), Some(info)) = (
+            self.keys.as_mut(),
+            self.storage.infos.iter().find(|ni| &ni.get_id() == dst),
+        ) else {
+            return Some(msg);
+        };
+        if !info.capabilities.contains(Capabilities::ENCRYPT) {
+            return Some(msg);
         }
+        keys.encrypt(info, &msg)
+            .map_err(|e| log::warn!("Couldn't encrypt message to {dst}: {e:?}"))
+            .ok()
+    }
+
+    // Decrypts the message. Plain messages from a node which announces the
+    // encryption are dropped, as they have been downgraded on the way.
+    fn decrypt(&mut self, src: &U256, msg: NetworkWrapper) -> Option<NetworkWrapper> {
+        let (Some(keys), Some(info)) = (
+            self.keys.as_mut(),
+            self.storage.infos.iter().find(|ni| &ni.get_id() == src),
+        ) else {
+            if msg.encryption.is_none() {
+                return Some(msg);
+            }
+            log::warn!("Cannot decrypt message from {src}");
+            return None;
+        };
+        keys.decrypt(info, msg)
+            .map_err(|e| log::warn!("Couldn't decrypt message from {src}: {e:?}"))
+            .ok()
     }
 
     /// Returns a clone of the connected NodeIDs.
@@ -134,7 +317,7 @@ impl RandomConnections {
     /// Fills up to ceil(nodes_needed / 2) nodes by emitting ConnectNode.
     fn new_connection(&mut self) -> Vec<RandomOut> {
         self.storage
-            .fill_up()
+            .fill_up_scored(&self.cfg.scoring)
             .0
             .into_iter()
             .map(|n| RandomOut::ConnectNode(n))
@@ -164,10 +347,11 @@ impl RandomConnections {
 
     fn churn(&mut self) -> Vec<RandomOut> {
         self.storage
-            .choose_new(
+            .choose_scored(
                 self.storage
                     .connected
                     .count_expired(self.cfg.churn_connected),
+                &self.cfg.scoring,
             )
             .0
             .into_iter()
@@ -182,7 +366,7 @@ impl RandomConnections {
             if self.storage.total_len() < self.storage.nodes_needed() {
                 return self
                     .storage
-                    .choose_new(1)
+                    .choose_scored(1, &self.cfg.scoring)
                     .0
                     .into_iter()
                     .map(|n| RandomOut::ConnectNode(n))
@@ -202,7 +386,6 @@ impl RandomConnections {
 }
 
 /// All intervals are indicated in ticks.
-#[derive(Debug)]
 pub struct Config {
     /// How many ticks a node stays in the list before it is
     /// possibly replaced by another node.
@@ -216,6 +399,18 @@ pub struct Config {
     /// every fill_connected interval one additional node will be
     /// connected.
     pub fill_connected: u32,
+
+    /// Weighs the candidates when choosing new nodes to connect to.
+    /// The default chooses them uniformly, see [`scoring`] for others.
+    pub scoring: Scoring,
+
+    /// Once the peer exchange is enabled with [`RandomIn::PeerExchange`],
+    /// every pex_interval the connected nodes get some signed NodeInfos.
+    pub pex_interval: u32,
+    /// How many NodeInfos are sent and accepted in one peer exchange.
+    pub pex_size: usize,
+    /// How many NodeInfos of other nodes are kept to be passed on.
+    pub pex_max: usize,
 }
 
 impl Config {
@@ -224,10 +419,27 @@ impl Config {
             churn_connected: 60 * 60,
             connecting_timeout: 10,
             fill_connected: 10,
+            scoring: scoring::uniform(),
+            pex_interval: 60,
+            pex_size: 16,
+            pex_max: 1000,
         }
     }
 }
 
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("churn_connected", &self.churn_connected)
+            .field("connecting_timeout", &self.connecting_timeout)
+            .field("fill_connected", &self.fill_connected)
+            .field("pex_interval", &self.pex_interval)
+            .field("pex_size", &self.pex_size)
+            .field("pex_max", &self.pex_max)
+            .finish_non_exhaustive()
+    }
+}
+
 impl From<RandomIn> for RandomMessage {
     fn from(msg: RandomIn) -> RandomMessage {
         RandomMessage::Input(msg)
@@ -244,7 +456,10 @@ impl From<RandomOut> for RandomMessage {
 mod tests {
     use flarch::start_logging;
 
-    use crate::{nodeconfig::NodeConfig, random_connections::nodes::Nodes};
+    use crate::{
+        nodeconfig::NodeConfig, overlay::compression::Compression,
+        random_connections::nodes::Nodes, Capabilities,
+    };
 
     use super::*;
     use core::fmt::Error;
@@ -270,4 +485,233 @@ mod tests {
 
         Ok(())
     }
+
+    // Only nodes announcing the capability get compressed messages, and zstd is
+    // only used if the remote node supports it.
+    #[test]
+    fn test_compression() -> Result<(), Box<dyn std::error::Error>> {
+        let mut old = NodeConfig::new().info;
+        old.capabilities = Capabilities::empty();
+        let mut browser = NodeConfig::new().info;
+        browser.capabilities = Capabilities::all() - Capabilities::COMPRESS_ZSTD;
+        let new = NodeConfig::new().info;
+        let ids = [old.get_id(), browser.get_id(), new.get_id()];
+        let mut rc = RandomConnections::new(Config::default());
+        rc.process_message(RandomIn::NodeList(vec![old, browser, new]));
+        rc.storage.connect(ids.to_vec().into());
+
+        let msg = NetworkWrapper::wrap_yaml("Gossip", &vec!["event"; 500])?;
+        let mut sent = vec![];
+        for id in ids {
+            if let [RandomOut::NodeCommToNetwork(_, ModuleMessage::Module(msg))] = rc
+                .process_message(RandomIn::NetworkMapperToNetwork(id, msg.clone()))
+                .as_slice()
+            {
+                sent.push(msg.clone());
+            }
+        }
+        assert_eq!(None, sent[0].compression);
+        assert_eq!(Some(Compression::Deflate), sent[1].compression);
+        assert_eq!(Some(Compression::Zstd), sent[2].compression);
+        assert_eq!(2, rc.storage.compression.modules["Gossip"].messages);
+
+        for (id, sent) in ids.iter().zip(sent).skip(1) {
+            assert_eq!(
+                vec![RandomOut::NetworkWrapperFromNetwork(*id, msg.clone())],
+                rc.network_msg(*id, ModuleMessage::Module(sent))
+            );
+        }
+        Ok(())
+    }
+
+    // Messages are only encrypted if the remote node announces it, and can
+    // only be read by the destination.
+    #[test]
+    fn test_encryption() -> Result<(), Box<dyn std::error::Error>> {
+        let (mut a, mut b) = (NodeConfig::new(), NodeConfig::new());
+        a.info.capabilities |= Capabilities::ENCRYPT;
+        b.info.capabilities |= Capabilities::ENCRYPT;
+        let mut old = NodeConfig::new().info;
+        old.capabilities = Capabilities::empty();
+        let infos = vec![a.info.clone(), b.info.clone(), old.clone()];
+        let start = |nc: &NodeConfig| -> Result<RandomConnections, Box<dyn std::error::Error>> {
+            let mut rc = RandomConnections::new(Config::default());
+            rc.set_keys(NodeKeys::new(nc)?);
+            rc.process_message(RandomIn::NodeList(infos.clone()));
+            rc.storage.connect(
+                infos
+                    .iter()
+                    .map(|ni| ni.get_id())
+                    .collect::<Vec<_>>()
+                    .into(),
+            );
+            Ok(rc)
+        };
+        let (mut rc_a, mut rc_b) = (start(&a)?, start(&b)?);
+
+        let msg = NetworkWrapper::wrap_yaml("Gossip", &"secret")?;
+        let mut sent = vec![];
+        for id in [b.info.get_id(), old.get_id()] {
+            if let [RandomOut::NodeCommToNetwork(_, ModuleMessage::Module(msg))] = rc_a
+                .process_message(RandomIn::NetworkMapperToNetwork(id, msg.clone()))
+                .as_slice()
+            {
+                sent.push(msg.clone());
+            }
+        }
+        assert!(sent[0].encryption.is_some());
+        assert_eq!(msg, sent[1]);
+
+        let id_a = a.info.get_id();
+        assert_eq!(
+            vec![RandomOut::NetworkWrapperFromNetwork(id_a, msg.clone())],
+            rc_b.network_msg(id_a, ModuleMessage::Module(sent[0].clone()))
+        );
+        // A plain message between two nodes announcing encryption is dropped.
+        assert!(rc_b
+            .network_msg(id_a, ModuleMessage::Module(msg.clone()))
+            .is_empty());
+        let id_old = old.get_id();
+        assert_eq!(
+            vec![RandomOut::NetworkWrapperFromNetwork(id_old, msg.clone())],
+            rc_b.network_msg(id_old, ModuleMessage::Module(msg))
+        );
+        // A node without the keys drops the message.
+        let mut rc_none = RandomConnections::new(Config::default());
+        assert!(rc_none
+            .network_msg(id_a, ModuleMessage::Module(sent[0].clone()))
+            .is_empty());
+        Ok(())
+    }
+
+    // The NodeInfos are passed on from node to node, so a node learns about
+    // nodes it is not connected to.
+    #[test]
+    fn test_peer_exchange() {
+        let ncs: Vec<NodeConfig> = (0..3).map(|_| NodeConfig::new()).collect();
+        let ids: Vec<NodeID> = ncs.iter().map(|nc| nc.info.get_id()).collect();
+        let mut rcs: Vec<RandomConnections> = ncs
+            .iter()
+            .map(|nc| {
+                let mut rc = RandomConnections::new(Config {
+                    pex_interval: 2,
+                    ..Config::default()
+                });
+                rc.process_message(RandomIn::PeerExchange(nc.signed_info()));
+                rc
+            })
+            .collect();
+        // 0 - 1 - 2
+        rcs[0].storage.connect(vec![ids[1]].into());
+        rcs[1].storage.connect(vec![ids[0], ids[2]].into());
+        rcs[2].storage.connect(vec![ids[1]].into());
+
+        let mut exchange = |rcs: &mut Vec<RandomConnections>| {
+            let mut msgs = vec![];
+            for (i, rc) in rcs.iter_mut().enumerate() {
+                for _ in 0..2 {
+                    for out in rc.process_message(RandomIn::Tick) {
+                        if let RandomOut::NodeCommToNetwork(dst, msg @ ModuleMessage::Peers(_)) =
+                            out
+                        {
+                            msgs.push((ids[i], dst, msg));
+                        }
+                    }
+                }
+            }
+            for (src, dst, msg) in msgs {
+                let i = ids.iter().position(|id| id == &dst).unwrap();
+                rcs[i].network_msg(src, msg);
+            }
+        };
+        let knows = |rc: &RandomConnections, id: &NodeID| {
+            rc.storage.infos.iter().any(|ni| &ni.get_id() == id)
+        };
+        exchange(&mut rcs);
+        assert!(knows(&rcs[1], &ids[0]));
+        assert!(!knows(&rcs[0], &ids[2]));
+        exchange(&mut rcs);
+        assert!(knows(&rcs[0], &ids[2]));
+        assert!(knows(&rcs[2], &ids[0]));
+        assert!(rcs[2].storage.known.0.contains(&ids[0]));
+        assert!(!knows(&rcs[0], &ids[0]));
+
+        // Changed NodeInfos are ignored.
+        let mut forged = NodeConfig::new().signed_info();
+        forged.info = ncs[0].signed_info().info;
+        let mut rc = RandomConnections::new(Config::default());
+        rc.process_message(RandomIn::PeerExchange(NodeConfig::new().signed_info()));
+        assert!(rc
+            .network_msg(ids[1], ModuleMessage::Peers(vec![forged]))
+            .is_empty());
+        assert!(rc.storage.known.0.is_empty());
+
+        // Expired NodeInfos are ignored.
+        let expired = ncs[0].signed_info_until(now() - 1);
+        rc.network_msg(ids[1], ModuleMessage::Peers(vec![expired]));
+        assert!(rc.storage.infos.is_empty());
+    }
+
+    // Every node is only learned once, and at most pex_max nodes are learned
+    // through the peer exchange.
+    #[test]
+    fn test_peer_exchange_limits() {
+        let mut rc = RandomConnections::new(Config {
+            pex_max: 3,
+            ..Config::default()
+        });
+        rc.process_message(RandomIn::PeerExchange(NodeConfig::new().signed_info()));
+        let ncs: Vec<NodeConfig> = (0..5).map(|_| NodeConfig::new()).collect();
+        let mut renamed = ncs[0].clone();
+        renamed.info.name = "renamed".into();
+        let peers = vec![
+            ncs[0].signed_info(),
+            renamed.signed_info(),
+            ncs[0].signed_info(),
+        ];
+        rc.network_msg(U256::rnd(), ModuleMessage::Peers(peers));
+        assert_eq!(1, rc.storage.infos.len());
+        assert_eq!(1, rc.peers.len());
+
+        rc.network_msg(
+            U256::rnd(),
+            ModuleMessage::Peers(vec![renamed.signed_info()]),
+        );
+        assert_eq!(1, rc.storage.infos.len());
+        assert_eq!("renamed", rc.storage.infos[0].name);
+
+        let peers = ncs.iter().map(|nc| nc.signed_info()).collect();
+        rc.network_msg(U256::rnd(), ModuleMessage::Peers(peers));
+        assert_eq!(3, rc.storage.infos.len());
+        assert_eq!(3, rc.peers.len());
+    }
+
+    // With a latency scoring, the nodes with the shortest round-trip time are
+    // connected first.
+    #[test]
+    fn test_scoring() {
+        let infos: Vec<NodeInfo> = (0..20).map(|_| NodeConfig::new().info).collect();
+        let latencies: scoring::Latencies = Default::default();
+        latencies.write().unwrap().extend(
+            infos
+                .iter()
+                .enumerate()
+                .map(|(i, ni)| (ni.get_id(), if i < 3 { 5 } else { 5000 })),
+        );
+        let fast: Vec<NodeID> = infos[0..3].iter().map(|ni| ni.get_id()).collect();
+        let mut fast_chosen = 0;
+        for _ in 0..20 {
+            let mut rc = RandomConnections::new(Config {
+                scoring: scoring::latency(latencies.clone(), 1000),
+                ..Config::default()
+            });
+            for out in rc.process_message(RandomIn::NodeList(infos.clone())) {
+                if let RandomOut::ConnectNode(id) = out {
+                    fast_chosen += fast.contains(&id) as usize;
+                }
+            }
+        }
+        // Weighted, the 3 fast nodes are chosen every time, uniformly only 0.45 on average.
+        assert!(fast_chosen > 50, "fast nodes chosen {fast_chosen} times");
+    }
 }
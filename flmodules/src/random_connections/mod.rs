@@ -1,4 +1,5 @@
 pub mod broker;
 pub mod messages;
 pub mod nodes;
+pub mod scoring;
 pub mod core;
\ No newline at end of file
@@ -0,0 +1,64 @@
+//! Scoring functions to weigh the candidates when [`super::messages::RandomConnections`]
+//! chooses new nodes to connect to.
+//!
+//! A node with twice the score of another node is chosen twice as often.
+//! A node with a score of 0 is never chosen, which lets an operator exclude nodes.
+//! The functions can be combined with [`product`].
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use flarch::{nodeids::NodeID, web_rtc::nat::NatType};
+
+use crate::nodeconfig::NodeInfo;
+
+/// Returns the score of a candidate node. The [`NodeInfo`] is missing if
+/// the node is only known by its ID.
+pub type Scoring = Arc<dyn Fn(&NodeID, Option<&NodeInfo>) -> f64 + Send + Sync>;
+
+/// Round-trip times in milliseconds, e.g., from [`crate::ping::core::PingStat::rtt_ms`].
+/// It is shared, so the caller can update it while the scoring function is in use.
+pub type Latencies = Arc<RwLock<HashMap<NodeID, u32>>>;
+
+/// All nodes have the same chance to be chosen. This is the default.
+pub fn uniform() -> Scoring {
+    Arc::new(|_, _| 1.)
+}
+
+/// Prefers nodes with a short round-trip time: the score is inversely
+/// proportional to it.
+/// Nodes without a measurement are scored as if they had a round-trip time
+/// of `unknown_ms`.
+pub fn latency(latencies: Latencies, unknown_ms: u32) -> Scoring {
+    Arc::new(move |id, _| {
+        let rtt = latencies
+            .read()
+            .ok()
+            .and_then(|l| l.get(id).copied())
+            .unwrap_or(unknown_ms);
+        1. / rtt.max(1) as f64
+    })
+}
+
+/// Prefers nodes not behind a symmetric NAT, as they can connect without
+/// a TURN server to most other nodes.
+/// Nodes behind a symmetric NAT get `symmetric` as a score, all others 1.
+pub fn nat(symmetric: f64) -> Scoring {
+    Arc::new(move |_, info| match info.and_then(|i| i.nat) {
+        Some(NatType::Symmetric) => symmetric,
+        _ => 1.,
+    })
+}
+
+/// Explicit preferences of the operator: the given nodes get the given score,
+/// all other nodes 1.
+pub fn operator(preferences: HashMap<NodeID, f64>) -> Scoring {
+    Arc::new(move |id, _| preferences.get(id).copied().unwrap_or(1.))
+}
+
+/// Multiplies the scores of all given functions.
+pub fn product(scorings: Vec<Scoring>) -> Scoring {
+    Arc::new(move |id, info| scorings.iter().map(|s| s(id, info)).product())
+}
@@ -1,13 +1,10 @@
-use flarch::{data_storage::DataStorage, platform_async_trait, tasks::spawn_local};
+use flarch::{data_storage::DataStorage, tasks::spawn_local};
 use std::error::Error;
 use tokio::sync::watch;
 
-use crate::{
-    overlay::messages::NetworkWrapper,
-    random_connections::messages::{RandomIn, RandomMessage, RandomOut},
-};
+use crate::random_connections::messages::RandomMessage;
 use flarch::{
-    broker::{Broker, BrokerError, Subsystem, SubsystemHandler},
+    broker::{Broker, BrokerError},
     nodeids::NodeID,
 };
 
@@ -21,7 +18,8 @@ const MODULE_NAME: &str = "Template";
 /// This links the Template module with other modules, so that
 /// all messages are correctly translated from one to the other.
 /// For this example, it uses the RandomConnections module to communicate
-/// with other nodes.
+/// with other nodes, and the [`crate::random_translate`] macro creates the
+/// translation.
 ///
 /// The [Template] holds the [Translate] and offers convenience methods
 /// to interact with [Translate] and [TemplateMessage].
@@ -75,84 +73,21 @@ impl Template {
     }
 }
 
-/// Translates the messages to/from the RandomMessage and calls `TemplateMessages.processMessages`.
-struct Translate {
+crate::random_translate! {
+    name: MODULE_NAME,
+    message: TemplateMessage,
+    input: TemplateIn,
+    output: TemplateOut,
     messages: TemplateMessages,
-}
-
-impl Translate {
-    async fn start(
-        random: Broker<RandomMessage>,
-        messages: TemplateMessages,
-    ) -> Result<Broker<TemplateMessage>, Box<dyn Error>> {
-        let mut template = Broker::new();
-
-        template
-            .add_subsystem(Subsystem::Handler(Box::new(Translate { messages })))
-            .await?;
-        template
-            .link_bi(
-                random,
-                Box::new(Self::link_rnd_template),
-                Box::new(Self::link_template_rnd),
-            )
-            .await?;
-        Ok(template)
-    }
-
-    fn link_rnd_template(msg: RandomMessage) -> Option<TemplateMessage> {
-        if let RandomMessage::Output(msg_out) = msg {
-            match msg_out {
-                RandomOut::NodeIDsConnected(list) => {
-                    Some(TemplateIn::UpdateNodeList(list.into()).into())
-                }
-                RandomOut::NetworkWrapperFromNetwork(id, msg) => msg
-                    .unwrap_yaml(MODULE_NAME)
-                    .map(|msg| TemplateIn::FromNetwork(id, msg).into()),
-                _ => None,
-            }
-        } else {
-            None
-        }
-    }
-
-    fn link_template_rnd(msg: TemplateMessage) -> Option<RandomMessage> {
-        if let TemplateMessage::Output(TemplateOut::ToNetwork(id, msg_node)) = msg {
-            Some(
-                RandomIn::NetworkMapperToNetwork(
-                    id,
-                    NetworkWrapper::wrap_yaml(MODULE_NAME, &msg_node).unwrap(),
-                )
-                .into(),
-            )
-        } else {
-            None
-        }
-    }
-}
-
-#[platform_async_trait()]
-impl SubsystemHandler<TemplateMessage> for Translate {
-    async fn messages(&mut self, msgs: Vec<TemplateMessage>) -> Vec<TemplateMessage> {
-        let msgs_in = msgs
-            .into_iter()
-            .filter_map(|msg| match msg {
-                TemplateMessage::Input(msg_in) => Some(msg_in),
-                TemplateMessage::Output(_) => None,
-            })
-            .collect();
-        self.messages
-            .process_messages(msgs_in)
-            .into_iter()
-            .map(|o| o.into())
-            .collect()
-    }
+    translate: Translate,
 }
 
 #[cfg(test)]
 mod tests {
     use flarch::{data_storage::DataStorageTemp, start_logging_filter_level};
 
+    use crate::random_connections::messages::RandomOut;
+
     use super::*;
 
     #[tokio::test]
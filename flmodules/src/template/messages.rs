@@ -13,13 +13,13 @@ pub enum ModuleMessage {
     Counter(u32),
 }
 
-/// First wrap all messages coming into this module and all messages going out in
-/// a single message time.
-#[derive(Clone, Debug)]
-pub enum TemplateMessage {
-    Input(TemplateIn),
-    Output(TemplateOut),
-}
+crate::module_message!(
+    /// First wrap all messages coming into this module and all messages going out in
+    /// a single message time.
+    pub TemplateMessage,
+    TemplateIn,
+    TemplateOut
+);
 
 /// The messages here represent all possible interactions with this module.
 #[derive(Debug, Clone)]
@@ -103,20 +103,6 @@ impl TemplateMessages {
     }
 }
 
-/// Convenience method to reduce long lines.
-impl From<TemplateIn> for TemplateMessage {
-    fn from(msg: TemplateIn) -> Self {
-        TemplateMessage::Input(msg)
-    }
-}
-
-/// Convenience method to reduce long lines.
-impl From<TemplateOut> for TemplateMessage {
-    fn from(msg: TemplateOut) -> Self {
-        TemplateMessage::Output(msg)
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,200 @@
+//! Fixtures to test the modules together, without starting a full node.
+//!
+//! A [`SimulNetwork`] wires simulated nodes with the same modules as flnode:
+//! a [`RandomBroker`] on top of the [`NetworkBrokerSimul`], and a
+//! [`GossipBroker`] on top of the random connections.
+//! Every node has its own [`VirtualTimer`], so the tests don't depend on
+//! the wall clock.
+//!
+//! ```ignore
+//! let mut net = SimulNetwork::new().await?;
+//! net.add_nodes(10).await?;
+//! net.nodes[0].gossip.add_event(event).await?;
+//! net.assert_converged(20, |node| node.gossip.chat_events().len() == 1).await;
+//! ```
+
+use flarch::{
+    broker::{Broker, BrokerError},
+    nodeids::NodeID,
+};
+
+use crate::{
+    gossip_events::broker::GossipBroker,
+    network::{
+        messages::{NetworkIn, NetworkMessage},
+        testing::NetworkBrokerSimul,
+    },
+    nodeconfig::NodeConfig,
+    random_connections::broker::RandomBroker,
+    timer::VirtualTimer,
+};
+
+/// A simulated node with the modules connected like in flnode.
+pub struct SimulNode {
+    pub config: NodeConfig,
+    pub net: Broker<NetworkMessage>,
+    pub random: RandomBroker,
+    pub gossip: GossipBroker,
+    pub timer: VirtualTimer,
+}
+
+impl SimulNode {
+    pub fn id(&self) -> NodeID {
+        self.config.info.get_id()
+    }
+
+    /// Copies the latest storages of the modules.
+    pub fn update(&mut self) {
+        self.random.update();
+        self.gossip.update();
+    }
+}
+
+/// Simulated nodes connected through a [`NetworkBrokerSimul`].
+pub struct SimulNetwork {
+    pub nodes: Vec<SimulNode>,
+    pub simul: NetworkBrokerSimul,
+    /// How many seconds have been simulated.
+    pub ticks: usize,
+}
+
+impl SimulNetwork {
+    pub async fn new() -> Result<Self, BrokerError> {
+        Ok(Self {
+            nodes: vec![],
+            simul: NetworkBrokerSimul::new().await?,
+            ticks: 0,
+        })
+    }
+
+    /// Adds `nbr` new nodes, and sends the new list of nodes to all nodes.
+    pub async fn add_nodes(&mut self, nbr: usize) -> Result<(), BrokerError> {
+        for _ in 0..nbr {
+            let (config, net) = self.simul.new_node().await?;
+            let id = config.info.get_id();
+            let timer = VirtualTimer::start().await?;
+            let mut random = RandomBroker::start(id, net.clone()).await?;
+            random.add_timer(timer.broker.clone()).await;
+            let mut gossip = GossipBroker::start(id, random.broker.clone()).await?;
+            gossip.add_timer(timer.broker.clone()).await;
+            self.nodes.push(SimulNode {
+                config,
+                net,
+                random,
+                gossip,
+                timer,
+            });
+        }
+        for node in self.nodes.iter_mut() {
+            node.net
+                .settle_msg(NetworkIn::WSUpdateListRequest.into())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Advances the timers of all nodes by one second, and updates
+    /// the storages of the modules.
+    pub async fn tick(&mut self) -> Result<(), BrokerError> {
+        for node in self.nodes.iter_mut() {
+            node.timer.advance(1000).await?;
+        }
+        for node in self.nodes.iter_mut() {
+            node.update();
+        }
+        self.ticks += 1;
+        Ok(())
+    }
+
+    /// Returns how many messages have been sent between the nodes.
+    pub fn messages(&self) -> u64 {
+        self.simul.messages()
+    }
+
+    /// Ticks until `done` is true for all nodes, and returns the number of
+    /// ticks it took.
+    /// Returns `None` if the nodes didn't converge after `max_ticks`.
+    pub async fn converge(
+        &mut self,
+        max_ticks: usize,
+        done: impl Fn(&SimulNode) -> bool,
+    ) -> Result<Option<usize>, BrokerError> {
+        for tick in 0..=max_ticks {
+            if self.nodes.iter().all(&done) {
+                return Ok(Some(tick));
+            }
+            if tick < max_ticks {
+                self.tick().await?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like [`SimulNetwork::converge`], but panics if the nodes don't converge,
+    /// listing the nodes for which `done` is false.
+    pub async fn assert_converged(
+        &mut self,
+        max_ticks: usize,
+        done: impl Fn(&SimulNode) -> bool,
+    ) -> usize {
+        match self.converge(max_ticks, &done).await {
+            Ok(Some(ticks)) => ticks,
+            Ok(None) => {
+                let missing: Vec<NodeID> = self
+                    .nodes
+                    .iter()
+                    .filter(|node| !done(node))
+                    .map(|node| node.id())
+                    .collect();
+                panic!("Nodes didn't converge after {max_ticks} ticks: {missing:?}");
+            }
+            Err(e) => panic!("Broker error while converging: {e}"),
+        }
+    }
+
+    /// Panics if more than `max` messages have been sent between the nodes.
+    pub fn assert_messages_below(&self, max: u64) {
+        let messages = self.messages();
+        assert!(
+            messages <= max,
+            "{messages} messages sent between the nodes, expected at most {max}"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gossip_events::core::{Category, Event};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gossip() -> Result<(), BrokerError> {
+        let mut net = SimulNetwork::new().await?;
+        net.add_nodes(5).await?;
+        net.assert_converged(10, |node| {
+            !node.random.storage.connected.get_nodes().0.is_empty()
+        })
+        .await;
+
+        let event = Event {
+            category: Category::TextMessage,
+            src: net.nodes[0].id(),
+            created: 0,
+            msg: "hello".into(),
+            signature: None,
+        }
+        .signed(&net.nodes[0].config);
+        net.nodes[0].gossip.add_event(event).await?;
+        net.assert_converged(20, |node| node.gossip.chat_events().len() == 1)
+            .await;
+        assert!(net.messages() > 0);
+        net.assert_messages_below(10_000);
+
+        net.add_nodes(5).await?;
+        net.assert_converged(30, |node| node.gossip.chat_events().len() == 1)
+            .await;
+        assert_eq!(None, net.converge(2, |_| false).await?);
+        Ok(())
+    }
+}
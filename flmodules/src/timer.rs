@@ -2,7 +2,7 @@ use flarch::platform_async_trait;
 use std::time::Duration;
 use tokio_stream::StreamExt;
 
-use flarch::broker::{Broker, BrokerError, Subsystem, SubsystemHandler};
+use flarch::broker::{Broker, BrokerError, Priority, Subsystem, SubsystemHandler};
 use flarch::tasks::{spawn_local, Interval};
 
 #[derive(Debug, Clone, PartialEq)]
@@ -19,34 +19,118 @@ pub struct TimerBroker {
 
 impl TimerBroker {
     pub async fn start() -> Result<Broker<TimerMessage>, BrokerError> {
-        let mut broker = Broker::new();
-        let timer_struct = TimerBroker { seconds: 0 };
-        broker
-            .add_subsystem(Subsystem::Handler(Box::new(timer_struct)))
-            .await?;
+        let broker = Self::new_broker().await?;
         let mut broker_cl = broker.clone();
         spawn_local(async move {
             let mut interval = Interval::new_interval(Duration::from_millis(1000));
             loop {
                 interval.next().await;
-                if let Err(e) = broker_cl.emit_msg(TimerMessage::Second) {
+                // The timer drives the pings and keepalives, so it must not wait
+                // behind the other messages.
+                if let Err(e) =
+                    broker_cl.enqueue_msg_priority(Priority::Control, TimerMessage::Second)
+                {
                     log::error!("While emitting timer: {e:?}");
                 }
             }
         });
         Ok(broker)
     }
+
+    /// Returns a broker which emits a [`TimerMessage::Minute`] every 60 [`TimerMessage::Second`]s,
+    /// but doesn't emit the seconds itself.
+    async fn new_broker() -> Result<Broker<TimerMessage>, BrokerError> {
+        let mut broker = Broker::new();
+        broker
+            .add_subsystem(Subsystem::Handler(Box::new(TimerBroker { seconds: 0 })))
+            .await?;
+        Ok(broker)
+    }
+}
+
+/// A timer which doesn't follow the wall clock, but is advanced manually.
+/// This makes simulations and tests deterministic, and lets them run as fast
+/// as the nodes can process the messages.
+pub struct VirtualTimer {
+    pub broker: Broker<TimerMessage>,
+    now: u64,
+    next_second: u64,
+}
+
+impl VirtualTimer {
+    pub async fn start() -> Result<Self, BrokerError> {
+        Ok(Self {
+            broker: TimerBroker::new_broker().await?,
+            now: 0,
+            next_second: 1000,
+        })
+    }
+
+    /// Advances the virtual time by `ms` milliseconds, and emits a [`TimerMessage::Second`]
+    /// for every full second passed.
+    /// Every message is settled before the next one is sent, so all subscribed
+    /// modules have processed it when this method returns.
+    pub async fn advance(&mut self, ms: u64) -> Result<(), BrokerError> {
+        self.now += ms;
+        while self.next_second <= self.now {
+            self.next_second += 1000;
+            self.broker.settle_msg(TimerMessage::Second).await?;
+        }
+        Ok(())
+    }
+
+    /// The virtual time in milliseconds since the start of the timer.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_virtual() -> Result<(), BrokerError> {
+        let mut timer = VirtualTimer::start().await?;
+        let (tap, _) = timer.broker.get_tap_sync().await?;
+
+        timer.advance(999).await?;
+        assert!(tap.try_recv().is_err());
+        // The first second also starts the first minute.
+        timer.advance(1).await?;
+        assert_eq!(
+            vec![TimerMessage::Second, TimerMessage::Minute],
+            tap.try_iter().collect::<Vec<_>>()
+        );
+
+        timer.advance(59_500).await?;
+        assert_eq!(
+            vec![TimerMessage::Second; 59],
+            tap.try_iter().collect::<Vec<_>>()
+        );
+        timer.advance(500).await?;
+        assert_eq!(
+            vec![TimerMessage::Second, TimerMessage::Minute],
+            tap.try_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(61_000, timer.now());
+        Ok(())
+    }
 }
 
 #[platform_async_trait()]
 impl SubsystemHandler<TimerMessage> for TimerBroker {
-    async fn messages(&mut self, _: Vec<TimerMessage>) -> Vec<TimerMessage> {
-        if self.seconds == 0 {
-            self.seconds = 59;
-            vec![TimerMessage::Minute]
-        } else {
-            self.seconds -= 1;
-            vec![]
+    async fn messages(&mut self, msgs: Vec<TimerMessage>) -> Vec<TimerMessage> {
+        let mut out = vec![];
+        // Only count the seconds, not the minutes sent by this handler.
+        for _ in msgs.iter().filter(|msg| **msg == TimerMessage::Second) {
+            if self.seconds == 0 {
+                self.seconds = 59;
+                out.push(TimerMessage::Minute);
+            } else {
+                self.seconds -= 1;
+            }
         }
+        out
     }
 }
@@ -2,25 +2,33 @@ use core::str;
 use flarch::{
     data_storage::DataStorage,
     platform_async_trait,
-    tasks::spawn_local,
     tasks::time::{timeout, Duration},
+    tasks::{now, spawn_local},
 };
 use thiserror::Error;
 use tokio::sync::{mpsc::channel, watch};
 
+use crate::nodeconfig::NodeConfig;
 use crate::overlay::messages::{NetworkWrapper, OverlayIn, OverlayMessage, OverlayOut};
 use flarch::{
     broker::{Broker, BrokerError, Subsystem, SubsystemHandler},
-    nodeids::{NodeID, U256},
+    nodeids::U256,
+    web_rtc::messages::Channel,
 };
 
 use super::{
-    core::{Counters, WebProxyConfig, WebProxyStorage, WebProxyStorageSave},
-    messages::{WebProxyIn, WebProxyMessage, WebProxyMessages, WebProxyOut},
+    congestion::{StreamStats, StreamStatsMap},
+    core::{
+        AuditEntry, Counters, RequestError, WebProxyConfig, WebProxyStorage, WebProxyStorageSave,
+    },
+    messages::{ModuleMessage, WebProxyIn, WebProxyMessage, WebProxyMessages, WebProxyOut},
     response::Response,
 };
 
-const MODULE_NAME: &str = "WebProxy";
+/// The name of the module, also used as the key of its [`DataStorage`] entry.
+pub const MODULE_NAME: &str = "WebProxy";
+/// The storage changes with every packet, so it is written at most this often.
+pub const STORAGE_SAVE_INTERVAL_MS: i64 = 10_000;
 
 #[derive(Debug, Error)]
 pub enum WebProxyError {
@@ -32,6 +40,8 @@ pub enum WebProxyError {
     NoNodes,
     #[error("Timeout while waiting for response")]
     ResponseTimeout,
+    #[error("Proxy node rejected request: {0}")]
+    Rejected(#[from] RequestError),
 }
 
 #[derive(Clone)]
@@ -39,43 +49,69 @@ pub struct WebProxy {
     /// Represents the underlying broker.
     pub web_proxy: Broker<WebProxyMessage>,
     storage: watch::Receiver<WebProxyStorage>,
+    stream_stats: StreamStatsMap,
 }
 
 impl WebProxy {
     pub async fn start(
         mut ds: Box<dyn DataStorage + Send>,
-        our_id: NodeID,
+        node_config: NodeConfig,
         overlay: Broker<OverlayMessage>,
         config: WebProxyConfig,
     ) -> Result<Self, WebProxyError> {
         let str = ds.get(MODULE_NAME).unwrap_or("".into());
         let storage = WebProxyStorageSave::from_str(&str).unwrap_or_default();
         let mut web_proxy = Broker::new();
-        let messages = WebProxyMessages::new(storage.clone(), config, our_id, web_proxy.clone())?;
+        let messages =
+            WebProxyMessages::new(storage.clone(), config, node_config, web_proxy.clone())?;
+        let stream_stats = messages.core.stream_stats_map();
 
         Translate::start(web_proxy.clone(), overlay, messages).await?;
 
         let (tx, storage) = watch::channel(storage);
-        let (mut tap, _) = web_proxy.get_tap().await?;
+        let (mut tap, _) = web_proxy
+            .get_tap_filtered(|msg| {
+                matches!(msg, WebProxyMessage::Output(WebProxyOut::UpdateStorage(_)))
+            })
+            .await?;
         spawn_local(async move {
+            let interval = Duration::from_millis(STORAGE_SAVE_INTERVAL_MS as u64);
+            let mut unsaved = None;
+            let mut last_save = 0;
             loop {
-                if let Some(WebProxyMessage::Output(WebProxyOut::UpdateStorage(sto))) =
-                    tap.recv().await
-                {
-                    tx.send(sto.clone()).expect("updated storage");
-                    if let Ok(val) = sto.to_yaml() {
+                let closed = match timeout(interval, tap.recv()).await {
+                    Ok(Some(WebProxyMessage::Output(WebProxyOut::UpdateStorage(sto)))) => {
+                        tx.send(sto.clone()).expect("updated storage");
+                        unsaved = Some(sto);
+                        false
+                    }
+                    Ok(None) => true,
+                    _ => false,
+                };
+                if closed || now() - last_save >= STORAGE_SAVE_INTERVAL_MS {
+                    if let Some(val) = unsaved.take().and_then(|sto| sto.to_yaml().ok()) {
                         ds.set(MODULE_NAME, &val).expect("updating storage");
+                        last_save = now();
                     }
                 }
+                if closed {
+                    return;
+                }
             }
         });
 
-        Ok(Self { web_proxy, storage })
+        Ok(Self {
+            web_proxy,
+            storage,
+            stream_stats,
+        })
     }
 
     /// Sends a GET request to one of the remote proxies with the given URL.
     /// If the remote proxy doesn't answer within 5 seconds, a timeout error is
     /// returned.
+    /// If the remote proxy refuses the request, the reason is returned in
+    /// [`WebProxyError::Rejected`].
     /// TODO: add GET headers and body, move timeout to configuration
     pub async fn get(&mut self, url: &str) -> Result<Response, WebProxyError> {
         log::debug!("Getting {url}");
@@ -83,14 +119,30 @@ impl WebProxy {
         let (tx, rx) = channel(128);
         self.web_proxy
             .emit_msg(WebProxyIn::RequestGet(our_rnd, url.to_string(), tx).into())?;
-        let (mut tap, id) = self.web_proxy.get_tap().await?;
+        let (mut tap, id) = self
+            .web_proxy
+            .get_tap_filtered(move |msg| match msg {
+                WebProxyMessage::Output(WebProxyOut::ResponseGet(_, rnd, _))
+                | WebProxyMessage::Output(WebProxyOut::ResponseError(_, rnd, _)) => rnd == &our_rnd,
+                _ => false,
+            })
+            .await?;
         timeout(Duration::from_secs(5), async move {
             while let Some(msg) = tap.recv().await {
-                if let WebProxyMessage::Output(WebProxyOut::ResponseGet(proxy, rnd, header)) = msg {
-                    if rnd == our_rnd {
+                match msg {
+                    WebProxyMessage::Output(WebProxyOut::ResponseGet(proxy, rnd, header))
+                        if rnd == our_rnd =>
+                    {
                         self.web_proxy.remove_subsystem(id).await?;
                         return Ok(Response::new(proxy, header, rx));
                     }
+                    WebProxyMessage::Output(WebProxyOut::ResponseError(_, rnd, err))
+                        if rnd == our_rnd =>
+                    {
+                        self.web_proxy.remove_subsystem(id).await?;
+                        return Err(err.into());
+                    }
+                    _ => {}
                 }
             }
             self.web_proxy.remove_subsystem(id).await?;
@@ -103,6 +155,19 @@ impl WebProxy {
     pub fn get_counters(&mut self) -> Counters {
         self.storage.borrow().counters.clone()
     }
+
+    /// Returns the audit log of the requests this node handled as a proxy.
+    pub fn get_audit_log(&mut self) -> Vec<AuditEntry> {
+        self.storage.borrow().audit_log.clone()
+    }
+
+    /// Returns the stats of the responses this node currently streams as a proxy.
+    pub fn get_stream_stats(&self) -> Vec<StreamStats> {
+        self.stream_stats
+            .lock()
+            .map(|stats| stats.values().cloned().collect())
+            .unwrap_or_default()
+    }
 }
 
 struct Translate {
@@ -135,7 +200,7 @@ impl Translate {
                     Some(WebProxyIn::NodeInfoConnected(list).into())
                 }
                 OverlayOut::NetworkWrapperFromNetwork(id, msg) => msg
-                    .unwrap_yaml(MODULE_NAME)
+                    .unwrap(MODULE_NAME)
                     .map(|msg| WebProxyIn::FromNetwork(id, msg).into()),
                 _ => None,
             }
@@ -144,12 +209,20 @@ impl Translate {
         }
     }
 
+    // The responses go over the bulk channel, so big downloads don't delay
+    // the other messages.
     fn link_proxy_overlay(msg: WebProxyMessage) -> Option<OverlayMessage> {
         if let WebProxyMessage::Output(WebProxyOut::ToNetwork(id, msg_node)) = msg {
+            let channel = match msg_node {
+                ModuleMessage::Response(_, _) => Channel::Bulk,
+                _ => Channel::Control,
+            };
             Some(
                 OverlayIn::NetworkWrapperToNetwork(
                     id,
-                    NetworkWrapper::wrap_yaml(MODULE_NAME, &msg_node).unwrap(),
+                    NetworkWrapper::wrap(MODULE_NAME, &msg_node)
+                        .unwrap()
+                        .on_channel(channel),
                 )
                 .into(),
             )
@@ -189,19 +262,19 @@ mod tests {
     async fn test_get() -> Result<(), WebProxyError> {
         start_logging_filter_level(vec![], log::LevelFilter::Debug);
         let cl_ds = Box::new(DataStorageTemp::new());
-        let cl_in = NodeConfig::new().info;
-        let cl_id = cl_in.get_id();
+        let cl_nc = NodeConfig::new();
+        let cl_in = cl_nc.info.clone();
         let mut cl_rnd = Broker::new();
 
         let wp_ds = Box::new(DataStorageTemp::new());
-        let wp_in = NodeConfig::new().info;
-        let wp_id = wp_in.get_id();
+        let wp_nc = NodeConfig::new();
+        let wp_in = wp_nc.info.clone();
         let mut wp_rnd = Broker::new();
 
         let mut cl =
-            WebProxy::start(cl_ds, cl_id, cl_rnd.clone(), WebProxyConfig::default()).await?;
+            WebProxy::start(cl_ds, cl_nc, cl_rnd.clone(), WebProxyConfig::default()).await?;
         let (mut cl_tap, _) = cl_rnd.get_tap().await?;
-        let _wp = WebProxy::start(wp_ds, wp_id, wp_rnd.clone(), WebProxyConfig::default()).await?;
+        let _wp = WebProxy::start(wp_ds, wp_nc, wp_rnd.clone(), WebProxyConfig::default()).await?;
         let (mut wp_tap, _) = wp_rnd.get_tap().await?;
 
         let list = vec![cl_in, wp_in];
@@ -0,0 +1,300 @@
+//! Congestion control for the streamed responses of a proxy node.
+//!
+//! The requester acknowledges the body chunks it gave to the reader every
+//! [`STREAM_ACK_EVERY`] chunks.
+//! A [`CongestionControl`] uses these acknowledgments to decide how many chunks
+//! can be in flight, so a fast path gets more chunks at a time than a slow one.
+//! [`Congestion`] chooses the algorithm in the [`super::core::WebProxyConfig`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use flarch::{
+    nodeids::{NodeID, U256},
+    tasks::{
+        now,
+        time::{timeout, Duration},
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::core::{STREAM_ACK_EVERY, STREAM_WINDOW};
+
+/// The window never gets smaller, else the requester never acknowledges.
+pub const MIN_WINDOW: usize = STREAM_ACK_EVERY;
+/// The window of [`Aimd`] never gets bigger.
+pub const MAX_WINDOW: usize = 256;
+
+/// Decides how many body chunks can be sent before they are acknowledged.
+pub trait CongestionControl: Debug + Send {
+    /// How many chunks can be in flight.
+    fn window(&self) -> usize;
+    /// The requester acknowledged `chunks` more chunks, and the last one took
+    /// `rtt_ms` between being sent and being acknowledged.
+    fn on_ack(&mut self, chunks: usize, rtt_ms: i64);
+}
+
+/// The available algorithms for [`CongestionControl`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum Congestion {
+    /// Always allows the same number of chunks in flight.
+    Fixed(usize),
+    /// Additive increase, multiplicative decrease: the window grows by one chunk
+    /// per acknowledged window, and is halved if the round-trip time doubles.
+    #[default]
+    Aimd,
+}
+
+impl Congestion {
+    /// Returns a new controller for one stream.
+    pub fn controller(&self) -> Box<dyn CongestionControl> {
+        match self {
+            Congestion::Fixed(window) => Box::new(Fixed(*window.max(&MIN_WINDOW))),
+            Congestion::Aimd => Box::new(Aimd::default()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fixed(usize);
+
+impl CongestionControl for Fixed {
+    fn window(&self) -> usize {
+        self.0
+    }
+
+    fn on_ack(&mut self, _chunks: usize, _rtt_ms: i64) {}
+}
+
+#[derive(Debug)]
+pub struct Aimd {
+    cwnd: f64,
+    min_rtt_ms: Option<i64>,
+    // Chunks to acknowledge after halving the window before it changes again.
+    recovery: usize,
+}
+
+impl Default for Aimd {
+    fn default() -> Self {
+        Self {
+            cwnd: STREAM_WINDOW as f64,
+            min_rtt_ms: None,
+            recovery: 0,
+        }
+    }
+}
+
+impl CongestionControl for Aimd {
+    fn window(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    fn on_ack(&mut self, chunks: usize, rtt_ms: i64) {
+        let min_rtt = self.min_rtt_ms.map_or(rtt_ms, |min| min.min(rtt_ms));
+        self.min_rtt_ms = Some(min_rtt);
+        if self.recovery > 0 {
+            self.recovery = self.recovery.saturating_sub(chunks);
+        } else if rtt_ms > 2 * min_rtt.max(1) {
+            self.cwnd = (self.cwnd / 2.).max(MIN_WINDOW as f64);
+            self.recovery = self.window();
+        } else {
+            self.cwnd = (self.cwnd + chunks as f64 / self.cwnd).min(MAX_WINDOW as f64);
+        }
+    }
+}
+
+/// The state of a streamed response, for tuning the congestion control.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamStats {
+    pub requester: NodeID,
+    /// Current number of chunks allowed in flight.
+    pub cwnd: usize,
+    pub sent_chunks: usize,
+    pub sent_bytes: usize,
+    pub acked_chunks: usize,
+    /// The last measured round-trip time.
+    pub rtt_ms: Option<i64>,
+    /// When the stream started, in milliseconds.
+    pub started: i64,
+}
+
+impl StreamStats {
+    /// Returns the bytes sent per second since the start of the stream.
+    pub fn throughput(&self, now: i64) -> f64 {
+        self.sent_bytes as f64 * 1000. / (now - self.started).max(1) as f64
+    }
+}
+
+/// The stats of all running streams of a proxy node.
+pub type StreamStatsMap = Arc<Mutex<HashMap<U256, StreamStats>>>;
+
+/// Sends the chunks of one response as fast as the [`CongestionControl`] allows.
+/// The stats of the stream are removed once it is dropped.
+pub struct StreamControl {
+    nonce: U256,
+    acked: watch::Receiver<usize>,
+    control: Box<dyn CongestionControl>,
+    // When the unacknowledged chunks were sent.
+    in_flight: VecDeque<i64>,
+    sent_chunks: usize,
+    stats: StreamStatsMap,
+}
+
+impl StreamControl {
+    pub fn new(
+        requester: NodeID,
+        nonce: U256,
+        acked: watch::Receiver<usize>,
+        control: Box<dyn CongestionControl>,
+        stats: StreamStatsMap,
+    ) -> Self {
+        if let Ok(mut stats) = stats.lock() {
+            stats.insert(
+                nonce,
+                StreamStats {
+                    requester,
+                    cwnd: control.window(),
+                    sent_chunks: 0,
+                    sent_bytes: 0,
+                    acked_chunks: 0,
+                    rtt_ms: None,
+                    started: now(),
+                },
+            );
+        }
+        Self {
+            nonce,
+            acked,
+            control,
+            in_flight: VecDeque::new(),
+            sent_chunks: 0,
+            stats,
+        }
+    }
+
+    /// Waits until another chunk can be sent.
+    /// Returns false if the requester didn't acknowledge within `max_wait`.
+    pub async fn ready(&mut self, max_wait: Duration) -> bool {
+        loop {
+            self.acknowledged();
+            if self.in_flight.len() < self.control.window() {
+                return true;
+            }
+            if !matches!(timeout(max_wait, self.acked.changed()).await, Ok(Ok(_))) {
+                return false;
+            }
+        }
+    }
+
+    /// Records a chunk as sent.
+    pub fn sent(&mut self, bytes: usize) {
+        self.in_flight.push_back(now());
+        self.sent_chunks += 1;
+        self.update_stats(|s| {
+            s.sent_chunks += 1;
+            s.sent_bytes += bytes;
+        });
+    }
+
+    // Gives the newly acknowledged chunks to the congestion control.
+    fn acknowledged(&mut self) {
+        let acked = *self.acked.borrow_and_update();
+        let newly = self
+            .in_flight
+            .len()
+            .saturating_sub(self.sent_chunks.saturating_sub(acked));
+        if newly == 0 {
+            return;
+        }
+        let last = self.in_flight.drain(..newly).next_back().unwrap_or_default();
+        let rtt_ms = now() - last;
+        self.control.on_ack(newly, rtt_ms);
+        let cwnd = self.control.window();
+        self.update_stats(|s| {
+            s.acked_chunks = acked;
+            s.rtt_ms = Some(rtt_ms);
+            s.cwnd = cwnd;
+        });
+    }
+
+    fn update_stats(&self, f: impl FnOnce(&mut StreamStats)) {
+        if let Ok(mut stats) = self.stats.lock() {
+            if let Some(entry) = stats.get_mut(&self.nonce) {
+                f(entry);
+            }
+        }
+    }
+}
+
+impl Drop for StreamControl {
+    fn drop(&mut self) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.remove(&self.nonce);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aimd() {
+        let mut aimd = Aimd::default();
+        assert_eq!(STREAM_WINDOW, aimd.window());
+        for _ in 0..STREAM_WINDOW / STREAM_ACK_EVERY + 1 {
+            aimd.on_ack(STREAM_ACK_EVERY, 100);
+        }
+        assert_eq!(STREAM_WINDOW + 1, aimd.window());
+
+        // The round-trip time tripled: the window is halved only once.
+        aimd.on_ack(STREAM_ACK_EVERY, 300);
+        assert_eq!((STREAM_WINDOW + 1) / 2, aimd.window());
+        aimd.on_ack(STREAM_ACK_EVERY, 300);
+        assert_eq!((STREAM_WINDOW + 1) / 2, aimd.window());
+
+        for _ in 0..10_000 {
+            aimd.on_ack(STREAM_ACK_EVERY, 100);
+        }
+        assert_eq!(MAX_WINDOW, aimd.window());
+        for _ in 0..100 {
+            aimd.on_ack(MAX_WINDOW, 1000);
+        }
+        assert_eq!(MIN_WINDOW, aimd.window());
+    }
+
+    #[tokio::test]
+    async fn test_stream_control() {
+        let stats = StreamStatsMap::default();
+        let (tx, rx) = watch::channel(0);
+        let (requester, nonce) = (U256::rnd(), U256::rnd());
+        let mut stream = StreamControl::new(
+            requester,
+            nonce,
+            rx,
+            Congestion::Fixed(MIN_WINDOW).controller(),
+            stats.clone(),
+        );
+        for _ in 0..MIN_WINDOW {
+            assert!(stream.ready(Duration::from_millis(10)).await);
+            stream.sent(100);
+        }
+        assert!(!stream.ready(Duration::from_millis(10)).await);
+
+        tx.send(STREAM_ACK_EVERY).unwrap();
+        assert!(stream.ready(Duration::from_millis(10)).await);
+        let s = stats.lock().unwrap()[&nonce].clone();
+        assert_eq!(
+            (requester, MIN_WINDOW, 100 * MIN_WINDOW, STREAM_ACK_EVERY),
+            (s.requester, s.sent_chunks, s.sent_bytes, s.acked_chunks)
+        );
+        assert!(s.rtt_ms.is_some());
+
+        drop(stream);
+        assert!(stats.lock().unwrap().is_empty());
+    }
+}
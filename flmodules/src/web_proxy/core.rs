@@ -1,22 +1,122 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use bytes::Bytes;
-use flarch::tasks::spawn_local;
+use ed25519_compact::{PublicKey, Signature};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Sender;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::{mpsc::UnboundedSender, watch};
 
 use flarch::nodeids::{NodeID, NodeIDs, U256};
 
-use super::response::{ResponseHeader, ResponseMessage};
+use crate::nodeconfig::NodeConfig;
 
+use super::{
+    congestion::{Congestion, StreamControl, StreamStats, StreamStatsMap},
+    response::{ResponseHeader, ResponseMessage},
+};
+
+/// How many body chunks a proxy sends before waiting for an acknowledgment,
+/// until the [`Congestion`] control adjusts it.
+pub const STREAM_WINDOW: usize = 16;
+/// The requester acknowledges the body chunks every time it delivered this many.
+pub const STREAM_ACK_EVERY: usize = STREAM_WINDOW / 2;
+
+/// The configuration of the proxy. Missing fields in a serialized configuration
+/// take their default values.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct WebProxyConfig {
     node: Option<NodeID>,
+    /// How many requests a single node can make during one quota period.
+    pub quota_requests: usize,
+    /// Length of a quota period in milliseconds.
+    pub quota_period_ms: i64,
+    /// Maximum number of entries kept in the audit log.
+    pub audit_log_size: usize,
+    /// If set, this node works as a CDN: it only fetches URLs starting with one
+    /// of these prefixes, and serves repeated requests from its cache.
+    pub allow_list: Option<Vec<String>>,
+    /// How long a cached response is served, in milliseconds.
+    pub cache_ttl_ms: i64,
+    /// Maximum number of responses kept in the cache.
+    pub cache_size: usize,
+    /// Maximum number of body bytes of all responses kept in the cache.
+    pub cache_bytes: usize,
+    /// Responses with a bigger body are not cached.
+    pub cache_max_body: usize,
+    /// If false, this node refuses all requests with [`RequestError::NoExit`].
+    pub exit: bool,
+    /// How the number of body chunks in flight adapts to the requester.
+    pub congestion: Congestion,
+}
+
+impl WebProxyConfig {
+    /// Returns a configuration for a CDN which only fetches URLs starting with
+    /// one of the given prefixes.
+    pub fn cdn(allow_list: Vec<String>) -> Self {
+        Self {
+            allow_list: Some(allow_list),
+            ..Self::default()
+        }
+    }
+
+    pub fn from_yaml(data: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(data)
+    }
+
+    /// Returns whether this node fetches the given URL.
+    pub fn allows(&self, url: &str) -> bool {
+        self.allow_list
+            .as_ref()
+            .is_none_or(|list| list.iter().any(|prefix| url.starts_with(prefix)))
+    }
 }
 
 impl Default for WebProxyConfig {
     fn default() -> Self {
-        Self { node: None }
+        Self {
+            node: None,
+            quota_requests: 60,
+            quota_period_ms: 60_000,
+            audit_log_size: 100,
+            allow_list: None,
+            cache_ttl_ms: 600_000,
+            cache_size: 100,
+            cache_bytes: 16 * 1024 * 1024,
+            cache_max_body: 1024 * 1024,
+            exit: true,
+            congestion: Congestion::default(),
+        }
+    }
+}
+
+/// Reasons why a proxy node refuses to handle a request.
+#[derive(Debug, Error, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RequestError {
+    #[error("Request is not signed")]
+    Unsigned,
+    #[error("Signature of request is invalid")]
+    InvalidSignature,
+    #[error("Requester exceeded its quota")]
+    QuotaExceeded,
+    #[error("URL is not in the allow-list")]
+    NotAllowed,
+    #[error("Node doesn't fetch URLs for other nodes")]
+    NoExit,
+}
+
+/// A response kept by a node in CDN mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub header: ResponseHeader,
+    pub body: Vec<Bytes>,
+    time: i64,
+}
+
+impl CachedResponse {
+    fn len(&self) -> usize {
+        self.body.iter().map(|chunk| chunk.len()).sum()
     }
 }
 
@@ -24,36 +124,204 @@ impl Default for WebProxyConfig {
 pub struct WebProxyCore {
     pub storage: WebProxyStorage,
     pub config: WebProxyConfig,
+    node_config: NodeConfig,
     nodes: NodeIDs,
     our_id: NodeID,
     node_index: usize,
-    requests: HashMap<U256, (NodeID, Sender<Bytes>)>,
+    streaming: HashSet<NodeID>,
+    requests: HashMap<U256, (NodeID, UnboundedSender<Bytes>)>,
+    streams: HashMap<U256, (NodeID, watch::Sender<usize>)>,
+    stream_stats: StreamStatsMap,
+    quotas: HashMap<NodeID, Vec<i64>>,
+    cache: HashMap<String, CachedResponse>,
 }
 
 impl WebProxyCore {
     /// Initializes a new Proxy.
-    pub fn new(storage: WebProxyStorage, config: WebProxyConfig, our_id: NodeID) -> Self {
+    pub fn new(storage: WebProxyStorage, config: WebProxyConfig, node_config: NodeConfig) -> Self {
         Self {
             storage,
             config,
+            our_id: node_config.info.get_id(),
+            node_config,
             nodes: NodeIDs::empty(),
             node_index: 0,
+            streaming: HashSet::new(),
             requests: HashMap::new(),
-            our_id,
+            streams: HashMap::new(),
+            stream_stats: StreamStatsMap::default(),
+            quotas: HashMap::new(),
+            cache: HashMap::new(),
         }
     }
 
-    pub fn start_request(&mut self, request: String, tx: Sender<Bytes>) {
-        spawn_local(async move {
-            println!("{request}");
-            tx.send(Bytes::from("something"))
-                .await
-                .expect("sending reply");
+    /// Returns the hash of a request which is signed by the requesting node.
+    pub fn request_hash(nonce: &U256, url: &str) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        hash.update(nonce);
+        hash.update(url);
+        hash.finalize().into()
+    }
+
+    /// Signs the request with the private key of this node.
+    pub fn sign_request(&self, nonce: &U256, url: &str) -> Vec<u8> {
+        self.node_config.sign(Self::request_hash(nonce, url))
+    }
+
+    /// Checks the signature and the quota of the requester, and stores the
+    /// result in the audit log.
+    /// Only the host of the URL is logged, so the audit log doesn't keep the
+    /// paths and queries of the requesters.
+    /// As the [`NodeID`] is the public key of the node, the signature is
+    /// verified directly against the ID of the requester.
+    pub fn check_request(
+        &mut self,
+        src: NodeID,
+        nonce: &U256,
+        url: &str,
+        signature: Option<&[u8]>,
+        time: i64,
+    ) -> Result<(), RequestError> {
+        let result = self.verify_request(src, nonce, url, signature, time);
+        match &result {
+            Ok(_) => self.storage.counters.rx_requests += 1,
+            Err(e) => log::warn!("Rejecting request from {src}: {e}"),
+        }
+        self.storage.audit_log.push(AuditEntry {
+            time,
+            requester: src,
+            host: AuditEntry::host(url),
+            rejected: result.clone().err(),
         });
+        if self.storage.audit_log.len() > self.config.audit_log_size {
+            let surplus = self.storage.audit_log.len() - self.config.audit_log_size;
+            self.storage.audit_log.drain(0..surplus);
+        }
+        result
+    }
+
+    fn verify_request(
+        &mut self,
+        src: NodeID,
+        nonce: &U256,
+        url: &str,
+        signature: Option<&[u8]>,
+        time: i64,
+    ) -> Result<(), RequestError> {
+        let sig = signature.ok_or(RequestError::Unsigned)?;
+        let pubkey =
+            PublicKey::from_slice(&src.to_bytes()).map_err(|_| RequestError::InvalidSignature)?;
+        let sig = Signature::from_slice(sig).map_err(|_| RequestError::InvalidSignature)?;
+        pubkey
+            .verify(Self::request_hash(nonce, url), &sig)
+            .map_err(|_| RequestError::InvalidSignature)?;
+        if !self.config.exit {
+            return Err(RequestError::NoExit);
+        }
+        if !self.config.allows(url) {
+            return Err(RequestError::NotAllowed);
+        }
+
+        // Forget the requesters without requests in the current period.
+        let start = time - self.config.quota_period_ms;
+        self.quotas
+            .retain(|_, requests| requests.last().is_some_and(|&t| t > start));
+        let requests = self.quotas.entry(src).or_default();
+        requests.retain(|&t| t > start);
+        if requests.len() >= self.config.quota_requests {
+            return Err(RequestError::QuotaExceeded);
+        }
+        requests.push(time);
+        Ok(())
     }
 
-    pub fn node_list(&mut self, mut nodes: NodeIDs) {
+    /// Returns the cached response for this URL, if it is not older than
+    /// [`WebProxyConfig::cache_ttl_ms`].
+    pub fn cache_get(&mut self, url: &str, time: i64) -> Option<CachedResponse> {
+        let ttl = self.config.cache_ttl_ms;
+        self.cache.retain(|_, resp| resp.time > time - ttl);
+        self.cache.get(url).cloned()
+    }
+
+    /// Stores a response in the cache, if this node is in CDN mode.
+    /// Bodies bigger than [`WebProxyConfig::cache_max_body`] are not stored.
+    /// The oldest responses are removed until the new one fits in
+    /// [`WebProxyConfig::cache_size`] and [`WebProxyConfig::cache_bytes`].
+    pub fn cache_add(&mut self, url: String, header: ResponseHeader, body: Vec<Bytes>, time: i64) {
+        let resp = CachedResponse { header, body, time };
+        let len = resp.len();
+        if self.config.allow_list.is_none()
+            || self.config.cache_size == 0
+            || len > self.config.cache_max_body.min(self.config.cache_bytes)
+        {
+            return;
+        }
+        self.cache.remove(&url);
+        while self.cache.len() >= self.config.cache_size
+            || self.cache.values().map(|r| r.len()).sum::<usize>() + len > self.config.cache_bytes
+        {
+            let Some(oldest) = self
+                .cache
+                .iter()
+                .min_by_key(|(_, resp)| resp.time)
+                .map(|(url, _)| url.clone())
+            else {
+                break;
+            };
+            self.cache.remove(&oldest);
+        }
+        self.cache.insert(url, resp);
+    }
+
+    /// Starts the flow control for a response sent to `src`, using the congestion
+    /// control of the configuration.
+    pub fn stream_start(&mut self, src: NodeID, nonce: U256) -> StreamControl {
+        self.streams.retain(|_, (_, tx)| !tx.is_closed());
+        let (tx, rx) = watch::channel(0);
+        self.streams.insert(nonce, (src, tx));
+        StreamControl::new(
+            src,
+            nonce,
+            rx,
+            self.config.congestion.controller(),
+            self.stream_stats.clone(),
+        )
+    }
+
+    /// Returns the shared stats of the running streams, which are updated by
+    /// every [`StreamControl`].
+    pub fn stream_stats_map(&self) -> StreamStatsMap {
+        self.stream_stats.clone()
+    }
+
+    /// Returns the stats of the running streams.
+    pub fn stream_stats(&self) -> Vec<StreamStats> {
+        self.stream_stats
+            .lock()
+            .map(|stats| stats.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Stores the number of body chunks the requester acknowledged.
+    /// Acknowledgments from other nodes than the requester are ignored.
+    pub fn stream_ack(&mut self, src: NodeID, nonce: U256, chunks: usize) {
+        if let Some((requester, tx)) = self.streams.get(&nonce) {
+            if requester == &src && tx.send(chunks).is_err() {
+                self.streams.remove(&nonce);
+            }
+        }
+    }
+
+    /// Stores the list of proxy nodes, and which of them support flow control
+    /// for their responses.
+    pub fn node_list(&mut self, mut nodes: NodeIDs, streaming: HashSet<NodeID>) {
         self.nodes = nodes.remove_missing(&vec![self.our_id].into());
+        self.streaming = streaming;
+    }
+
+    /// Returns whether the proxy node waits for acknowledgments of the body chunks.
+    pub fn supports_stream(&self, node: &NodeID) -> bool {
+        self.streaming.contains(node)
     }
 
     pub fn get_node(&mut self) -> Option<NodeID> {
@@ -66,14 +334,23 @@ impl WebProxyCore {
         Some(*node)
     }
 
-    pub fn request_get(&mut self, rnd: U256, tx: Sender<Bytes>) -> Option<NodeID> {
+    pub fn request_get(&mut self, rnd: U256, tx: UnboundedSender<Bytes>) -> Option<NodeID> {
         if let Some(node) = self.get_node() {
+            self.storage.counters.tx_requests += 1;
             self.requests.insert(rnd, (node, tx));
             return Some(node);
         }
         None
     }
 
+    /// Returns whether the request with this nonce has been sent to `node`.
+    /// Responses from other nodes must be ignored.
+    pub fn requested_from(&self, nonce: &U256, node: &NodeID) -> bool {
+        self.requests
+            .get(nonce)
+            .is_some_and(|(proxy, _)| proxy == node)
+    }
+
     pub fn handle_response(&mut self, nonce: U256, msg: ResponseMessage) -> Option<ResponseHeader> {
         if let Some((_, tx)) = self.requests.get(&nonce) {
             match msg {
@@ -83,8 +360,10 @@ impl WebProxyCore {
                 }
                 ResponseMessage::Body(body) => {
                     self.storage.counters.rx_packets += 1;
-                    let tx = tx.clone();
-                    spawn_local(async move { tx.send(body).await.expect("sending body") })
+                    if tx.send(body).is_err() {
+                        log::warn!("Reader of response with nonce {nonce} is gone");
+                        self.requests.remove(&nonce);
+                    }
                 }
                 ResponseMessage::Done => {
                     self.requests.remove(&nonce);
@@ -92,6 +371,10 @@ impl WebProxyCore {
                 ResponseMessage::Error(err) => {
                     log::warn!("Got error {err} for response of nonce {nonce}")
                 }
+                ResponseMessage::Rejected(err) => {
+                    log::warn!("Request with nonce {nonce} got rejected: {err}");
+                    self.requests.remove(&nonce);
+                }
             }
         }
         None
@@ -134,21 +417,55 @@ impl Default for Counters {
     }
 }
 
+/// One entry of the audit log, kept by the proxy node for every request it
+/// receives.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub time: i64,
+    pub requester: NodeID,
+    /// Only the host of the requested URL.
+    /// Entries stored by older versions hold the full URL.
+    #[serde(alias = "url")]
+    pub host: String,
+    pub rejected: Option<RequestError>,
+}
+
+impl AuditEntry {
+    /// Returns the host of the URL, or an empty string for an invalid URL.
+    pub fn host(url: &str) -> String {
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct WebProxyStorage {
     pub counters: Counters,
+    #[serde(default)]
+    pub audit_log: Vec<AuditEntry>,
 }
 
 impl WebProxyStorage {
     pub fn to_yaml(&self) -> Result<String, serde_yaml::Error> {
         serde_yaml::to_string::<WebProxyStorageSave>(&WebProxyStorageSave::V1(self.clone()))
     }
+
+    /// Removes the entries of the audit log older than `time`, and returns how
+    /// many have been removed.
+    pub fn remove_before(&mut self, time: i64) -> usize {
+        let before = self.audit_log.len();
+        self.audit_log.retain(|entry| entry.time >= time);
+        before - self.audit_log.len()
+    }
 }
 
 impl Default for WebProxyStorage {
     fn default() -> Self {
         Self {
             counters: Counters::default(),
+            audit_log: vec![],
         }
     }
 }
@@ -157,10 +474,227 @@ impl Default for WebProxyStorage {
 mod tests {
     use std::error::Error;
 
-    // use super::*;
+    use flarch::tasks::time::Duration;
+
+    use crate::web_proxy::response::ResponseStatus;
+
+    use super::*;
 
     #[test]
     fn test_increase() -> Result<(), Box<dyn Error>> {
         Ok(())
     }
+
+    #[test]
+    fn test_check_request() -> Result<(), Box<dyn Error>> {
+        let requester = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig::default(),
+            NodeConfig::new(),
+        );
+        let src = requester.our_id;
+        let mut proxy = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig {
+                quota_requests: 2,
+                ..WebProxyConfig::default()
+            },
+            NodeConfig::new(),
+        );
+        let nonce = U256::rnd();
+        let url = "https://fledg.re";
+        let sig = requester.sign_request(&nonce, url);
+
+        assert_eq!(
+            Err(RequestError::Unsigned),
+            proxy.check_request(src, &nonce, url, None, 0)
+        );
+        assert_eq!(
+            Err(RequestError::InvalidSignature),
+            proxy.check_request(src, &nonce, "https://other", Some(&sig), 0)
+        );
+        assert_eq!(
+            Err(RequestError::InvalidSignature),
+            proxy.check_request(U256::rnd(), &nonce, url, Some(&sig), 0)
+        );
+        assert_eq!(Ok(()), proxy.check_request(src, &nonce, url, Some(&sig), 0));
+        assert_eq!(
+            Ok(()),
+            proxy.check_request(src, &nonce, url, Some(&sig), 1000)
+        );
+        assert_eq!(
+            Err(RequestError::QuotaExceeded),
+            proxy.check_request(src, &nonce, url, Some(&sig), 2000)
+        );
+        assert_eq!(
+            Ok(()),
+            proxy.check_request(src, &nonce, url, Some(&sig), 60_001)
+        );
+
+        assert_eq!(7, proxy.storage.audit_log.len());
+        assert_eq!(src, proxy.storage.audit_log[3].requester);
+        assert_eq!("fledg.re", proxy.storage.audit_log[3].host);
+        assert_eq!(1, proxy.quotas.len());
+        assert_eq!(3, proxy.storage.counters.rx_requests);
+        assert_eq!(6, proxy.storage.remove_before(60_001));
+        assert_eq!(1, proxy.storage.audit_log.len());
+
+        proxy.config.exit = false;
+        assert_eq!(
+            Err(RequestError::NoExit),
+            proxy.check_request(src, &nonce, url, Some(&sig), 60_002)
+        );
+
+        // The quotas of requesters without recent requests are removed.
+        proxy.config.exit = true;
+        let other = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig::default(),
+            NodeConfig::new(),
+        );
+        let sig_other = other.sign_request(&nonce, url);
+        assert_eq!(
+            Ok(()),
+            proxy.check_request(other.our_id, &nonce, url, Some(&sig_other), 200_000)
+        );
+        assert_eq!(vec![&other.our_id], proxy.quotas.keys().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_requested_from() {
+        let mut requester = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig::default(),
+            NodeConfig::new(),
+        );
+        let proxy = U256::rnd();
+        requester.node_list(vec![proxy].into(), HashSet::new());
+        let nonce = U256::rnd();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        assert_eq!(Some(proxy), requester.request_get(nonce, tx));
+        assert!(requester.requested_from(&nonce, &proxy));
+        assert!(!requester.requested_from(&nonce, &U256::rnd()));
+        assert!(!requester.requested_from(&U256::rnd(), &proxy));
+    }
+
+    #[test]
+    fn test_cdn() -> Result<(), Box<dyn Error>> {
+        let requester = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig::default(),
+            NodeConfig::new(),
+        );
+        let src = requester.our_id;
+        let mut proxy = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig {
+                cache_size: 1,
+                ..WebProxyConfig::cdn(vec!["https://fledg.re/".into()])
+            },
+            NodeConfig::new(),
+        );
+        let nonce = U256::rnd();
+        for (url, result) in [
+            ("https://fledg.re/index.html", Ok(())),
+            ("https://fledg.re.evil.com/", Err(RequestError::NotAllowed)),
+            ("https://other.org/", Err(RequestError::NotAllowed)),
+        ] {
+            let sig = requester.sign_request(&nonce, url);
+            assert_eq!(result, proxy.check_request(src, &nonce, url, Some(&sig), 0));
+        }
+
+        let header = ResponseHeader {
+            status: ResponseStatus {
+                code: 200,
+                msg: "".into(),
+            },
+            headers: HashMap::new(),
+        };
+        let body = vec![Bytes::from("body")];
+        proxy.cache_add("a".into(), header.clone(), body.clone(), 0);
+        assert_eq!(
+            Some(body.clone()),
+            proxy.cache_get("a", 1000).map(|r| r.body)
+        );
+        proxy.cache_add("b".into(), header, body, 1000);
+        assert_eq!(None, proxy.cache_get("a", 1000));
+        assert!(proxy.cache_get("b", 1000).is_some());
+        assert_eq!(None, proxy.cache_get("b", 1000 + proxy.config.cache_ttl_ms));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_bytes() {
+        let mut proxy = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig {
+                cache_bytes: 10,
+                cache_max_body: 6,
+                ..WebProxyConfig::cdn(vec!["https://fledg.re/".into()])
+            },
+            NodeConfig::new(),
+        );
+        let header = ResponseHeader {
+            status: ResponseStatus {
+                code: 200,
+                msg: "".into(),
+            },
+            headers: HashMap::new(),
+        };
+        let body = |len: usize| {
+            vec![
+                Bytes::from(vec![0u8; len / 2]),
+                Bytes::from(vec![0u8; len - len / 2]),
+            ]
+        };
+
+        proxy.cache_add("big".into(), header.clone(), body(7), 0);
+        assert_eq!(None, proxy.cache_get("big", 0));
+
+        proxy.cache_add("a".into(), header.clone(), body(4), 0);
+        proxy.cache_add("b".into(), header.clone(), body(4), 1);
+        assert!(proxy.cache_get("a", 1).is_some());
+        proxy.cache_add("c".into(), header.clone(), body(6), 2);
+        assert_eq!(None, proxy.cache_get("a", 2));
+        assert!(proxy.cache_get("b", 2).is_some());
+        assert!(proxy.cache_get("c", 2).is_some());
+        proxy.cache_add("d".into(), header, body(4), 3);
+        assert_eq!(None, proxy.cache_get("b", 3));
+        assert!(proxy.cache_get("c", 3).is_some());
+        assert!(proxy.cache_get("d", 3).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_stream() -> Result<(), Box<dyn Error>> {
+        let mut proxy = WebProxyCore::new(
+            WebProxyStorage::default(),
+            WebProxyConfig::default(),
+            NodeConfig::new(),
+        );
+        let src = U256::rnd();
+        let nonce = U256::rnd();
+        let mut stream = proxy.stream_start(src, nonce);
+        for _ in 0..STREAM_WINDOW {
+            stream.sent(1);
+        }
+        let stats = proxy.stream_stats();
+        assert_eq!(1, stats.len());
+        assert_eq!(
+            (src, STREAM_WINDOW, 0),
+            (stats[0].requester, stats[0].cwnd, stats[0].acked_chunks)
+        );
+
+        proxy.stream_ack(U256::rnd(), nonce, STREAM_ACK_EVERY);
+        assert!(!stream.ready(Duration::ZERO).await);
+        proxy.stream_ack(src, nonce, STREAM_ACK_EVERY);
+        assert!(stream.ready(Duration::ZERO).await);
+        assert_eq!(STREAM_ACK_EVERY, proxy.stream_stats()[0].acked_chunks);
+
+        drop(stream);
+        assert!(proxy.stream_stats().is_empty());
+        proxy.stream_ack(src, nonce, STREAM_WINDOW);
+        assert!(proxy.streams.is_empty());
+        Ok(())
+    }
 }
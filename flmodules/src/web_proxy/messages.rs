@@ -1,15 +1,15 @@
 use bytes::Bytes;
-use flarch::tasks::spawn_local;
+use flarch::tasks::{now, spawn_local, time::Duration};
 use flarch::{
     broker::Broker,
     nodeids::{NodeID, U256},
 };
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{unbounded_channel, Sender};
 
-use crate::nodeconfig::NodeInfo;
-use crate::Modules;
+use crate::nodeconfig::{NodeConfig, NodeInfo};
+use crate::{Capabilities, Modules};
 
 use super::{
     broker::WebProxyError,
@@ -17,12 +17,23 @@ use super::{
     response::{ResponseHeader, ResponseMessage},
 };
 
+/// How long a proxy waits for the requester to acknowledge body chunks.
+const STREAM_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Messages between different instances of this module.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ModuleMessage {
-    /// The request holds a random ID so that the reply can be mapped to the correct
-    /// request.
+    /// Unsigned request from older nodes, which is always rejected.
     Request(U256, String),
+    /// The request holds a random ID so that the reply can be mapped to the correct
+    /// request, the URL, and a signature of the requesting node over both.
+    RequestSigned(U256, String, Vec<u8>),
+    /// Like [`ModuleMessage::RequestSigned`], but the requester acknowledges the
+    /// body chunks with [`ModuleMessage::Ack`], and the proxy never sends more
+    /// unacknowledged chunks than its [`super::congestion::Congestion`] control allows.
+    RequestStream(U256, String, Vec<u8>),
+    /// How many body chunks of the response have been given to the reader.
+    Ack(U256, usize),
     /// The reply uses the random ID from the request and returns blocks of the
     /// reply, indexed by the second argument.
     Response(U256, ResponseMessage),
@@ -42,6 +53,8 @@ pub enum WebProxyIn {
     FromNetwork(NodeID, ModuleMessage),
     NodeInfoConnected(Vec<NodeInfo>),
     RequestGet(U256, String, Sender<Bytes>),
+    /// A response fetched by this node, to be kept in the cache in CDN mode.
+    CacheResponse(String, ResponseHeader, Vec<Bytes>),
 }
 
 /// All possible replies FROM this module.
@@ -49,6 +62,7 @@ pub enum WebProxyIn {
 pub enum WebProxyOut {
     ToNetwork(NodeID, ModuleMessage),
     ResponseGet(NodeID, U256, ResponseHeader),
+    ResponseError(NodeID, U256, RequestError),
     UpdateStorage(WebProxyStorage),
 }
 
@@ -63,11 +77,11 @@ impl WebProxyMessages {
     pub fn new(
         storage: WebProxyStorage,
         cfg: WebProxyConfig,
-        our_id: NodeID,
+        node_config: NodeConfig,
         broker: Broker<WebProxyMessage>,
     ) -> Result<Self, WebProxyError> {
         Ok(Self {
-            core: WebProxyCore::new(storage, cfg, our_id),
+            core: WebProxyCore::new(storage, cfg, node_config),
             broker,
         })
     }
@@ -80,6 +94,10 @@ impl WebProxyMessages {
                 WebProxyIn::FromNetwork(src, node_msg) => self.process_node_message(src, node_msg),
                 WebProxyIn::NodeInfoConnected(ids) => self.node_list(ids),
                 WebProxyIn::RequestGet(rnd, url, tx) => self.request_get(rnd, url, tx),
+                WebProxyIn::CacheResponse(url, header, body) => {
+                    self.core.cache_add(url, header, body, now());
+                    vec![]
+                }
             })
             .flatten()
             .collect()
@@ -89,7 +107,17 @@ impl WebProxyMessages {
     /// MessageOut.
     pub fn process_node_message(&mut self, src: NodeID, msg: ModuleMessage) -> Vec<WebProxyOut> {
         let mut out = match msg {
-            ModuleMessage::Request(nonce, request) => self.start_request(src, nonce, request),
+            ModuleMessage::Request(nonce, request) => self.check_request(src, nonce, request, None),
+            ModuleMessage::RequestSigned(nonce, request, sig) => {
+                self.check_request(src, nonce, request, Some(sig))
+            }
+            ModuleMessage::RequestStream(nonce, request, sig) => {
+                self.check_request_stream(src, nonce, request, sig)
+            }
+            ModuleMessage::Ack(nonce, chunks) => {
+                self.core.stream_ack(src, nonce, chunks);
+                return vec![];
+            }
             ModuleMessage::Response(nonce, response) => self.handle_response(src, nonce, response),
         };
         out.push(WebProxyOut::UpdateStorage(self.core.storage.clone()));
@@ -98,54 +126,173 @@ impl WebProxyMessages {
 
     /// Stores the new node list, excluding the ID of this node.
     fn node_list(&mut self, nodes: Vec<NodeInfo>) -> Vec<WebProxyOut> {
+        let proxies: Vec<&NodeInfo> = nodes
+            .iter()
+            .filter(|ni| ni.modules.contains(Modules::ENABLE_WEBPROXY_REQUESTS))
+            .collect();
         self.core.node_list(
-            nodes
+            proxies
                 .iter()
-                .filter(|ni| ni.modules.contains(Modules::ENABLE_WEBPROXY_REQUESTS))
                 .map(|ni| ni.get_id())
                 .collect::<Vec<NodeID>>()
                 .into(),
+            proxies
+                .iter()
+                .filter(|ni| ni.capabilities.contains(Capabilities::WEBPROXY_STREAM))
+                .map(|ni| ni.get_id())
+                .collect(),
         );
         vec![]
     }
 
+    // The body chunks are given to the reader by a separate task, which
+    // acknowledges them to the proxy once the reader has room for them.
     fn request_get(&mut self, rnd: U256, url: String, tx: Sender<Bytes>) -> Vec<WebProxyOut> {
-        self.core.request_get(rnd, tx).map_or(vec![], |node| {
-            vec![WebProxyOut::ToNetwork(node, ModuleMessage::Request(rnd, url))]
-        })
+        let (chunk_tx, mut chunk_rx) = unbounded_channel::<Bytes>();
+        let Some(node) = self.core.request_get(rnd, chunk_tx) else {
+            return vec![];
+        };
+        let stream = self.core.supports_stream(&node);
+        let mut broker = self.broker.clone();
+        spawn_local(async move {
+            let mut delivered = 0;
+            while let Some(chunk) = chunk_rx.recv().await {
+                if tx.send(chunk).await.is_err() {
+                    return;
+                }
+                delivered += 1;
+                if stream && delivered % STREAM_ACK_EVERY == 0 {
+                    let ack = WebProxyOut::ToNetwork(node, ModuleMessage::Ack(rnd, delivered));
+                    if broker.emit_msg(ack.into()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        let sig = self.core.sign_request(&rnd, &url);
+        let request = if stream {
+            ModuleMessage::RequestStream(rnd, url, sig)
+        } else {
+            ModuleMessage::RequestSigned(rnd, url, sig)
+        };
+        vec![WebProxyOut::ToNetwork(node, request)]
     }
 
-    fn start_request(&mut self, src: NodeID, nonce: U256, request: String) -> Vec<WebProxyOut> {
+    fn check_request(
+        &mut self,
+        src: NodeID,
+        nonce: U256,
+        request: String,
+        sig: Option<Vec<u8>>,
+    ) -> Vec<WebProxyOut> {
+        match self
+            .core
+            .check_request(src, &nonce, &request, sig.as_deref(), now())
+        {
+            Ok(_) => self.start_request(src, nonce, request, false),
+            Err(e) => vec![WebProxyOut::ToNetwork(
+                src,
+                ModuleMessage::Response(nonce, ResponseMessage::Rejected(e)),
+            )],
+        }
+    }
+
+    fn check_request_stream(
+        &mut self,
+        src: NodeID,
+        nonce: U256,
+        request: String,
+        sig: Vec<u8>,
+    ) -> Vec<WebProxyOut> {
+        match self
+            .core
+            .check_request(src, &nonce, &request, Some(&sig), now())
+        {
+            Ok(_) => self.start_request(src, nonce, request, true),
+            Err(e) => vec![WebProxyOut::ToNetwork(
+                src,
+                ModuleMessage::Response(nonce, ResponseMessage::Rejected(e)),
+            )],
+        }
+    }
+
+    // If `stream` is true, the body chunks are only sent as long as the congestion
+    // control allows more unacknowledged chunks.
+    // Cached responses are sent at once, as they are already in memory.
+    fn start_request(
+        &mut self,
+        src: NodeID,
+        nonce: U256,
+        request: String,
+        stream: bool,
+    ) -> Vec<WebProxyOut> {
+        if let Some(cached) = self.core.cache_get(&request, now()) {
+            log::trace!("Serving {request} from cache");
+            return [ResponseMessage::Header(cached.header)]
+                .into_iter()
+                .chain(cached.body.into_iter().map(ResponseMessage::Body))
+                .chain([ResponseMessage::Done])
+                .map(|msg| WebProxyOut::ToNetwork(src, ModuleMessage::Response(nonce, msg)))
+                .collect();
+        }
+        let mut cache = self.core.config.allow_list.is_some();
+        let cache_max_body = self.core.config.cache_max_body;
+        let mut control = stream.then(|| self.core.stream_start(src, nonce));
         let mut broker = self.broker.clone();
         spawn_local(async move {
-            match reqwest::get(request).await {
+            match reqwest::get(&request).await {
                 Ok(resp) => {
+                    let header: ResponseHeader = (&resp).into();
                     broker
                         .emit_msg(WebProxyMessage::Output(WebProxyOut::ToNetwork(
                             src,
-                            ModuleMessage::Response(nonce, ResponseMessage::Header((&resp).into())),
+                            ModuleMessage::Response(nonce, ResponseMessage::Header(header.clone())),
                         )))
                         .expect("sending header");
-                    let mut stream = resp.bytes_stream().chunks(1024);
-                    while let Some(chunks) = stream.next().await {
-                        for chunk in chunks {
-                            broker
-                                .emit_msg(WebProxyMessage::Output(WebProxyOut::ToNetwork(
-                                    src,
-                                    ModuleMessage::Response(
-                                        nonce,
-                                        ResponseMessage::Body(chunk.expect("getting chunk")),
-                                    ),
-                                )))
-                                .expect("sending body");
+                    let mut body = vec![];
+                    let mut body_len = 0;
+                    let mut chunks = resp.bytes_stream();
+                    while let Some(chunk) = chunks.next().await {
+                        let chunk = chunk.expect("getting chunk");
+                        if let Some(control) = control.as_mut() {
+                            if !control.ready(STREAM_ACK_TIMEOUT).await {
+                                log::warn!("Requester {src} stopped acknowledging {request}");
+                                broker
+                                    .emit_msg(WebProxyMessage::Output(WebProxyOut::ToNetwork(
+                                        src,
+                                        ModuleMessage::Response(
+                                            nonce,
+                                            ResponseMessage::Error("Missing ack".into()),
+                                        ),
+                                    )))
+                                    .expect("sending error");
+                                return;
+                            }
+                        }
+                        if let Some(control) = control.as_mut() {
+                            control.sent(chunk.len());
+                        }
+                        if cache {
+                            body_len += chunk.len();
+                            body.push(chunk.clone());
+                            // Bodies which are too big are not collected for the cache.
+                            if body_len > cache_max_body {
+                                cache = false;
+                                body = vec![];
+                            }
                         }
+                        broker
+                            .emit_msg(WebProxyMessage::Output(WebProxyOut::ToNetwork(
+                                src,
+                                ModuleMessage::Response(nonce, ResponseMessage::Body(chunk)),
+                            )))
+                            .expect("sending body");
+                    }
+                    if cache {
+                        broker
+                            .emit_msg(WebProxyIn::CacheResponse(request, header, body).into())
+                            .expect("caching response");
                     }
-                    broker
-                        .emit_msg(WebProxyMessage::Output(WebProxyOut::ToNetwork(
-                            src,
-                            ModuleMessage::Response(nonce, ResponseMessage::Done),
-                        )))
-                        .expect("sending done");
                 }
                 Err(e) => {
                     broker
@@ -173,6 +320,17 @@ impl WebProxyMessages {
         nonce: U256,
         msg: ResponseMessage,
     ) -> Vec<WebProxyOut> {
+        if !self.core.requested_from(&nonce, &src) {
+            log::warn!(
+                "Dropping response with nonce {nonce} from {src}, which didn't get the request"
+            );
+            return vec![];
+        }
+        if let ResponseMessage::Rejected(e) = &msg {
+            let e = e.clone();
+            self.core.handle_response(nonce, msg);
+            return vec![WebProxyOut::ResponseError(src, nonce, e)];
+        }
         self.core
             .handle_response(nonce, msg)
             .map_or(vec![], |header| {
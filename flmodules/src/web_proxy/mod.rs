@@ -2,6 +2,8 @@
 pub mod response;
 // The core algorithm of this module
 pub mod core;
+// Congestion control of the streamed responses
+pub mod congestion;
 // Messages for this module
 pub mod messages;
 // Integrating with other modules
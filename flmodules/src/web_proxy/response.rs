@@ -7,6 +7,8 @@ use tokio::sync::mpsc::Receiver;
 
 use flarch::nodeids::NodeID;
 
+use super::core::RequestError;
+
 #[derive(Debug)]
 pub struct Response {
     proxy: NodeID,
@@ -50,6 +52,7 @@ pub enum ResponseMessage {
     Header(ResponseHeader),
     Body(#[serde_as(as = "Base64")]Bytes),
     Error(String),
+    Rejected(RequestError),
     Done,
 }
 
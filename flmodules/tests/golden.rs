@@ -0,0 +1,242 @@
+//! Golden-file tests for all types which are serialized and sent between nodes,
+//! or stored on a node.
+//!
+//! Each file in `tests/golden` holds a list of samples of one type. The tests make
+//! sure that the files can be read and written back byte-identically, both on libc
+//! and on wasm, so that browser and CLI nodes keep understanding each other.
+//!
+//! To update the files after an intentional change of a serialized type, run
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and review the diff.
+
+use flarch::{
+    nodeids::U256,
+    web_rtc::messages::{PeerInfo, PeerMessage},
+};
+use flmodules::{
+    gossip_events::{
+        self,
+        core::{Category, Event},
+    },
+    network::signal::{MessageAnnounce, NodeStat, WSSignalMessageFromNode, WSSignalMessageToNode},
+    nodeconfig::{NodeConfig, NodeInfo},
+    overlay::messages::NetworkWrapper,
+    ping, random_connections, web_proxy,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(target_family = "wasm")]
+use wasm_bindgen_test::wasm_bindgen_test as test;
+
+fn check<T: Serialize + DeserializeOwned>(name: &str, golden: &str, samples: Vec<T>) {
+    let samples_str = serde_yaml::to_string(&samples).expect("serializing samples");
+    #[cfg(not(target_family = "wasm"))]
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::write(format!("tests/golden/{name}.yaml"), &samples_str)
+            .expect("writing golden file");
+        return;
+    }
+
+    let parsed: Vec<T> = serde_yaml::from_str(golden)
+        .unwrap_or_else(|e| panic!("Couldn't deserialize golden file {name}: {e}"));
+    assert_eq!(
+        golden,
+        serde_yaml::to_string(&parsed).expect("serializing golden"),
+        "Golden file {name} is not serialized byte-identically"
+    );
+    assert_eq!(
+        golden, samples_str,
+        "Samples of {name} don't serialize to the golden file"
+    );
+}
+
+fn node_config() -> NodeConfig {
+    NodeConfig::decode(include_str!("load/fledger_nodeConfig.yaml")).expect("decoding config")
+}
+
+fn node_info() -> NodeInfo {
+    node_config().info
+}
+
+fn id(nbr: u8) -> U256 {
+    U256::from([nbr; 32])
+}
+
+#[test]
+fn golden_node_config() {
+    check(
+        "node_config",
+        include_str!("golden/node_config.yaml"),
+        vec![node_config()],
+    );
+    assert_eq!(
+        node_info(),
+        NodeInfo::decode(&node_info().encode()).expect("decoding info")
+    );
+}
+
+#[test]
+fn golden_signal_messages() {
+    let peer_info = PeerInfo {
+        id_init: id(1),
+        id_follow: id(2),
+        message: PeerMessage::Offer("offer".into()),
+    };
+    check(
+        "signal_to_node",
+        include_str!("golden/signal_to_node.yaml"),
+        vec![
+            WSSignalMessageToNode::Challenge(3, id(3)),
+            WSSignalMessageToNode::ListIDsReply(vec![node_info()]),
+            WSSignalMessageToNode::PeerSetup(peer_info.clone()),
+            WSSignalMessageToNode::RendezvousReply(id(6), node_info()),
+        ],
+    );
+    check(
+        "signal_from_node",
+        include_str!("golden/signal_from_node.yaml"),
+        vec![
+            WSSignalMessageFromNode::Announce(MessageAnnounce {
+                version: 3,
+                challenge: id(3),
+                node_info: node_info(),
+                signature: vec![4; 64],
+            }),
+            WSSignalMessageFromNode::ListIDsRequest,
+            WSSignalMessageFromNode::PeerSetup(peer_info),
+            WSSignalMessageFromNode::NodeStats(vec![NodeStat {
+                id: id(5),
+                version: "0.8.0".into(),
+                ping_ms: 10,
+                ping_rx: 20,
+            }]),
+            WSSignalMessageFromNode::Rendezvous(id(6)),
+        ],
+    );
+}
+
+#[test]
+fn golden_module_messages() {
+    check(
+        "gossip_events",
+        include_str!("golden/gossip_events.yaml"),
+        vec![
+            gossip_events::messages::ModuleMessage::KnownEventIDs(vec![id(1)]),
+            gossip_events::messages::ModuleMessage::Events(vec![
+                Event {
+                    category: Category::TextMessage,
+                    src: id(2),
+                    created: 1_700_000_000_000,
+                    msg: "golden".into(),
+                    signature: None,
+                },
+                Event {
+                    category: Category::TextMessage,
+                    src: id(2),
+                    created: 1_700_000_000_000,
+                    msg: "signed".into(),
+                    signature: Some(vec![4; 64]),
+                },
+            ]),
+            gossip_events::messages::ModuleMessage::RequestEventIDs,
+            gossip_events::messages::ModuleMessage::RequestEvents(vec![id(3)]),
+        ],
+    );
+    check(
+        "ping",
+        include_str!("golden/ping.yaml"),
+        vec![
+            ping::messages::ModuleMessage::Ping,
+            ping::messages::ModuleMessage::Pong,
+            ping::messages::ModuleMessage::TimeRequest(1_700_000_000_000),
+            ping::messages::ModuleMessage::TimeReply(ping::messages::SignedTime {
+                request: 1_700_000_000_000,
+                local: 1_700_000_000_500,
+                signature: vec![5; 64],
+            }),
+        ],
+    );
+    let wrapper = NetworkWrapper::wrap_yaml("Ping", &ping::messages::ModuleMessage::Ping)
+        .expect("wrapping message");
+    check(
+        "random_connections",
+        include_str!("golden/random_connections.yaml"),
+        vec![
+            random_connections::messages::ModuleMessage::Module(wrapper),
+            random_connections::messages::ModuleMessage::DropConnection,
+        ],
+    );
+    check(
+        "web_proxy",
+        include_str!("golden/web_proxy.yaml"),
+        vec![
+            web_proxy::messages::ModuleMessage::Request(id(1), "https://fledg.re".into()),
+            web_proxy::messages::ModuleMessage::RequestSigned(
+                id(2),
+                "https://fledg.re".into(),
+                vec![1, 2, 3],
+            ),
+            web_proxy::messages::ModuleMessage::Response(
+                id(3),
+                web_proxy::response::ResponseMessage::Body("body".into()),
+            ),
+            web_proxy::messages::ModuleMessage::Response(
+                id(4),
+                web_proxy::response::ResponseMessage::Rejected(
+                    web_proxy::core::RequestError::QuotaExceeded,
+                ),
+            ),
+            web_proxy::messages::ModuleMessage::Response(
+                id(5),
+                web_proxy::response::ResponseMessage::Done,
+            ),
+            web_proxy::messages::ModuleMessage::Response(
+                id(6),
+                web_proxy::response::ResponseMessage::Rejected(
+                    web_proxy::core::RequestError::NotAllowed,
+                ),
+            ),
+        ],
+    );
+}
+
+#[test]
+// The storage uses HashMaps, so its serialization is not deterministic and only
+// the content is compared.
+fn golden_storage() {
+    let nc = node_config();
+    let event = Event {
+        category: Category::TextMessage,
+        src: nc.info.get_id(),
+        created: 1_700_000_000_000,
+        msg: "stored".into(),
+        signature: None,
+    }
+    .signed(&nc);
+    let mut events = gossip_events::core::EventsStorage::new();
+    events.add_event(event.clone());
+    #[cfg(not(target_family = "wasm"))]
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        std::fs::write(
+            "tests/golden/events_storage.yaml",
+            events.get().expect("events"),
+        )
+        .expect("writing golden file");
+        return;
+    }
+    let mut events_parsed = gossip_events::core::EventsStorage::new();
+    events_parsed
+        .set(include_str!("golden/events_storage.yaml"))
+        .expect("parsing events");
+    // The signatures are randomized, so only their validity is checked.
+    let parsed = events_parsed.events(Category::TextMessage);
+    assert!(parsed.iter().all(|ev| ev.verify()));
+    let unsigned = |ev: Event| Event {
+        signature: None,
+        ..ev
+    };
+    assert_eq!(
+        vec![unsigned(event)],
+        parsed.into_iter().map(unsigned).collect::<Vec<_>>()
+    );
+    assert_eq!(0, events_parsed.events(Category::NodeInfo).len());
+}
@@ -0,0 +1,569 @@
+//! Opt-in collection of crash reports.
+//!
+//! An application which wants to report its crashes wraps its logger with
+//! [`RecentLogs::wrap`], and calls [`install_panic_hook`].
+//! Every panic is then stored as a [`CrashReport`] in the storage of the node,
+//! together with the backtrace and the most recent log lines.
+//!
+//! The stored reports are only sent if the [`CrashConfig`] has a collector,
+//! and [`crate::node::Node::submit_crash_reports`] is called.
+//! They are encrypted for the collector with the keys of the nodes, so the nodes
+//! relaying them cannot read them, and the collector knows which node sent them.
+//! A node with [`CrashConfig::collect`] set stores the reports it receives, see
+//! [`CrashReporter::received`], and acknowledges them.
+//! The reporting node only removes a report once the collector acknowledged it.
+
+use std::{
+    backtrace::Backtrace,
+    collections::VecDeque,
+    sync::{mpsc::Receiver, Arc, Mutex},
+};
+
+use ed25519_compact::PublicKey;
+use log::{Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use flarch::{
+    broker::{Broker, BrokerError},
+    data_storage::{DataStorage, StorageError},
+    nodeids::NodeID,
+    tasks::now,
+};
+use flmodules::{
+    nodeconfig::{NodeConfig, NodeInfo},
+    overlay::{
+        encryption::{EncryptionError, NodeKeys},
+        format::FormatError,
+        messages::NetworkWrapper,
+    },
+    random_connections::messages::{RandomIn, RandomMessage, RandomOut},
+};
+
+use crate::version::VERSION_STRING;
+
+/// The name of the module in the [`NetworkWrapper`] of the reports.
+pub const MODULE_NAME: &str = "CrashReport";
+/// How many reports are kept in the storage, older reports are dropped.
+/// The collector also keeps at most this many reports per node.
+pub const MAX_REPORTS: usize = 10;
+/// How many reports the collector keeps in total, older reports are dropped.
+pub const MAX_RECEIVED: usize = 100;
+
+const STORAGE_REPORTS: &str = "crashReports";
+const STORAGE_RECEIVED: &str = "crashReportsReceived";
+
+#[derive(Debug, Error)]
+pub enum CrashError {
+    #[error("Crash reports must be encrypted")]
+    NotEncrypted,
+    #[error("Not a crash report")]
+    NoReport,
+    #[error("Acknowledgement from {0}, which is not the collector")]
+    NotCollector(NodeID),
+    #[error("Invalid key: {0}")]
+    Key(#[from] ed25519_compact::Error),
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+    #[error(transparent)]
+    Format(#[from] FormatError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Broker(#[from] BrokerError),
+}
+
+/// Where the crash reports go. It is stored as yaml, like the firewall rules.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CrashConfig {
+    /// The node the reports are sent to. No reports are sent if it is not set.
+    pub collector: Option<NodeID>,
+    /// Whether this node stores the reports sent by other nodes.
+    pub collect: bool,
+}
+
+impl CrashConfig {
+    pub fn from_yaml(data: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(data)
+    }
+}
+
+/// The messages between the reporting nodes and the collector.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+enum CrashMessage {
+    /// A report encrypted for the collector. It is wrapped in another
+    /// [`NetworkWrapper`], so decrypting the connection doesn't remove its encryption.
+    Report(NetworkWrapper),
+    /// The collector stored the report with this time.
+    Ack(i64),
+}
+
+/// A panic or a serious error of a node.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CrashReport {
+    pub time: i64,
+    pub version: String,
+    pub message: String,
+    pub backtrace: String,
+    /// The last log lines before the crash.
+    pub logs: Vec<String>,
+}
+
+impl CrashReport {
+    /// Creates a new report with the backtrace of the caller.
+    pub fn new(message: String, logs: &RecentLogs) -> Self {
+        Self {
+            time: now(),
+            version: VERSION_STRING.into(),
+            message,
+            backtrace: Backtrace::force_capture().to_string(),
+            logs: logs.lines(),
+        }
+    }
+
+    /// Returns the report encrypted for the collector.
+    pub fn seal(
+        &self,
+        node_config: &NodeConfig,
+        collector: &NodeID,
+    ) -> Result<NetworkWrapper, CrashError> {
+        let msg = NetworkWrapper::wrap(MODULE_NAME, self)?;
+        let sealed = NodeKeys::new(node_config)?.encrypt(&Self::node_info(collector)?, &msg)?;
+        Ok(NetworkWrapper::wrap(
+            MODULE_NAME,
+            &CrashMessage::Report(sealed),
+        )?)
+    }
+
+    /// Decrypts a report sent by the `src` node to this node.
+    pub fn open(
+        node_config: &NodeConfig,
+        src: &NodeID,
+        msg: NetworkWrapper,
+    ) -> Result<Self, CrashError> {
+        let Some(CrashMessage::Report(sealed)) = msg.unwrap(MODULE_NAME) else {
+            return Err(CrashError::NoReport);
+        };
+        if sealed.encryption.is_none() {
+            return Err(CrashError::NotEncrypted);
+        }
+        NodeKeys::new(node_config)?
+            .decrypt(&Self::node_info(src)?, sealed)?
+            .unwrap(MODULE_NAME)
+            .ok_or(CrashError::NoReport)
+    }
+
+    // The ID of a node is its public key.
+    fn node_info(id: &NodeID) -> Result<NodeInfo, CrashError> {
+        Ok(NodeInfo::new(PublicKey::from_slice(id.as_ref())?))
+    }
+
+    /// Returns the reports of this node which haven't been sent yet.
+    pub fn stored(storage: &dyn DataStorage) -> Vec<CrashReport> {
+        Self::get(storage, STORAGE_REPORTS)
+    }
+
+    /// Stores a report, and drops the oldest reports if there are more than
+    /// [`MAX_REPORTS`].
+    pub fn store(&self, storage: &mut dyn DataStorage) -> Result<(), CrashError> {
+        let mut reports = Self::stored(storage);
+        reports.push(self.clone());
+        let drop = reports.len().saturating_sub(MAX_REPORTS);
+        Self::set(storage, STORAGE_REPORTS, &reports[drop..])
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(storage: &dyn DataStorage, key: &str) -> Vec<T> {
+        storage
+            .get(key)
+            .ok()
+            .and_then(|s| serde_yaml::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    fn set<T: Serialize>(
+        storage: &mut dyn DataStorage,
+        key: &str,
+        value: &[T],
+    ) -> Result<(), CrashError> {
+        Ok(storage.set(key, &serde_yaml::to_string(value)?)?)
+    }
+}
+
+/// The most recent log lines, to be added to the [`CrashReport`]s.
+#[derive(Debug, Clone)]
+pub struct RecentLogs {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    size: usize,
+}
+
+impl RecentLogs {
+    /// Keeps the last `size` log lines.
+    pub fn new(size: usize) -> Self {
+        Self {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+            size,
+        }
+    }
+
+    pub fn push(&self, line: String) {
+        if let Ok(mut lines) = self.lines.lock() {
+            if lines.len() == self.size {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    /// Returns the log lines, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines
+            .lock()
+            .map(|lines| lines.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns a logger which keeps the lines logged by `inner` in this buffer.
+    /// It has to be installed instead of `inner`, e.g., with `log::set_boxed_logger`.
+    pub fn wrap(&self, inner: Box<dyn Log>) -> RecentLogger {
+        RecentLogger {
+            inner,
+            logs: self.clone(),
+        }
+    }
+}
+
+/// Passes all records to another logger, and keeps the most recent ones.
+pub struct RecentLogger {
+    inner: Box<dyn Log>,
+    logs: RecentLogs,
+}
+
+impl Log for RecentLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.enabled(record.metadata()) {
+            self.logs.push(format!(
+                "{} {} {}: {}",
+                now(),
+                record.level(),
+                record.target(),
+                record.args()
+            ));
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Stores a [`CrashReport`] for every panic, before calling the previous hook.
+pub fn install_panic_hook(storage: Box<dyn DataStorage + Send>, logs: RecentLogs) {
+    let storage = Mutex::new(storage);
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = CrashReport::new(info.to_string(), &logs);
+        if let Ok(mut storage) = storage.lock() {
+            if let Err(e) = report.store(storage.as_mut()) {
+                log::error!("Couldn't store crash report: {e:?}");
+            }
+        }
+        previous(info);
+    }));
+}
+
+/// Sends the crash reports of this node to the collector, and stores the
+/// reports of other nodes if it is a collector.
+pub struct CrashReporter {
+    pub config: CrashConfig,
+    node_config: NodeConfig,
+    storage: Box<dyn DataStorage + Send>,
+    random: Broker<RandomMessage>,
+    connected: Vec<NodeID>,
+    tap: Receiver<RandomMessage>,
+}
+
+impl CrashReporter {
+    pub async fn start(
+        config: CrashConfig,
+        node_config: NodeConfig,
+        storage: Box<dyn DataStorage + Send>,
+        mut random: Broker<RandomMessage>,
+    ) -> Result<Self, BrokerError> {
+        let (tap, _) = random.get_tap_sync().await?;
+        Ok(Self {
+            config,
+            node_config,
+            storage,
+            random,
+            connected: vec![],
+            tap,
+        })
+    }
+
+    /// Keeps track of the connected nodes, and stores the received reports.
+    pub fn update(&mut self) {
+        let msgs: Vec<RandomMessage> = self.tap.try_iter().collect();
+        for msg in msgs {
+            match msg {
+                RandomMessage::Output(RandomOut::NodeIDsConnected(ids)) => self.connected = ids.0,
+                RandomMessage::Output(RandomOut::NetworkWrapperFromNetwork(src, msg))
+                    if msg.module == MODULE_NAME =>
+                {
+                    if let Err(e) = self.receive(src, msg) {
+                        log::warn!("Dropping crash report from {src}: {e:?}");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends the stored reports if the collector is connected, and returns
+    /// how many reports have been sent.
+    /// The reports stay in the storage until the collector acknowledges them,
+    /// so the next call sends the reports again which got lost.
+    pub fn submit(&mut self) -> Result<usize, CrashError> {
+        let Some(collector) = self.config.collector else {
+            return Ok(0);
+        };
+        let reports = CrashReport::stored(self.storage.as_ref());
+        if reports.is_empty() || !self.connected.contains(&collector) {
+            return Ok(0);
+        }
+        for report in &reports {
+            let msg = report.seal(&self.node_config, &collector)?;
+            self.random
+                .emit_msg(RandomIn::NetworkMapperToNetwork(collector, msg).into())?;
+        }
+        Ok(reports.len())
+    }
+
+    /// Returns the reports received from other nodes.
+    pub fn received(&self) -> Vec<(NodeID, CrashReport)> {
+        CrashReport::get(self.storage.as_ref(), STORAGE_RECEIVED)
+    }
+
+    fn receive(&mut self, src: NodeID, msg: NetworkWrapper) -> Result<(), CrashError> {
+        match msg.unwrap(MODULE_NAME) {
+            Some(CrashMessage::Ack(time)) => self.acknowledged(src, time),
+            _ => self.collect(src, msg),
+        }
+    }
+
+    // Stores the report if it is new, and acknowledges it.
+    // Only the oldest reports of the sending node are dropped if it sent more
+    // than MAX_REPORTS, so a single node cannot push out the reports of the others.
+    fn collect(&mut self, src: NodeID, msg: NetworkWrapper) -> Result<(), CrashError> {
+        if !self.config.collect {
+            return Ok(());
+        }
+        let report = CrashReport::open(&self.node_config, &src, msg)?;
+        let time = report.time;
+        let mut received = self.received();
+        if !received.iter().any(|(id, r)| id == &src && r.time == time) {
+            log::info!("Received crash report from {src}: {}", report.message);
+            received.push((src, report));
+            if received.iter().filter(|(id, _)| id == &src).count() > MAX_REPORTS {
+                if let Some(pos) = received.iter().position(|(id, _)| id == &src) {
+                    received.remove(pos);
+                }
+            }
+            let drop = received.len().saturating_sub(MAX_RECEIVED);
+            CrashReport::set(self.storage.as_mut(), STORAGE_RECEIVED, &received[drop..])?;
+        }
+        let ack = NetworkWrapper::wrap(MODULE_NAME, &CrashMessage::Ack(time))?;
+        self.random
+            .emit_msg(RandomIn::NetworkMapperToNetwork(src, ack).into())?;
+        Ok(())
+    }
+
+    // Removes the report acknowledged by the collector.
+    fn acknowledged(&mut self, src: NodeID, time: i64) -> Result<(), CrashError> {
+        if self.config.collector != Some(src) {
+            return Err(CrashError::NotCollector(src));
+        }
+        let reports: Vec<CrashReport> = CrashReport::stored(self.storage.as_ref())
+            .into_iter()
+            .filter(|r| r.time != time)
+            .collect();
+        CrashReport::set(self.storage.as_mut(), STORAGE_REPORTS, &reports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flarch::data_storage::DataStorageTemp;
+
+    use super::*;
+
+    #[test]
+    fn test_seal() -> Result<(), CrashError> {
+        let reporter = NodeConfig::new();
+        let collector = NodeConfig::new();
+        let logs = RecentLogs::new(2);
+        for line in ["one", "two", "three"] {
+            logs.push(line.into());
+        }
+        let report = CrashReport::new("panicked".into(), &logs);
+        assert_eq!(vec!["two".to_string(), "three".into()], report.logs);
+
+        let msg = report.seal(&reporter, &collector.info.get_id())?;
+        assert!(!msg.msg.contains("panicked"));
+        let opened = CrashReport::open(&collector, &reporter.info.get_id(), msg.clone())?;
+        assert_eq!(report, opened);
+        assert!(
+            CrashReport::open(&NodeConfig::new(), &reporter.info.get_id(), msg.clone()).is_err()
+        );
+
+        // The connection between the nodes can be encrypted, too.
+        let mut keys_reporter = NodeKeys::new(&reporter)?;
+        let mut keys_collector = NodeKeys::new(&collector)?;
+        let twice = keys_reporter.encrypt(&collector.info, &msg)?;
+        let once = keys_collector.decrypt(&reporter.info, twice)?;
+        assert_eq!(
+            report,
+            CrashReport::open(&collector, &reporter.info.get_id(), once)?
+        );
+
+        let plain = NetworkWrapper::wrap(
+            MODULE_NAME,
+            &CrashMessage::Report(NetworkWrapper::wrap(MODULE_NAME, &report)?),
+        )?;
+        assert!(matches!(
+            CrashReport::open(&collector, &reporter.info.get_id(), plain),
+            Err(CrashError::NotEncrypted)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_submit() -> Result<(), CrashError> {
+        let (nc_a, nc_b) = (NodeConfig::new(), NodeConfig::new());
+        let (id_a, id_b) = (nc_a.info.get_id(), nc_b.info.get_id());
+        let mut storage_a = DataStorageTemp::new();
+        let storage_b = DataStorageTemp::new();
+        let mut random_a = Broker::new();
+        let mut random_b = Broker::new();
+        let config = CrashConfig {
+            collector: Some(id_b),
+            collect: false,
+        };
+        let mut reporter =
+            CrashReporter::start(config, nc_a, storage_a.clone(), random_a.clone()).await?;
+        let config = CrashConfig {
+            collector: None,
+            collect: true,
+        };
+        let mut collector =
+            CrashReporter::start(config, nc_b, storage_b.clone(), random_b.clone()).await?;
+        let (tap_a, _) = random_a.get_tap_sync().await?;
+        let (tap_b, _) = random_b.get_tap_sync().await?;
+
+        CrashReport::new("panicked".into(), &RecentLogs::new(1)).store(&mut storage_a)?;
+        assert_eq!(0, reporter.submit()?);
+        random_a
+            .settle_msg(RandomOut::NodeIDsConnected(vec![id_b].into()).into())
+            .await?;
+        reporter.update();
+        // Sending twice doesn't store the report twice.
+        assert_eq!(1, reporter.submit()?);
+        assert_eq!(1, reporter.submit()?);
+        assert_eq!(1, CrashReport::stored(&storage_a).len());
+        random_a.settle_msg(RandomIn::Tick.into()).await?;
+
+        forward(&tap_a, &mut random_b, id_a).await?;
+        collector.update();
+        let received = collector.received();
+        assert_eq!(1, received.len());
+        assert_eq!(
+            (id_a, "panicked"),
+            (received[0].0, received[0].1.message.as_str())
+        );
+
+        // The report is only removed once the collector acknowledged it.
+        assert_eq!(1, CrashReport::stored(&storage_a).len());
+        random_b.settle_msg(RandomIn::Tick.into()).await?;
+        forward(&tap_b, &mut random_a, id_b).await?;
+        reporter.update();
+        assert_eq!(0, CrashReport::stored(&storage_a).len());
+        Ok(())
+    }
+
+    // Sends the messages from one node to the other.
+    async fn forward(
+        tap: &Receiver<RandomMessage>,
+        to: &mut Broker<RandomMessage>,
+        from: NodeID,
+    ) -> Result<(), CrashError> {
+        for msg in tap.try_iter() {
+            if let RandomMessage::Input(RandomIn::NetworkMapperToNetwork(_, msg)) = msg {
+                to.settle_msg(RandomOut::NetworkWrapperFromNetwork(from, msg).into())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collect_limits() -> Result<(), CrashError> {
+        let nc = NodeConfig::new();
+        let id = nc.info.get_id();
+        let storage = DataStorageTemp::new();
+        let config = CrashConfig {
+            collector: None,
+            collect: true,
+        };
+        let mut collector =
+            CrashReporter::start(config, nc, Box::new(storage), Broker::new()).await?;
+        let mut crash = CrashReport::new("crash".into(), &RecentLogs::new(1));
+        crash.backtrace.clear();
+
+        let reporter = NodeConfig::new();
+        for i in 0..MAX_REPORTS + 2 {
+            let mut report = crash.clone();
+            report.time = i as i64;
+            report.message = format!("crash {i}");
+            collector.receive(reporter.info.get_id(), report.seal(&reporter, &id)?)?;
+        }
+        let received = collector.received();
+        assert_eq!(MAX_REPORTS, received.len());
+        assert_eq!("crash 2", received[0].1.message);
+
+        for _ in 0..MAX_RECEIVED {
+            let reporter = NodeConfig::new();
+            collector.receive(reporter.info.get_id(), crash.seal(&reporter, &id)?)?;
+        }
+        assert_eq!(MAX_RECEIVED, collector.received().len());
+
+        // Only the collector can acknowledge reports.
+        assert!(matches!(
+            collector.receive(
+                reporter.info.get_id(),
+                NetworkWrapper::wrap(MODULE_NAME, &CrashMessage::Ack(0))?
+            ),
+            Err(CrashError::NotCollector(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_store() -> Result<(), CrashError> {
+        let mut storage = DataStorageTemp::new();
+        let logs = RecentLogs::new(10);
+        for i in 0..MAX_REPORTS + 2 {
+            CrashReport::new(format!("crash {i}"), &logs).store(&mut storage)?;
+        }
+        let reports = CrashReport::stored(&storage);
+        assert_eq!(MAX_REPORTS, reports.len());
+        assert_eq!("crash 2", reports[0].message);
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use flarch::{
+    broker::{Broker, BrokerError},
+    nodeids::NodeID,
+};
+use flmodules::random_connections::messages::{RandomMessage, RandomOut};
+
+/// What to do with a message matching a [`Rule`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// A rule matches a message if all of its fields which are set match the message.
+/// A rule with no fields set matches all messages.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    /// Name of the module, e.g., "WebProxy" or "Gossip".
+    pub module: Option<String>,
+    pub source: Option<NodeID>,
+}
+
+impl Rule {
+    fn matches(&self, module: &str, source: &NodeID) -> bool {
+        self.module.as_ref().is_none_or(|m| m == module)
+            && self.source.as_ref().is_none_or(|s| s == source)
+    }
+}
+
+/// The rules are evaluated in order, and the first matching rule decides.
+/// If no rule matches, the message is allowed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct FirewallConfig {
+    pub rules: Vec<Rule>,
+}
+
+impl FirewallConfig {
+    pub fn from_yaml(data: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(data)
+    }
+
+    /// Returns whether a message for the module from the given source passes.
+    pub fn allows(&self, module: &str, source: &NodeID) -> bool {
+        self.rules
+            .iter()
+            .find(|r| r.matches(module, source))
+            .is_none_or(|r| r.action == Action::Allow)
+    }
+
+    /// Returns whether all messages for the module are denied, whatever their source.
+    pub fn denies_module(&self, module: &str) -> bool {
+        for rule in self
+            .rules
+            .iter()
+            .filter(|r| r.module.as_ref().is_none_or(|m| m == module))
+        {
+            match (rule.action, rule.source) {
+                (action, None) => return action == Action::Deny,
+                (Action::Allow, Some(_)) => return false,
+                (Action::Deny, Some(_)) => {}
+            }
+        }
+        false
+    }
+}
+
+/// Sits between the random_connections broker and the modules, and drops all
+/// module messages from the network which are denied by the [`FirewallConfig`].
+pub struct Firewall {
+    /// The broker the modules have to connect to.
+    pub broker: Broker<RandomMessage>,
+    denied: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Firewall {
+    pub async fn start(
+        config: FirewallConfig,
+        random: Broker<RandomMessage>,
+    ) -> Result<Self, BrokerError> {
+        let mut broker = Broker::new();
+        let denied = Arc::new(Mutex::new(HashMap::new()));
+        let denied_cl = denied.clone();
+        broker
+            .link_bi(
+                random,
+                Box::new(move |msg| match msg {
+                    RandomMessage::Output(RandomOut::NetworkWrapperFromNetwork(src, ref nw))
+                        if !config.allows(&nw.module, &src) =>
+                    {
+                        log::trace!("Denied message from {src} for module {}", nw.module);
+                        *denied_cl
+                            .lock()
+                            .unwrap()
+                            .entry(nw.module.clone())
+                            .or_default() += 1;
+                        None
+                    }
+                    RandomMessage::Output(_) => Some(msg),
+                    _ => None,
+                }),
+                Box::new(|msg| matches!(msg, RandomMessage::Input(_)).then(|| msg)),
+            )
+            .await?;
+        Ok(Self { broker, denied })
+    }
+
+    /// Returns how many messages have been denied for each module.
+    pub fn denied(&self) -> HashMap<String, u64> {
+        self.denied.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flarch::nodeids::U256;
+    use flmodules::overlay::messages::NetworkWrapper;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deny() -> Result<(), Box<dyn std::error::Error>> {
+        let blocked = U256::rnd();
+        let config = FirewallConfig {
+            rules: vec![
+                Rule {
+                    action: Action::Allow,
+                    module: Some("Gossip".into()),
+                    source: Some(blocked),
+                },
+                Rule {
+                    action: Action::Deny,
+                    module: None,
+                    source: Some(blocked),
+                },
+                Rule {
+                    action: Action::Deny,
+                    module: Some("WebProxy".into()),
+                    source: None,
+                },
+            ],
+        };
+        assert_eq!(
+            config,
+            FirewallConfig::from_yaml(&serde_yaml::to_string(&config)?)?
+        );
+        let mut random = Broker::new();
+        let mut fw = Firewall::start(config, random.clone()).await?;
+        let (tap, _) = fw.broker.get_tap_sync().await?;
+
+        let other = U256::rnd();
+        for (src, module) in [
+            (blocked, "Gossip"),
+            (blocked, "Ping"),
+            (other, "WebProxy"),
+            (other, "Ping"),
+        ] {
+            random
+                .settle_msg(RandomMessage::Output(RandomOut::NetworkWrapperFromNetwork(
+                    src,
+                    NetworkWrapper::wrap_yaml(module, &"msg")?,
+                )))
+                .await?;
+        }
+
+        let passed: Vec<(NodeID, String)> = tap
+            .try_iter()
+            .filter_map(|msg| match msg {
+                RandomMessage::Output(RandomOut::NetworkWrapperFromNetwork(src, nw)) => {
+                    Some((src, nw.module))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![(blocked, "Gossip".to_string()), (other, "Ping".to_string())],
+            passed
+        );
+        assert_eq!(Some(&1), fw.denied().get("WebProxy"));
+        assert_eq!(Some(&1), fw.denied().get("Ping"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_denies_module() -> Result<(), Box<dyn std::error::Error>> {
+        let config = FirewallConfig::from_yaml(
+            "rules:\n\
+            - action: Deny\n  module: Gossip\n  source: \"0000000000000000000000000000000000000000000000000000000000000001\"\n\
+            - action: Deny\n  module: WebProxy\n\
+            - action: Allow\n  source: \"0000000000000000000000000000000000000000000000000000000000000002\"\n\
+            - action: Deny\n",
+        )?;
+        assert!(config.denies_module("WebProxy"));
+        assert!(!config.denies_module("Gossip"));
+        assert!(!config.denies_module("Ping"));
+        assert!(!FirewallConfig::default().denies_module("WebProxy"));
+        Ok(())
+    }
+}
@@ -1,3 +1,7 @@
+pub mod crash;
+pub mod firewall;
 pub mod node;
+pub mod policy;
+pub mod snapshot;
 pub mod version;
 pub mod stat;
\ No newline at end of file
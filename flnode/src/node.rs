@@ -1,27 +1,47 @@
 use log::{error, info};
 use std::collections::HashMap;
 use thiserror::Error;
+use tokio::sync::watch;
 
 use flarch::{
-    broker::{Broker, BrokerError},
+    broker::{Broker, BrokerError, BrokerInfo, BrokerRegistry},
     nodeids::NodeID,
 };
 use flarch::{
-    data_storage::{DataStorage, StorageError},
+    data_storage::{DataStorage, DataStorageAsync, DataStorageSync, StorageError},
+    metrics::{MetricType, Metrics, Sample},
     tasks::now,
 };
 use flmodules::{
     gossip_events::{
+        bot::Bot,
         broker::GossipBroker,
-        core::{self, Category, Event},
+        core::{self, ArchiveError, Category, Event, EventsArchive, EventsStorage},
         messages::{GossipIn, GossipMessage},
-    }, network::messages::{NetworkError, NetworkIn, NetworkMessage}, nodeconfig::{ConfigError, NodeConfig, NodeInfo}, overlay::broker::OverlayRandom, ping::{broker::PingBroker, messages::PingConfig}, random_connections::broker::RandomBroker, timer::{TimerBroker, TimerMessage}, web_proxy::{
-        broker::{WebProxy, WebProxyError},
-        core::WebProxyConfig,
-    }, Modules
+    },
+    network::messages::{NetworkError, NetworkIn, NetworkMessage},
+    nodeconfig::{ConfigError, NodeConfig, NodeInfo, NodeRole},
+    overlay::{
+        broker::OverlayRandom,
+        encryption::{EncryptionError, NodeKeys},
+    },
+    ping::{broker::PingBroker, messages::PingConfig},
+    random_connections::{broker::RandomBroker, messages::RandomIn},
+    timer::{TimerBroker, TimerMessage},
+    web_proxy::{
+        broker::{self as web_proxy_broker, WebProxy, WebProxyError},
+        core::{WebProxyConfig, WebProxyStorageSave},
+    },
+    Capabilities, Modules,
 };
 
-use crate::stat::StatBroker;
+use crate::{
+    crash::{CrashConfig, CrashError, CrashReporter},
+    firewall::{Firewall, FirewallConfig},
+    policy::Policy,
+    snapshot::NodeSnapshot,
+    stat::StatBroker,
+};
 
 #[derive(Error, Debug)]
 pub enum NodeError {
@@ -29,6 +49,8 @@ pub enum NodeError {
     Lock,
     #[error("Missing subsystem {0}")]
     Missing(String),
+    #[error("A read-only node cannot publish or sign")]
+    ReadOnly,
     #[error(transparent)]
     Config(#[from] ConfigError),
     #[error(transparent)]
@@ -41,6 +63,12 @@ pub enum NodeError {
     Yaml(#[from] serde_yaml::Error),
     #[error(transparent)]
     WebProxy(#[from] WebProxyError),
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+    #[error(transparent)]
+    Crash(#[from] CrashError),
 }
 
 /// The node structure holds it all together. It is the main structure of the project.
@@ -48,7 +76,7 @@ pub struct Node {
     /// The node configuration
     pub node_config: NodeConfig,
     /// Storage to be used
-    pub storage: Box<dyn DataStorage>,
+    pub storage: Box<dyn DataStorageAsync + Send>,
     /// Network broker
     pub broker_net: Broker<NetworkMessage>,
 
@@ -57,16 +85,50 @@ pub struct Node {
     pub stat: Option<StatBroker>,
     /// Handles a random number of connections
     pub random: Option<RandomBroker>,
+    /// Filters the module messages from the network before they reach the modules
+    pub firewall: Option<Firewall>,
     /// Gossip-events sent and received
     pub gossip: Option<GossipBroker>,
     /// Pings all connected nodes and informs about failing nodes
     pub ping: Option<PingBroker>,
     /// Answers GET requests from another node
     pub webproxy: Option<WebProxy>,
+    /// Sends and collects crash reports
+    pub crash: Option<CrashReporter>,
+    /// All brokers of this node, to check their health
+    pub registry: BrokerRegistry,
+    /// Minimum time between two snapshots sent by [`Node::snapshot`].
+    pub snapshot_interval_ms: i64,
+    snapshot: watch::Sender<NodeSnapshot>,
+}
+
+/// What [`Node::gc`] removed from the storage.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcReport {
+    /// NodeInfos of nodes which haven't been seen for too long
+    pub node_infos: usize,
+    /// Entries of the web proxy audit log which are too old
+    pub audit_entries: usize,
+    /// The size of the cleaned entries before and after the garbage collection
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+}
+
+impl GcReport {
+    /// Returns the number of bytes freed in the storage.
+    pub fn reclaimed(&self) -> usize {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
 }
 
 const STORAGE_GOSSIP_EVENTS: &str = "gossip_events";
 const STORAGE_CONFIG: &str = "nodeConfig";
+const STORAGE_FIREWALL: &str = "firewall";
+const STORAGE_WEBPROXY_CONFIG: &str = "webproxyConfig";
+const STORAGE_POLICY: &str = "policy";
+const STORAGE_CRASH_CONFIG: &str = "crashConfig";
+/// Requests per quota period a dedicated proxy accepts from a single node.
+pub const PROXY_QUOTA_REQUESTS: usize = 600;
 
 impl Node {
     /// Create new node by loading the config from the storage.
@@ -77,6 +139,18 @@ impl Node {
         storage: Box<dyn DataStorage + Send>,
         node_config: NodeConfig,
         broker_net: Broker<NetworkMessage>,
+    ) -> Result<Self, NodeError> {
+        let timer = TimerBroker::start().await?;
+        Self::start_with_timer(storage, node_config, broker_net, timer).await
+    }
+
+    /// Like [`Node::start`], but uses the given timer instead of one following
+    /// the wall clock, e.g., a [`flmodules::timer::VirtualTimer`] for simulations.
+    pub async fn start_with_timer(
+        storage: Box<dyn DataStorage + Send>,
+        node_config: NodeConfig,
+        broker_net: Broker<NetworkMessage>,
+        timer: Broker<TimerMessage>,
     ) -> Result<Self, NodeError> {
         info!(
             "Starting node: {} = {}",
@@ -84,38 +158,80 @@ impl Node {
             node_config.info.get_id()
         );
 
+        let storage_async: Box<dyn DataStorageAsync + Send> =
+            Box::new(DataStorageSync::new(storage.clone()));
         let modules = node_config.info.modules;
         let id = node_config.info.get_id();
         let mut random = None;
+        let mut firewall = None;
         let mut gossip = None;
         let mut ping = None;
         let mut webproxy = None;
+        let mut crash = None;
+        let policy = Self::get_policy(storage.as_ref())?;
         if modules.contains(Modules::ENABLE_RAND) {
-            let rnd = RandomBroker::start(id, broker_net.clone()).await?;
+            let keys = if node_config
+                .info
+                .capabilities
+                .contains(Capabilities::ENCRYPT)
+            {
+                Some(NodeKeys::new(&node_config)?)
+            } else {
+                None
+            };
+            let mut rnd = RandomBroker::start_with_keys(id, keys, broker_net.clone()).await?;
+            // Other nodes can only pass on the NodeInfo if it is signed.
+            if !node_config.read_only {
+                rnd.broker
+                    .emit_msg(RandomIn::PeerExchange(node_config.signed_info()).into())?;
+            }
+            let fw =
+                Firewall::start(Self::get_firewall(storage.as_ref())?, rnd.broker.clone()).await?;
             if modules.contains(Modules::ENABLE_GOSSIP) {
-                gossip = Some(GossipBroker::start(id, rnd.broker.clone()).await?);
+                gossip = Some(GossipBroker::start(id, fw.broker.clone()).await?);
                 Self::init_gossip(
                     &mut gossip.as_mut().unwrap(),
-                    storage.clone(),
-                    &node_config.info,
+                    storage_async.as_ref(),
+                    &node_config,
+                    &policy,
                 )
                 .await?;
             }
             if modules.contains(Modules::ENABLE_PING) {
-                ping = Some(PingBroker::start(PingConfig::default(), rnd.broker.clone()).await?);
+                ping = Some(
+                    PingBroker::start(
+                        PingConfig::default(),
+                        node_config.clone(),
+                        fw.broker.clone(),
+                    )
+                    .await?,
+                );
             }
             if modules.contains(Modules::ENABLE_WEBPROXY) {
+                let mut webproxy_config =
+                    Self::get_webproxy_config(storage.as_ref(), node_config.info.role);
+                policy.restrict_webproxy(&mut webproxy_config);
                 webproxy = Some(
                     WebProxy::start(
                         storage.clone(),
-                        id,
-                        OverlayRandom::start(rnd.broker.clone()).await?,
-                        WebProxyConfig::default(),
+                        node_config.clone(),
+                        OverlayRandom::start(fw.broker.clone()).await?,
+                        webproxy_config,
                     )
                     .await?,
                 );
             }
+            crash = Some(
+                CrashReporter::start(
+                    Self::get_crash_config(storage.as_ref()),
+                    node_config.clone(),
+                    storage.clone(),
+                    fw.broker.clone(),
+                )
+                .await?,
+            );
             random = Some(rnd);
+            firewall = Some(fw);
         }
         let stat = if modules.contains(Modules::ENABLE_STAT) {
             Some(StatBroker::start(broker_net.clone()).await?)
@@ -123,17 +239,40 @@ impl Node {
             None
         };
 
+        let registry = BrokerRegistry::new();
+        registry.add("network", &broker_net).await;
+        if let Some(r) = random.as_ref() {
+            registry.add("random", &r.broker).await;
+        }
+        if let Some(f) = firewall.as_ref() {
+            registry.add("firewall", &f.broker).await;
+        }
+        if let Some(g) = gossip.as_ref() {
+            registry.add("gossip", &g.broker).await;
+        }
+        if let Some(p) = ping.as_ref() {
+            registry.add("ping", &p.broker).await;
+        }
+        if let Some(w) = webproxy.as_ref() {
+            registry.add("webproxy", &w.web_proxy).await;
+        }
+
         let mut node = Self {
-            storage,
+            storage: storage_async,
             node_config,
             broker_net,
             stat,
             random,
+            firewall,
             gossip,
             ping,
             webproxy,
+            crash,
+            registry,
+            snapshot_interval_ms: 1000,
+            snapshot: watch::channel(NodeSnapshot::default()).0,
         };
-        node.add_timer(TimerBroker::start().await?).await;
+        node.add_timer(timer).await;
         Ok(node)
     }
 
@@ -147,13 +286,27 @@ impl Node {
             .await;
         if let Some(r) = self.random.as_mut() {
             r.add_timer(timer.clone()).await;
+            // The signed NodeInfo expires, so it is signed again every minute.
+            if !self.node_config.read_only {
+                let node_config = self.node_config.clone();
+                timer
+                    .forward(
+                        r.broker.clone(),
+                        Box::new(move |msg| {
+                            (msg == TimerMessage::Minute)
+                                .then(|| RandomIn::PeerExchange(node_config.signed_info()).into())
+                        }),
+                    )
+                    .await;
+            }
         }
         if let Some(g) = self.gossip.as_mut() {
             g.add_timer(timer.clone()).await;
         }
         if let Some(p) = self.ping.as_mut() {
-            p.add_timer(timer).await;
+            p.add_timer(timer.clone()).await;
         }
+        self.registry.add("timer", &timer).await;
     }
 
     /// Update all data-storage. Goes through all storage modules, reads the queues of messages,
@@ -171,6 +324,24 @@ impl Node {
         if let Some(p) = self.ping.as_mut() {
             p.update();
         }
+        if let Some(c) = self.crash.as_mut() {
+            c.update();
+        }
+        let (version, time) = {
+            let last = self.snapshot.borrow();
+            (last.version + 1, last.time)
+        };
+        let now = now();
+        if now - time >= self.snapshot_interval_ms {
+            let snapshot = NodeSnapshot::new(self, version, now);
+            self.snapshot.send_replace(snapshot);
+        }
+    }
+
+    /// Returns a receiver for the snapshots of the state of the node, which are
+    /// updated by [`Node::update`] at most every `snapshot_interval_ms`.
+    pub fn snapshot(&self) -> watch::Receiver<NodeSnapshot> {
+        self.snapshot.subscribe()
     }
 
     /// Start processing of network and logic messages, in case they haven't been
@@ -179,11 +350,31 @@ impl Node {
     pub async fn process(&mut self) -> Result<(), NodeError> {
         self.update();
         if let Some(g) = self.gossip.as_mut() {
-            self.storage.set(STORAGE_GOSSIP_EVENTS, &g.storage.get()?)?;
+            self.storage
+                .set(STORAGE_GOSSIP_EVENTS, &g.storage.get()?)
+                .await?;
         }
         Ok(())
     }
 
+    /// Stops the node gracefully: the gossip events are written to the storage,
+    /// the connections to the other nodes and the signalling servers are closed,
+    /// and all brokers are stopped.
+    pub async fn shutdown(mut self) -> Result<(), NodeError> {
+        if let Some(g) = self.gossip.as_mut() {
+            // Makes sure the events still in the queue are stored.
+            g.broker
+                .settle_msg(GossipMessage::Input(GossipIn::GetStorage))
+                .await?;
+        }
+        self.process().await?;
+        self.broker_net
+            .settle_msg(NetworkIn::Shutdown.into())
+            .await?;
+        self.registry.stop_all().await;
+        Ok(())
+    }
+
     /// Requests a list of all connected nodes
     pub async fn request_list(&mut self) -> Result<(), NodeError> {
         self.broker_net
@@ -231,21 +422,257 @@ impl Node {
                     Err(e) => log::error!("Parse-error {e:?} for {}", ni.msg),
                 }
             }
+            // Drop the NodeInfos of keys which have been replaced.
+            let rotated: Vec<NodeID> = nodeinfos.values().filter_map(|i| i.previous_id()).collect();
+            for id in rotated {
+                nodeinfos.remove(&id);
+            }
             Ok(nodeinfos)
         } else {
             Err(NodeError::Missing("Gossip".into()))
         }
     }
 
+    /// Returns the current time in milliseconds, corrected by the clock offset
+    /// to the other nodes as estimated by the ping module.
+    pub fn network_time(&self) -> i64 {
+        now()
+            + self
+                .ping
+                .as_ref()
+                .and_then(|p| p.storage.clock_offset_ms())
+                .unwrap_or(0)
+    }
+
+    /// Returns one line per running broker with its name, the number of subsystems,
+    /// the waiting messages, and the crashes of its subsystems.
+    pub async fn health(&self) -> String {
+        self.registry
+            .list()
+            .await
+            .iter()
+            .map(|info| format!("{info}\n"))
+            .collect()
+    }
+
+    /// Returns the counters and gauges of the network, gossip_events, ping, and
+    /// web_proxy modules, and of all brokers, in the Prometheus format.
+    /// Missing modules are left out.
+    pub async fn metrics(&mut self) -> Metrics {
+        let mut m = Metrics::default();
+        if let Some(stat) = self.stat.as_ref() {
+            m.family(
+                MetricType::Gauge,
+                "fledger_network_connections",
+                "Connections to other nodes",
+                &[
+                    Sample::label("state", "pending", stat.setups.pending as f64),
+                    Sample::label("state", "established", stat.setups.established as f64),
+                ],
+            );
+            let total = stat.traffic_total();
+            m.family(
+                MetricType::Counter,
+                "fledger_network_bytes_total",
+                "Bytes exchanged with other nodes, directly or through a TURN server",
+                &[
+                    Sample::label("path", "direct", total.direct as f64),
+                    Sample::label("path", "turn", total.turn as f64),
+                ],
+            )
+            .family(
+                MetricType::Counter,
+                "fledger_network_turn_bytes_total",
+                "Bytes exchanged with a node through a TURN server",
+                &stat
+                    .traffic
+                    .iter()
+                    .filter(|(_, t)| t.turn > 0)
+                    .map(|(id, t)| Sample::label("node", &format!("{id}"), t.turn as f64))
+                    .collect::<Vec<_>>(),
+            )
+            .family(
+                MetricType::Gauge,
+                "fledger_network_bitrate_bps",
+                "Current bitrate of the WebRTC connection to a node",
+                &stat
+                    .transport
+                    .iter()
+                    .map(|(id, t)| Sample::label("node", &format!("{id}"), t.bitrate_bps as f64))
+                    .collect::<Vec<_>>(),
+            )
+            .family(
+                MetricType::Gauge,
+                "fledger_network_buffered_bytes",
+                "Bytes waiting in the data channels to a node",
+                &stat
+                    .transport
+                    .iter()
+                    .map(|(id, t)| Sample::label("node", &format!("{id}"), t.buffered_bytes as f64))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if let Some(r) = self.random.as_ref() {
+            m.gauge(
+                "fledger_network_nodes_connected",
+                "Nodes this node is connected to",
+                r.storage.connected.get_nodes().0.len() as f64,
+            )
+            .gauge(
+                "fledger_network_nodes_online",
+                "Nodes known to be online",
+                r.storage.known.0.len() as f64,
+            );
+        }
+        if let Some(g) = self.gossip.as_ref() {
+            let categories = [Category::TextMessage, Category::NodeInfo];
+            let evicted = g.storage.evicted();
+            let invalid = g.storage.invalid();
+            m.family(
+                MetricType::Gauge,
+                "fledger_gossip_events",
+                "Gossip events stored",
+                &categories
+                    .iter()
+                    .map(|c| {
+                        Sample::label("category", &format!("{c:?}"), g.events(*c).len() as f64)
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .family(
+                MetricType::Counter,
+                "fledger_gossip_events_evicted_total",
+                "Gossip events evicted because of the quotas",
+                &categories
+                    .iter()
+                    .map(|c| {
+                        let count = evicted.get(c).copied().unwrap_or(0);
+                        Sample::label("category", &format!("{c:?}"), count as f64)
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .family(
+                MetricType::Counter,
+                "fledger_gossip_events_invalid_total",
+                "Gossip events dropped because of an invalid signature",
+                &categories
+                    .iter()
+                    .map(|c| {
+                        let count = invalid.get(c).copied().unwrap_or(0);
+                        Sample::label("category", &format!("{c:?}"), count as f64)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if let Some(p) = self.ping.as_ref().map(|p| &p.storage) {
+            m.gauge(
+                "fledger_ping_nodes",
+                "Nodes being pinged",
+                p.stats.len() as f64,
+            )
+            .gauge(
+                "fledger_ping_clock_offset_ms",
+                "Estimated offset of the local clock to the other nodes",
+                p.clock_offset_ms().unwrap_or(0) as f64,
+            )
+            .family(
+                MetricType::Gauge,
+                "fledger_ping_rtt_ms",
+                "Smoothed round-trip time to a node",
+                &p.stats
+                    .iter()
+                    .filter_map(|(id, s)| {
+                        s.rtt_ms
+                            .map(|rtt| Sample::label("node", &format!("{id}"), rtt as f64))
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .family(
+                MetricType::Gauge,
+                "fledger_ping_jitter_ms",
+                "Smoothed variation of the round-trip time to a node",
+                &p.stats
+                    .iter()
+                    .filter_map(|(id, s)| {
+                        s.jitter_ms
+                            .map(|jitter| Sample::label("node", &format!("{id}"), jitter as f64))
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        if let Some(wp) = self.webproxy.as_mut() {
+            let counters = wp.get_counters();
+            m.family(
+                MetricType::Counter,
+                "fledger_webproxy_requests_total",
+                "Web proxy requests",
+                &[
+                    Sample::label("direction", "rx", counters.rx_requests as f64),
+                    Sample::label("direction", "tx", counters.tx_requests as f64),
+                ],
+            )
+            .family(
+                MetricType::Counter,
+                "fledger_webproxy_packets_total",
+                "Web proxy packets",
+                &[
+                    Sample::label("direction", "rx", counters.rx_packets as f64),
+                    Sample::label("direction", "tx", counters.tx_packets as f64),
+                ],
+            );
+        }
+        let brokers = self.registry.list().await;
+        let per_broker = |value: fn(&BrokerInfo) -> usize| {
+            brokers
+                .iter()
+                .map(|b| Sample::label("broker", &b.name, value(b) as f64))
+                .collect::<Vec<_>>()
+        };
+        m.family(
+            MetricType::Gauge,
+            "fledger_broker_pending",
+            "Messages waiting to be processed",
+            &per_broker(|b| b.health.pending),
+        )
+        .family(
+            MetricType::Counter,
+            "fledger_broker_crashes_total",
+            "Crashes of the subsystems",
+            &per_broker(|b| b.health.crashes.values().map(|c| *c as usize).sum()),
+        );
+        m
+    }
+
+    /// Removes the NodeInfos created before `before` from the gossip events,
+    /// except for the nodes which are currently online.
+    /// Like [`Node::gc`], but for a running node.
+    pub fn gc_events(&mut self, before: i64) -> Result<(), NodeError> {
+        let mut keep = vec![self.node_config.info.get_id()];
+        if let Some(r) = self.random.as_ref() {
+            keep.extend(r.storage.known.0.iter().cloned());
+        }
+        if let Some(g) = self.gossip.as_mut() {
+            g.broker
+                .emit_msg(GossipIn::RemoveBefore(Category::NodeInfo, before, keep).into())?;
+        }
+        Ok(())
+    }
+
     /// Adds a new chat message that will be broadcasted to the system.
     pub async fn add_chat_message(&mut self, msg: String) -> Result<(), NodeError> {
+        if self.node_config.read_only {
+            return Err(NodeError::ReadOnly);
+        }
+        let created = self.network_time();
         if let Some(g) = self.gossip.as_mut() {
             let event = core::Event {
                 category: core::Category::TextMessage,
                 src: self.node_config.info.get_id(),
-                created: now(),
+                created,
                 msg,
-            };
+                signature: None,
+            }
+            .signed(&self.node_config);
             g.add_event(event).await?;
             Ok(())
         } else {
@@ -253,24 +680,72 @@ impl Node {
         }
     }
 
+    /// Creates a new keypair for this node, with a NodeInfo linking to the
+    /// current key, signed by the current key.
+    /// The new configuration is stored and used on the next start of the node,
+    /// which also changes its ID.
+    /// Until then the node keeps running with the current ID, which stays valid.
+    /// Only the restarted node publishes the new NodeInfo, and other nodes then
+    /// drop the NodeInfo of the current key.
+    pub async fn rotate_key(&mut self) -> Result<NodeConfig, NodeError> {
+        if self.node_config.read_only {
+            return Err(NodeError::ReadOnly);
+        }
+        let config = self.node_config.rotate();
+        self.storage.set(STORAGE_CONFIG, &config.encode()).await?;
+        Ok(config)
+    }
+
+    /// Sends the stored crash reports to the collector of the
+    /// [`CrashConfig`], and returns how many reports have been sent.
+    /// The reports are kept until the collector acknowledges them.
+    pub fn submit_crash_reports(&mut self) -> Result<usize, NodeError> {
+        match self.crash.as_mut() {
+            Some(c) => Ok(c.submit()?),
+            None => Err(NodeError::Missing("CrashReporter".into())),
+        }
+    }
+
+    /// Adds a bot answering the chat messages as this node.
+    pub async fn add_bot(&mut self, bot: Bot) -> Result<(), NodeError> {
+        if self.node_config.read_only {
+            return Err(NodeError::ReadOnly);
+        }
+        match self.gossip.as_mut() {
+            Some(g) => Ok(g.add_bot(bot).await?),
+            None => Err(NodeError::Missing("Gossip".into())),
+        }
+    }
+
     // Reads the gossip configuration and stores it in the gossip-storage.
+    // A read-only node doesn't add its own NodeInfo.
     async fn init_gossip(
         gossip: &mut GossipBroker,
-        gossip_storage: Box<dyn DataStorage>,
-        node_info: &NodeInfo,
+        gossip_storage: &dyn DataStorageAsync,
+        node_config: &NodeConfig,
+        policy: &Policy,
     ) -> Result<(), NodeError> {
-        let gossip_msgs_str = gossip_storage.get(STORAGE_GOSSIP_EVENTS).unwrap();
+        let gossip_msgs_str = gossip_storage.get(STORAGE_GOSSIP_EVENTS).await?;
         if !gossip_msgs_str.is_empty() {
             if let Err(e) = gossip.storage.set(&gossip_msgs_str) {
                 log::warn!("Couldn't load gossip messages: {}", e);
             }
         }
-        gossip.storage.add_event(Event {
-            category: Category::NodeInfo,
-            src: node_info.get_id(),
-            created: now(),
-            msg: node_info.encode(),
-        });
+        let mut limits = gossip.storage.limits();
+        policy.restrict_events(&mut limits);
+        gossip.storage.set_limits(limits);
+        if !node_config.read_only {
+            gossip.storage.add_event(
+                Event {
+                    category: Category::NodeInfo,
+                    src: node_config.info.get_id(),
+                    created: now(),
+                    msg: node_config.info.encode(),
+                    signature: None,
+                }
+                .signed(node_config),
+            );
+        }
         gossip
             .broker
             .emit_msg(GossipMessage::Input(GossipIn::SetStorage(
@@ -293,18 +768,165 @@ impl Node {
         let mut config = NodeConfig::decode(&config_str)?;
         #[cfg(target_family = "wasm")]
         let enable_webproxy_request = false;
-        // Only unix based clients can send http GET requests.
+        // Only unix based clients can send http GET requests, and only if the
+        // operator allows it and the firewall lets the requests through.
         #[cfg(target_family = "unix")]
-        let enable_webproxy_request = true;
+        let enable_webproxy_request = Self::get_policy(storage.as_ref())?.webproxy_exit
+            && !Self::get_firewall(storage.as_ref())?.denies_module(web_proxy_broker::MODULE_NAME);
 
         config
             .info
             .modules
             .set(Modules::ENABLE_WEBPROXY_REQUESTS, enable_webproxy_request);
+        // Older configurations don't have any capabilities stored.
+        config.info.capabilities = Capabilities::supported();
         Self::set_config(storage, &config.encode())?;
         Ok(config)
     }
 
+    /// Fetches the firewall rules. They are stored as yaml, so that operators can
+    /// change them without recompiling the node.
+    /// Invalid rules return an error, so the node doesn't start without them.
+    pub fn get_firewall(storage: &dyn DataStorage) -> Result<FirewallConfig, NodeError> {
+        let config_str = storage.get(STORAGE_FIREWALL).unwrap_or_default();
+        if config_str.is_empty() {
+            return Ok(FirewallConfig::default());
+        }
+        Ok(FirewallConfig::from_yaml(&config_str)?)
+    }
+
+    /// Fetches the policy of the operator. It is stored as yaml, like the
+    /// firewall rules.
+    /// An invalid policy returns an error, so the node doesn't start without it.
+    pub fn get_policy(storage: &dyn DataStorage) -> Result<Policy, NodeError> {
+        let config_str = storage.get(STORAGE_POLICY).unwrap_or_default();
+        if config_str.is_empty() {
+            return Ok(Policy::default());
+        }
+        Ok(Policy::from_yaml(&config_str)?)
+    }
+
+    /// Fetches where the crash reports go. It is stored as yaml, like the
+    /// firewall rules.
+    pub fn get_crash_config(storage: &dyn DataStorage) -> CrashConfig {
+        let config_str = storage.get(STORAGE_CRASH_CONFIG).unwrap_or_default();
+        if config_str.is_empty() {
+            return CrashConfig::default();
+        }
+        CrashConfig::from_yaml(&config_str).unwrap_or_else(|e| {
+            log::warn!("Couldn't load crash report configuration, not sending reports: {e}");
+            CrashConfig::default()
+        })
+    }
+
+    /// Fetches the configuration of the web proxy, e.g., to only serve an
+    /// allow-list of URLs. It is stored as yaml, like the firewall rules.
+    /// If there is no stored configuration, a node with the [`NodeRole::Proxy`]
+    /// accepts more requests per node than the default.
+    pub fn get_webproxy_config(
+        storage: &dyn DataStorage,
+        role: Option<NodeRole>,
+    ) -> WebProxyConfig {
+        let config_str = storage.get(STORAGE_WEBPROXY_CONFIG).unwrap_or_default();
+        if config_str.is_empty() {
+            let mut config = WebProxyConfig::default();
+            if role == Some(NodeRole::Proxy) {
+                config.quota_requests = PROXY_QUOTA_REQUESTS;
+            }
+            return config;
+        }
+        WebProxyConfig::from_yaml(&config_str).unwrap_or_else(|e| {
+            log::warn!("Couldn't load web proxy configuration, using default: {e}");
+            WebProxyConfig::default()
+        })
+    }
+
+    /// Returns a signed archive of the stored events of the category created in
+    /// the range `from..to`, without starting the node.
+    /// A read-only node has no key to sign the archive.
+    pub fn gossip_export(
+        storage: &dyn DataStorage,
+        cat: Category,
+        from: i64,
+        to: i64,
+    ) -> Result<EventsArchive, NodeError> {
+        let node_config = NodeConfig::decode(&storage.get(STORAGE_CONFIG)?)?;
+        if node_config.read_only {
+            return Err(NodeError::ReadOnly);
+        }
+        let events = Self::stored_events(storage)?;
+        Ok(EventsArchive::new(
+            &node_config,
+            now(),
+            events.export(cat, from, to),
+        ))
+    }
+
+    /// Adds the valid events of a verified archive to the stored events, without
+    /// starting the node. Returns how many events have been added.
+    pub fn gossip_import(
+        storage: &mut dyn DataStorage,
+        archive: &EventsArchive,
+    ) -> Result<usize, NodeError> {
+        let mut events = Self::stored_events(storage)?;
+        let mut added = 0;
+        for event in archive.valid_events() {
+            if events.add_event(event) {
+                added += 1;
+            }
+        }
+        storage.set(STORAGE_GOSSIP_EVENTS, &events.get()?)?;
+        Ok(added)
+    }
+
+    /// Removes old data from the storage without starting the node, and stores
+    /// the rest in the latest format:
+    /// - NodeInfos created before `before`, except the one of this node
+    /// - entries of the web proxy audit log older than `before`
+    ///
+    /// The events are also trimmed to the current limits.
+    pub fn gc(storage: &mut dyn DataStorage, before: i64) -> Result<GcReport, NodeError> {
+        let mut report = GcReport::default();
+        let config_str = storage.get(STORAGE_CONFIG)?;
+        let keep = if config_str.is_empty() {
+            vec![]
+        } else {
+            vec![NodeConfig::decode(&config_str)?.info.get_id()]
+        };
+
+        let events_str = storage.get(STORAGE_GOSSIP_EVENTS)?;
+        if !events_str.is_empty() {
+            let mut events = EventsStorage::new();
+            events.set(&events_str)?;
+            report.node_infos = events.remove_before(Category::NodeInfo, before, &keep);
+            events.set_limits(events.limits());
+            let events_str_new = events.get()?;
+            report.bytes_before += events_str.len();
+            report.bytes_after += events_str_new.len();
+            storage.set(STORAGE_GOSSIP_EVENTS, &events_str_new)?;
+        }
+
+        let proxy_str = storage.get(web_proxy_broker::MODULE_NAME)?;
+        if !proxy_str.is_empty() {
+            let mut proxy = WebProxyStorageSave::from_str(&proxy_str)?;
+            report.audit_entries = proxy.remove_before(before);
+            let proxy_str_new = proxy.to_yaml()?;
+            report.bytes_before += proxy_str.len();
+            report.bytes_after += proxy_str_new.len();
+            storage.set(web_proxy_broker::MODULE_NAME, &proxy_str_new)?;
+        }
+        Ok(report)
+    }
+
+    fn stored_events(storage: &dyn DataStorage) -> Result<EventsStorage, NodeError> {
+        let mut events = EventsStorage::new();
+        let events_str = storage.get(STORAGE_GOSSIP_EVENTS)?;
+        if !events_str.is_empty() {
+            events.set(&events_str)?;
+        }
+        Ok(events)
+    }
+
     /// Updates the config of the node
     pub fn set_config(mut storage: Box<dyn DataStorage>, config: &str) -> Result<(), NodeError> {
         storage.set(STORAGE_CONFIG, config)?;
@@ -314,13 +936,24 @@ impl Node {
 
 #[cfg(test)]
 mod tests {
-    use flarch::{data_storage::DataStorageTemp, start_logging};
-    use flmodules::gossip_events::{
-        core::{Category, Event},
-        messages::GossipIn,
+    use flarch::{
+        data_storage::DataStorageTemp,
+        start_logging,
+        web_rtc::{
+            messages::{ConnType, SendAdvice, SignalingState, TransportStats, BUFFER_HIGH_BYTES},
+            node_connection::Direction,
+        },
+    };
+    use flmodules::{
+        gossip_events::{
+            core::{Category, Event},
+            messages::GossipIn,
+        },
+        network::messages::{ConnStats, NetworkConnectionState, NetworkOut},
     };
 
     use super::*;
+    use crate::stat::Traffic;
 
     #[tokio::test]
     async fn test_storage() -> Result<(), Box<dyn std::error::Error>> {
@@ -334,7 +967,9 @@ mod tests {
             src: nc.info.get_id(),
             created: 0,
             msg: "something".into(),
-        };
+            signature: None,
+        }
+        .signed(&nc);
         nd.gossip
             .as_mut()
             .unwrap()
@@ -365,4 +1000,288 @@ mod tests {
         assert_eq!(1, node.gossip.unwrap().events(Category::NodeInfo).len());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_metrics() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut node = Node::start(
+            Box::new(DataStorageTemp::new()),
+            NodeConfig::new(),
+            Broker::new(),
+        )
+        .await?;
+        node.update();
+        let metrics = node.metrics().await.to_string();
+        assert!(metrics.contains("fledger_gossip_events{category=\"NodeInfo\"} 1\n"));
+        assert!(metrics.contains("fledger_gossip_events_invalid_total{category=\"NodeInfo\"} 0\n"));
+        assert!(node.gossip.as_ref().unwrap().events(Category::NodeInfo)[0].verify());
+        assert!(metrics.contains("fledger_broker_pending{broker=\"network\"} "));
+        assert!(metrics.contains("# TYPE fledger_webproxy_requests_total counter\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_traffic() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut node = Node::start(
+            Box::new(DataStorageTemp::new()),
+            NodeConfig::new(),
+            Broker::new(),
+        )
+        .await?;
+        let (relayed, direct) = (NodeID::rnd(), NodeID::rnd());
+        for (id, type_local, rx_bytes) in [
+            (relayed, ConnType::TURN, 100),
+            (relayed, ConnType::TURN, 300),
+            (direct, ConnType::Host, 50),
+            // A new connection starts counting at 0 again.
+            (relayed, ConnType::Host, 20),
+        ] {
+            node.broker_net
+                .settle_msg(
+                    NetworkOut::ConnectionState(NetworkConnectionState {
+                        id,
+                        dir: Direction::Outgoing,
+                        s: ConnStats {
+                            type_local,
+                            type_remote: ConnType::Host,
+                            signaling: SignalingState::Stable,
+                            rx_bytes,
+                            tx_bytes: 0,
+                            delay_ms: 0,
+                            delayed: 0,
+                            dropped: 0,
+                            nat: None,
+                        },
+                    })
+                    .into(),
+                )
+                .await?;
+        }
+        let stats = TransportStats {
+            bitrate_bps: 8_000,
+            buffered_bytes: BUFFER_HIGH_BYTES as u64,
+            ..Default::default()
+        };
+        node.broker_net
+            .settle_msg(NetworkOut::ConnectionStats(relayed, Direction::Outgoing, stats).into())
+            .await?;
+        node.update();
+        let stat = node.stat.as_ref().unwrap();
+        assert_eq!(
+            Traffic {
+                direct: 70,
+                turn: 300
+            },
+            stat.traffic_total()
+        );
+        assert_eq!(SendAdvice::After(1000), stat.can_send(&relayed, 1000));
+        assert_eq!(SendAdvice::Now, stat.can_send(&direct, 1000));
+        let metrics = node.metrics().await.to_string();
+        assert!(metrics.contains("fledger_network_bytes_total{path=\"turn\"} 300\n"));
+        assert!(metrics.contains(&format!(
+            "fledger_network_turn_bytes_total{{node=\"{relayed}\"}} 300\n"
+        )));
+        assert!(metrics.contains(&format!(
+            "fledger_network_buffered_bytes{{node=\"{relayed}\"}} {BUFFER_HIGH_BYTES}\n"
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc() -> Result<(), Box<dyn std::error::Error>> {
+        let mut storage = DataStorageTemp::new();
+        let nc = NodeConfig::new();
+        storage.set(STORAGE_CONFIG, &nc.encode())?;
+        let mut events = EventsStorage::new();
+        for (signer, created) in [
+            (nc.clone(), 0),
+            (NodeConfig::new(), 0),
+            (NodeConfig::new(), 2000),
+        ] {
+            events.add_event(
+                Event {
+                    category: Category::NodeInfo,
+                    src: signer.info.get_id(),
+                    created,
+                    msg: "info".into(),
+                    signature: None,
+                }
+                .signed(&signer),
+            );
+        }
+        storage.set(STORAGE_GOSSIP_EVENTS, &events.get()?)?;
+
+        let report = Node::gc(&mut storage, 1000)?;
+        assert_eq!(1, report.node_infos);
+        assert_eq!(0, report.audit_entries);
+        assert!(report.reclaimed() > 0);
+        let events = Node::stored_events(&storage)?;
+        assert_eq!(2, events.events(Category::NodeInfo).len());
+
+        let report = Node::gc(&mut storage, 1000)?;
+        assert_eq!(0, report.node_infos);
+        assert_eq!(0, report.reclaimed());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let storage = DataStorageTemp::new();
+        let mut node =
+            Node::start(storage.clone(), NodeConfig::new_read_only(), Broker::new()).await?;
+        node.update();
+        assert_eq!(
+            0,
+            node.gossip
+                .as_ref()
+                .unwrap()
+                .events(Category::NodeInfo)
+                .len()
+        );
+        assert!(matches!(
+            node.add_chat_message("hello".into()).await,
+            Err(NodeError::ReadOnly)
+        ));
+
+        Node::set_config(storage.clone(), &node.node_config.encode())?;
+        assert!(matches!(
+            Node::gossip_export(&storage, Category::TextMessage, 0, i64::MAX),
+            Err(NodeError::ReadOnly)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_policy() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut storage = DataStorageTemp::new();
+        storage.set(STORAGE_POLICY, "webproxy_exit: false\nmax_events: 10")?;
+        let nc = Node::get_config(storage.clone())?;
+        assert!(!nc.info.modules.contains(Modules::ENABLE_WEBPROXY_REQUESTS));
+
+        let mut node = Node::start(storage.clone(), nc, Broker::new()).await?;
+        node.update();
+        assert_eq!(
+            Some(10),
+            node.gossip.as_ref().unwrap().storage.limits().max_total
+        );
+
+        // An invalid policy doesn't let the node start.
+        storage.set(STORAGE_POLICY, "webproxy_exit: never")?;
+        assert!(Node::get_config(storage.clone()).is_err());
+        assert!(Node::start(storage.clone(), NodeConfig::new(), Broker::new())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_firewall() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut storage = DataStorageTemp::new();
+        storage.set(
+            STORAGE_FIREWALL,
+            "rules:\n- action: Deny\n  module: WebProxy\n",
+        )?;
+        let nc = Node::get_config(storage.clone())?;
+        assert!(!nc.info.modules.contains(Modules::ENABLE_WEBPROXY_REQUESTS));
+
+        // Invalid rules don't let the node start.
+        storage.set(STORAGE_FIREWALL, "rules: allow everything")?;
+        assert!(Node::get_config(storage.clone()).is_err());
+        assert!(Node::start(storage.clone(), nc, Broker::new())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let mut node = Node::start(
+            Box::new(DataStorageTemp::new()),
+            NodeConfig::new(),
+            Broker::new(),
+        )
+        .await?;
+        let snapshot = node.snapshot();
+        assert_eq!(0, snapshot.borrow().version);
+        node.update();
+        assert_eq!(1, snapshot.borrow().version);
+        assert_eq!(
+            Some(&1),
+            snapshot.borrow().gossip_events.get(&Category::NodeInfo)
+        );
+        assert!(snapshot.borrow().webproxy.is_some());
+
+        // Not more than one snapshot per interval.
+        node.update();
+        assert_eq!(1, snapshot.borrow().version);
+        node.snapshot_interval_ms = 0;
+        node.update();
+        assert_eq!(2, snapshot.borrow().version);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotate_key() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let storage = DataStorageTemp::new();
+        let nc = NodeConfig::new();
+        let mut node = Node::start(storage.clone(), nc.clone(), Broker::new()).await?;
+        node.update();
+        let old_id = nc.info.get_id();
+        assert!(node.nodes_info_all()?.contains_key(&old_id));
+
+        // The running node keeps its ID until it is restarted.
+        let rotated = node.rotate_key().await?;
+        node.update();
+        let infos = node.nodes_info_all()?;
+        assert!(infos.contains_key(&old_id));
+        assert!(!infos.contains_key(&rotated.info.get_id()));
+        assert_eq!(old_id, node.node_config.info.get_id());
+        node.shutdown().await?;
+
+        let mut node = Node::start(
+            storage.clone(),
+            Node::get_config(storage.clone())?,
+            Broker::new(),
+        )
+        .await?;
+        node.update();
+        assert_eq!(rotated.info.get_id(), node.node_config.info.get_id());
+        let infos = node.nodes_info_all()?;
+        assert!(!infos.contains_key(&old_id));
+        assert_eq!(
+            Some(old_id),
+            infos.get(&rotated.info.get_id()).unwrap().previous_id()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<(), Box<dyn std::error::Error>> {
+        start_logging();
+
+        let storage = DataStorageTemp::new();
+        let mut node = Node::start(storage.clone(), NodeConfig::new(), Broker::new()).await?;
+        let mut net = node.broker_net.clone();
+        let (mut tap, _) = net.get_tap().await?;
+        node.add_chat_message("bye".into()).await?;
+        node.shutdown().await?;
+
+        assert!(storage.get(STORAGE_GOSSIP_EVENTS)?.contains("bye"));
+        while tap.recv().await.is_some() {}
+        assert!(net.emit_msg(NetworkIn::Tick.into()).is_err());
+        Ok(())
+    }
 }
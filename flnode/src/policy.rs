@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+use flmodules::{gossip_events::core::EventsLimits, web_proxy::core::WebProxyConfig};
+
+/// What the operator declares this node will not do.
+/// Missing fields in a serialized policy take their default values, which
+/// allow everything.
+///
+/// Requests refused because of the policy are answered with a reason, e.g.,
+/// [`flmodules::web_proxy::core::RequestError::NoExit`], so the requester
+/// can tell the user why instead of waiting for a timeout.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Policy {
+    /// Whether this node fetches URLs for other nodes with the web proxy.
+    pub webproxy_exit: bool,
+    /// Maximum number of gossip events this node stores over all categories.
+    pub max_events: Option<usize>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            webproxy_exit: true,
+            max_events: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_yaml(data: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(data)
+    }
+
+    /// Restricts the web proxy configuration to this policy.
+    pub fn restrict_webproxy(&self, config: &mut WebProxyConfig) {
+        config.exit &= self.webproxy_exit;
+    }
+
+    /// Restricts the gossip limits to this policy.
+    pub fn restrict_events(&self, limits: &mut EventsLimits) {
+        if let Some(max) = self.max_events {
+            limits.max_total = Some(limits.max_total.map_or(max, |total| total.min(max)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restrict() -> Result<(), serde_yaml::Error> {
+        let mut webproxy = WebProxyConfig::default();
+        let mut limits = EventsLimits::default();
+        Policy::default().restrict_webproxy(&mut webproxy);
+        Policy::default().restrict_events(&mut limits);
+        assert!(webproxy.exit);
+        assert_eq!(None, limits.max_total);
+
+        let policy = Policy::from_yaml("webproxy_exit: false\nmax_events: 1000")?;
+        policy.restrict_webproxy(&mut webproxy);
+        policy.restrict_events(&mut limits);
+        assert!(!webproxy.exit);
+        assert_eq!(Some(1000), limits.max_total);
+
+        limits.max_total = Some(10);
+        policy.restrict_events(&mut limits);
+        assert_eq!(Some(10), limits.max_total);
+        Ok(())
+    }
+}
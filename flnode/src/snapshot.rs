@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use flarch::{nodeids::NodeID, web_rtc::messages::TransportStats};
+use flmodules::{
+    gossip_events::core::Category, network::messages::NetworkConnectionState, ping::core::PingStat,
+    web_proxy::core::Counters,
+};
+
+use crate::{node::Node, stat::Traffic};
+
+/// The state of all modules of a node at one point in time, for user interfaces.
+/// It is sent by [`Node::snapshot`] at most every [`Node::snapshot_interval_ms`],
+/// so a user interface only needs to watch one channel.
+/// Fields of disabled modules are empty.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NodeSnapshot {
+    /// Increases by one with every new snapshot.
+    pub version: u64,
+    /// When the snapshot was taken, in milliseconds.
+    pub time: i64,
+    /// Nodes known to be online, from random_connections.
+    pub nodes_online: usize,
+    /// Nodes this node is connected to, from random_connections.
+    pub nodes_connected: usize,
+    /// The state of the connection to every node, from the network.
+    pub connections: HashMap<NodeID, NetworkConnectionState>,
+    /// The traffic over direct connections and through TURN servers.
+    pub traffic: Traffic,
+    /// The transport statistics of the WebRTC connection to every node.
+    pub transport: HashMap<NodeID, TransportStats>,
+    /// The number of stored gossip events per category.
+    pub gossip_events: HashMap<Category, usize>,
+    /// The ping statistics of every connected node.
+    pub pings: HashMap<NodeID, PingStat>,
+    /// The requests handled by the web proxy.
+    pub webproxy: Option<Counters>,
+}
+
+impl NodeSnapshot {
+    /// Collects the current state of the modules of the node.
+    pub fn new(node: &mut Node, version: u64, time: i64) -> Self {
+        let mut snapshot = Self {
+            version,
+            time,
+            ..Self::default()
+        };
+        if let Some(r) = node.random.as_ref() {
+            snapshot.nodes_online = r.storage.known.0.len();
+            snapshot.nodes_connected = r.storage.connected.get_nodes().0.len();
+        }
+        if let Some(s) = node.stat.as_ref() {
+            snapshot.connections = s.states.clone();
+            snapshot.traffic = s.traffic_total();
+            snapshot.transport = s.transport.clone();
+        }
+        if let Some(g) = node.gossip.as_ref() {
+            snapshot.gossip_events = [Category::TextMessage, Category::NodeInfo]
+                .into_iter()
+                .map(|c| (c, g.events(c).len()))
+                .collect();
+        }
+        if let Some(p) = node.ping.as_ref() {
+            snapshot.pings = p.storage.stats.clone();
+        }
+        snapshot.webproxy = node.webproxy.as_mut().map(|w| w.get_counters());
+        snapshot
+    }
+}
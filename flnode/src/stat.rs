@@ -1,14 +1,31 @@
 use std::{collections::HashMap, sync::mpsc::Receiver};
 
-use flmodules::network::messages::{NetworkOut, NetworkConnectionState, NetworkMessage};
 use flarch::{
     broker::{Broker, BrokerError},
     nodeids::U256,
+    web_rtc::messages::{SendAdvice, TransportStats},
 };
+use flmodules::network::messages::{
+    NetworkConnectionState, NetworkMessage, NetworkOut, SetupStats,
+};
+
+/// The bytes sent and received with a node, over direct connections and
+/// through a TURN server.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    pub direct: u64,
+    pub turn: u64,
+}
 
 /// Collects the statistics of the connections sent by the network broker.
 pub struct StatBroker {
     pub states: HashMap<U256, NetworkConnectionState>,
+    /// The number of pending and established connections
+    pub setups: SetupStats,
+    /// The traffic with every node since the start, also over past connections.
+    pub traffic: HashMap<U256, Traffic>,
+    /// The latest transport statistics of the WebRTC connection to every node.
+    pub transport: HashMap<U256, TransportStats>,
     tap: Receiver<NetworkMessage>,
 }
 
@@ -17,15 +34,60 @@ impl StatBroker {
         let (tap, _) = broker_net.get_tap_sync().await?;
         Ok(Self {
             states: HashMap::new(),
+            setups: SetupStats::default(),
+            traffic: HashMap::new(),
+            transport: HashMap::new(),
             tap,
         })
     }
 
     pub fn update(&mut self) {
-        for msg in self.tap.try_iter() {
-            if let NetworkMessage::Output(NetworkOut::ConnectionState(state)) = msg {
-                self.states.insert(state.id, state);
+        let msgs: Vec<NetworkMessage> = self.tap.try_iter().collect();
+        for msg in msgs {
+            match msg {
+                NetworkMessage::Output(NetworkOut::ConnectionState(state)) => {
+                    self.add_traffic(&state);
+                    self.states.insert(state.id, state);
+                }
+                NetworkMessage::Output(NetworkOut::SetupStats(setups)) => self.setups = setups,
+                NetworkMessage::Output(NetworkOut::ConnectionStats(id, _, stats)) => {
+                    self.transport.insert(id, stats);
+                }
+                _ => {}
             }
         }
     }
+
+    /// Returns whether `bytes` can be sent to the node now, or how long its data
+    /// channels need to drain, according to the latest transport statistics.
+    /// Nodes without statistics can always be sent to.
+    pub fn can_send(&self, id: &U256, bytes: usize) -> SendAdvice {
+        self.transport
+            .get(id)
+            .map_or(SendAdvice::Now, |t| t.can_send(bytes))
+    }
+
+    /// Returns the sum of the traffic with all nodes.
+    pub fn traffic_total(&self) -> Traffic {
+        self.traffic
+            .values()
+            .fold(Traffic::default(), |a, t| Traffic {
+                direct: a.direct + t.direct,
+                turn: a.turn + t.turn,
+            })
+    }
+
+    // The byte counters start at 0 for every new connection.
+    fn add_traffic(&mut self, state: &NetworkConnectionState) {
+        let bytes = |s: &NetworkConnectionState| s.s.rx_bytes + s.s.tx_bytes;
+        let new = bytes(state);
+        let old = self.states.get(&state.id).map(bytes).unwrap_or(0);
+        let delta = if new >= old { new - old } else { new };
+        let traffic = self.traffic.entry(state.id).or_default();
+        if state.s.relayed() {
+            traffic.turn += delta;
+        } else {
+            traffic.direct += delta;
+        }
+    }
 }
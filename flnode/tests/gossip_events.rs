@@ -1,5 +1,5 @@
 use flarch::{nodeids::U256, start_logging_filter_level};
-use flmodules::{gossip_events::core, Modules};
+use flmodules::{gossip_events::core, nodeconfig::NodeConfig, Modules};
 
 mod helpers;
 use helpers::*;
@@ -59,12 +59,15 @@ async fn gossip(nbr_nodes: usize) -> Result<(), NetworkError> {
 }
 
 async fn add_chat_message(net: &mut NetworkSimul, id: &U256, step: i32) {
+    let nc = NodeConfig::new();
     let msg = core::Event {
         category: core::Category::TextMessage,
-        src: U256::rnd(),
+        src: nc.info.get_id(),
         created: step as i64,
         msg: "msg".into(),
-    };
+        signature: None,
+    }
+    .signed(&nc);
     let node_timer = net.nodes.get_mut(id).expect("getting node");
     node_timer
         .node
@@ -8,7 +8,7 @@ use flarch::{
 };
 use flmodules::{
     nodeconfig::{NodeConfig, NodeInfo},
-    timer::TimerMessage,
+    timer::VirtualTimer,
     Modules,
 };
 use flmodules::network::messages::{NetworkIn, NetworkOut, NetworkMessage};
@@ -51,7 +51,7 @@ impl NetworkSimul {
 
     pub async fn tick(&mut self) -> Result<(), NetworkError> {
         for node in self.nodes.values_mut() {
-            node.timer.settle_msg(TimerMessage::Second).await?;
+            node.timer.advance(1000).await?;
         }
         Ok(())
     }
@@ -134,13 +134,16 @@ impl NetworkSimul {
                     (id_dst, NetworkOut::Connected(*id).into()),
                 ]
             }
-            NetworkMessage::Input(NetworkIn::MessageToNode(from_id, msg_str)) => vec![(
+            NetworkMessage::Input(
+                NetworkIn::MessageToNode(from_id, msg_str)
+                | NetworkIn::MessageToNodeChannel(from_id, _, msg_str),
+            ) => vec![(
                 from_id,
                 NetworkOut::MessageFromNode(id.clone(), msg_str).into(),
             )],
             NetworkMessage::WebRTC(WebRTCConnMessage::InputNC(
                 id_dst,
-                NCInput::Text(msg_node),
+                NCInput::Text(msg_node) | NCInput::TextChannel(_, msg_node),
             )) => {
                 vec![(
                     id_dst.clone(),
@@ -158,7 +161,7 @@ impl NetworkSimul {
 
 pub struct NodeTimer {
     pub node: Node,
-    pub timer: Broker<TimerMessage>,
+    pub timer: VirtualTimer,
 }
 
 impl NodeTimer {
@@ -181,14 +184,14 @@ impl NodeTimer {
             node_config = NodeConfig::new();
         }
         node_config.info.modules = modules;
-        let mut node_data = Node::start(
+        let timer = VirtualTimer::start().await?;
+        let node_data = Node::start_with_timer(
             Box::new(DataStorageTemp::new()),
             node_config,
             broker_net.clone(),
+            timer.broker.clone(),
         )
         .await?;
-        let timer = Broker::new();
-        node_data.add_timer(timer.clone()).await;
 
         Ok(Self {
             node: node_data,